@@ -0,0 +1,144 @@
+//! Benchmarks of the serving pipeline: path resolution, encoding negotiation, ETag computation
+//! and compression. Run natively with `make bench`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use spin_static_fs::{bench, SupportedEncoding};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const ENCODINGS: [SupportedEncoding; 4] = [
+    SupportedEncoding::None,
+    SupportedEncoding::Gzip,
+    SupportedEncoding::Deflate,
+    SupportedEncoding::Brotli,
+];
+
+/// A directory of files of typical sizes, removed when dropped.
+struct Fixtures(PathBuf);
+
+impl Fixtures {
+    fn new() -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("spin-fileserver-bench-{}", std::process::id()));
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        let page = "<p>The quick brown fox jumps over the lazy dog.</p>\n".repeat(40);
+        fs::write(dir.join("docs").join("index.html"), &page).unwrap();
+        let log = (0..20_000)
+            .map(|i| {
+                format!(
+                    "2024-01-01T00:00:{:02}Z GET /assets/{i}.js 200 {}\n",
+                    i % 60,
+                    i * 7
+                )
+            })
+            .collect::<String>();
+        fs::write(dir.join("access.log"), log).unwrap();
+        Self(dir)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+
+    fn files(&self) -> [(&'static str, PathBuf); 2] {
+        [
+            ("page", self.path("docs/index.html")),
+            ("log", self.path("access.log")),
+        ]
+    }
+}
+
+impl Drop for Fixtures {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn size(file: &Path) -> u64 {
+    fs::metadata(file).unwrap().len()
+}
+
+fn resolution(c: &mut Criterion) {
+    let fixtures = Fixtures::new();
+    let mut group = c.benchmark_group("resolve");
+    for (name, path) in [
+        ("file", fixtures.path("access.log")),
+        ("directory", fixtures.path("docs")),
+        ("missing", fixtures.path("missing.html")),
+    ] {
+        let path = path.to_str().unwrap().to_string();
+        group.bench_function(name, |b| b.iter(|| bench::resolve(black_box(&path))));
+    }
+    group.finish();
+}
+
+fn negotiation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("negotiate");
+    for (name, accept_encoding) in [
+        ("browser", "gzip, deflate, br, zstd"),
+        ("weighted", "gzip;q=0.8, br;q=1.0, deflate;q=0.5, *;q=0.1"),
+        ("identity", "identity"),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter(|| bench::negotiate(black_box(accept_encoding)))
+        });
+    }
+    group.finish();
+}
+
+fn etags(c: &mut Criterion) {
+    let fixtures = Fixtures::new();
+    let mut group = c.benchmark_group("etag");
+    for (name, file) in fixtures.files() {
+        group.throughput(Throughput::Bytes(size(&file)));
+        for encoding in ENCODINGS {
+            group.bench_with_input(
+                BenchmarkId::new(format!("hash/{encoding:?}"), name),
+                &file,
+                |b, file| b.iter(|| bench::etag(file, encoding).unwrap()),
+            );
+        }
+    }
+
+    // ETags precomputed by spin-fileserver-precompress are looked up instead of hashing files
+    let manifest = fixtures.path("etags.json");
+    let etags = fixtures.files().map(|(_, file)| {
+        let etags = serde_json::json!({ "identity": "\"0\"" });
+        (file.to_str().unwrap().to_string(), etags)
+    });
+    fs::write(
+        &manifest,
+        serde_json::Value::Object(etags.into_iter().collect()).to_string(),
+    )
+    .unwrap();
+    std::env::set_var("ETAG_MANIFEST", &manifest);
+    for (name, file) in fixtures.files() {
+        group.throughput(Throughput::Bytes(size(&file)));
+        group.bench_with_input(BenchmarkId::new("manifest", name), &file, |b, file| {
+            b.iter(|| bench::etag(file, SupportedEncoding::None).unwrap())
+        });
+    }
+    std::env::remove_var("ETAG_MANIFEST");
+    group.finish();
+}
+
+fn compression(c: &mut Criterion) {
+    let fixtures = Fixtures::new();
+    let mut group = c.benchmark_group("body");
+    for (name, file) in fixtures.files() {
+        group.throughput(Throughput::Bytes(size(&file)));
+        for encoding in ENCODINGS {
+            group.bench_with_input(
+                BenchmarkId::new(format!("{encoding:?}"), name),
+                &file,
+                |b, file| b.iter(|| bench::body(file, encoding).unwrap()),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, resolution, negotiation, etags, compression);
+criterion_main!(benches);
@@ -9,12 +9,13 @@ use {
 
 mod bindings {
     wit_bindgen::generate!({
-        path: "../wit",
-        world: "delegate",
+        path: "../../wit",
+        world: "fermyon:spin-fileserver/delegate",
         with: {
-            "wasi:http/types@0.2.0-rc-2023-10-18": spin_sdk::wit::wasi::http::types,
-            "wasi:io/streams@0.2.0-rc-2023-10-18": spin_sdk::wit::wasi::io::streams,
-            "wasi:io/poll@0.2.0-rc-2023-10-18": spin_sdk::wit::wasi::io,
+            "wasi:http/types@0.2.0": spin_sdk::wit::wasi::http::types,
+            "wasi:io/streams@0.2.0": spin_sdk::wit::wasi::io::streams,
+            "wasi:io/error@0.2.0": spin_sdk::wit::wasi::io::error,
+            "wasi:io/poll@0.2.0": spin_sdk::wit::wasi::io::poll,
         }
     });
 }
@@ -23,10 +24,10 @@ mod bindings {
 async fn handle_request(request: IncomingRequest, response_out: ResponseOutparam) {
     match (request.method(), request.path_with_query().as_deref()) {
         (Method::Get, Some("/hello")) => {
-            let response = OutgoingResponse::new(
-                200,
-                &Fields::new(&[("content-type".to_string(), b"text/plain".to_vec())]),
-            );
+            let headers = Fields::new();
+            let _ = headers.append(&"content-type".to_string(), &b"text/plain".to_vec());
+            let response = OutgoingResponse::new(headers);
+            let _ = response.set_status_code(200);
 
             let mut body = response.take_body();
 
@@ -43,7 +44,9 @@ async fn handle_request(request: IncomingRequest, response_out: ResponseOutparam
         }
 
         _ => {
-            response_out.set(OutgoingResponse::new(405, &Fields::new(&[])));
+            let response = OutgoingResponse::new(Fields::new());
+            let _ = response.set_status_code(405);
+            response_out.set(response);
         }
     }
 }
@@ -0,0 +1,10 @@
+//! Values of the `Accept-Encoding` header.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use spin_static_fs::fuzz;
+
+fuzz_target!(|value: &[u8]| {
+    fuzz::accept_encoding(value);
+});
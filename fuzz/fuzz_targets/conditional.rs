@@ -0,0 +1,17 @@
+//! Values of the `Range` header, optionally followed by a newline and the value of `If-Range`,
+//! evaluated for representations of various lengths.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use spin_static_fs::fuzz;
+
+fuzz_target!(|data: &[u8]| {
+    let (range, if_range) = match data.iter().position(|&b| b == b'\n') {
+        Some(newline) => (&data[..newline], Some(&data[newline + 1..])),
+        None => (data, None),
+    };
+    for len in [0, 1, 1024, u64::MAX] {
+        fuzz::conditional(range, if_range, "\"4dca0fd5\"", len);
+    }
+});
@@ -0,0 +1,18 @@
+//! Request headers, one `name: value` per line.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use spin_static_fs::fuzz;
+
+fuzz_target!(|data: &[u8]| {
+    let headers = data
+        .split(|&b| b == b'\n')
+        .filter_map(|line| {
+            let colon = line.iter().position(|&b| b == b':')?;
+            let name = String::from_utf8_lossy(&line[..colon]).trim().to_lowercase();
+            Some((name, line[colon + 1..].trim_ascii().to_vec()))
+        })
+        .collect::<Vec<_>>();
+    fuzz::request_headers(&headers);
+});
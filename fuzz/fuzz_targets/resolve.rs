@@ -0,0 +1,10 @@
+//! Request paths, as they reach the file server after routing.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use spin_static_fs::fuzz;
+
+fuzz_target!(|path: &str| {
+    fuzz::resolve(path);
+});
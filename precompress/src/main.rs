@@ -0,0 +1,170 @@
+//! Precompress the assets served by the file server at build time.
+//!
+//! Walks an asset directory, writes `.br` and `.gz` siblings next to every file that compresses
+//! well, and writes the ETag manifest the file server reads from `ETAG_MANIFEST`.
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Default file name of the manifest, written to the root of the asset directory
+const MANIFEST_FILENAME: &str = "spin-fileserver-etags.json";
+/// Brotli quality, as high as it goes since compression only happens once
+const BROTLI_QUALITY: u32 = 11;
+/// Brotli window size
+const BROTLI_WINDOW: u32 = 22;
+/// Extensions of the precompressed siblings, which are also the encoding names in the manifest
+const PRECOMPRESSED_EXTENSIONS: [&str; 2] = ["br", "gz"];
+
+fn main() -> Result<()> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let (dir, manifest) = match args.as_slice() {
+        [dir] => (
+            PathBuf::from(dir),
+            PathBuf::from(dir).join(MANIFEST_FILENAME),
+        ),
+        [dir, manifest] => (PathBuf::from(dir), PathBuf::from(manifest)),
+        _ => bail!("usage: spin-fileserver-precompress <ASSET_DIR> [MANIFEST]"),
+    };
+    let etags = precompress(&dir, &manifest)?;
+    fs::write(&manifest, serde_json::to_vec_pretty(&etags)?)
+        .with_context(|| format!("cannot write {}", manifest.display()))?;
+    println!(
+        "Precompressed {} files, wrote {}",
+        etags.len(),
+        manifest.display()
+    );
+    Ok(())
+}
+
+/// Precompress every file under `dir` and return the ETags of each file by encoding.
+/// Files are keyed by their path relative to `dir`, with a leading `/` as they are mounted by Spin.
+fn precompress(
+    dir: &Path,
+    manifest: &Path,
+) -> Result<BTreeMap<String, BTreeMap<&'static str, String>>> {
+    let mut etags = BTreeMap::new();
+    for file in walk(dir)? {
+        let is_sibling = file
+            .extension()
+            .is_some_and(|ext| PRECOMPRESSED_EXTENSIONS.iter().any(|e| ext == *e));
+        if is_sibling || file == manifest {
+            continue;
+        }
+        let contents =
+            fs::read(&file).with_context(|| format!("cannot read {}", file.display()))?;
+        let mut file_etags = BTreeMap::from([("identity", etag(&contents))]);
+        for ext in PRECOMPRESSED_EXTENSIONS {
+            let sibling = sibling(&file, ext);
+            let compressed = compress(&contents, ext)?;
+            // only keep compressed files that are worth it, otherwise compress on the fly
+            if compressed.len() < contents.len() {
+                fs::write(&sibling, &compressed)
+                    .with_context(|| format!("cannot write {}", sibling.display()))?;
+                file_etags.insert(ext, etag(&compressed));
+            } else if sibling.exists() {
+                fs::remove_file(&sibling)?;
+            }
+        }
+
+        let relative = file.strip_prefix(dir)?;
+        let key = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .fold(String::new(), |key, c| key + "/" + &c);
+        etags.insert(key, file_etags);
+    }
+    Ok(etags)
+}
+
+fn compress(contents: &[u8], ext: &str) -> Result<Vec<u8>> {
+    let mut compressed = Vec::new();
+    match ext {
+        "br" => {
+            let mut writer = brotli::CompressorWriter::new(
+                &mut compressed,
+                64 * 1024,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            );
+            writer.write_all(contents)?;
+        }
+        _ => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(&mut compressed, flate2::Compression::best());
+            encoder.write_all(contents)?;
+            encoder.finish()?;
+        }
+    }
+    Ok(compressed)
+}
+
+/// The ETag the file server computes for a response body.
+fn etag(contents: &[u8]) -> String {
+    hex::encode(Sha256::digest(contents))
+}
+
+fn sibling(file: &Path, ext: &str) -> PathBuf {
+    let mut sibling = file.as_os_str().to_owned();
+    sibling.push(".");
+    sibling.push(ext);
+    PathBuf::from(sibling)
+}
+
+/// List all files under a directory, recursively.
+fn walk(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("cannot read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(walk(&path)?);
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_precompress() {
+        let dir = std::env::temp_dir().join(format!("precompress-{}", std::process::id()));
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        let html = "<p>hello</p>".repeat(100);
+        fs::write(dir.join("nested/index.html"), &html).unwrap();
+        fs::write(dir.join("tiny.txt"), "a").unwrap();
+
+        let manifest = dir.join(MANIFEST_FILENAME);
+        let etags = precompress(&dir, &manifest).unwrap();
+        let html_etags = &etags["/nested/index.html"];
+        assert_eq!(html_etags["identity"], etag(html.as_bytes()));
+
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(fs::File::open(dir.join("nested/index.html.gz")).unwrap())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, html);
+        assert_eq!(
+            html_etags["br"],
+            etag(&fs::read(dir.join("nested/index.html.br")).unwrap())
+        );
+
+        // compressing a single byte doesn't pay off
+        assert_eq!(etags["/tiny.txt"].len(), 1);
+        assert!(!dir.join("tiny.txt.gz").exists());
+
+        // running again skips the siblings written before
+        assert_eq!(precompress(&dir, &manifest).unwrap(), etags);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+//! Ordered access rules from the configuration file, matching requests on a path glob, methods
+//! and headers, so sites with several protected or retired areas declare them in one place
+//! instead of combining settings.
+//!
+//! The first matching rule decides: it allows the request, denies it, requires a bearer token or
+//! redirects it. Requests no rule matches are served as usual.
+
+use http::{HeaderName, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{admin, config, plain_response, FileServer, RequestContext, Response};
+
+/// A rule deciding what happens to the requests it matches.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct AccessRule {
+    /// Glob matched against the request path, where `*` and `?` match within a path segment and
+    /// `**` matches any number of segments.
+    pub path: String,
+    /// Methods the rule applies to, or all methods if empty.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Headers requests must carry for the rule to apply, with their exact value or `*` for any.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// What to do with matching requests.
+    pub action: Access,
+    /// Bearer token required by `auth-required` rules.
+    #[serde(default, serialize_with = "config::redact")]
+    pub token: Option<String>,
+    /// Location `redirect` rules redirect to with `302 Found`.
+    #[serde(default)]
+    pub location: Option<String>,
+}
+
+/// What to do with a request matched by an access rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Access {
+    /// Serve the request, skipping later rules.
+    Allow,
+    /// Respond with `403 Forbidden`.
+    Deny,
+    /// Respond with `401 Unauthorized` unless the request carries the rule's bearer token.
+    AuthRequired,
+    /// Redirect to the rule's location.
+    Redirect,
+}
+
+impl AccessRule {
+    /// Check whether the rule applies to a request for a path.
+    fn matches(&self, req: &RequestContext, path: &str) -> bool {
+        glob_match(&self.path, path)
+            && (self.methods.is_empty()
+                || self
+                    .methods
+                    .iter()
+                    .any(|m| m.eq_ignore_ascii_case(req.method.as_str())))
+            && self.headers.iter().all(|(name, expected)| {
+                let value = HeaderName::from_bytes(name.as_bytes())
+                    .ok()
+                    .and_then(|name| req.header(&name));
+                match value {
+                    Some(_) if expected == "*" => true,
+                    Some(value) => value == expected.as_bytes(),
+                    None => false,
+                }
+            })
+    }
+
+    /// Check that the rule has the fields its action needs and valid methods and header names.
+    pub fn validate(&self) -> Result<(), String> {
+        match self.action {
+            Access::AuthRequired if self.token.as_deref().unwrap_or_default().is_empty() => {
+                return Err(format!("auth-required rule for {} has no token", self.path))
+            }
+            Access::Redirect if self.location.is_none() => {
+                return Err(format!("redirect rule for {} has no location", self.path))
+            }
+            _ => {}
+        }
+        for method in &self.methods {
+            Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                .map_err(|_| format!("{method:?} is not a valid method"))?;
+        }
+        for name in self.headers.keys() {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("{name:?} is not a valid header name"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Apply the first rule matching a request for a path, returning the response to send instead
+/// of serving the request, if any.
+pub(crate) fn check(rules: &[AccessRule], req: &RequestContext, path: &str) -> Option<Response> {
+    let rule = rules.iter().find(|rule| rule.matches(req, path))?;
+    match rule.action {
+        Access::Allow => None,
+        Access::Deny => Some(plain_response(StatusCode::FORBIDDEN)),
+        Access::AuthRequired => {
+            // rules without a token lock everyone out rather than letting everyone in
+            let token = rule.token.as_deref().unwrap_or_default();
+            (!admin::is_authorized(req, token)).then(admin::unauthorized)
+        }
+        Access::Redirect => {
+            let location = rule.location.as_deref().unwrap_or("/");
+            // don't redirect requests for the redirect target itself
+            (!config::same_path(location, path))
+                .then(|| FileServer::redirect(req, StatusCode::FOUND, location))
+        }
+    }
+}
+
+/// Match a request path against a glob, segment by segment.
+pub(crate) fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern = pattern
+        .trim_start_matches('/')
+        .split('/')
+        .collect::<Vec<_>>();
+    let path = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+    match_segments(&pattern, &path)
+}
+
+fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match (pattern.first(), path.first()) {
+        (None, None) => true,
+        (Some(&"**"), _) => {
+            match_segments(&pattern[1..], path)
+                || (!path.is_empty() && match_segments(pattern, &path[1..]))
+        }
+        (Some(p), Some(s)) => {
+            let p = p.chars().collect::<Vec<_>>();
+            let s = s.chars().collect::<Vec<_>>();
+            match_segment(&p, &s) && match_segments(&pattern[1..], &path[1..])
+        }
+        _ => false,
+    }
+}
+
+fn match_segment(pattern: &[char], segment: &[char]) -> bool {
+    match (pattern.first(), segment.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            match_segment(&pattern[1..], segment)
+                || (!segment.is_empty() && match_segment(pattern, &segment[1..]))
+        }
+        (Some('?'), Some(_)) => match_segment(&pattern[1..], &segment[1..]),
+        (Some(p), Some(s)) if p == s => match_segment(&pattern[1..], &segment[1..]),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("/admin/**", "/admin/users/1.json"));
+        assert!(glob_match("/admin/**", "/admin"));
+        assert!(glob_match("/**/*.map", "/js/app.js.map"));
+        assert!(glob_match("**/*.map", "app.js.map"));
+        assert!(glob_match("/drafts/post-?.html", "/drafts/post-1.html"));
+        assert!(!glob_match("/drafts/post-?.html", "/drafts/post-10.html"));
+        assert!(!glob_match("/admin/*", "/admin/users/1.json"));
+        assert!(!glob_match("/admin/**", "/administrator"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let rules: Vec<AccessRule> = toml::from_str::<HashMap<String, Vec<AccessRule>>>(
+            r#"
+            access = [
+                { path = "/internal/**", action = "auth-required" },
+                { path = "/old/**", action = "redirect" },
+                { path = "/api/**", methods = ["BAD METHOD"], action = "deny" },
+                { path = "/**", headers = { "X Bad" = "*" }, action = "allow" },
+                { path = "/**", methods = ["post"], action = "deny" },
+            ]
+            "#,
+        )
+        .unwrap()
+        .remove("access")
+        .unwrap();
+        let problems = rules
+            .iter()
+            .filter_map(|r| r.validate().err())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            [
+                "auth-required rule for /internal/** has no token",
+                "redirect rule for /old/** has no location",
+                r#""BAD METHOD" is not a valid method"#,
+                r#""X Bad" is not a valid header name"#,
+            ]
+        );
+    }
+}
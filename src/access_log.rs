@@ -0,0 +1,213 @@
+//! Access logs written to stdout, as JSON or in the Apache common/combined log formats.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::settings;
+
+/// Environment variable for the access log format (`json`, `common` or `combined`)
+pub(crate) const ACCESS_LOG_ENV: &str = "ACCESS_LOG";
+/// Abbreviated month names used by the common log format
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// The format of access log lines.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub(crate) enum AccessLogFormat {
+    /// One JSON object per line.
+    Json,
+    /// The Apache common log format.
+    Common,
+    /// The Apache combined log format, which adds the referrer and user agent.
+    Combined,
+}
+
+/// The details of a served request.
+#[derive(Debug)]
+pub(crate) struct AccessLogEntry<'a> {
+    pub remote_addr: Option<&'a str>,
+    pub time: SystemTime,
+    pub method: &'a str,
+    pub target: &'a str,
+    pub status: u16,
+    pub bytes: usize,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+}
+
+impl AccessLogFormat {
+    /// Return the configured format, if access logs are enabled.
+    pub fn from_env() -> Option<Self> {
+        match settings::var(ACCESS_LOG_ENV)?
+            .trim()
+            .to_lowercase()
+            .as_str()
+        {
+            "json" => Some(Self::Json),
+            "common" => Some(Self::Common),
+            "combined" => Some(Self::Combined),
+            _ => None,
+        }
+    }
+
+    /// Format an entry as a single log line.
+    pub fn format(self, entry: &AccessLogEntry) -> String {
+        let ((year, month, day), time) = civil_time(entry.time);
+        match self {
+            Self::Json => serde_json::json!({
+                "time": format!("{year:04}-{month:02}-{day:02}T{time}Z"),
+                "remote_addr": entry.remote_addr,
+                "method": entry.method,
+                "target": entry.target,
+                "status": entry.status,
+                "bytes": entry.bytes,
+                "referer": entry.referer,
+                "user_agent": entry.user_agent,
+            })
+            .to_string(),
+            Self::Common | Self::Combined => {
+                let mut line = format!(
+                    "{} - - [{day:02}/{}/{year}:{time} +0000] \"{} {} HTTP/1.1\" {} {}",
+                    entry.remote_addr.unwrap_or("-"),
+                    MONTHS[month as usize - 1],
+                    entry.method,
+                    escape(entry.target),
+                    entry.status,
+                    match entry.bytes {
+                        0 => "-".to_string(),
+                        bytes => bytes.to_string(),
+                    },
+                );
+                if self == Self::Combined {
+                    line.push_str(&format!(
+                        " \"{}\" \"{}\"",
+                        escape(entry.referer.unwrap_or("-")),
+                        escape(entry.user_agent.unwrap_or("-"))
+                    ));
+                }
+                line
+            }
+        }
+    }
+}
+
+/// Strip the port from a client address like `127.0.0.1:1234` or `[::1]:1234`.
+pub(crate) fn strip_port(addr: &str) -> &str {
+    if let Some(rest) = addr.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') && port.parse::<u16>().is_ok() => host,
+        _ => addr,
+    }
+}
+
+/// Escape quotes and backslashes so fields can't break out of their quoted section.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Split a time into its UTC (year, month, day) and `HH:MM:SS`.
+pub(crate) fn civil_time(time: SystemTime) -> ((i64, u32, u32), String) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    let (days, secs_of_day) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let time = format!(
+        "{:02}:{:02}:{:02}",
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    );
+
+    // civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    ((year, month, day), time)
+}
+
+/// Days since the Unix epoch of a UTC date (Howard Hinnant's algorithm).
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn entry() -> AccessLogEntry<'static> {
+        AccessLogEntry {
+            remote_addr: Some("127.0.0.1"),
+            // 2000-10-10T13:55:36Z
+            time: UNIX_EPOCH + Duration::from_secs(971186136),
+            method: "GET",
+            target: "/apache_pb.gif?x=\"y\"",
+            status: 200,
+            bytes: 2326,
+            referer: Some("http://www.example.com/start.html"),
+            user_agent: None,
+        }
+    }
+
+    #[test]
+    fn test_common_log_format() {
+        assert_eq!(
+            AccessLogFormat::Common.format(&entry()),
+            r#"127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /apache_pb.gif?x=\"y\" HTTP/1.1" 200 2326"#
+        );
+        assert_eq!(
+            AccessLogFormat::Combined.format(&AccessLogEntry {
+                bytes: 0,
+                ..entry()
+            }),
+            r#"127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /apache_pb.gif?x=\"y\" HTTP/1.1" 200 - "http://www.example.com/start.html" "-""#
+        );
+    }
+
+    #[test]
+    fn test_json_log_format() {
+        let line: serde_json::Value =
+            serde_json::from_str(&AccessLogFormat::Json.format(&entry())).unwrap();
+        assert_eq!(line["time"], "2000-10-10T13:55:36Z");
+        assert_eq!(line["status"], 200);
+        assert_eq!(line["user_agent"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_civil_time() {
+        assert_eq!(
+            civil_time(UNIX_EPOCH),
+            ((1970, 1, 1), "00:00:00".to_string())
+        );
+        assert_eq!(
+            civil_time(UNIX_EPOCH + Duration::from_secs(951782400)),
+            ((2000, 2, 29), "00:00:00".to_string())
+        );
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 2, 29), 951782400 / 86400);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+    }
+
+    #[test]
+    fn test_strip_port() {
+        assert_eq!(strip_port("127.0.0.1:1234"), "127.0.0.1");
+        assert_eq!(strip_port("[::1]:1234"), "::1");
+        assert_eq!(strip_port("::1"), "::1");
+        assert_eq!(strip_port("example.com"), "example.com");
+    }
+}
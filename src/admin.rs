@@ -0,0 +1,252 @@
+//! Administrative routes under `/__fileserver/`, authenticated with a bearer token.
+
+use anyhow::Result;
+use http::{
+    header::{ACCEPT, ALLOW, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, WWW_AUTHENTICATE},
+    Method, StatusCode,
+};
+use serde::Serialize;
+use std::{io::Cursor, path::Path};
+
+use crate::{
+    analytics, asset_manifest::AssetManifest, backend, checksums, downloads, drafts, etag_cache,
+    languages, metadata, plain_response, purge_webhook, quota, releases, request_url::RequestUrl,
+    secrets, self_check, settings, sitemap, version, FileServer, RequestContext, Response,
+};
+
+/// Prefix of the admin routes, relative to the component route
+pub(crate) const ADMIN_PREFIX: &str = "/__fileserver/";
+/// Environment variable for the bearer token protecting the admin routes
+pub(crate) const ADMIN_TOKEN_ENV: &str = "ADMIN_TOKEN";
+/// Prefix of the route describing files, followed by the file path
+const STAT_ROUTE: &str = "stat/";
+/// Route streaming the checksum manifest of all files, or of a directory appended to it
+const CHECKSUMS_ROUTE: &str = "checksums";
+/// Route listing the releases, or activating the release appended to it
+const RELEASES_ROUTE: &str = "releases";
+/// Route issuing preview tokens for the drafts, or for those under the path appended to it
+const PREVIEW_ROUTE: &str = "preview";
+/// Route listing, or deleting, the files of the key-value backend that don't match their digest
+const INTEGRITY_ROUTE: &str = "integrity";
+
+/// Handle a request for an admin route.
+/// Returns `None` if the path isn't an admin route or the admin routes aren't enabled.
+pub(crate) fn handle(req: &RequestContext, path: &str) -> Option<Result<Response>> {
+    let route = path.strip_prefix(ADMIN_PREFIX)?;
+    let token = settings::var(ADMIN_TOKEN_ENV)?;
+    if !is_authorized(req, &token) {
+        return Some(Ok(unauthorized()));
+    }
+
+    Some(match route {
+        "downloads" => downloads::download_counts().and_then(|counts| json_response(&counts)),
+        "bandwidth" => quota::report().and_then(|report| json_response(&report)),
+        "analytics" => analytics::report().and_then(|report| {
+            if accepts_html(req) {
+                Ok(analytics::dashboard(&report))
+            } else {
+                json_response(&report)
+            }
+        }),
+        "etag-cache" if req.method == Method::DELETE => etag_cache::purge().and_then(|deleted| {
+            purge_webhook::notify(&purge_webhook::Purge::All);
+            json_response(&serde_json::json!({ "deleted": deleted }))
+        }),
+        "etag-cache" => {
+            let (status, mut headers, body) = plain_response(StatusCode::METHOD_NOT_ALLOWED);
+            headers.push((ALLOW.as_str().to_string(), b"DELETE".to_vec()));
+            Ok((status, headers, body))
+        }
+        "config" => settings::effective().and_then(|config| json_response(&config)),
+        version::VERSION_ROUTE => {
+            version::Version::current().and_then(|version| json_response(&version))
+        }
+        "check" => json_response(&serde_json::json!({ "problems": self_check::check() })),
+        "warm" if etag_cache::enabled() => {
+            FileServer::warm(req, Path::new("/")).and_then(|(warmed, errors)| {
+                json_response(&serde_json::json!({ "warmed": warmed, "errors": errors }))
+            })
+        }
+        "sitemap" => sitemap::check(req).and_then(|report| match report {
+            Some(report) => json_response(&report),
+            None => Ok(plain_response(StatusCode::NOT_FOUND)),
+        }),
+        _ if route.starts_with(STAT_ROUTE) => stat(req, &route[STAT_ROUTE.len()..]),
+        RELEASES_ROUTE => releases::list().and_then(|releases| match releases {
+            Some(releases) => json_response(&releases),
+            None => Ok(plain_response(StatusCode::NOT_FOUND)),
+        }),
+        _ if route.starts_with(&format!("{RELEASES_ROUTE}/")) => {
+            activate_release(req, &route[RELEASES_ROUTE.len() + 1..])
+        }
+        PREVIEW_ROUTE => drafts::issue(req, ""),
+        _ if route.starts_with(&format!("{PREVIEW_ROUTE}/")) => {
+            drafts::issue(req, &route[PREVIEW_ROUTE.len()..])
+        }
+        INTEGRITY_ROUTE => integrity(req),
+        CHECKSUMS_ROUTE => checksum_manifest(""),
+        _ if route.starts_with(&format!("{CHECKSUMS_ROUTE}/")) => {
+            checksum_manifest(&route[CHECKSUMS_ROUTE.len()..])
+        }
+        _ => Ok(plain_response(StatusCode::NOT_FOUND)),
+    })
+}
+
+/// Respond with the metadata of the file a path resolves to.
+fn stat(req: &RequestContext, path: &str) -> Result<Response> {
+    let resolved = FileServer::resolve(path, &[languages::default_language()]);
+    let assets = AssetManifest::from_env()?;
+    let url = RequestUrl::of(req);
+    match metadata::describe(path, &resolved, assets.as_ref(), url.base())? {
+        Some(metadata) => json_response(&metadata),
+        None => Ok(plain_response(StatusCode::NOT_FOUND)),
+    }
+}
+
+/// Make a release the active one, responding with the releases.
+fn activate_release(req: &RequestContext, version: &str) -> Result<Response> {
+    if req.method != Method::PUT {
+        let (status, mut headers, body) = plain_response(StatusCode::METHOD_NOT_ALLOWED);
+        headers.push((ALLOW.as_str().to_string(), b"PUT".to_vec()));
+        return Ok((status, headers, body));
+    }
+    if !releases::activate(version)? {
+        return Ok(plain_response(StatusCode::NOT_FOUND));
+    }
+    match releases::list()? {
+        Some(releases) => json_response(&releases),
+        None => Ok(plain_response(StatusCode::NOT_FOUND)),
+    }
+}
+
+/// Respond with the corrupted files of the key-value backend, deleting them on `DELETE` requests
+/// so they can be uploaded again.
+fn integrity(req: &RequestContext) -> Result<Response> {
+    let delete = match req.method {
+        Method::GET | Method::HEAD => false,
+        Method::DELETE => true,
+        _ => {
+            let (status, mut headers, body) = plain_response(StatusCode::METHOD_NOT_ALLOWED);
+            headers.push((ALLOW.as_str().to_string(), b"GET, HEAD, DELETE".to_vec()));
+            return Ok((status, headers, body));
+        }
+    };
+    match backend::corrupted(delete)? {
+        Some(files) if delete => json_response(&serde_json::json!({ "deleted": files })),
+        Some(files) => json_response(&serde_json::json!({ "corrupted": files })),
+        None => Ok(plain_response(StatusCode::NOT_FOUND)),
+    }
+}
+
+/// Respond with the checksum manifest of a directory of the served files.
+fn checksum_manifest(dir: &str) -> Result<Response> {
+    let dir = dir.trim_matches('/');
+    if dir.split('/').any(|segment| segment == "..") {
+        return Ok(plain_response(StatusCode::BAD_REQUEST));
+    }
+    let root = Path::new("/");
+    let dir = root.join(dir);
+    let backend = backend::current();
+    if !backend.is_dir(&dir) {
+        return Ok(plain_response(StatusCode::NOT_FOUND));
+    }
+    let manifest = checksums::Manifest::new(backend, root, &dir)?;
+    Ok((
+        StatusCode::OK,
+        vec![
+            (
+                CONTENT_TYPE.as_str().to_string(),
+                checksums::MANIFEST_CONTENT_TYPE.as_bytes().to_vec(),
+            ),
+            (CACHE_CONTROL.as_str().to_string(), b"no-store".to_vec()),
+        ],
+        Some(Box::new(manifest)),
+    ))
+}
+
+/// Check the bearer token of the request against the accepted values of a secret.
+pub(crate) fn is_authorized(req: &RequestContext, token: &str) -> bool {
+    req.header(&AUTHORIZATION)
+        .and_then(|v| v.strip_prefix(b"Bearer "))
+        .is_some_and(|v| secrets::matches(v, token))
+}
+
+/// Build the response to requests without a valid bearer token.
+pub(crate) fn unauthorized() -> Response {
+    let (status, mut headers, body) = plain_response(StatusCode::UNAUTHORIZED);
+    headers.push((WWW_AUTHENTICATE.as_str().to_string(), b"Bearer".to_vec()));
+    (status, headers, body)
+}
+
+/// Check whether the request prefers an HTML page, as browsers do.
+fn accepts_html(req: &RequestContext) -> bool {
+    req.header(&ACCEPT)
+        .is_some_and(|v| String::from_utf8_lossy(v).contains("text/html"))
+}
+
+/// Build an uncacheable JSON response.
+pub(crate) fn json_response(value: &impl Serialize) -> Result<Response> {
+    let body = serde_json::to_vec_pretty(value)?;
+    Ok((
+        StatusCode::OK,
+        vec![
+            (
+                CONTENT_TYPE.as_str().to_string(),
+                b"application/json".to_vec(),
+            ),
+            (CACHE_CONTROL.as_str().to_string(), b"no-store".to_vec()),
+        ],
+        Some(Box::new(Cursor::new(body))),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::BTreeMap, io::Read};
+
+    fn with_authorization(value: &[u8]) -> RequestContext {
+        RequestContext {
+            headers: vec![(AUTHORIZATION.to_string(), value.to_vec())],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_is_authorized() {
+        assert!(is_authorized(
+            &with_authorization(b"Bearer s3cret"),
+            "s3cret"
+        ));
+        assert!(!is_authorized(
+            &with_authorization(b"Bearer wrong"),
+            "s3cret"
+        ));
+        assert!(!is_authorized(&with_authorization(b"s3cret"), "s3cret"));
+        assert!(!is_authorized(&with_authorization(b"Bearer "), ""));
+        assert!(!is_authorized(&RequestContext::default(), "s3cret"));
+        // rotated tokens accept the previous value too
+        assert!(is_authorized(
+            &with_authorization(b"Bearer 0ld"),
+            "s3cret 0ld"
+        ));
+        assert!(!is_authorized(
+            &with_authorization(b"Bearer s3cret 0ld"),
+            "s3cret 0ld"
+        ));
+    }
+
+    #[test]
+    fn test_json_response() {
+        let (status, headers, body) =
+            json_response(&BTreeMap::from([("/release.zip", 3)])).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains(&(CONTENT_TYPE.to_string(), b"application/json".to_vec())));
+        let mut body_str = String::new();
+        body.unwrap().read_to_string(&mut body_str).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body_str).unwrap(),
+            serde_json::json!({ "/release.zip": 3 })
+        );
+    }
+}
@@ -0,0 +1,301 @@
+//! Cookie-less aggregate analytics: daily page view counts by path and referrer, kept in the
+//! default key-value store.
+//!
+//! Nothing identifying a visitor is recorded: no cookies are set, client addresses and user
+//! agents are ignored and referrers are reduced to their host. Requests sending `DNT: 1` or
+//! `Sec-GPC: 1` aren't counted at all.
+//!
+//! Views are counted by the file served rather than the requested path, and referrer hosts are
+//! capped per day, so clients can't grow the store by requesting ever new paths that resolve to
+//! a fallback document or sending ever new referrers.
+
+use anyhow::{Context, Result};
+use http::{
+    header::{CACHE_CONTROL, CONTENT_TYPE, DNT, HOST, REFERER},
+    HeaderName, Method, StatusCode,
+};
+use serde::Serialize;
+use std::{collections::BTreeMap, io::Cursor, str, time::SystemTime};
+
+use crate::{
+    access_log::civil_time, downloads::parse_count, host::Store, settings, RequestContext, Response,
+};
+
+/// Environment variable enabling page view analytics
+pub(crate) const ANALYTICS_ENV: &str = "ANALYTICS";
+/// Key-value store key prefix of the analytics counters
+const ANALYTICS_PREFIX: &str = "spin-fileserver:analytics:";
+/// Key-value store key suffix of the referrer counts of a day
+const REFERRERS_SUFFIX: &str = ":referrers";
+/// Maximum number of referrer hosts counted per day
+const MAX_REFERRERS: usize = 100;
+/// Name the views referred by hosts beyond [`MAX_REFERRERS`] are counted under
+const OTHER_REFERRERS: &str = "(other)";
+/// The Global Privacy Control header, which the `http` crate doesn't define
+const SEC_GPC: HeaderName = HeaderName::from_static("sec-gpc");
+
+/// Page view counts of a single day.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct DailyViews {
+    paths: BTreeMap<String, u64>,
+    referrers: BTreeMap<String, u64>,
+}
+
+/// Check whether analytics are enabled.
+pub(crate) fn enabled() -> bool {
+    settings::var(ANALYTICS_ENV).is_some_and(|v| v.trim() == "true")
+}
+
+/// Check whether a response counts as a page view, i.e. whether it serves an HTML document to a
+/// `GET` request.
+pub(crate) fn is_page_view(
+    req: &RequestContext,
+    status: StatusCode,
+    headers: &[(String, Vec<u8>)],
+) -> bool {
+    req.method == Method::GET
+        && (status == StatusCode::OK || status == StatusCode::NOT_MODIFIED)
+        && headers.iter().any(|(k, v)| {
+            k.eq_ignore_ascii_case(CONTENT_TYPE.as_str()) && v.starts_with(b"text/html")
+        })
+}
+
+/// Count a page view of the file at `path`, along with the host of its referrer.
+pub(crate) fn record(req: &RequestContext, path: &str) -> Result<()> {
+    if req.header(&DNT) == Some(b"1") || req.header(&SEC_GPC) == Some(b"1") {
+        return Ok(());
+    }
+    let store =
+        Store::open_default().context("cannot open the key-value store to record analytics")?;
+    let day = today();
+    increment(&store, &format!("{ANALYTICS_PREFIX}{day}:path:{path}"))?;
+
+    let own_host = req.header(&HOST).and_then(|h| str::from_utf8(h).ok());
+    let referrer = req
+        .header(&REFERER)
+        .and_then(|r| str::from_utf8(r).ok())
+        .and_then(referrer_host);
+    if let Some(referrer) = referrer.filter(|r| Some(*r) != own_host) {
+        let key = format!("{ANALYTICS_PREFIX}{day}{REFERRERS_SUFFIX}");
+        let mut referrers = parse_referrers(&store.get(&key)?.unwrap_or_default());
+        count_referrer(&mut referrers, referrer);
+        store.set(&key, &referrers_to_bytes(&referrers))?;
+    }
+    Ok(())
+}
+
+/// Return the page view counts of all recorded days.
+pub(crate) fn report() -> Result<BTreeMap<String, DailyViews>> {
+    let store =
+        Store::open_default().context("cannot open the key-value store to read analytics")?;
+    let mut report = BTreeMap::<String, DailyViews>::new();
+    for key in store.get_keys()? {
+        let Some(rest) = key.strip_prefix(ANALYTICS_PREFIX) else {
+            continue;
+        };
+        if let Some(day) = rest.strip_suffix(REFERRERS_SUFFIX) {
+            let referrers = parse_referrers(&store.get(&key)?.unwrap_or_default());
+            report.entry(day.to_string()).or_default().referrers = referrers;
+            continue;
+        }
+        let Some((day, path)) = parse_key(&key) else {
+            continue;
+        };
+        let count = parse_count(&store.get(&key)?.unwrap_or_default());
+        let views = report.entry(day.to_string()).or_default();
+        views.paths.insert(path.to_string(), count);
+    }
+    Ok(report)
+}
+
+/// Render the report as a minimal HTML dashboard, most recent day first.
+pub(crate) fn dashboard(report: &BTreeMap<String, DailyViews>) -> Response {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Page views</title></head>\n<body>\n<h1>Page views</h1>\n",
+    );
+    for (day, views) in report.iter().rev() {
+        html.push_str(&format!("<h2>{}</h2>\n", escape_html(day)));
+        for (title, counts) in [("Path", &views.paths), ("Referrer", &views.referrers)] {
+            if counts.is_empty() {
+                continue;
+            }
+            html.push_str(&format!(
+                "<table>\n<tr><th>{title}</th><th>Views</th></tr>\n"
+            ));
+            let mut counts = counts.iter().collect::<Vec<_>>();
+            counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            for (name, count) in counts {
+                html.push_str(&format!(
+                    "<tr><td>{}</td><td>{count}</td></tr>\n",
+                    escape_html(name)
+                ));
+            }
+            html.push_str("</table>\n");
+        }
+    }
+    html.push_str("</body>\n</html>\n");
+    (
+        StatusCode::OK,
+        vec![
+            (
+                CONTENT_TYPE.as_str().to_string(),
+                b"text/html; charset=utf-8".to_vec(),
+            ),
+            (CACHE_CONTROL.as_str().to_string(), b"no-store".to_vec()),
+        ],
+        Some(Box::new(Cursor::new(html.into_bytes()))),
+    )
+}
+
+fn increment(store: &Store, key: &str) -> Result<()> {
+    let count = parse_count(&store.get(key)?.unwrap_or_default());
+    store.set(key, (count + 1).to_string().as_bytes())?;
+    Ok(())
+}
+
+/// Split a page view counter key into its day and path.
+fn parse_key(key: &str) -> Option<(&str, &str)> {
+    let rest = key.strip_prefix(ANALYTICS_PREFIX)?;
+    let (day, rest) = rest.split_once(':')?;
+    Some((day, rest.strip_prefix("path:")?))
+}
+
+/// Parse the referrer counts of a day, stored as lines of `<count> <host>`.
+fn parse_referrers(bytes: &[u8]) -> BTreeMap<String, u64> {
+    str::from_utf8(bytes)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let (count, host) = line.split_once(' ')?;
+            Some((host.to_string(), count.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Count a view referred by a host, or by another host once [`MAX_REFERRERS`] are counted.
+fn count_referrer(referrers: &mut BTreeMap<String, u64>, host: &str) {
+    let host = if referrers.contains_key(host) || referrers.len() < MAX_REFERRERS {
+        host
+    } else {
+        OTHER_REFERRERS
+    };
+    *referrers.entry(host.to_string()).or_default() += 1;
+}
+
+fn referrers_to_bytes(referrers: &BTreeMap<String, u64>) -> Vec<u8> {
+    referrers
+        .iter()
+        .map(|(host, count)| format!("{count} {host}\n"))
+        .collect::<String>()
+        .into_bytes()
+}
+
+/// Reduce a referrer URL to its host.
+fn referrer_host(referer: &str) -> Option<&str> {
+    let (_, rest) = referer.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?;
+    (!host.is_empty()).then_some(host)
+}
+
+/// Today's UTC date as `YYYY-MM-DD`.
+fn today() -> String {
+    let ((year, month, day), _) = civil_time(SystemTime::now());
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_is_page_view() {
+        let html = vec![(
+            CONTENT_TYPE.to_string(),
+            b"text/html; charset=utf-8".to_vec(),
+        )];
+        let get = RequestContext::default();
+        assert!(is_page_view(&get, StatusCode::OK, &html));
+        assert!(is_page_view(&get, StatusCode::NOT_MODIFIED, &html));
+        assert!(!is_page_view(&get, StatusCode::NOT_FOUND, &html));
+        assert!(!is_page_view(
+            &get,
+            StatusCode::OK,
+            &[(CONTENT_TYPE.to_string(), b"image/png".to_vec())]
+        ));
+        let head = RequestContext {
+            method: Method::HEAD,
+            ..Default::default()
+        };
+        assert!(!is_page_view(&head, StatusCode::OK, &html));
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(
+            parse_key("spin-fileserver:analytics:2024-01-02:path:/a:b.html"),
+            Some(("2024-01-02", "/a:b.html"))
+        );
+        assert_eq!(
+            parse_key("spin-fileserver:analytics:2024-01-02:referrers"),
+            None
+        );
+        assert_eq!(parse_key("spin-fileserver:analytics:2024-01-02:x:y"), None);
+        assert_eq!(parse_key("spin-fileserver:downloads:/a"), None);
+    }
+
+    #[test]
+    fn test_referrers() {
+        let mut referrers = parse_referrers(b"3 example.com\n1 news.example.org\nbroken\n");
+        assert_eq!(referrers.len(), 2);
+        count_referrer(&mut referrers, "example.com");
+        assert_eq!(
+            parse_referrers(&referrers_to_bytes(&referrers))["example.com"],
+            4
+        );
+
+        for i in 0..MAX_REFERRERS {
+            count_referrer(&mut referrers, &format!("spam-{i}.example"));
+        }
+        assert_eq!(referrers.len(), MAX_REFERRERS + 1);
+        assert_eq!(referrers[OTHER_REFERRERS], 2);
+        count_referrer(&mut referrers, "example.com");
+        assert_eq!(referrers["example.com"], 5);
+    }
+
+    #[test]
+    fn test_referrer_host() {
+        assert_eq!(
+            referrer_host("https://user@example.com:8080/path?q#f"),
+            Some("example.com:8080")
+        );
+        assert_eq!(referrer_host("https://example.com"), Some("example.com"));
+        assert_eq!(referrer_host("not a url"), None);
+    }
+
+    #[test]
+    fn test_dashboard() {
+        let report = BTreeMap::from([(
+            "2024-01-02".to_string(),
+            DailyViews {
+                paths: BTreeMap::from([("/<script>".to_string(), 1), ("/".to_string(), 5)]),
+                referrers: BTreeMap::new(),
+            },
+        )]);
+        let (status, _, body) = dashboard(&report);
+        assert_eq!(status, StatusCode::OK);
+        let mut html = String::new();
+        body.unwrap().read_to_string(&mut html).unwrap();
+        assert!(html.contains("<tr><td>/</td><td>5</td></tr>\n<tr><td>/&lt;script&gt;</td>"));
+        assert!(!html.contains("Referrer"));
+    }
+}
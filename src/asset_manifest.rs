@@ -0,0 +1,222 @@
+//! Fingerprinted assets described by a manifest emitted by a build tool, mapping logical names
+//! such as `app.js` to hashed file names such as `app.3f2a9.js`.
+
+use anyhow::{Context, Result};
+use regex::{Captures, Regex};
+use std::{collections::HashMap, path::Path};
+
+use crate::settings;
+
+/// Environment variable for the path of the asset manifest
+pub(crate) const ASSET_MANIFEST_ENV: &str = "ASSET_MANIFEST";
+/// Environment variable for how logical names are served (`serve` or `redirect`)
+pub(crate) const ASSET_MANIFEST_MODE_ENV: &str = "ASSET_MANIFEST_MODE";
+/// Environment variable enabling the rewriting of references in HTML documents
+pub(crate) const ASSET_MANIFEST_REWRITE_ENV: &str = "ASSET_MANIFEST_REWRITE_HTML";
+/// The `Cache-Control` value of fingerprinted files, whose contents never change
+pub(crate) const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+/// Environment variable enabling immutable caching of files whose names look fingerprinted
+pub(crate) const IMMUTABLE_FINGERPRINTS_ENV: &str = "IMMUTABLE_FINGERPRINTS";
+/// Environment variable for the pattern of fingerprinted file names
+pub(crate) const FINGERPRINT_PATTERN_ENV: &str = "FINGERPRINT_PATTERN";
+/// Default pattern of fingerprinted file names, such as `app.3f2a9c1d.js`
+pub(crate) const DEFAULT_FINGERPRINT_PATTERN: &str = r"\.[0-9a-f]{8,}\.";
+
+/// The loaded asset manifest.
+#[derive(Debug, Default)]
+pub(crate) struct AssetManifest {
+    /// Hashed file names by logical name, both without a leading slash.
+    assets: HashMap<String, String>,
+    /// Whether requests for logical names are redirected instead of served.
+    pub redirect: bool,
+    /// Whether references in HTML documents are rewritten to the hashed file names.
+    rewrite_html: bool,
+}
+
+impl AssetManifest {
+    /// Load the manifest configured in the environment, if any.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(path) = settings::var(ASSET_MANIFEST_ENV) else {
+            return Ok(None);
+        };
+        let mut manifest = Self::read(&path)?;
+        manifest.redirect = settings::var(ASSET_MANIFEST_MODE_ENV)
+            .is_some_and(|m| m.trim().eq_ignore_ascii_case("redirect"));
+        manifest.rewrite_html =
+            settings::var(ASSET_MANIFEST_REWRITE_ENV).is_some_and(|v| v.trim() == "true");
+        Ok(Some(manifest))
+    }
+
+    /// Return the SHA-256 digest of the configured manifest, to tell which build it belongs to.
+    pub fn digest() -> Result<Option<String>> {
+        use sha2::Digest;
+        let Some(path) = settings::var(ASSET_MANIFEST_ENV) else {
+            return Ok(None);
+        };
+        let contents =
+            std::fs::read(&path).with_context(|| format!("cannot read asset manifest {path}"))?;
+        Ok(Some(hex::encode(sha2::Sha256::digest(contents))))
+    }
+
+    /// Read and parse the manifest at `path`.
+    pub fn read(path: &str) -> Result<Self> {
+        let contents =
+            std::fs::read(path).with_context(|| format!("cannot read asset manifest {path}"))?;
+        Self::parse(&contents).with_context(|| format!("invalid asset manifest {path}"))
+    }
+
+    /// Parse a manifest: a JSON object of logical names to hashed names, optionally nested in a
+    /// `files` object as emitted by some tools. Entries that aren't strings are ignored.
+    fn parse(contents: &[u8]) -> Result<Self> {
+        let mut json: serde_json::Value = serde_json::from_slice(contents)?;
+        if json.get("files").is_some_and(|f| f.is_object()) {
+            json = json["files"].take();
+        }
+        let assets = json
+            .as_object()
+            .context("expected a JSON object")?
+            .iter()
+            .filter_map(|(logical, hashed)| {
+                Some((
+                    normalize(logical).to_string(),
+                    normalize(hashed.as_str()?).to_string(),
+                ))
+            })
+            .collect();
+        Ok(Self {
+            assets,
+            ..Default::default()
+        })
+    }
+
+    /// Return the hashed file name of a logical request path, keeping its leading slash.
+    pub fn hashed(&self, path: &str) -> Option<String> {
+        let hashed = self.assets.get(normalize(path))?;
+        let prefix = if path.starts_with('/') { "/" } else { "" };
+        Some(format!("{prefix}{hashed}"))
+    }
+
+    /// Check whether a request path is a hashed file name.
+    pub fn is_hashed(&self, path: &str) -> bool {
+        let path = normalize(path);
+        self.assets.values().any(|hashed| hashed == path)
+    }
+
+    /// Check whether the contents of a file get rewritten.
+    pub fn rewrites(&self, file: &Path) -> bool {
+        self.rewrite_html
+            && file.extension().is_some_and(|ext| {
+                ext.eq_ignore_ascii_case("html") || ext.eq_ignore_ascii_case("htm")
+            })
+    }
+
+    /// Rewrite the `src` and `href` attributes of an HTML document that reference logical names.
+    pub fn rewrite(&self, html: &str) -> String {
+        let attribute = Regex::new(r#"(?i)\b(src|href)\s*=\s*("[^"]*"|'[^']*')"#)
+            .expect("valid attribute pattern");
+        attribute
+            .replace_all(html, |caps: &Captures| {
+                let quoted = &caps[2];
+                let (quote, value) = (&quoted[..1], &quoted[1..quoted.len() - 1]);
+                let (prefix, path) = match value.strip_prefix("./") {
+                    Some(path) => ("./", path),
+                    None => ("", value),
+                };
+                match self.hashed(path) {
+                    Some(hashed) => format!("{}={quote}{prefix}{hashed}{quote}", &caps[1]),
+                    None => caps[0].to_string(),
+                }
+            })
+            .into_owned()
+    }
+}
+
+/// Check whether the name of a file looks fingerprinted, without a manifest, if enabled.
+/// An invalid pattern is ignored.
+pub(crate) fn is_fingerprinted(file: &Path) -> bool {
+    if settings::var(IMMUTABLE_FINGERPRINTS_ENV)
+        .as_deref()
+        .map(str::trim)
+        != Some("true")
+    {
+        return false;
+    }
+    let pattern = settings::var(FINGERPRINT_PATTERN_ENV)
+        .unwrap_or_else(|| DEFAULT_FINGERPRINT_PATTERN.to_string());
+    match Regex::new(pattern.trim()) {
+        Ok(pattern) => matches_fingerprint(&pattern, file),
+        Err(e) => {
+            eprintln!("{FINGERPRINT_PATTERN_ENV} {pattern:?} is invalid, ignoring it: {e}");
+            false
+        }
+    }
+}
+
+fn matches_fingerprint(pattern: &Regex, file: &Path) -> bool {
+    file.file_name()
+        .is_some_and(|name| pattern.is_match(&name.to_string_lossy()))
+}
+
+fn normalize(path: &str) -> &str {
+    path.trim_start_matches('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> AssetManifest {
+        AssetManifest::parse(br#"{ "files": { "app.js": "/static/app.3f2a9.js", "main.css": "main.1b2c.css", "entrypoints": ["app.js"] } }"#)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_hashed() {
+        let manifest = manifest();
+        assert_eq!(
+            manifest.hashed("/app.js"),
+            Some("/static/app.3f2a9.js".to_string())
+        );
+        assert_eq!(
+            manifest.hashed("main.css"),
+            Some("main.1b2c.css".to_string())
+        );
+        assert_eq!(manifest.hashed("/entrypoints"), None);
+        assert!(manifest.is_hashed("/static/app.3f2a9.js"));
+        assert!(!manifest.is_hashed("/app.js"));
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let manifest = manifest();
+        assert_eq!(
+            manifest.rewrite(
+                r#"<script src="/app.js"></script><link href='./main.css'><a href="myapp.js">"#
+            ),
+            r#"<script src="/static/app.3f2a9.js"></script><link href='./main.1b2c.css'><a href="myapp.js">"#
+        );
+    }
+
+    #[test]
+    fn test_rewrites_html_only() {
+        let manifest = AssetManifest {
+            rewrite_html: true,
+            ..manifest()
+        };
+        assert!(manifest.rewrites(Path::new("/index.HTML")));
+        assert!(!manifest.rewrites(Path::new("/app.js")));
+        assert!(!AssetManifest::default().rewrites(Path::new("/index.html")));
+    }
+
+    #[test]
+    fn test_matches_fingerprint() {
+        let pattern = Regex::new(DEFAULT_FINGERPRINT_PATTERN).unwrap();
+        let matches = |file: &str| matches_fingerprint(&pattern, Path::new(file));
+        assert!(matches("/static/app.3f2a9c1d.js"));
+        assert!(matches("main.0123456789abcdef.chunk.css"));
+        assert!(!matches("/static/app.3f2a9.js"));
+        assert!(!matches("/releases/v1.2.3/app.js"));
+        assert!(!matches("/3f2a9c1d3f2a9c1d.d/app.js"));
+        assert!(!matches("/docs/index.html"));
+    }
+}
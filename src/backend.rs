@@ -0,0 +1,549 @@
+//! Storage backends the served files are read from: the directory mounted into the component by
+//! default, or a key-value store whose keys are the paths of the files.
+//!
+//! Applications using the file server as a library can register backends of their own, which are
+//! selected by name like the built-in ones.
+
+use sha2::{Digest, Sha256};
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+    fs::{self, File},
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    path::{Component, Path},
+    rc::Rc,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use crate::{error::FileServerError, host::Store, session_gate, settings};
+
+/// Environment variable for the name of the backend files are read from
+pub(crate) const STORAGE_BACKEND_ENV: &str = "STORAGE_BACKEND";
+/// Environment variable for the label of the key-value store of the `key-value` backend
+pub(crate) const STORAGE_STORE_ENV: &str = "STORAGE_STORE";
+/// The backend used when none is configured
+pub(crate) const DEFAULT_BACKEND: &str = "filesystem";
+/// The name of the key-value backend
+pub(crate) const KEY_VALUE_BACKEND: &str = "key-value";
+/// Prefix of the keys the file server keeps its own state under, never served as files
+pub(crate) const INTERNAL_KEY_PREFIX: &str = "spin-fileserver:";
+/// Environment variable requiring files of the key-value backend to match their stored digest
+pub(crate) const STORAGE_CHECKSUMS_ENV: &str = "STORAGE_CHECKSUMS";
+/// Key prefix of the SHA-256 digests stored along with the files of the key-value backend
+const CHECKSUM_PREFIX: &str = "spin-fileserver:sha256:";
+
+/// Values read from key-value stores in the current scope, by store label and key, including keys
+/// found missing. `None` outside of a scope.
+type Fetched = Option<HashMap<(String, String), Option<Rc<[u8]>>>>;
+
+thread_local! {
+    static FETCHED: RefCell<Fetched> = const { RefCell::new(None) };
+}
+
+/// Guard of the scope values read from key-value stores are kept in.
+pub(crate) struct Scope(());
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        FETCHED.with_borrow_mut(|fetched| *fetched = None);
+    }
+}
+
+/// Start a scope keeping the values read from key-value stores, for the duration of a request,
+/// so looking up whether a file exists, its size and its contents reads the value once.
+pub(crate) fn scope() -> Scope {
+    FETCHED.with_borrow_mut(|fetched| *fetched = Some(HashMap::new()));
+    Scope(())
+}
+
+/// A store of files, addressed by their paths relative to the root of the file server.
+pub trait Backend {
+    /// Check whether a path names a file.
+    fn is_file(&self, path: &Path) -> bool;
+
+    /// Check whether a path names a directory.
+    fn is_dir(&self, path: &Path) -> bool;
+
+    /// Check whether a path names a file or a directory.
+    fn exists(&self, path: &Path) -> bool {
+        self.is_file(path) || self.is_dir(path)
+    }
+
+    /// List the names of the entries of a directory.
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>>;
+
+    /// Return the size of a file in bytes.
+    fn size(&self, path: &Path) -> io::Result<u64>;
+
+    /// Check whether looking up directories is costly, as when it lists every key of a store, so
+    /// that paths found missing are worth remembering.
+    fn costly_listing(&self) -> bool {
+        false
+    }
+
+    /// Return when a file was last modified, if the backend knows.
+    fn modified(&self, _path: &Path) -> io::Result<Option<SystemTime>> {
+        Ok(None)
+    }
+
+    /// Open a file for reading.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read>>;
+
+    /// Read the whole contents of a file.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        self.open(path)?.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    /// Open a file for reading `len` bytes from `start`. Backends that can seek should override
+    /// this, since the default reads and discards the bytes before `start`.
+    fn open_range(&self, path: &Path, start: u64, len: u64) -> io::Result<Box<dyn Read>> {
+        let mut reader = self.open(path)?;
+        io::copy(&mut (&mut reader).take(start), &mut io::sink())?;
+        Ok(Box::new(reader.take(len)))
+    }
+}
+
+/// Builds a backend for a request.
+pub type Factory = fn() -> Box<dyn Backend>;
+
+/// Backends registered by applications, by name.
+static REGISTERED: Mutex<Vec<(&'static str, Factory)>> = Mutex::new(Vec::new());
+
+/// Register a backend, used when `STORAGE_BACKEND` is set to `name`. Registering a name again
+/// replaces the backend, and the built-in backends can't be replaced.
+pub fn register(name: &'static str, factory: Factory) {
+    let mut registered = REGISTERED.lock().unwrap_or_else(|e| e.into_inner());
+    registered.retain(|(registered, _)| *registered != name);
+    registered.push((name, factory));
+}
+
+/// Return the factory of the backend with the given name, if there is one.
+fn factory(name: &str) -> Option<Factory> {
+    match name {
+        DEFAULT_BACKEND => Some(|| Box::new(Filesystem)),
+        KEY_VALUE_BACKEND => Some(|| Box::new(KeyValue::from_env())),
+        _ => REGISTERED
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .find_map(|(registered, factory)| (*registered == name).then_some(*factory)),
+    }
+}
+
+/// Check whether a backend with the given name exists.
+pub(crate) fn is_known(name: &str) -> bool {
+    factory(name.trim()).is_some()
+}
+
+/// Return the configured backend. Unknown backends are logged, and files are read from the
+/// mounted directory instead.
+pub(crate) fn current() -> Box<dyn Backend> {
+    let name = settings::var(STORAGE_BACKEND_ENV);
+    let name = name.as_deref().map(str::trim).unwrap_or(DEFAULT_BACKEND);
+    match factory(name) {
+        Some(factory) => factory(),
+        None => {
+            eprintln!("{STORAGE_BACKEND_ENV} {name} is not a known backend, ignoring it");
+            Box::new(Filesystem)
+        }
+    }
+}
+
+/// Reads files from the directory mounted into the component.
+pub struct Filesystem;
+
+impl Backend for Filesystem {
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect()
+    }
+
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<Option<SystemTime>> {
+        Ok(fs::metadata(path)?.modified().ok())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn open_range(&self, path: &Path, start: u64, len: u64) -> io::Result<Box<dyn Read>> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(start))?;
+        Ok(Box::new(file.take(len)))
+    }
+}
+
+/// Reads files from a key-value store, keyed by their paths without a leading slash, such as
+/// `docs/index.html`. Directories are the prefixes of keys, so looking one up lists the keys.
+pub struct KeyValue {
+    /// The label of the store, if `STORAGE_STORE` is set.
+    label: Option<String>,
+    /// Prefixes of the keys that aren't files: the file server's own state, and the sessions
+    /// checked by the session gate when it shares the store.
+    hidden: Vec<String>,
+    /// Whether files are checked against the digests stored along with them before being read.
+    checksums: bool,
+}
+
+impl KeyValue {
+    /// Read from the store configured in `STORAGE_STORE`, which must be set so that files aren't
+    /// read from the default store holding the file server's own state by accident.
+    fn from_env() -> Self {
+        let label = settings::var(STORAGE_STORE_ENV)
+            .map(|label| label.trim().to_string())
+            .filter(|label| !label.is_empty());
+        let mut hidden = vec![INTERNAL_KEY_PREFIX.to_string()];
+        hidden.extend(label.as_deref().and_then(session_gate::key_prefix_in));
+        let checksums = settings::var(STORAGE_CHECKSUMS_ENV).is_some_and(|v| v.trim() == "true");
+        Self {
+            label,
+            hidden,
+            checksums,
+        }
+    }
+
+    /// Check whether a key isn't a file, even though it is in the store.
+    fn hides(&self, key: &str) -> bool {
+        self.hidden.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    fn label(&self) -> io::Result<&str> {
+        self.label.as_deref().ok_or_else(|| {
+            io::Error::other(FileServerError::BackendUnavailable(format!(
+                "{STORAGE_STORE_ENV} must name the key-value store files are read from"
+            )))
+        })
+    }
+
+    fn store(&self) -> io::Result<Store> {
+        let label = self.label()?;
+        Store::open(label).map_err(|e| {
+            io::Error::other(FileServerError::BackendUnavailable(format!(
+                "cannot open the key-value store {label}: {e}"
+            )))
+        })
+    }
+
+    /// Read the value of a file, once per scope.
+    fn get(&self, path: &Path) -> io::Result<Rc<[u8]>> {
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, path.display().to_string());
+        let key = key(path)
+            .filter(|key| !self.hides(key))
+            .ok_or_else(not_found)?;
+        let scoped = (self.label()?.to_string(), key);
+        if let Some(fetched) =
+            FETCHED.with_borrow(|fetched| fetched.as_ref()?.get(&scoped).cloned())
+        {
+            return fetched.ok_or_else(not_found);
+        }
+        let value = self
+            .store()?
+            .get(&scoped.1)
+            .map_err(io::Error::other)?
+            .map(Rc::from);
+        FETCHED.with_borrow_mut(|fetched| {
+            if let Some(fetched) = fetched {
+                fetched.insert(scoped, value.clone());
+            }
+        });
+        value.ok_or_else(not_found)
+    }
+
+    /// Read the value of a file to serve it, refusing values that don't match their stored
+    /// digest when checksums are required.
+    fn verified(&self, path: &Path) -> io::Result<Rc<[u8]>> {
+        let value = self.get(path)?;
+        if self.checksums {
+            self.verify(path, &value)?
+                .map_err(|problem| io::Error::new(io::ErrorKind::InvalidData, problem))?;
+        }
+        Ok(value)
+    }
+
+    /// Check the value of a file against the digest stored along with it.
+    fn verify(&self, path: &Path, value: &[u8]) -> io::Result<Result<(), String>> {
+        let key = key(path).unwrap_or_default();
+        let stored = self
+            .store()?
+            .get(&format!("{CHECKSUM_PREFIX}{key}"))
+            .map_err(io::Error::other)?;
+        Ok(verify(value, stored.as_deref())
+            .map_err(|problem| format!("{} is corrupted: {problem}", path.display())))
+    }
+
+    /// Return the files whose value doesn't match their stored digest, deleting them along with
+    /// their digest if asked to.
+    fn corrupted(&self, delete: bool) -> io::Result<Vec<String>> {
+        let store = self.store()?;
+        let mut corrupted = Vec::new();
+        for key in store.get_keys().map_err(io::Error::other)? {
+            if self.hides(&key) {
+                continue;
+            }
+            let path = Path::new(&key);
+            let Ok(value) = self.get(path) else {
+                continue;
+            };
+            if self.verify(path, &value)?.is_ok() {
+                continue;
+            }
+            if delete {
+                store.delete(&key).map_err(io::Error::other)?;
+                store
+                    .delete(&format!("{CHECKSUM_PREFIX}{key}"))
+                    .map_err(io::Error::other)?;
+            }
+            corrupted.push(key);
+        }
+        Ok(corrupted)
+    }
+}
+
+/// Check a value against its stored SHA-256 digest, hex-encoded, returning the problem if it
+/// doesn't match.
+fn verify(value: &[u8], stored: Option<&[u8]>) -> Result<(), String> {
+    let Some(stored) = stored else {
+        return Err("no digest is stored for it".to_string());
+    };
+    let digest = hex::encode(Sha256::digest(value));
+    if !stored.trim_ascii().eq_ignore_ascii_case(digest.as_bytes()) {
+        return Err(format!(
+            "its SHA-256 digest is {digest}, {:?} is stored",
+            String::from_utf8_lossy(stored)
+        ));
+    }
+    Ok(())
+}
+
+/// Return the files of the key-value backend that don't match their stored digest, deleting them
+/// if asked to, or `None` if files aren't read from a key-value store with checksums.
+pub(crate) fn corrupted(delete: bool) -> io::Result<Option<Vec<String>>> {
+    let name = settings::var(STORAGE_BACKEND_ENV);
+    if name.as_deref().map(str::trim) != Some(KEY_VALUE_BACKEND) {
+        return Ok(None);
+    }
+    let backend = KeyValue::from_env();
+    if !backend.checksums {
+        return Ok(None);
+    }
+    backend.corrupted(delete).map(Some)
+}
+
+/// Return the key of a path, or `None` if it leaves the root.
+fn key(path: &Path) -> Option<String> {
+    let mut segments = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => segments.push(name.to_str()?),
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(segments.join("/"))
+}
+
+/// Return the names of the entries of the directory whose key is `dir`, given all keys.
+fn entries(keys: &[String], dir: &str) -> BTreeSet<String> {
+    let prefix = if dir.is_empty() {
+        String::new()
+    } else {
+        format!("{dir}/")
+    };
+    keys.iter()
+        .filter_map(|key| key.strip_prefix(&prefix)?.split('/').next())
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl Backend for KeyValue {
+    fn is_file(&self, path: &Path) -> bool {
+        self.get(path).is_ok()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.list(path).is_ok()
+    }
+
+    fn costly_listing(&self) -> bool {
+        true
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, dir.display().to_string());
+        let dir = key(dir).ok_or_else(not_found)?;
+        let mut keys = self.store()?.get_keys().map_err(io::Error::other)?;
+        keys.retain(|key| !self.hides(key));
+        let entries = entries(&keys, &dir);
+        if entries.is_empty() && !dir.is_empty() {
+            return Err(not_found());
+        }
+        Ok(entries.into_iter().collect())
+    }
+
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        Ok(self.get(path)?.len() as u64)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        Ok(Box::new(Cursor::new(self.verified(path)?)))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        Ok(self.verified(path)?.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key() {
+        assert_eq!(
+            key(Path::new("docs/index.html")).unwrap(),
+            "docs/index.html"
+        );
+        assert_eq!(
+            key(Path::new("/docs/./index.html")).unwrap(),
+            "docs/index.html"
+        );
+        assert_eq!(key(Path::new("")).unwrap(), "");
+        assert_eq!(key(Path::new("docs/../../etc/passwd")), None);
+    }
+
+    #[test]
+    fn test_entries() {
+        let keys = [
+            "index.html",
+            "docs/index.html",
+            "docs/api/v1.html",
+            "docs.txt",
+        ]
+        .map(String::from);
+        assert_eq!(
+            entries(&keys, ""),
+            BTreeSet::from(["docs", "docs.txt", "index.html"].map(String::from))
+        );
+        assert_eq!(
+            entries(&keys, "docs"),
+            BTreeSet::from(["api", "index.html"].map(String::from))
+        );
+        assert!(entries(&keys, "doc").is_empty());
+        assert!(entries(&keys, "index.html").is_empty());
+    }
+
+    #[test]
+    fn test_hides() {
+        let backend = KeyValue {
+            label: Some("default".to_string()),
+            hidden: vec![INTERNAL_KEY_PREFIX.to_string(), "session:".to_string()],
+            checksums: false,
+        };
+        assert!(backend.hides("spin-fileserver:etag:gzip:/index.html"));
+        assert!(backend.hides("session:3f2a"));
+        assert!(!backend.hides("docs/session:3f2a"));
+        assert!(!backend.hides("index.html"));
+    }
+
+    #[test]
+    fn test_store_required() {
+        let backend = KeyValue {
+            label: None,
+            hidden: Vec::new(),
+            checksums: false,
+        };
+        let e = backend.is_file(Path::new("index.html"));
+        assert!(!e);
+        let e = backend.size(Path::new("index.html")).unwrap_err();
+        assert!(e.to_string().contains(STORAGE_STORE_ENV));
+    }
+
+    #[test]
+    fn test_verify() {
+        let digest = hex::encode(Sha256::digest("hello, world!\n"));
+        assert_eq!(verify(b"hello, world!\n", Some(digest.as_bytes())), Ok(()));
+        assert_eq!(
+            verify(b"hello, world!\n", Some(digest.to_uppercase().as_bytes())),
+            Ok(())
+        );
+        assert_eq!(
+            verify(b"hello, world!\n", Some(format!("{digest}\n").as_bytes())),
+            Ok(())
+        );
+        // tampered and truncated values are refused
+        assert!(verify(b"hello, there!\n", Some(digest.as_bytes())).is_err());
+        assert!(verify(b"hello", Some(digest.as_bytes())).is_err());
+        assert_eq!(
+            verify(b"hello, world!\n", None),
+            Err("no digest is stored for it".to_string())
+        );
+    }
+
+    #[test]
+    fn test_filesystem_range() {
+        let mut range = String::new();
+        Filesystem
+            .open_range(Path::new("hello-test.txt"), 7, 5)
+            .unwrap()
+            .read_to_string(&mut range)
+            .unwrap();
+        assert_eq!(range, "world");
+    }
+
+    #[test]
+    fn test_default_range() {
+        struct Memory;
+        impl Backend for Memory {
+            fn is_file(&self, _: &Path) -> bool {
+                true
+            }
+            fn is_dir(&self, _: &Path) -> bool {
+                false
+            }
+            fn list(&self, _: &Path) -> io::Result<Vec<String>> {
+                Ok(Vec::new())
+            }
+            fn size(&self, _: &Path) -> io::Result<u64> {
+                Ok(14)
+            }
+            fn open(&self, _: &Path) -> io::Result<Box<dyn Read>> {
+                Ok(Box::new(Cursor::new("hello, world!\n")))
+            }
+        }
+
+        let mut range = String::new();
+        Memory
+            .open_range(Path::new("hello"), 7, 5)
+            .unwrap()
+            .read_to_string(&mut range)
+            .unwrap();
+        assert_eq!(range, "world");
+
+        register("memory", || Box::new(Memory));
+        assert!(is_known("memory"));
+        assert!(is_known(KEY_VALUE_BACKEND));
+        assert!(!is_known("s3"));
+    }
+}
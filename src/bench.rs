@@ -0,0 +1,38 @@
+//! Entry points into the serving pipeline for the benchmarks in `benches/`, which link the crate
+//! natively. Not part of the interface of the component.
+
+use anyhow::Result;
+use std::{io, path::Path};
+
+use crate::{languages, FileServer, FileServerPath, SupportedEncoding};
+
+/// Resolve a request path, returning whether it names a file.
+pub fn resolve(path: &str) -> bool {
+    matches!(
+        FileServer::resolve(path, &[languages::default_language()]),
+        FileServerPath::Physical(_)
+    )
+}
+
+/// Negotiate the encoding of a response to a request with an `Accept-Encoding` header.
+pub fn negotiate(accept_encoding: &str) -> SupportedEncoding {
+    SupportedEncoding::best_encoding(&[(
+        http::header::ACCEPT_ENCODING.to_string(),
+        accept_encoding.as_bytes().to_vec(),
+    )])
+}
+
+/// Return the ETag of a file in an encoding, as served.
+pub fn etag(file: &Path, encoding: SupportedEncoding) -> Result<String> {
+    let resolved = FileServerPath::Physical(file.to_path_buf());
+    FileServer::etag(&resolved, encoding, None, "").map(|(etag, _)| etag)
+}
+
+/// Read the body of a file in an encoding, as served, returning its length.
+pub fn body(file: &Path, encoding: SupportedEncoding) -> Result<u64> {
+    let resolved = FileServerPath::Physical(file.to_path_buf());
+    match FileServer::open(&resolved, encoding, None, "").transpose()? {
+        Some(mut reader) => Ok(io::copy(&mut reader, &mut io::sink())?),
+        None => Ok(0),
+    }
+}
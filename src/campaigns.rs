@@ -0,0 +1,216 @@
+//! Campaign redirects from the configuration file, sending visitors arriving from a newsletter,
+//! an ad or a partner site to a tailored landing page based on a query parameter (such as
+//! `?ref=newsletter` or `?utm_source=...`) or the `Referer` header, without client-side scripts.
+//!
+//! Rules are evaluated in order before the path is resolved, and the first matching rule
+//! redirects with `302 Found`. The query string is kept, so analytics on the landing page still
+//! see the campaign parameters.
+
+use http::{
+    header::{REFERER, VARY},
+    StatusCode,
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::str;
+
+use crate::{
+    access::glob_match, config::same_path, oidc::query_param, FileServer, RequestContext, Response,
+};
+
+/// A rule redirecting the requests of a campaign to its landing page.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CampaignRule {
+    /// Glob matched against the request path, as in access rules.
+    #[serde(default = "any_path")]
+    pub path: String,
+    /// Query parameter requests must carry for the rule to apply.
+    pub param: Option<String>,
+    /// Value the query parameter must have, or any value if not set.
+    pub value: Option<String>,
+    /// Regular expression the `Referer` header must match for the rule to apply.
+    pub referer: Option<String>,
+    /// Location of the landing page.
+    pub location: String,
+}
+
+fn any_path() -> String {
+    "**".to_string()
+}
+
+impl CampaignRule {
+    /// Check that the rule matches on a query parameter or the `Referer` header, with a valid
+    /// pattern.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.param.is_none() && self.referer.is_none() {
+            return Err(format!(
+                "campaign rule for {} has neither param nor referer",
+                self.location
+            ));
+        }
+        if self.value.is_some() && self.param.is_none() {
+            return Err(format!(
+                "campaign rule for {} has a value but no param",
+                self.location
+            ));
+        }
+        if let Some(referer) = &self.referer {
+            Regex::new(referer).map_err(|e| format!("invalid referer pattern {referer}: {e}"))?;
+        }
+        Ok(())
+    }
+
+    /// Check whether the rule applies to a request for a path. Invalid patterns never match.
+    fn matches(&self, req: &RequestContext, path: &str) -> bool {
+        if !glob_match(&self.path, path) {
+            return false;
+        }
+        if let Some(param) = &self.param {
+            match query_param(&req.query, param) {
+                Some(value) if self.value.as_ref().is_none_or(|v| *v == value) => {}
+                _ => return false,
+            }
+        }
+        if let Some(referer) = &self.referer {
+            let value = req
+                .header(&REFERER)
+                .and_then(|v| str::from_utf8(v).ok())
+                .unwrap_or_default();
+            if !Regex::new(referer).is_ok_and(|pattern| pattern.is_match(value)) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Redirect a request for a path to the landing page of the first rule matching it.
+pub(crate) fn check(rules: &[CampaignRule], req: &RequestContext, path: &str) -> Option<Response> {
+    let rule = rules.iter().find(|rule| rule.matches(req, path))?;
+    let (location_path, location_query) = match rule.location.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (rule.location.as_str(), None),
+    };
+    // don't redirect requests for the landing page itself
+    if same_path(location_path, path) {
+        return None;
+    }
+    let location = match (location_query, req.query.as_str()) {
+        (_, "") => rule.location.clone(),
+        (Some(_), query) => format!("{}&{query}", rule.location),
+        (None, query) => format!("{}?{query}", rule.location),
+    };
+    let (status, mut headers, body) = FileServer::redirect(req, StatusCode::FOUND, &location);
+    if varies_on_referer(rules, path) {
+        headers.push((
+            VARY.as_str().to_string(),
+            REFERER.as_str().as_bytes().to_vec(),
+        ));
+    }
+    Some((status, headers, body))
+}
+
+/// Check whether the responses for a path depend on the `Referer` header, because a rule
+/// matching on it applies to the path.
+pub(crate) fn varies_on_referer(rules: &[CampaignRule], path: &str) -> bool {
+    rules
+        .iter()
+        .any(|rule| rule.referer.is_some() && glob_match(&rule.path, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::LOCATION;
+
+    fn rule(param: Option<&str>, value: Option<&str>, referer: Option<&str>) -> CampaignRule {
+        CampaignRule {
+            path: "/".to_string(),
+            param: param.map(str::to_string),
+            value: value.map(str::to_string),
+            referer: referer.map(str::to_string),
+            location: "/welcome/newsletter.html".to_string(),
+        }
+    }
+
+    fn location(rules: &[CampaignRule], req: &RequestContext, path: &str) -> Option<String> {
+        let (status, headers, _) = check(rules, req, path)?;
+        assert_eq!(status, StatusCode::FOUND);
+        headers
+            .into_iter()
+            .find(|(name, _)| name == LOCATION.as_str())
+            .map(|(_, value)| String::from_utf8(value).unwrap())
+    }
+
+    #[test]
+    fn test_query_param() {
+        let rules = [rule(Some("ref"), Some("newsletter"), None)];
+        let req = |query: &str| RequestContext {
+            query: query.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            location(&rules, &req("ref=newsletter"), "/"),
+            Some("/welcome/newsletter.html?ref=newsletter".to_string())
+        );
+        assert_eq!(
+            location(&rules, &req("utm_medium=email&ref=newsletter"), "/"),
+            Some("/welcome/newsletter.html?utm_medium=email&ref=newsletter".to_string())
+        );
+        assert_eq!(location(&rules, &req("ref=ads"), "/"), None);
+        assert_eq!(location(&rules, &req("ref=newsletter"), "/about"), None);
+        assert_eq!(location(&rules, &req(""), "/"), None);
+
+        // any value, and locations with a query string of their own
+        let mut any = rule(Some("utm_source"), None, None);
+        any.location = "/landing?from=campaign".to_string();
+        assert_eq!(
+            location(&[any], &req("utm_source=partner%20site"), "/"),
+            Some("/landing?from=campaign&utm_source=partner%20site".to_string())
+        );
+    }
+
+    #[test]
+    fn test_referer() {
+        let rules = [rule(None, None, Some(r"^https://news\.example\.com/"))];
+        let req = |referer: &str| RequestContext {
+            headers: vec![("referer".to_string(), referer.as_bytes().to_vec())],
+            ..Default::default()
+        };
+        assert_eq!(
+            location(&rules, &req("https://news.example.com/item?id=1"), "/"),
+            Some("/welcome/newsletter.html".to_string())
+        );
+        assert_eq!(location(&rules, &req("https://example.org/"), "/"), None);
+        assert_eq!(location(&rules, &RequestContext::default(), "/"), None);
+        assert!(varies_on_referer(&rules, "/"));
+        assert!(!varies_on_referer(&rules, "/about"));
+
+        // the landing page itself is served
+        let mut everywhere = rule(None, None, Some("example"));
+        everywhere.path = any_path();
+        let everywhere = [everywhere];
+        let referred = req("https://example.com/");
+        assert!(location(&everywhere, &referred, "/about").is_some());
+        assert_eq!(
+            location(&everywhere, &referred, "/welcome/newsletter.html"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(rule(Some("ref"), None, None).validate().is_ok());
+        assert!(rule(None, None, Some("example")).validate().is_ok());
+        assert_eq!(
+            rule(None, None, None).validate(),
+            Err("campaign rule for /welcome/newsletter.html has neither param nor referer".into())
+        );
+        assert_eq!(
+            rule(None, Some("x"), Some("example")).validate(),
+            Err("campaign rule for /welcome/newsletter.html has a value but no param".into())
+        );
+        assert!(rule(None, None, Some("(")).validate().is_err());
+    }
+}
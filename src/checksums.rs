@@ -0,0 +1,112 @@
+//! Checksum manifest of a directory tree, so that mirroring and deployment verification tools can
+//! compare a deployed site with the build output.
+//!
+//! The manifest is streamed as JSON lines, and every file is only hashed once the lines before it
+//! have been sent, so responses for large trees start right away.
+
+use anyhow::Result;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, Cursor, Read},
+    path::{Path, PathBuf},
+};
+
+use crate::{backend::Backend, FileServer};
+
+/// Media type of the manifest
+pub(crate) const MANIFEST_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// A line of the manifest.
+#[derive(Debug, Serialize)]
+struct Entry {
+    /// Path of the file relative to the root of the served files, starting with `/`.
+    path: String,
+    sha256: String,
+    size: u64,
+}
+
+/// Reader producing the manifest of the files under a directory, one line per file.
+pub(crate) struct Manifest {
+    backend: Box<dyn Backend>,
+    /// Directory the paths of the manifest are relative to.
+    base: PathBuf,
+    files: std::vec::IntoIter<PathBuf>,
+    line: Cursor<Vec<u8>>,
+}
+
+impl Manifest {
+    /// List the files under `dir` in a backend, with paths relative to `base`.
+    pub fn new(backend: Box<dyn Backend>, base: &Path, dir: &Path) -> Result<Self> {
+        let files = FileServer::walk(backend.as_ref(), dir)?;
+        Ok(Self {
+            backend,
+            base: base.to_path_buf(),
+            files: files.into_iter(),
+            line: Cursor::default(),
+        })
+    }
+
+    /// Hash the next file, returning its line of the manifest.
+    fn next_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let Some(file) = self.files.next() else {
+            return Ok(None);
+        };
+        let mut hasher = Sha256::new();
+        let size = io::copy(&mut self.backend.open(&file)?, &mut hasher)?;
+        let path = file.strip_prefix(&self.base).unwrap_or(&file);
+        let entry = Entry {
+            path: format!("/{}", path.to_string_lossy().trim_start_matches('/')),
+            sha256: hex::encode(hasher.finalize()),
+            size,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        Ok(Some(line))
+    }
+}
+
+impl Read for Manifest {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.line.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.next_line()? {
+                Some(line) => self.line = Cursor::new(line),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Filesystem;
+
+    #[test]
+    fn test_manifest() {
+        let mut manifest = String::new();
+        Manifest::new(Box::new(Filesystem), Path::new("src"), Path::new("src"))
+            .unwrap()
+            .read_to_string(&mut manifest)
+            .unwrap();
+        let entries = manifest
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries.len(),
+            FileServer::walk(&Filesystem, Path::new("src"))
+                .unwrap()
+                .len()
+        );
+
+        let lib = entries.iter().find(|e| e["path"] == "/lib.rs").unwrap();
+        let contents = std::fs::read("src/lib.rs").unwrap();
+        assert_eq!(lib["size"], contents.len());
+        assert_eq!(lib["sha256"], hex::encode(Sha256::digest(&contents)));
+    }
+}
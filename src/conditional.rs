@@ -0,0 +1,197 @@
+//! Conditional and range requests: `If-Match`, `If-None-Match`, `Range` and `If-Range`.
+//!
+//! Conditions are evaluated before ranges, in the order of RFC 9110, so a failed `If-Match` gets
+//! `412 Precondition Failed` and a matching `If-None-Match` gets `304 Not Modified`, even when a
+//! range is requested. Ranges are only served for a single byte range of an unencoded
+//! file, since the length of compressed responses isn't known up front.
+
+use std::str;
+
+/// The outcome of evaluating the `Range` header of a request.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RangeOutcome {
+    /// Serve the whole representation, because no usable range was requested or `If-Range`
+    /// doesn't match.
+    Full,
+    /// Serve the bytes from `start` to `end`, inclusive.
+    Partial { start: u64, end: u64 },
+    /// The range starts past the end of the representation.
+    Unsatisfiable,
+}
+
+/// Check whether an `If-None-Match` header matches the ETag of a response, using the weak
+/// comparison required for it.
+pub(crate) fn none_match(if_none_match: &[u8], etag: &str) -> bool {
+    let Ok(if_none_match) = str::from_utf8(if_none_match) else {
+        return false;
+    };
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .any(|tag| opaque_tag(tag.trim().trim_start_matches("W/")) == opaque_tag(etag))
+}
+
+/// Check whether an `If-Match` header fails for the ETag of a response, using the strong
+/// comparison required for it. Requests without the header never fail.
+pub(crate) fn match_fails(if_match: Option<&[u8]>, etag: &str) -> bool {
+    let Some(if_match) = if_match else {
+        return false;
+    };
+    let Ok(if_match) = str::from_utf8(if_match) else {
+        return true;
+    };
+    if_match.trim() != "*"
+        && !if_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| !tag.starts_with("W/") && opaque_tag(tag) == opaque_tag(etag))
+}
+
+/// Evaluate the `Range` and `If-Range` headers of a request for a representation of `len` bytes.
+pub(crate) fn evaluate_range(
+    range: Option<&[u8]>,
+    if_range: Option<&[u8]>,
+    etag: &str,
+    len: u64,
+) -> RangeOutcome {
+    let Some(range) = range.and_then(|r| str::from_utf8(r).ok()) else {
+        return RangeOutcome::Full;
+    };
+    // If-Range requires a strong match; dates never match since no Last-Modified is sent
+    if let Some(if_range) = if_range {
+        let if_range = str::from_utf8(if_range).unwrap_or_default().trim();
+        if if_range.starts_with("W/") || opaque_tag(if_range) != opaque_tag(etag) {
+            return RangeOutcome::Full;
+        }
+    }
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return RangeOutcome::Full;
+    };
+    // multiple ranges would need a multipart response
+    if spec.contains(',') {
+        return RangeOutcome::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return RangeOutcome::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+    let parse = |n: &str| n.parse::<u64>().ok();
+    let range = match (start.is_empty(), end.is_empty()) {
+        // the last `end` bytes
+        (true, false) => match parse(end) {
+            Some(0) => return RangeOutcome::Unsatisfiable,
+            Some(suffix) => (len.saturating_sub(suffix), len.saturating_sub(1)),
+            None => return RangeOutcome::Full,
+        },
+        (false, true) => match parse(start) {
+            Some(start) => (start, len.saturating_sub(1)),
+            None => return RangeOutcome::Full,
+        },
+        (false, false) => match (parse(start), parse(end)) {
+            (Some(start), Some(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+            _ => return RangeOutcome::Full,
+        },
+        (true, true) => return RangeOutcome::Full,
+    };
+    match range {
+        (start, _) if start >= len => RangeOutcome::Unsatisfiable,
+        (start, end) => RangeOutcome::Partial { start, end },
+    }
+}
+
+/// Return an entity tag without its quotes. ETags are sent quoted, as RFC 9110 requires, but
+/// clients that stored them unquoted before are still accepted.
+fn opaque_tag(tag: &str) -> &str {
+    let tag = tag.trim();
+    tag.strip_prefix('"')
+        .and_then(|t| t.strip_suffix('"'))
+        .unwrap_or(tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_none_match() {
+        let etag = "abc";
+        for (if_none_match, expected) in [
+            (&b"abc"[..], true),
+            (b"\"abc\"", true),
+            (b"W/\"abc\"", true),
+            (b"\"xyz\", \"abc\"", true),
+            (b"*", true),
+            (b"", false),
+            (b"\"abcd\"", false),
+            (b"\"xyz\"", false),
+            (b"\xff", false),
+        ] {
+            assert_eq!(
+                none_match(if_none_match, etag),
+                expected,
+                "{}",
+                String::from_utf8_lossy(if_none_match)
+            );
+        }
+    }
+
+    #[test]
+    fn test_match_fails() {
+        let etag = "abc";
+        for (if_match, expected) in [
+            (None, false),
+            (Some(&b"\"abc\""[..]), false),
+            (Some(b"abc"), false),
+            (Some(b"\"xyz\", \"abc\""), false),
+            (Some(b"*"), false),
+            (Some(b"W/\"abc\""), true),
+            (Some(b"\"xyz\""), true),
+            (Some(b""), true),
+            (Some(b"\xff"), true),
+        ] {
+            assert_eq!(match_fails(if_match, etag), expected, "{if_match:?}");
+        }
+    }
+
+    #[test]
+    fn test_evaluate_range() {
+        let partial = |start, end| RangeOutcome::Partial { start, end };
+        for (range, if_range, expected) in [
+            (None, None, RangeOutcome::Full),
+            (Some(&b"bytes=0-4"[..]), None, partial(0, 4)),
+            (Some(b"bytes=5-"), None, partial(5, 9)),
+            (Some(b"bytes=-3"), None, partial(7, 9)),
+            (Some(b"bytes=-30"), None, partial(0, 9)),
+            (Some(b"bytes=8-100"), None, partial(8, 9)),
+            (Some(b"bytes=10-"), None, RangeOutcome::Unsatisfiable),
+            (Some(b"bytes=-0"), None, RangeOutcome::Unsatisfiable),
+            (Some(b"bytes=4-2"), None, RangeOutcome::Full),
+            (Some(b"bytes=0-1,4-5"), None, RangeOutcome::Full),
+            (Some(b"items=0-4"), None, RangeOutcome::Full),
+            (Some(b"bytes=a-b"), None, RangeOutcome::Full),
+            // If-Range only lets the range through when it strongly matches the ETag
+            (Some(b"bytes=0-4"), Some(&b"\"abc\""[..]), partial(0, 4)),
+            (Some(b"bytes=0-4"), Some(b"abc"), partial(0, 4)),
+            (Some(b"bytes=0-4"), Some(b"W/\"abc\""), RangeOutcome::Full),
+            (Some(b"bytes=0-4"), Some(b"\"xyz\""), RangeOutcome::Full),
+            (
+                Some(b"bytes=0-4"),
+                Some(b"Wed, 21 Oct 2015 07:28:00 GMT"),
+                RangeOutcome::Full,
+            ),
+            (Some(b"bytes=10-"), Some(b"\"xyz\""), RangeOutcome::Full),
+        ] {
+            assert_eq!(
+                evaluate_range(range, if_range, "abc", 10),
+                expected,
+                "{:?} {:?}",
+                range.map(String::from_utf8_lossy),
+                if_range.map(String::from_utf8_lossy)
+            );
+        }
+        assert_eq!(
+            evaluate_range(Some(b"bytes=0-"), None, "abc", 0),
+            RangeOutcome::Unsatisfiable
+        );
+    }
+}
@@ -0,0 +1,492 @@
+//! Optional configuration file for settings that don't fit in an environment variable.
+
+use anyhow::{Context, Result};
+use http::StatusCode;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, rc::Rc};
+
+use crate::{
+    access::{self, AccessRule},
+    campaigns::CampaignRule,
+    deploy_window::DeployWindow,
+    embargo::EmbargoRule,
+    settings,
+};
+
+/// Environment variable for the path of the configuration file.
+pub(crate) const CONFIG_FILE_ENV: &str = "CONFIG_FILE";
+
+/// Settings read from the TOML file referenced by `CONFIG_FILE`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Virtual request paths mapped to physical paths.
+    pub aliases: HashMap<String, String>,
+    /// Request paths mapped to the status code they are served with.
+    pub status: HashMap<String, u16>,
+    /// Rules applied based on the User-Agent header, in order.
+    #[serde(rename = "user-agent")]
+    pub user_agent: Vec<UserAgentRule>,
+    /// Defaults for settings, overridden by Spin variables and environment variables.
+    pub settings: HashMap<String, toml::Value>,
+    /// Directories served under request path prefixes.
+    #[serde(rename = "mount")]
+    pub mounts: Vec<Mount>,
+    /// Access rules matched against requests, in order.
+    pub access: Vec<AccessRule>,
+    /// Methods allowed by path, in order.
+    pub methods: Vec<MethodRule>,
+    /// Request headers responses vary on, by path.
+    pub vary: Vec<VaryRule>,
+    /// Time windows request paths are served in, in order.
+    #[serde(rename = "embargo")]
+    pub embargoes: Vec<EmbargoRule>,
+    /// Time windows during which HTML documents are cached for less time.
+    #[serde(rename = "deploy-window")]
+    pub deploy_windows: Vec<DeployWindow>,
+    /// Rules redirecting campaign traffic to landing pages, in order.
+    #[serde(rename = "campaign")]
+    pub campaigns: Vec<CampaignRule>,
+}
+
+/// A directory served under a request path prefix, with its own rules.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Mount {
+    /// Request path prefix, matched on whole path segments.
+    pub prefix: String,
+    /// Directory the prefix is mapped to.
+    pub directory: String,
+    /// `Cache-Control` header of files served from the mount.
+    pub cache_control: Option<String>,
+    /// Bearer token requests to the mount must carry.
+    #[serde(serialize_with = "redact")]
+    pub token: Option<String>,
+}
+
+/// A rule matching the User-Agent header of a request.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct UserAgentRule {
+    /// Regular expression matched against the User-Agent header.
+    pub pattern: String,
+    /// What to do with matching requests.
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+/// The methods allowed for the request paths matching a glob.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MethodRule {
+    /// Glob matched against the request path, as in access rules.
+    pub path: String,
+    /// The allowed methods.
+    pub allow: Vec<String>,
+}
+
+/// The request headers the responses for the request paths matching a glob vary on.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct VaryRule {
+    /// Glob matched against the request path, as in access rules.
+    pub path: String,
+    /// Names of the request headers.
+    pub headers: Vec<String>,
+}
+
+/// What to do with a request matched by a rule.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Action {
+    /// Redirect to the given location with `302 Found`.
+    Redirect(String),
+    /// Serve the file at the given path instead.
+    Rewrite(String),
+}
+
+impl Config {
+    /// Load the configuration file, or return an empty configuration if none is set.
+    /// Within a settings scope the file is only parsed once, and shared by every lookup.
+    pub fn load() -> Result<Rc<Self>> {
+        if let Some(config) = settings::scoped_config() {
+            return Ok(config);
+        }
+        let config = Rc::new(match settings::var(CONFIG_FILE_ENV) {
+            Some(path) => Self::read(&path)?,
+            None => Self::default(),
+        });
+        settings::scope_config(&config);
+        Ok(config)
+    }
+
+    /// Read and parse the configuration file at `path`.
+    pub fn read(path: &str) -> Result<Self> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("cannot read config file {path}"))?;
+        toml::from_str(&contents).with_context(|| format!("cannot parse config file {path}"))
+    }
+
+    /// Return the physical path configured for a virtual request path.
+    pub fn alias(&self, req_path: &str) -> Option<&str> {
+        self.aliases
+            .iter()
+            .find_map(|(from, to)| same_path(from, req_path).then_some(to.as_str()))
+    }
+
+    /// Return the SHA-256 digest of the configuration file, to tell which version was loaded.
+    pub fn digest() -> Result<Option<String>> {
+        use sha2::Digest;
+        let Some(path) = settings::var(CONFIG_FILE_ENV) else {
+            return Ok(None);
+        };
+        let contents =
+            fs::read(&path).with_context(|| format!("cannot read config file {path}"))?;
+        Ok(Some(hex::encode(sha2::Sha256::digest(contents))))
+    }
+
+    /// Return the value of a setting, given by its environment variable name in any case.
+    pub fn setting(&self, name: &str) -> Option<String> {
+        self.settings
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| match value {
+                toml::Value::String(value) => value.clone(),
+                value => value.to_string(),
+            })
+    }
+
+    /// Return the mount with the longest prefix matching a request path, along with the rest of
+    /// the path.
+    pub fn mount<'a>(&self, req_path: &'a str) -> Option<(&Mount, &'a str)> {
+        let path = req_path.trim_start_matches('/');
+        self.mounts
+            .iter()
+            .filter_map(|mount| {
+                let prefix = mount.prefix.trim_matches('/');
+                let rest = path.strip_prefix(prefix)?;
+                if !prefix.is_empty() && !rest.is_empty() && !rest.starts_with('/') {
+                    return None;
+                }
+                Some((mount, prefix.len(), rest.trim_start_matches('/')))
+            })
+            .max_by_key(|(_, len, _)| *len)
+            .map(|(mount, _, rest)| (mount, rest))
+    }
+
+    /// Return the status code a request path should be served with, if overridden.
+    pub fn status(&self, req_path: &str) -> Result<Option<StatusCode>> {
+        self.status
+            .iter()
+            .find(|(path, _)| same_path(path, req_path))
+            .map(|(path, status)| {
+                StatusCode::from_u16(*status)
+                    .with_context(|| format!("invalid status {status} configured for {path}"))
+            })
+            .transpose()
+    }
+}
+
+impl Config {
+    /// Return the methods allowed for a request path by the first method rule matching it, in
+    /// the format of the `Allow` header.
+    pub fn allowed_methods(&self, req_path: &str) -> Option<String> {
+        let rule = self
+            .methods
+            .iter()
+            .find(|rule| access::glob_match(&rule.path, req_path))?;
+        let allow = rule
+            .allow
+            .iter()
+            .map(|method| method.trim().to_uppercase())
+            .collect::<Vec<_>>();
+        Some(allow.join(", "))
+    }
+
+    /// Return the request headers of every Vary rule matching a request path, in order.
+    pub fn vary(&self, req_path: &str) -> Vec<&str> {
+        self.vary
+            .iter()
+            .filter(|rule| access::glob_match(&rule.path, req_path))
+            .flat_map(|rule| rule.headers.iter().map(|name| name.trim()))
+            .collect()
+    }
+
+    /// Return the action of the first User-Agent rule matching `user_agent`.
+    pub fn user_agent_action(&self, user_agent: &str) -> Result<Option<&Action>> {
+        for rule in &self.user_agent {
+            let pattern = Regex::new(&rule.pattern)
+                .with_context(|| format!("invalid User-Agent pattern {}", rule.pattern))?;
+            if pattern.is_match(user_agent) {
+                return Ok(Some(&rule.action));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Mount {
+    /// Return the path of a file in the mounted directory, given the rest of the request path.
+    /// Returns `None` for paths that could escape the directory.
+    pub fn path(&self, rest: &str) -> Option<String> {
+        if rest
+            .split('/')
+            .any(|segment| segment == "." || segment == "..")
+        {
+            return None;
+        }
+        Some(match self.directory.trim_matches('/') {
+            "" => rest.to_string(),
+            directory => format!("{directory}/{rest}"),
+        })
+    }
+}
+
+/// Serialize a secret without revealing it.
+pub(crate) fn redact<S: serde::Serializer>(
+    secret: &Option<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    secret.as_ref().map(|_| "<redacted>").serialize(serializer)
+}
+
+/// Compare two request paths once normalized, so a leading slash or dot segments don't matter.
+pub(crate) fn same_path(a: &str, b: &str) -> bool {
+    normalize(a) == normalize(b)
+}
+
+/// Return a request path with a leading slash and without `.`, `..` or empty segments, naming
+/// the file it resolves to. A trailing slash is kept.
+pub(crate) fn normalize(path: &str) -> String {
+    let mut segments = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let trailing = path.ends_with('/') && !segments.is_empty();
+    format!("/{}{}", segments.join("/"), if trailing { "/" } else { "" })
+}
+
+/// Check whether a request path is under one of a comma-separated list of prefixes, matched on
+/// whole path segments of the normalized path.
+pub(crate) fn under_prefixes(prefixes: &str, path: &str) -> bool {
+    let path = normalize(path);
+    prefixes
+        .split(',')
+        .map(|p| format!("/{}", p.trim().trim_matches('/')))
+        .any(|prefix| {
+            prefix == "/"
+                || path == prefix
+                || path
+                    .strip_prefix(&prefix)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [aliases]
+            "/downloads/latest.zip" = "releases/v1.4.2.zip"
+
+            [status]
+            "/coming-soon" = 503
+            "/bad" = 1000
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.alias("downloads/latest.zip"),
+            Some("releases/v1.4.2.zip")
+        );
+        assert_eq!(
+            config.status("/coming-soon").unwrap(),
+            Some(StatusCode::SERVICE_UNAVAILABLE)
+        );
+        assert_eq!(config.status("/index.html").unwrap(), None);
+        assert!(config.status("/bad").is_err());
+    }
+
+    #[test]
+    fn test_mounts() {
+        let config: Config = toml::from_str(
+            r#"
+            [[mount]]
+            prefix = "/"
+            directory = "site"
+
+            [[mount]]
+            prefix = "/downloads/"
+            directory = "artifacts"
+            token = "secret"
+            "#,
+        )
+        .unwrap();
+        let mount = |path| {
+            config
+                .mount(path)
+                .map(|(mount, rest)| (mount.directory.as_str(), rest))
+        };
+        assert_eq!(mount("/downloads/app.zip"), Some(("artifacts", "app.zip")));
+        assert_eq!(mount("/downloads"), Some(("artifacts", "")));
+        assert_eq!(
+            mount("/downloadsx/app.zip"),
+            Some(("site", "downloadsx/app.zip"))
+        );
+        assert_eq!(mount("index.html"), Some(("site", "index.html")));
+
+        let (downloads, _) = config.mount("/downloads/").unwrap();
+        assert_eq!(
+            downloads.path("v1/app.zip"),
+            Some("artifacts/v1/app.zip".to_string())
+        );
+        assert_eq!(downloads.path("../site/index.html"), None);
+        assert_eq!(
+            serde_json::to_value(downloads).unwrap()["token"],
+            "<redacted>"
+        );
+    }
+
+    #[test]
+    fn test_settings() {
+        let config: Config = toml::from_str(
+            r#"
+            [settings]
+            CACHE_CONTROL = "no-cache"
+            etag_cache = true
+            canary_percent = 5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.setting("CACHE_CONTROL"),
+            Some("no-cache".to_string())
+        );
+        assert_eq!(config.setting("ETAG_CACHE"), Some("true".to_string()));
+        assert_eq!(config.setting("CANARY_PERCENT"), Some("5".to_string()));
+        assert_eq!(config.setting("FALLBACK_PATH"), None);
+    }
+
+    #[test]
+    fn test_user_agent_rules() {
+        let config: Config = toml::from_str(
+            r#"
+            [[user-agent]]
+            pattern = "MSIE|Trident/"
+            redirect = "/unsupported.html"
+
+            [[user-agent]]
+            pattern = "(?i)googlebot"
+            rewrite = "/prerendered/index.html"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config
+                .user_agent_action("Mozilla/5.0 (compatible; MSIE 10.0)")
+                .unwrap(),
+            Some(&Action::Redirect("/unsupported.html".to_string()))
+        );
+        assert_eq!(
+            config.user_agent_action("GoogleBot/2.1").unwrap(),
+            Some(&Action::Rewrite("/prerendered/index.html".to_string()))
+        );
+        assert_eq!(config.user_agent_action("curl/8.0").unwrap(), None);
+    }
+
+    #[test]
+    fn test_method_rules() {
+        let config: Config = toml::from_str(
+            r#"
+            [[methods]]
+            path = "/api-mocks/**"
+            allow = ["get", "HEAD", "OPTIONS", "POST"]
+
+            [[methods]]
+            path = "/**"
+            allow = ["GET", "HEAD"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.allowed_methods("/api-mocks/users.json"),
+            Some("GET, HEAD, OPTIONS, POST".to_string())
+        );
+        assert_eq!(
+            config.allowed_methods("index.html"),
+            Some("GET, HEAD".to_string())
+        );
+        assert_eq!(Config::default().allowed_methods("index.html"), None);
+    }
+
+    #[test]
+    fn test_vary_rules() {
+        let config: Config = toml::from_str(
+            r#"
+            [[vary]]
+            path = "/api/**"
+            headers = ["Origin"]
+
+            [[vary]]
+            path = "/**/*.json"
+            headers = [" Accept-Language ", "Cookie"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.vary("/api/users.json"),
+            ["Origin", "Accept-Language", "Cookie"]
+        );
+        assert_eq!(config.vary("/api/users"), ["Origin"]);
+        assert!(config.vary("index.html").is_empty());
+    }
+
+    #[test]
+    fn test_under_prefixes() {
+        assert!(under_prefixes("/docs, internal/", "docs/index.html"));
+        assert!(under_prefixes("/docs, internal/", "/internal"));
+        assert!(!under_prefixes("/docs, internal/", "/docs2/index.html"));
+        assert!(under_prefixes("/", "/index.html"));
+        // other spellings of a path under a prefix are under it too
+        for path in [
+            "/./docs/a.html",
+            "/x/../docs/a.html",
+            "//docs/a.html",
+            "docs//a.html",
+        ] {
+            assert!(under_prefixes("/docs", path), "{path}");
+        }
+        assert!(!under_prefixes("/docs", "/docs/../index.html"));
+    }
+
+    #[test]
+    fn test_same_path() {
+        assert!(same_path("/docs/a.html", "docs/a.html"));
+        assert!(same_path("/docs/a.html", "/./docs//a.html"));
+        assert!(!same_path("/docs/", "/docs"));
+        assert!(!same_path("/Docs/a.html", "/docs/a.html"));
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("docs/./a/../b.html"), "/docs/b.html");
+        assert_eq!(normalize("//docs//"), "/docs/");
+        assert_eq!(normalize("/../.."), "/");
+        assert_eq!(normalize(""), "/");
+    }
+
+    #[test]
+    fn test_reject_unknown_fields() {
+        assert!(toml::from_str::<Config>("[unknown]\nkey = 1").is_err());
+    }
+}
@@ -0,0 +1,97 @@
+//! Content-addressed serving: files can be requested by the SHA-256 digest of their contents as
+//! `/_cas/<sha256>`, looked up in the ETag manifest. The response for a digest never changes, so
+//! it is cached forever, and identical files share one URL.
+//!
+//! The manifest also tells which files are identical, so responses for duplicated files can point
+//! caches at one canonical copy with a `Content-Location` header.
+
+use anyhow::Result;
+use std::{collections::HashMap, path::Path};
+
+use crate::{
+    precompressed::{self, ETAG_MANIFEST_ENV},
+    settings,
+};
+
+/// Environment variable enabling content-addressed paths
+pub(crate) const CONTENT_ADDRESSED_ENV: &str = "CONTENT_ADDRESSED";
+/// Environment variable enabling `Content-Location` headers naming the canonical copy of files
+pub(crate) const CANONICAL_LOCATION_ENV: &str = "CANONICAL_CONTENT_LOCATION";
+/// Prefix of content-addressed paths
+const CAS_PREFIX: &str = "_cas/";
+
+/// Return the digest requested by a content-addressed path, lowercased, if the mode is enabled.
+pub(crate) fn digest(path: &str) -> Option<String> {
+    if settings::var(CONTENT_ADDRESSED_ENV)
+        .as_deref()
+        .map(str::trim)
+        != Some("true")
+    {
+        return None;
+    }
+    let digest = path.trim_start_matches('/').strip_prefix(CAS_PREFIX)?;
+    (digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit()))
+        .then(|| digest.to_ascii_lowercase())
+}
+
+/// Look up the file with the given digest in the ETag manifest.
+/// Returns `None` if there is no manifest or no such file.
+pub(crate) fn file(digest: &str) -> Result<Option<String>> {
+    let Some(manifest) = settings::var(ETAG_MANIFEST_ENV) else {
+        return Ok(None);
+    };
+    Ok(find(
+        &precompressed::read_manifest(manifest.trim())?,
+        digest,
+    ))
+}
+
+/// Return the canonical copy of a file listed in the ETag manifest along with identical files, if
+/// enabled and it isn't the file itself.
+pub(crate) fn canonical(file: &Path) -> Result<Option<String>> {
+    if settings::var(CANONICAL_LOCATION_ENV)
+        .as_deref()
+        .map(str::trim)
+        != Some("true")
+    {
+        return Ok(None);
+    }
+    let (Some(manifest), Some(file)) = (settings::var(ETAG_MANIFEST_ENV), file.to_str()) else {
+        return Ok(None);
+    };
+    let manifest = precompressed::read_manifest(manifest.trim())?;
+    let Some(digest) = manifest.get(file).and_then(|etags| etags.get("identity")) else {
+        return Ok(None);
+    };
+    Ok(find(&manifest, digest).filter(|canonical| canonical != file))
+}
+
+/// Find the file whose unencoded contents have the given digest. Identical files are served from
+/// the first of their paths in lexicographic order, so the choice doesn't change between requests.
+fn find(manifest: &HashMap<String, HashMap<String, String>>, digest: &str) -> Option<String> {
+    manifest
+        .iter()
+        .filter(|(_, etags)| etags.get("identity").is_some_and(|etag| etag == digest))
+        .map(|(path, _)| path)
+        .min()
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let digest = "a".repeat(64);
+        let etags =
+            |identity: &str| HashMap::from([("identity".to_string(), identity.to_string())]);
+        let manifest = HashMap::from([
+            ("/fr/logo.svg".to_string(), etags(&digest)),
+            ("/en/logo.svg".to_string(), etags(&digest)),
+            ("/index.html".to_string(), etags(&"b".repeat(64))),
+        ]);
+        assert_eq!(find(&manifest, &digest), Some("/en/logo.svg".to_string()));
+        assert_eq!(find(&manifest, &"c".repeat(64)), None);
+    }
+}
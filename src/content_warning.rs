@@ -0,0 +1,111 @@
+//! A content warning served before the files under some path prefixes, for sites whose content
+//! legally requires visitors to click through an acknowledgment first.
+//!
+//! Visitors without the acknowledgment cookie get the warning page instead of the file, at the
+//! same URL. The page posts a form back to that URL, which sets the cookie and redirects to it.
+
+use std::{io::Cursor, path::Path};
+
+use anyhow::Result;
+use http::{
+    header::{CACHE_CONTROL, CONTENT_TYPE, LOCATION, SET_COOKIE, VARY},
+    StatusCode,
+};
+
+use crate::{config, request_url::RequestUrl, settings, FileServer, RequestContext, Response};
+
+/// Environment variable for the comma-separated path prefixes behind the warning, enabling it
+pub(crate) const CONTENT_WARNING_PREFIXES_ENV: &str = "CONTENT_WARNING_PREFIXES";
+/// Environment variable for the HTML file served as the warning
+pub(crate) const CONTENT_WARNING_PAGE_ENV: &str = "CONTENT_WARNING_PAGE";
+/// Environment variable for the number of seconds an acknowledgment lasts
+pub(crate) const CONTENT_WARNING_MAX_AGE_ENV: &str = "CONTENT_WARNING_MAX_AGE";
+/// Default number of seconds an acknowledgment lasts
+const DEFAULT_CONTENT_WARNING_MAX_AGE: u64 = 86400;
+/// Name of the cookie recording the acknowledgment
+const ACKNOWLEDGED_COOKIE: &str = "content_warning";
+/// Value of the cookie recording the acknowledgment
+const ACKNOWLEDGED: &str = "acknowledged";
+/// Warning served when no page is configured
+const DEFAULT_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="robots" content="noindex">
+<title>Content warning</title>
+</head>
+<body>
+<p>This page contains content you must agree to view before continuing.</p>
+<form method="post"><button type="submit">I understand, continue</button></form>
+</body>
+</html>
+"#;
+
+/// Serve the warning for requests under the configured prefixes that haven't acknowledged it,
+/// and record acknowledgments.
+/// Returns `None` if the warning isn't enabled, or the request may be served.
+pub(crate) fn handle(req: &RequestContext, path: &str) -> Option<Result<Response>> {
+    if !applies(path) {
+        return None;
+    }
+    if req.method == http::Method::POST {
+        return Some(Ok(acknowledge(req, path)));
+    }
+    if req.cookie(ACKNOWLEDGED_COOKIE) == Some(ACKNOWLEDGED) {
+        return None;
+    }
+    Some(warning(req))
+}
+
+/// Check whether a request path is behind the warning, so its response depends on the cookie.
+pub(crate) fn applies(path: &str) -> bool {
+    settings::var(CONTENT_WARNING_PREFIXES_ENV)
+        .is_some_and(|prefixes| config::under_prefixes(&prefixes, path))
+}
+
+/// Set the acknowledgment cookie and send the visitor back to the page they asked for.
+fn acknowledge(req: &RequestContext, path: &str) -> Response {
+    let max_age = settings::var(CONTENT_WARNING_MAX_AGE_ENV)
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_CONTENT_WARNING_MAX_AGE);
+    let cookie = format!(
+        "{ACKNOWLEDGED_COOKIE}={ACKNOWLEDGED}; Path=/; Max-Age={max_age}; HttpOnly; SameSite=Lax"
+    );
+    let mut location = RequestUrl::of(req).path(path);
+    if !req.query.is_empty() {
+        location = format!("{location}?{}", req.query);
+    }
+    (
+        StatusCode::SEE_OTHER,
+        vec![
+            (LOCATION.as_str().to_string(), location.into_bytes()),
+            (SET_COOKIE.as_str().to_string(), cookie.into_bytes()),
+            (CACHE_CONTROL.as_str().to_string(), b"no-store".to_vec()),
+        ],
+        None,
+    )
+}
+
+/// Build the response serving the warning page in place of the file.
+fn warning(req: &RequestContext) -> Result<Response> {
+    let page = match settings::var(CONTENT_WARNING_PAGE_ENV) {
+        Some(page) => FileServer::read_to_string(Path::new(page.trim()))?,
+        None => DEFAULT_PAGE.to_string(),
+    };
+    let body = (req.method != http::Method::HEAD)
+        .then(|| Box::new(Cursor::new(page.into_bytes())) as Box<dyn std::io::Read>);
+    Ok((
+        StatusCode::OK,
+        vec![
+            (
+                CONTENT_TYPE.as_str().to_string(),
+                b"text/html; charset=utf-8".to_vec(),
+            ),
+            // caches mustn't serve the warning to visitors who acknowledged it, or the file to
+            // those who didn't
+            (CACHE_CONTROL.as_str().to_string(), b"no-store".to_vec()),
+            (VARY.as_str().to_string(), b"cookie".to_vec()),
+        ],
+        body,
+    ))
+}
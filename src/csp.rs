@@ -0,0 +1,123 @@
+//! `Content-Security-Policy` header of HTML documents. Policies using a `{nonce}` placeholder get
+//! a nonce generated for every response, which is also added to the `<script>` and `<style>` tags
+//! of the document, so static sites can use strict policies without `'unsafe-inline'`.
+//!
+//! Documents served with a nonce differ on every response, so they aren't cached or revalidated.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::header::CONTENT_SECURITY_POLICY;
+
+use crate::{reproducible, settings};
+
+/// Environment variable for the `Content-Security-Policy` header of HTML documents
+pub(crate) const CONTENT_SECURITY_POLICY_ENV: &str = "CONTENT_SECURITY_POLICY";
+/// Placeholder replaced by the nonce of the response in the policy
+pub(crate) const NONCE_PLACEHOLDER: &str = "{nonce}";
+/// Tags the nonce is added to
+const NONCE_TAGS: [&str; 2] = ["script", "style"];
+
+/// Check whether a file is an HTML document, which the policy applies to.
+fn is_html(file: &Path) -> bool {
+    file.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("html") || ext.eq_ignore_ascii_case("htm"))
+}
+
+/// Check whether a file is served with a nonce: an HTML document whose policy has a nonce
+/// placeholder, unless responses must be byte-for-byte reproducible.
+pub(crate) fn uses_nonce(file: &Path) -> bool {
+    is_html(file)
+        && !reproducible::enabled()
+        && settings::var(CONTENT_SECURITY_POLICY_ENV)
+            .is_some_and(|policy| policy.contains(NONCE_PLACEHOLDER))
+}
+
+/// Generate a nonce for a response.
+pub(crate) fn nonce() -> Result<String> {
+    let mut nonce = [0_u8; 16];
+    getrandom::getrandom(&mut nonce).map_err(|e| anyhow!("cannot generate a CSP nonce: {e}"))?;
+    Ok(STANDARD.encode(nonce))
+}
+
+/// Return the `Content-Security-Policy` header of a file, if it is an HTML document and a policy
+/// is configured, with the nonce of the response filled in.
+pub(crate) fn header(file: &Path, nonce: Option<&str>) -> Option<(String, Vec<u8>)> {
+    let policy = settings::var(CONTENT_SECURITY_POLICY_ENV).filter(|_| is_html(file))?;
+    let policy = match nonce {
+        Some(nonce) => policy.trim().replace(NONCE_PLACEHOLDER, nonce),
+        None => policy.trim().to_string(),
+    };
+    Some((
+        CONTENT_SECURITY_POLICY.as_str().to_string(),
+        policy.into_bytes(),
+    ))
+}
+
+/// Add a `nonce` attribute to the `<script>` and `<style>` tags of a document that don't have
+/// one.
+pub(crate) fn inject(html: &str, nonce: &str) -> String {
+    let mut injected = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        let (before, tag) = rest.split_at(start);
+        injected.push_str(before);
+        let end = tag.find('>').map_or(tag.len(), |end| end + 1);
+        let (tag, after) = tag.split_at(end);
+        injected.push_str(&with_nonce(tag, nonce));
+        rest = after;
+    }
+    injected.push_str(rest);
+    injected
+}
+
+/// Add a `nonce` attribute to an opening tag, if it is one of the tags nonces apply to.
+fn with_nonce(tag: &str, nonce: &str) -> String {
+    let name_len = tag[1..]
+        .find(|c: char| c.is_ascii_whitespace() || c == '>' || c == '/')
+        .unwrap_or(tag.len() - 1);
+    let name = &tag[1..=name_len];
+    let applies = NONCE_TAGS.iter().any(|t| t.eq_ignore_ascii_case(name))
+        && !tag.to_ascii_lowercase().contains("nonce=");
+    if !applies {
+        return tag.to_string();
+    }
+    let (open, attributes) = tag.split_at(name_len + 1);
+    format!("{open} nonce=\"{nonce}\"{attributes}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inject() {
+        let html = r#"<!DOCTYPE html>
+<html><head>
+<SCRIPT src="/app.js"></SCRIPT>
+<style>body { color: red; }</style>
+<script nonce="fixed">1 < 2</script>
+<link rel="stylesheet" href="/app.css"><scripts></scripts>
+</head></html>"#;
+        assert_eq!(
+            inject(html, "abc"),
+            r#"<!DOCTYPE html>
+<html><head>
+<SCRIPT nonce="abc" src="/app.js"></SCRIPT>
+<style nonce="abc">body { color: red; }</style>
+<script nonce="fixed">1 < 2</script>
+<link rel="stylesheet" href="/app.css"><scripts></scripts>
+</head></html>"#
+        );
+        assert_eq!(inject("a < b", "abc"), "a < b");
+    }
+
+    #[test]
+    fn test_nonce() {
+        let (a, b) = (nonce().unwrap(), nonce().unwrap());
+        assert_eq!(a.len(), 24);
+        assert_ne!(a, b);
+    }
+}
@@ -0,0 +1,147 @@
+//! Deploy windows, during which HTML documents are cached for less time, so visitors see a
+//! release soon after it is deployed without giving up caching the rest of the time.
+//!
+//! Only the `max-age` and `s-maxage` directives of the `Cache-Control` header of HTML responses
+//! are lowered; other responses keep theirs, since fingerprinted assets change names instead.
+
+use serde::{Deserialize, Serialize};
+use toml::value::Datetime;
+
+use crate::embargo::timestamp;
+
+/// Cache lifetime directives lowered during a deploy window
+const MAX_AGE_DIRECTIVES: [&str; 2] = ["max-age", "s-maxage"];
+
+/// A time window during which HTML documents are cached for at most a number of seconds.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct DeployWindow {
+    /// The time the window starts at.
+    pub start: Datetime,
+    /// The time the window ends at, when the configured caching is restored.
+    pub end: Datetime,
+    /// The number of seconds HTML documents are cached for at most during the window.
+    #[serde(default)]
+    pub max_age: u64,
+}
+
+impl DeployWindow {
+    /// Check that the window has valid times, and ends after it starts.
+    pub fn validate(&self) -> Result<(), String> {
+        match (timestamp(&self.start), timestamp(&self.end)) {
+            (Some(start), Some(end)) if start < end => Ok(()),
+            (Some(_), Some(_)) => Err(format!(
+                "deploy window from {} ends before it starts",
+                self.start
+            )),
+            _ => Err(format!(
+                "deploy window from {} has a time without a date",
+                self.start
+            )),
+        }
+    }
+
+    /// Check whether the window is open at a time, in seconds since the Unix epoch.
+    fn is_open(&self, now: i64) -> bool {
+        match (timestamp(&self.start), timestamp(&self.end)) {
+            (Some(start), Some(end)) => start <= now && now < end,
+            _ => false,
+        }
+    }
+}
+
+/// Return the number of seconds HTML documents are cached for at most at a time, in seconds
+/// since the Unix epoch, if a deploy window is open then.
+pub(crate) fn max_age(windows: &[DeployWindow], now: i64) -> Option<u64> {
+    windows
+        .iter()
+        .filter(|window| window.is_open(now))
+        .map(|window| window.max_age)
+        .min()
+}
+
+/// Lower the cache lifetimes of a `Cache-Control` value to at most `max_age` seconds. Documents
+/// without a lifetime get one, unless they aren't stored at all, and none stay immutable.
+pub(crate) fn tighten(cache_control: &str, max_age: u64) -> String {
+    let mut directives = Vec::new();
+    let mut has_lifetime = false;
+    let mut uncached = false;
+    for directive in cache_control.split(',').map(str::trim) {
+        let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+        let name = name.trim().to_ascii_lowercase();
+        if name == "immutable" || directive.is_empty() {
+            continue;
+        }
+        uncached |= name == "no-store" || name == "no-cache";
+        if MAX_AGE_DIRECTIVES.contains(&name.as_str()) {
+            has_lifetime = true;
+            let lifetime = value
+                .trim()
+                .parse()
+                .map_or(max_age, |v: u64| v.min(max_age));
+            directives.push(format!("{name}={lifetime}"));
+        } else {
+            directives.push(directive.to_string());
+        }
+    }
+    if !has_lifetime && !uncached {
+        directives.push(format!("max-age={max_age}"));
+    }
+    directives.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(start: &str, end: &str, max_age: u64) -> DeployWindow {
+        DeployWindow {
+            start: start.parse().unwrap(),
+            end: end.parse().unwrap(),
+            max_age,
+        }
+    }
+
+    #[test]
+    fn test_max_age() {
+        let windows = [
+            window("2024-02-29T12:00:00Z", "2024-02-29T13:00:00Z", 60),
+            window("2024-02-29T12:30:00Z", "2024-02-29T12:45:00Z", 0),
+        ];
+        let noon = 1709208000;
+        assert_eq!(max_age(&windows, noon - 1), None);
+        assert_eq!(max_age(&windows, noon), Some(60));
+        assert_eq!(max_age(&windows, noon + 40 * 60), Some(0));
+        assert_eq!(max_age(&windows, noon + 3600), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(window("2024-02-29T12:00:00Z", "2024-02-29T13:00:00Z", 0)
+            .validate()
+            .is_ok());
+        assert_eq!(
+            window("2024-02-29T12:00:00Z", "2024-02-29", 0).validate(),
+            Err("deploy window from 2024-02-29T12:00:00Z ends before it starts".to_string())
+        );
+        assert_eq!(
+            window("12:00:00", "2024-02-29", 0).validate(),
+            Err("deploy window from 12:00:00 has a time without a date".to_string())
+        );
+    }
+
+    #[test]
+    fn test_tighten() {
+        assert_eq!(
+            tighten("public, max-age=3600, s-maxage=86400", 60),
+            "public, max-age=60, s-maxage=60"
+        );
+        assert_eq!(tighten("max-age=30", 60), "max-age=30");
+        assert_eq!(
+            tighten("public, max-age=31536000, immutable", 0),
+            "public, max-age=0"
+        );
+        assert_eq!(tighten("public", 0), "public, max-age=0");
+        assert_eq!(tighten("no-store", 0), "no-store");
+    }
+}
@@ -0,0 +1,170 @@
+//! `Content-Disposition` and `X-Content-Type-Options` headers by file extension, so documents
+//! such as PDFs open in the browser while executables and scripts are always downloaded, whatever
+//! browsers would guess from their contents.
+
+use http::header::{CONTENT_DISPOSITION, X_CONTENT_TYPE_OPTIONS};
+use std::path::Path;
+
+use crate::settings;
+
+/// Environment variable for the comma-separated `extension=inline|attachment` rules
+pub(crate) const CONTENT_DISPOSITION_ENV: &str = "CONTENT_DISPOSITION";
+/// Rule standing for the built-in rules
+const DEFAULT_RULES: &str = "default";
+/// Extensions of documents browsers can display, opened inline by the built-in rules
+const INLINE_EXTENSIONS: &[&str] = &["pdf"];
+/// Extensions of executables, installers and scripts, downloaded by the built-in rules
+const ATTACHMENT_EXTENSIONS: &[&str] = &[
+    "apk", "bat", "bin", "cmd", "deb", "dmg", "exe", "jar", "msi", "pkg", "ps1", "rpm", "sh",
+];
+
+/// How browsers present a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Disposition {
+    /// Display the file in the browser.
+    Inline,
+    /// Download the file.
+    Attachment,
+}
+
+/// Return the `Content-Disposition` and `X-Content-Type-Options` headers of a response for a
+/// request path, if a rule applies to its extension.
+pub(crate) fn headers(path: &str) -> Vec<(String, Vec<u8>)> {
+    let Some(rules) = settings::var(CONTENT_DISPOSITION_ENV) else {
+        return Vec::new();
+    };
+    let Some(disposition) = disposition_for(&rules, path) else {
+        return Vec::new();
+    };
+    let value = match disposition {
+        Disposition::Inline => "inline".to_string(),
+        Disposition::Attachment => attachment(path),
+    };
+    vec![
+        (CONTENT_DISPOSITION.as_str().to_string(), value.into_bytes()),
+        // browsers must not override the disposition by sniffing the contents
+        (
+            X_CONTENT_TYPE_OPTIONS.as_str().to_string(),
+            b"nosniff".to_vec(),
+        ),
+    ]
+}
+
+/// Parse the configured rules, where later rules take precedence over earlier ones.
+pub(crate) fn parse_rules(configured: &str) -> Result<Vec<(String, Disposition)>, String> {
+    let mut rules = Vec::new();
+    for rule in configured
+        .split(',')
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+    {
+        if rule == DEFAULT_RULES {
+            let inline = INLINE_EXTENSIONS.iter().map(|e| (e, Disposition::Inline));
+            let attachment = ATTACHMENT_EXTENSIONS
+                .iter()
+                .map(|e| (e, Disposition::Attachment));
+            rules.extend(inline.chain(attachment).map(|(e, d)| (e.to_string(), d)));
+            continue;
+        }
+        let (extension, disposition) = rule
+            .split_once('=')
+            .ok_or_else(|| format!("{rule:?} is not of the form extension=disposition"))?;
+        let disposition = match disposition.trim() {
+            "inline" => Disposition::Inline,
+            "attachment" => Disposition::Attachment,
+            other => return Err(format!("{other:?} is not one of inline, attachment")),
+        };
+        let extension = extension.trim().trim_start_matches('.').to_lowercase();
+        rules.push((extension, disposition));
+    }
+    Ok(rules)
+}
+
+/// Return the disposition of a request path under the configured rules.
+fn disposition_for(configured: &str, path: &str) -> Option<Disposition> {
+    let extension = Path::new(path).extension()?.to_str()?.to_lowercase();
+    let rules = parse_rules(configured).ok()?;
+    rules
+        .into_iter()
+        .rev()
+        .find(|(e, _)| *e == extension)
+        .map(|(_, disposition)| disposition)
+}
+
+/// Return the `Content-Disposition` value downloading a request path under its file name.
+fn attachment(path: &str) -> String {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    if name.is_ascii() {
+        return format!("attachment; filename=\"{}\"", quote(name));
+    }
+    // older clients only understand `filename`, so non-ASCII names get an approximation there
+    let fallback = name
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect::<String>();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        quote(&fallback),
+        encode(name)
+    )
+}
+
+/// Escape a file name for a quoted string.
+fn quote(name: &str) -> String {
+    name.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Percent-encode a file name for an extended parameter value (RFC 8187).
+fn encode(name: &str) -> String {
+    name.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => (b as char).to_string(),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disposition_for() {
+        let rules = "default, sh=inline, .TXT=attachment";
+        assert_eq!(
+            disposition_for(rules, "/docs/Report.PDF"),
+            Some(Disposition::Inline)
+        );
+        assert_eq!(
+            disposition_for(rules, "/setup.exe"),
+            Some(Disposition::Attachment)
+        );
+        assert_eq!(
+            disposition_for(rules, "/install.sh"),
+            Some(Disposition::Inline)
+        );
+        assert_eq!(
+            disposition_for(rules, "/notes.txt"),
+            Some(Disposition::Attachment)
+        );
+        assert_eq!(disposition_for(rules, "/index.html"), None);
+        assert_eq!(disposition_for(rules, "/LICENSE"), None);
+        assert!(parse_rules("pdf").is_err());
+        assert!(parse_rules("pdf=download").is_err());
+    }
+
+    #[test]
+    fn test_attachment() {
+        assert_eq!(
+            attachment("/bin/setup \"v2\".exe"),
+            r#"attachment; filename="setup \"v2\".exe""#
+        );
+        assert_eq!(
+            attachment("/résumé.pdf"),
+            r#"attachment; filename="r_sum_.pdf"; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf"#
+        );
+    }
+}
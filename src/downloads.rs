@@ -0,0 +1,279 @@
+//! Download bookkeeping in the default key-value store: limits on concurrent large downloads
+//! and per-path download counters.
+//!
+//! Spin instantiates the component for every request, so state is recorded in the key-value
+//! store where all instances can see it. The store has no atomic updates, which makes limits and
+//! counts best-effort under heavy contention. Every download slot expires after [`SLOT_TTL`] so
+//! downloads interrupted without releasing their slot don't count forever.
+
+use anyhow::{anyhow, Context, Result};
+use http::{header::RETRY_AFTER, Method, StatusCode};
+use std::{
+    collections::BTreeMap,
+    io::{Cursor, Read},
+    str,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{host::Store, settings, RequestContext, Response};
+
+/// Environment variable for the maximum number of concurrent large downloads
+pub(crate) const LARGE_DOWNLOAD_LIMIT_ENV: &str = "LARGE_DOWNLOAD_LIMIT";
+/// Environment variable for the size in bytes above which a download counts as large
+pub(crate) const LARGE_DOWNLOAD_SIZE_ENV: &str = "LARGE_DOWNLOAD_SIZE";
+/// Environment variable for the `Retry-After` value sent when the limit is reached
+pub(crate) const LARGE_DOWNLOAD_RETRY_AFTER_ENV: &str = "LARGE_DOWNLOAD_RETRY_AFTER";
+/// Default size in bytes above which a download counts as large
+pub(crate) const DEFAULT_LARGE_DOWNLOAD_SIZE: u64 = 10 * 1024 * 1024;
+/// Default `Retry-After` value in seconds
+pub(crate) const DEFAULT_RETRY_AFTER: u64 = 30;
+/// Key-value store key holding the in-flight download slots
+const SLOTS_KEY: &str = "spin-fileserver:download-slots";
+/// Seconds after which a slot is considered abandoned
+const SLOT_TTL: u64 = 60 * 60;
+/// Environment variable enabling per-path download counters
+pub(crate) const DOWNLOAD_COUNTER_ENV: &str = "DOWNLOAD_COUNTER";
+/// Key-value store key prefix of the download counters
+const COUNTER_PREFIX: &str = "spin-fileserver:downloads:";
+
+/// Limits the number of large downloads served at the same time.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct DownloadLimiter {
+    /// Size in bytes above which a download counts as large.
+    size: u64,
+    /// Maximum number of concurrent large downloads.
+    limit: usize,
+    /// Seconds clients are asked to wait when the limit is reached.
+    retry_after: u64,
+}
+
+impl DownloadLimiter {
+    /// Return the configured limiter, if `LARGE_DOWNLOAD_LIMIT` is set.
+    pub fn from_env() -> Option<Self> {
+        let limit = settings::var(LARGE_DOWNLOAD_LIMIT_ENV)?
+            .trim()
+            .parse()
+            .ok()?;
+        let size = settings::var(LARGE_DOWNLOAD_SIZE_ENV)
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(DEFAULT_LARGE_DOWNLOAD_SIZE);
+        let retry_after = settings::var(LARGE_DOWNLOAD_RETRY_AFTER_ENV)
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(DEFAULT_RETRY_AFTER);
+        Some(Self {
+            size,
+            limit,
+            retry_after,
+        })
+    }
+
+    /// Check whether a file of the given size counts as a large download.
+    pub fn applies_to(&self, size: u64) -> bool {
+        size > self.size
+    }
+
+    /// Try to take a download slot, returning `None` if the limit is reached.
+    pub fn acquire(&self) -> Result<Option<Slot>> {
+        let store = Store::open_default()
+            .context("cannot open the key-value store to track large downloads")?;
+        let mut slots = Slots::parse(&store.get(SLOTS_KEY)?.unwrap_or_default());
+        slots.prune(now());
+        if slots.0.len() >= self.limit {
+            return Ok(None);
+        }
+
+        let mut id = [0_u8; 8];
+        getrandom::getrandom(&mut id)
+            .map_err(|e| anyhow!("cannot generate a download slot id: {e}"))?;
+        let id = hex::encode(id);
+        slots.0.push((id.clone(), now()));
+        store.set(SLOTS_KEY, &slots.to_bytes())?;
+        Ok(Some(Slot { id }))
+    }
+
+    /// The response sent when no slot is available.
+    pub fn unavailable(&self) -> Response {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            vec![(
+                RETRY_AFTER.as_str().to_string(),
+                self.retry_after.to_string().into_bytes(),
+            )],
+            Some(Box::new(Cursor::new(b"Service Unavailable"))),
+        )
+    }
+}
+
+/// Check whether download counters are enabled.
+pub(crate) fn counter_enabled() -> bool {
+    settings::var(DOWNLOAD_COUNTER_ENV).is_some_and(|v| v.trim() == "true")
+}
+
+/// Check whether a response is a download: a full or partial response to a `GET` request.
+/// `HEAD` requests and revalidations don't transfer the file.
+pub(crate) fn is_download(req: &RequestContext, status: StatusCode) -> bool {
+    req.method == Method::GET && matches!(status, StatusCode::OK | StatusCode::PARTIAL_CONTENT)
+}
+
+/// Increment the download counter of a request path.
+pub(crate) fn count_download(path: &str) -> Result<()> {
+    let store =
+        Store::open_default().context("cannot open the key-value store to count downloads")?;
+    let key = format!("{COUNTER_PREFIX}{path}");
+    let count = parse_count(&store.get(&key)?.unwrap_or_default());
+    store.set(&key, (count + 1).to_string().as_bytes())?;
+    Ok(())
+}
+
+/// Return the download counts of all counted paths.
+pub(crate) fn download_counts() -> Result<BTreeMap<String, u64>> {
+    let store =
+        Store::open_default().context("cannot open the key-value store to count downloads")?;
+    let mut counts = BTreeMap::new();
+    for key in store.get_keys()? {
+        if let Some(path) = key.strip_prefix(COUNTER_PREFIX) {
+            let count = parse_count(&store.get(&key)?.unwrap_or_default());
+            counts.insert(path.to_string(), count);
+        }
+    }
+    Ok(counts)
+}
+
+pub(crate) fn parse_count(bytes: &[u8]) -> u64 {
+    str::from_utf8(bytes)
+        .ok()
+        .and_then(|c| c.trim().parse().ok())
+        .unwrap_or_default()
+}
+
+/// A taken download slot, released when dropped.
+pub(crate) struct Slot {
+    id: String,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let release = || -> Result<()> {
+            let store = Store::open_default()?;
+            let mut slots = Slots::parse(&store.get(SLOTS_KEY)?.unwrap_or_default());
+            slots.0.retain(|(id, _)| *id != self.id);
+            slots.prune(now());
+            store.set(SLOTS_KEY, &slots.to_bytes())?;
+            Ok(())
+        };
+        if let Err(e) = release() {
+            eprintln!("Error releasing download slot: {e}");
+        }
+    }
+}
+
+/// A reader that holds a download slot until the body has been sent.
+pub(crate) struct SlotReader {
+    inner: Box<dyn Read>,
+    _slot: Slot,
+}
+
+impl SlotReader {
+    pub fn new(inner: Box<dyn Read>, slot: Slot) -> Self {
+        Self { inner, _slot: slot }
+    }
+}
+
+impl Read for SlotReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+/// In-flight download slots, stored as lines of `<id> <started-at>`.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Slots(Vec<(String, u64)>);
+
+impl Slots {
+    fn parse(bytes: &[u8]) -> Self {
+        let slots = str::from_utf8(bytes)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| {
+                let (id, started) = line.split_once(' ')?;
+                Some((id.to_string(), started.parse().ok()?))
+            })
+            .collect();
+        Self(slots)
+    }
+
+    /// Drop slots that have been held for longer than [`SLOT_TTL`].
+    fn prune(&mut self, now: u64) {
+        self.0
+            .retain(|(_, started)| now.saturating_sub(*started) < SLOT_TTL);
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0
+            .iter()
+            .map(|(id, started)| format!("{id} {started}\n"))
+            .collect::<String>()
+            .into_bytes()
+    }
+}
+
+/// Seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_roundtrip_and_prune() {
+        let mut slots = Slots::parse(b"a 100\nb 5000\ngarbage\nc x\n");
+        assert_eq!(
+            slots,
+            Slots(vec![("a".to_string(), 100), ("b".to_string(), 5000)])
+        );
+        assert_eq!(Slots::parse(&slots.to_bytes()), slots);
+
+        slots.prune(100 + SLOT_TTL);
+        assert_eq!(slots, Slots(vec![("b".to_string(), 5000)]));
+    }
+
+    #[test]
+    fn test_is_download() {
+        let get = RequestContext::default();
+        let head = RequestContext {
+            method: Method::HEAD,
+            ..Default::default()
+        };
+        assert!(is_download(&get, StatusCode::OK));
+        assert!(is_download(&get, StatusCode::PARTIAL_CONTENT));
+        assert!(!is_download(&get, StatusCode::NOT_MODIFIED));
+        assert!(!is_download(&get, StatusCode::NOT_FOUND));
+        assert!(!is_download(&head, StatusCode::OK));
+    }
+
+    #[test]
+    fn test_parse_count() {
+        assert_eq!(parse_count(b"42"), 42);
+        assert_eq!(parse_count(b""), 0);
+        assert_eq!(parse_count(b"not a number"), 0);
+    }
+
+    #[test]
+    fn test_unavailable_response() {
+        let limiter = DownloadLimiter {
+            size: 1024,
+            limit: 2,
+            retry_after: 10,
+        };
+        assert!(!limiter.applies_to(1024));
+        assert!(limiter.applies_to(1025));
+        let (status, headers, _) = limiter.unavailable();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(headers, vec![("retry-after".to_string(), b"10".to_vec())]);
+    }
+}
@@ -0,0 +1,182 @@
+//! Drafts under `/drafts/`, only served to requests carrying a signed preview token, so content
+//! authors can share unpublished pages on the production host.
+//!
+//! Tokens are issued by the `preview` admin route for the drafts under a path, and expire. The
+//! link it returns carries the token in its query string: the first request sets it as a cookie
+//! and redirects to the draft without it, so the token doesn't linger in the address bar. Drafts
+//! requested without a valid token are not found, rather than forbidden, so their names aren't
+//! disclosed.
+
+use anyhow::Result;
+use http::{
+    header::{ALLOW, CACHE_CONTROL, LOCATION, SET_COOKIE},
+    Method, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin, config, etag_cache::now, oidc, plain_response, request_url::RequestUrl, settings,
+    RequestContext, Response,
+};
+
+/// Environment variable for the secret preview tokens are signed with, enabling drafts
+pub(crate) const DRAFTS_SECRET_ENV: &str = "DRAFTS_SECRET";
+/// Environment variable for the number of seconds preview tokens are valid
+pub(crate) const DRAFTS_PREVIEW_MAX_AGE_ENV: &str = "DRAFTS_PREVIEW_MAX_AGE";
+/// Default number of seconds preview tokens are valid
+const DEFAULT_PREVIEW_MAX_AGE: u64 = 7 * 24 * 60 * 60;
+/// Request path prefix of the drafts
+pub(crate) const DRAFTS_PREFIX: &str = "/drafts";
+/// Query parameter carrying the token in preview links
+const PREVIEW_PARAM: &str = "preview";
+/// Cookie holding the token
+const PREVIEW_COOKIE: &str = "spin-fileserver-preview";
+
+/// Access to the drafts under a path until a time, signed into a token.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Preview {
+    /// The request path of the drafts the token gives access to.
+    path: String,
+    /// The time in seconds since the Unix epoch the token expires at.
+    expires: u64,
+}
+
+impl Preview {
+    /// Check whether the token gives access to a request path now.
+    fn allows(&self, path: &str) -> bool {
+        self.expires > now() && config::under_prefixes(&self.path, path)
+    }
+}
+
+/// Check whether a request path is a draft, so its response depends on the cookie. Paths are
+/// compared once normalized, so no spelling of a draft's path escapes the check.
+pub(crate) fn applies(path: &str) -> bool {
+    settings::var(DRAFTS_SECRET_ENV).is_some() && config::under_prefixes(DRAFTS_PREFIX, path)
+}
+
+/// Answer requests for drafts without a valid preview token, and turn the tokens of preview links
+/// into cookies.
+/// Returns `None` if drafts aren't enabled, or the request may be served.
+pub(crate) fn handle(req: &RequestContext, path: &str) -> Option<Response> {
+    if !applies(path) {
+        return None;
+    }
+    let secret = settings::var(DRAFTS_SECRET_ENV)?;
+    let verify = |token: &str| oidc::verify::<Preview>(&secret, token).filter(|p| p.allows(path));
+
+    let (linked, query) = without_token(&req.query);
+    if let Some(token) = linked {
+        return Some(match verify(token) {
+            Some(preview) => open(req, path, &query, token, preview.expires),
+            None => plain_response(StatusCode::NOT_FOUND),
+        });
+    }
+    if req.cookie(PREVIEW_COOKIE).and_then(verify).is_some() {
+        return None;
+    }
+    Some(plain_response(StatusCode::NOT_FOUND))
+}
+
+/// Issue a preview token for the drafts under a path relative to the drafts directory, and
+/// respond with the link sharing it. Served by the `preview` admin route.
+pub(crate) fn issue(req: &RequestContext, path: &str) -> Result<Response> {
+    if req.method != Method::POST {
+        let (status, mut headers, body) = plain_response(StatusCode::METHOD_NOT_ALLOWED);
+        headers.push((ALLOW.as_str().to_string(), b"POST".to_vec()));
+        return Ok((status, headers, body));
+    }
+    let Some(secret) = settings::var(DRAFTS_SECRET_ENV) else {
+        return Ok(plain_response(StatusCode::NOT_FOUND));
+    };
+    if path.split('/').any(|segment| segment == "..") {
+        return Ok(plain_response(StatusCode::BAD_REQUEST));
+    }
+    let max_age = settings::var(DRAFTS_PREVIEW_MAX_AGE_ENV)
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_PREVIEW_MAX_AGE);
+    let preview = Preview {
+        path: format!("{DRAFTS_PREFIX}/{}", path.trim_matches('/')),
+        expires: now() + max_age,
+    };
+    let token = oidc::sign(&secret, &preview)?;
+    let url = RequestUrl::of(req);
+    let link = format!("{}?{PREVIEW_PARAM}={token}", preview.path);
+    admin::json_response(&serde_json::json!({
+        "url": url.absolute(&link).unwrap_or_else(|| url.path(&link)),
+        "expires": preview.expires,
+    }))
+}
+
+/// Set the token of a preview link as a cookie, and redirect to the draft without it.
+fn open(req: &RequestContext, path: &str, query: &str, token: &str, expires: u64) -> Response {
+    let max_age = expires.saturating_sub(now());
+    let cookie = format!(
+        "{PREVIEW_COOKIE}={token}; Path={}; Max-Age={max_age}; HttpOnly; Secure; SameSite=Lax",
+        RequestUrl::of(req).path(DRAFTS_PREFIX)
+    );
+    let mut location = RequestUrl::of(req).path(path);
+    if !query.is_empty() {
+        location = format!("{location}?{query}");
+    }
+    (
+        StatusCode::SEE_OTHER,
+        vec![
+            (LOCATION.as_str().to_string(), location.into_bytes()),
+            (SET_COOKIE.as_str().to_string(), cookie.into_bytes()),
+            (CACHE_CONTROL.as_str().to_string(), b"no-store".to_vec()),
+        ],
+        None,
+    )
+}
+
+/// Split the preview token out of a query string, returning it and the rest of the query.
+fn without_token(query: &str) -> (Option<&str>, String) {
+    let mut token = None;
+    let mut rest = Vec::new();
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        match param
+            .strip_prefix(PREVIEW_PARAM)
+            .and_then(|p| p.strip_prefix('='))
+        {
+            Some(value) if token.is_none() => token = Some(value),
+            Some(_) => {}
+            None => rest.push(param),
+        }
+    }
+    (token, rest.join("&"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_without_token() {
+        assert_eq!(without_token(""), (None, String::new()));
+        assert_eq!(without_token("a=1&b"), (None, "a=1&b".to_string()));
+        assert_eq!(
+            without_token("a=1&preview=x.y&previews=2"),
+            (Some("x.y"), "a=1&previews=2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_allows() {
+        let preview = Preview {
+            path: "/drafts/launch".to_string(),
+            expires: now() + 60,
+        };
+        assert!(preview.allows("/drafts/launch"));
+        assert!(preview.allows("/drafts/launch/index.html"));
+        assert!(!preview.allows("/drafts/launches.html"));
+        assert!(!preview.allows("/drafts/other.html"));
+        assert!(preview.allows("/./drafts//launch"));
+        assert!(!preview.allows("/drafts/launch/../other.html"));
+
+        let expired = Preview {
+            expires: now() - 1,
+            ..preview
+        };
+        assert!(!expired.allows("/drafts/launch"));
+    }
+}
@@ -0,0 +1,204 @@
+//! Content embargoes: request paths only served from a release time on, or until a withdrawal
+//! time, for product launches and press kits. Embargoed paths are not found, or serve a teaser
+//! page until they are released.
+//!
+//! Times are TOML datetimes, in UTC unless they have an offset. Responses for embargoed paths
+//! aren't cached, so the content is served as soon as it is released.
+
+use serde::{Deserialize, Serialize};
+use toml::value::{Datetime, Offset};
+
+use crate::{access::glob_match, access_log::days_from_civil};
+
+/// The time window the request paths matching a glob are served in.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct EmbargoRule {
+    /// Glob matched against the request path, as in access rules.
+    pub path: String,
+    /// The time the paths are released at.
+    pub not_before: Option<Datetime>,
+    /// The time the paths are withdrawn at.
+    pub not_after: Option<Datetime>,
+    /// Path of the page served instead before the release.
+    pub teaser: Option<String>,
+}
+
+/// What to do with a request for an embargoed path.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Embargo<'a> {
+    /// Serve the teaser page at the given path instead.
+    Teaser(&'a str),
+    /// Respond with `404 Not Found`.
+    NotFound,
+}
+
+impl EmbargoRule {
+    /// Check that the rule has a time window with valid times.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.not_before.is_none() && self.not_after.is_none() {
+            return Err(format!(
+                "embargo rule for {} has neither not-before nor not-after",
+                self.path
+            ));
+        }
+        for time in [&self.not_before, &self.not_after].into_iter().flatten() {
+            if timestamp(time).is_none() {
+                return Err(format!(
+                    "embargo rule for {} has a time without a date: {time}",
+                    self.path
+                ));
+            }
+        }
+        if self.teaser.is_some() && self.not_before.is_none() {
+            return Err(format!(
+                "embargo rule for {} has a teaser but no not-before",
+                self.path
+            ));
+        }
+        Ok(())
+    }
+
+    /// Return what to do with a request at a time, in seconds since the Unix epoch, if the rule
+    /// embargoes it. Invalid times are ignored.
+    fn embargo(&self, now: i64) -> Option<Embargo<'_>> {
+        let not_before = self.not_before.as_ref().and_then(timestamp);
+        let not_after = self.not_after.as_ref().and_then(timestamp);
+        if not_before.is_some_and(|release| now < release) {
+            return Some(match &self.teaser {
+                Some(teaser) => Embargo::Teaser(teaser),
+                None => Embargo::NotFound,
+            });
+        }
+        not_after
+            .is_some_and(|withdrawal| now >= withdrawal)
+            .then_some(Embargo::NotFound)
+    }
+}
+
+/// Apply the first rule matching a request path at a time, in seconds since the Unix epoch.
+/// Returns `None` if the path is served.
+pub(crate) fn check<'a>(rules: &'a [EmbargoRule], path: &str, now: i64) -> Option<Embargo<'a>> {
+    rules
+        .iter()
+        .find(|rule| glob_match(&rule.path, path))?
+        .embargo(now)
+}
+
+/// Return the seconds since the Unix epoch of a datetime, if it has a date. Times default to
+/// midnight, and offsets to UTC.
+pub(crate) fn timestamp(time: &Datetime) -> Option<i64> {
+    let date = time.date?;
+    let days = days_from_civil(
+        i64::from(date.year),
+        u32::from(date.month),
+        u32::from(date.day),
+    );
+    let secs = time.time.map_or(0, |t| {
+        i64::from(t.hour) * 3600 + i64::from(t.minute) * 60 + i64::from(t.second)
+    });
+    let offset = match time.offset {
+        Some(Offset::Custom { minutes }) => i64::from(minutes) * 60,
+        Some(Offset::Z) | None => 0,
+    };
+    Some(days * 86400 + secs - offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(toml: &str) -> Vec<EmbargoRule> {
+        #[derive(Deserialize)]
+        struct Rules {
+            embargo: Vec<EmbargoRule>,
+        }
+        toml::from_str::<Rules>(toml).unwrap().embargo
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let at = |s: &str| timestamp(&s.parse().unwrap());
+        assert_eq!(at("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(at("2024-02-29T12:00:00Z"), Some(1709208000));
+        assert_eq!(at("2024-02-29T14:00:00+02:00"), Some(1709208000));
+        assert_eq!(at("2024-02-29 12:00:00"), Some(1709208000));
+        assert_eq!(at("2024-02-29"), Some(1709164800));
+        assert_eq!(at("12:00:00"), None);
+    }
+
+    #[test]
+    fn test_check() {
+        let rules = rules(
+            r#"
+            [[embargo]]
+            path = "/launch/**"
+            not-before = 2024-02-29T12:00:00Z
+            teaser = "/coming-soon.html"
+
+            [[embargo]]
+            path = "/press/**"
+            not-before = 2024-02-29T12:00:00Z
+            not-after = 2024-03-01
+
+            [[embargo]]
+            path = "/**"
+            not-after = 1970-01-01
+            "#,
+        );
+        let release = 1709208000;
+        assert_eq!(
+            check(&rules, "/launch/index.html", release - 1),
+            Some(Embargo::Teaser("/coming-soon.html"))
+        );
+        assert_eq!(check(&rules, "/launch/index.html", release), None);
+        assert_eq!(
+            check(&rules, "/press/kit.zip", release - 1),
+            Some(Embargo::NotFound)
+        );
+        assert_eq!(check(&rules, "/press/kit.zip", release), None);
+        assert_eq!(
+            check(&rules, "/press/kit.zip", release + 12 * 3600),
+            Some(Embargo::NotFound)
+        );
+        // the first matching rule decides
+        assert_eq!(
+            check(&rules, "/index.html", release),
+            Some(Embargo::NotFound)
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let problems = rules(
+            r#"
+            [[embargo]]
+            path = "/a/**"
+            not-before = 2024-02-29T12:00:00Z
+
+            [[embargo]]
+            path = "/b/**"
+
+            [[embargo]]
+            path = "/c/**"
+            not-after = 12:00:00
+
+            [[embargo]]
+            path = "/d/**"
+            not-after = 2024-02-29
+            teaser = "/soon.html"
+            "#,
+        )
+        .iter()
+        .filter_map(|rule| rule.validate().err())
+        .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            [
+                "embargo rule for /b/** has neither not-before nor not-after",
+                "embargo rule for /c/** has a time without a date: 12:00:00",
+                "embargo rule for /d/** has a teaser but no not-before",
+            ]
+        );
+    }
+}
@@ -0,0 +1,87 @@
+//! Errors that decide the status of the response to a request, so failures are reported to
+//! clients as what they are instead of all as `500 Internal Server Error`.
+//!
+//! They are returned as `anyhow` errors like any other, and turned into a response in one place
+//! by `FileServer::make_response`. Other errors are internal errors.
+
+use http::StatusCode;
+use std::{fmt, io};
+
+/// An error with the status of the response it results in.
+#[derive(Debug)]
+pub(crate) enum FileServerError {
+    /// The request names nothing that can be served.
+    NotFound(String),
+    /// The client isn't allowed to see the response.
+    AccessDenied(String),
+    /// The request can't be served as sent.
+    BadRequest(String),
+    /// A store or server needed to build the response can't be used.
+    BackendUnavailable(String),
+}
+
+impl FileServerError {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::AccessDenied(_) => StatusCode::FORBIDDEN,
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::BackendUnavailable(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+impl fmt::Display for FileServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(problem)
+            | Self::AccessDenied(problem)
+            | Self::BadRequest(problem)
+            | Self::BackendUnavailable(problem) => f.write_str(problem),
+        }
+    }
+}
+
+impl std::error::Error for FileServerError {}
+
+/// Return the status of the response to a request that failed with `error`, or `None` for
+/// internal errors. Errors of storage backends are found inside the I/O errors they return.
+pub(crate) fn status(error: &anyhow::Error) -> Option<StatusCode> {
+    error.chain().find_map(|cause| {
+        let error = match cause.downcast_ref::<io::Error>() {
+            Some(io) => io.get_ref()?.downcast_ref::<FileServerError>(),
+            None => cause.downcast_ref::<FileServerError>(),
+        };
+        error.map(FileServerError::status)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_status() {
+        let error = anyhow::Error::new(FileServerError::BadRequest("bad".to_string()));
+        assert_eq!(status(&error), Some(StatusCode::BAD_REQUEST));
+
+        // the status is found behind context added by callers
+        let error = Err::<(), _>(FileServerError::BackendUnavailable("down".to_string()))
+            .context("cannot check sessions")
+            .unwrap_err();
+        assert_eq!(status(&error), Some(StatusCode::BAD_GATEWAY));
+
+        // missing files are internal errors, since resolved paths exist
+        let error = anyhow::Error::new(io::Error::from(io::ErrorKind::NotFound));
+        assert_eq!(status(&error), None);
+        let error = anyhow::Error::new(io::Error::other(FileServerError::AccessDenied(
+            "no".to_string(),
+        )));
+        assert_eq!(status(&error), Some(StatusCode::FORBIDDEN));
+
+        assert_eq!(status(&anyhow::anyhow!("broken")), None);
+        let error = anyhow::Error::new(io::Error::other("broken"));
+        assert_eq!(status(&error), None);
+    }
+}
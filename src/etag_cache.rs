@@ -0,0 +1,285 @@
+//! ETags cached in the default key-value store, so they don't have to be recomputed by hashing
+//! (and compressing) the whole file on every request.
+//!
+//! Entries are keyed by path and encoding and remember the size and modification time of the file
+//! they were computed from, or its size and SHA-256 digest for backends that don't know when files
+//! were modified, as well as when they were cached. A file that changed since is hashed again, so
+//! stale entries are never served, as long as modification times change along with the files.
+//! Concurrent instances may compute the same ETag more than once.
+//!
+//! Compressed bodies of up to [`MAX_CACHED_BODY`] bytes are cached along with their ETag, also
+//! keyed by path and encoding, so each file takes at most one entry per encoding. A body cached
+//! with another ETag than the current one is compressed again.
+
+use anyhow::{Context, Result};
+use http::header::AGE;
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    backend::{self, Backend},
+    host::Store,
+    settings, SupportedEncoding,
+};
+
+/// Environment variable enabling the ETag cache
+pub(crate) const ETAG_CACHE_ENV: &str = "ETAG_CACHE";
+/// Key-value store key prefix of the cached ETags
+const ETAG_PREFIX: &str = "spin-fileserver:etag:";
+/// Key-value store key prefix of the cached compressed bodies
+const BODY_PREFIX: &str = "spin-fileserver:body:";
+/// Size in bytes of the largest files whose compressed bodies are cached
+pub(crate) const MAX_CACHED_BODY: u64 = 1024 * 1024;
+/// The `X-Cache` header, which the `http` crate doesn't define
+const X_CACHE: &str = "x-cache";
+
+/// Whether an ETag was found in the cache.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum CacheStatus {
+    /// The ETag was cached `age` seconds ago.
+    Hit { age: u64 },
+    /// The ETag was computed and cached.
+    Miss,
+    /// The ETag was computed and cached, and differs from the one cached for an earlier version
+    /// of the file.
+    Changed,
+}
+
+impl CacheStatus {
+    /// Return the `X-Cache` and, on hits, `Age` response headers.
+    pub fn headers(self) -> Vec<(String, Vec<u8>)> {
+        match self {
+            Self::Hit { age } => vec![
+                (X_CACHE.to_string(), b"HIT".to_vec()),
+                (AGE.as_str().to_string(), age.to_string().into_bytes()),
+            ],
+            Self::Miss | Self::Changed => vec![(X_CACHE.to_string(), b"MISS".to_vec())],
+        }
+    }
+}
+
+/// Check whether the ETag cache is enabled.
+pub(crate) fn enabled() -> bool {
+    settings::var(ETAG_CACHE_ENV).is_some_and(|v| v.trim() == "true")
+}
+
+/// Return the cached ETag of a file, calling `compute` and caching its result on a miss.
+pub(crate) fn get_or_insert(
+    file: &Path,
+    encoding: SupportedEncoding,
+    compute: impl FnOnce() -> Result<String>,
+) -> Result<(String, CacheStatus)> {
+    let store = Store::open_default().context("cannot open the key-value store to cache ETags")?;
+    let key = key(file, encoding);
+    let fingerprint = fingerprint(backend::current().as_ref(), file)?;
+    let now = now();
+    let entry = store.get(&key)?;
+    let cached = entry.as_deref().and_then(parse_entry);
+    if let Some((_, cached_at, etag)) = cached.as_ref().filter(|(f, ..)| *f == fingerprint) {
+        let age = now.saturating_sub(*cached_at);
+        return Ok((etag.clone(), CacheStatus::Hit { age }));
+    }
+    let etag = compute()?;
+    store.set(&key, format!("{fingerprint} {now} {etag}").as_bytes())?;
+    let status = match cached {
+        Some((_, _, previous)) if previous != etag => CacheStatus::Changed,
+        _ => CacheStatus::Miss,
+    };
+    Ok((etag, status))
+}
+
+/// Return the cached compressed body of a file, if it was cached with the given ETag.
+pub(crate) fn body(file: &Path, encoding: SupportedEncoding, etag: &str) -> Option<Vec<u8>> {
+    let entry = Store::open_default()
+        .context("cannot open the key-value store to read cached bodies")
+        .and_then(|store| Ok(store.get(&body_key(file, encoding))?));
+    match entry {
+        Ok(entry) => parse_body(entry?, etag),
+        Err(e) => {
+            eprintln!("Error reading the cached body of {}: {e:#}", file.display());
+            None
+        }
+    }
+}
+
+/// Cache the compressed body of a file along with its ETag.
+pub(crate) fn insert_body(file: &Path, encoding: SupportedEncoding, etag: &str, body: &[u8]) {
+    let mut entry = Vec::with_capacity(etag.len() + 1 + body.len());
+    entry.extend_from_slice(etag.as_bytes());
+    entry.push(b'\n');
+    entry.extend_from_slice(body);
+    let stored = Store::open_default()
+        .context("cannot open the key-value store to cache bodies")
+        .and_then(|store| Ok(store.set(&body_key(file, encoding), &entry)?));
+    if let Err(e) = stored {
+        eprintln!("Error caching the body of {}: {e:#}", file.display());
+    }
+}
+
+/// Return the body of a cache entry, stored as `<etag>\n<body>`, if it has the given ETag.
+fn parse_body(mut entry: Vec<u8>, etag: &str) -> Option<Vec<u8>> {
+    let newline = entry.iter().position(|b| *b == b'\n')?;
+    if entry[..newline] != *etag.as_bytes() {
+        return None;
+    }
+    entry.drain(..=newline);
+    Some(entry)
+}
+
+/// Delete all cached ETags and bodies, returning how many entries were deleted.
+pub(crate) fn purge() -> Result<usize> {
+    let store = Store::open_default().context("cannot open the key-value store to purge ETags")?;
+    let mut deleted = 0;
+    for key in store.get_keys()? {
+        if key.starts_with(ETAG_PREFIX) || key.starts_with(BODY_PREFIX) {
+            store.delete(&key)?;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+fn key(file: &Path, encoding: SupportedEncoding) -> String {
+    format!("{ETAG_PREFIX}{encoding}:{}", file.display())
+}
+
+fn body_key(file: &Path, encoding: SupportedEncoding) -> String {
+    format!("{BODY_PREFIX}{encoding}:{}", file.display())
+}
+
+/// Identify the contents of a file by its size and modification time, or by its size and digest
+/// for backends that don't know when files were modified. Hashing the uncompressed file is still
+/// cheaper than compressing it to compute its ETag.
+fn fingerprint(backend: &dyn Backend, file: &Path) -> Result<String> {
+    let size = backend.size(file)?;
+    let modified = backend
+        .modified(file)?
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok());
+    if let Some(modified) = modified {
+        return Ok(format!("{size}-{}", modified.as_nanos()));
+    }
+    let mut hasher = Sha256::new();
+    io::copy(&mut backend.open(file)?, &mut hasher)?;
+    Ok(format!("{size}-{}", hex::encode(hasher.finalize())))
+}
+
+/// Return the fingerprint of the file a cache entry was computed from, when it was cached and its
+/// ETag.
+fn parse_entry(entry: &[u8]) -> Option<(String, u64, String)> {
+    let mut parts = std::str::from_utf8(entry).ok()?.splitn(3, ' ');
+    let (fingerprint, cached_at, etag) = (parts.next()?, parts.next()?, parts.next()?);
+    Some((
+        fingerprint.to_string(),
+        cached_at.parse().ok()?,
+        etag.to_string(),
+    ))
+}
+
+/// Seconds since the Unix epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        cell::RefCell,
+        io::{Cursor, Read},
+    };
+
+    /// A backend holding a single file, without modification times.
+    struct Memory(RefCell<&'static str>);
+
+    impl Backend for Memory {
+        fn is_file(&self, _: &Path) -> bool {
+            true
+        }
+        fn is_dir(&self, _: &Path) -> bool {
+            false
+        }
+        fn list(&self, _: &Path) -> io::Result<Vec<String>> {
+            Ok(Vec::new())
+        }
+        fn size(&self, _: &Path) -> io::Result<u64> {
+            Ok(self.0.borrow().len() as u64)
+        }
+        fn open(&self, _: &Path) -> io::Result<Box<dyn Read>> {
+            Ok(Box::new(Cursor::new(*self.0.borrow())))
+        }
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let file = Path::new("hello-test.txt");
+        let modified = fingerprint(&backend::Filesystem, file).unwrap();
+        assert!(modified.starts_with(&format!("{}-", std::fs::metadata(file).unwrap().len())));
+        assert_eq!(fingerprint(&backend::Filesystem, file).unwrap(), modified);
+
+        let backend = Memory(RefCell::new("hello, world!\n"));
+        let before = fingerprint(&backend, file).unwrap();
+        assert_eq!(
+            before,
+            format!("14-{}", hex::encode(Sha256::digest("hello, world!\n")))
+        );
+        assert_eq!(fingerprint(&backend, file).unwrap(), before);
+        *backend.0.borrow_mut() = "hello, there!\n";
+        assert_ne!(fingerprint(&backend, file).unwrap(), before);
+    }
+
+    #[test]
+    fn test_parse_entry() {
+        assert_eq!(
+            parse_entry(b"12-34 1700000000 abc"),
+            Some(("12-34".to_string(), 1700000000, "abc".to_string()))
+        );
+        assert_eq!(parse_entry(b"12-34 abc"), None);
+        assert_eq!(parse_entry(b"12-34 abc def"), None);
+        assert_eq!(parse_entry(b"garbage"), None);
+    }
+
+    #[test]
+    fn test_key() {
+        assert_eq!(
+            key(Path::new("/a b.txt"), SupportedEncoding::Gzip),
+            "spin-fileserver:etag:gzip:/a b.txt"
+        );
+        assert_eq!(
+            body_key(Path::new("/a b.txt"), SupportedEncoding::Gzip),
+            "spin-fileserver:body:gzip:/a b.txt"
+        );
+    }
+
+    #[test]
+    fn test_parse_body() {
+        assert_eq!(
+            parse_body(b"abc\n\x1f\x8b\nbody".to_vec(), "abc"),
+            Some(b"\x1f\x8b\nbody".to_vec())
+        );
+        assert_eq!(parse_body(b"abc\n".to_vec(), "abc"), Some(Vec::new()));
+        assert_eq!(parse_body(b"old\nbody".to_vec(), "abc"), None);
+        assert_eq!(parse_body(b"abc".to_vec(), "abc"), None);
+    }
+
+    #[test]
+    fn test_cache_status_headers() {
+        assert_eq!(
+            CacheStatus::Hit { age: 42 }.headers(),
+            vec![
+                ("x-cache".to_string(), b"HIT".to_vec()),
+                ("age".to_string(), b"42".to_vec())
+            ]
+        );
+        assert_eq!(
+            CacheStatus::Miss.headers(),
+            vec![("x-cache".to_string(), b"MISS".to_vec())]
+        );
+    }
+}
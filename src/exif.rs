@@ -0,0 +1,222 @@
+//! Stripping of EXIF, XMP and IPTC metadata from JPEG images, since mounted photo directories
+//! frequently contain location data users don't intend to publish.
+//!
+//! Metadata segments are dropped without re-encoding the image. The orientation is kept in a
+//! minimal EXIF segment, so photos taken sideways aren't displayed rotated. Stripped images are
+//! cached in the default key-value store, keyed by the ETag of the source image.
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+use crate::{backend, host::Store, reproducible, settings};
+
+/// Environment variable enabling metadata stripping
+pub(crate) const STRIP_EXIF_ENV: &str = "STRIP_EXIF";
+/// Prefix of the key-value store keys holding stripped images
+const CACHE_PREFIX: &str = "spin-fileserver:exif-stripped:";
+/// Marker of the APP1 segment holding EXIF or XMP metadata
+const APP1: u8 = 0xe1;
+/// Marker of the APP13 segment holding IPTC metadata
+const APP13: u8 = 0xed;
+/// Marker of the start of the compressed image data, after which there are no more segments
+const START_OF_SCAN: u8 = 0xda;
+/// Header of EXIF segments
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+/// Tag of the orientation in the first IFD
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/// Check whether a file is a JPEG image whose metadata is stripped, if enabled and responses
+/// aren't reproducible.
+pub(crate) fn applies(file: &Path) -> bool {
+    settings::var(STRIP_EXIF_ENV).is_some_and(|v| v.trim() == "true")
+        && !reproducible::enabled()
+        && file
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| e.eq_ignore_ascii_case("jpg") || e.eq_ignore_ascii_case("jpeg"))
+}
+
+/// Strip the metadata of a JPEG image with the given ETag, returning the ETag and contents of the
+/// stripped image.
+pub(crate) fn strip_file(file: &Path, etag: &str) -> Result<(String, Vec<u8>)> {
+    // the cache is optional, so stripping works without a key-value store
+    let store = Store::open_default().ok();
+    let key = format!("{CACHE_PREFIX}{etag}");
+    if let Some(cached) = store.as_ref().and_then(|s| s.get(&key).ok().flatten()) {
+        return Ok((hex::encode(Sha256::digest(&cached)), cached));
+    }
+    let jpeg = backend::current()
+        .read(file)
+        .with_context(|| format!("reading {}", file.display()))?;
+    let stripped =
+        strip(&jpeg).with_context(|| format!("{} is not a JPEG image", file.display()))?;
+    if let Some(store) = store {
+        if let Err(e) = store.set(&key, &stripped) {
+            eprintln!("Error caching stripped image {}: {e}", file.display());
+        }
+    }
+    Ok((hex::encode(Sha256::digest(&stripped)), stripped))
+}
+
+/// Remove the metadata segments of a JPEG image, keeping its orientation.
+/// Returns `None` if the image isn't a well-formed JPEG image.
+fn strip(jpeg: &[u8]) -> Option<Vec<u8>> {
+    let mut rest = jpeg.strip_prefix(&[0xff, 0xd8])?;
+    let mut stripped = vec![0xff, 0xd8];
+    let mut orientation = None;
+    while let [0xff, marker, ..] = *rest {
+        if marker == START_OF_SCAN {
+            break;
+        }
+        // fill bytes may precede markers
+        if marker == 0xff {
+            rest = &rest[1..];
+            continue;
+        }
+        let len = usize::from(u16::from_be_bytes([*rest.get(2)?, *rest.get(3)?]));
+        if len < 2 {
+            return None;
+        }
+        let segment = rest.get(..2 + len)?;
+        match marker {
+            APP1 => {
+                if let Some(exif) = segment[4..].strip_prefix(EXIF_HEADER) {
+                    orientation = orientation.or_else(|| read_orientation(exif));
+                }
+            }
+            APP13 => {}
+            _ => stripped.extend_from_slice(segment),
+        }
+        rest = &rest[2 + len..];
+    }
+    if let Some(orientation) = orientation.filter(|&o| o != 1) {
+        // the EXIF segment must follow the JFIF segment, if any
+        let position = match stripped.get(2..4) {
+            Some([0xff, 0xe0]) => 4 + usize::from(u16::from_be_bytes([stripped[4], stripped[5]])),
+            _ => 2,
+        };
+        stripped.splice(position..position, orientation_segment(orientation));
+    }
+    stripped.extend_from_slice(rest);
+    Some(stripped)
+}
+
+/// Read the orientation from the TIFF structure of an EXIF segment.
+fn read_orientation(tiff: &[u8]) -> Option<u16> {
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |offset: usize| {
+        let bytes = [*tiff.get(offset)?, *tiff.get(offset + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let u32_at = |offset: usize| {
+        let bytes = tiff.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+    let ifd = usize::try_from(u32_at(4)?).ok()?;
+    (0..usize::from(u16_at(ifd)?))
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| u16_at(entry) == Some(ORIENTATION_TAG))
+        .and_then(|entry| u16_at(entry + 8))
+        .filter(|orientation| (1..=8).contains(orientation))
+}
+
+/// Build an EXIF segment holding only an orientation.
+fn orientation_segment(orientation: u16) -> Vec<u8> {
+    let mut tiff = b"II*\0".to_vec();
+    tiff.extend_from_slice(&8u32.to_le_bytes());
+    // one IFD entry: the orientation as a single SHORT value, padded to four bytes
+    tiff.extend_from_slice(&1u16.to_le_bytes());
+    tiff.extend_from_slice(&ORIENTATION_TAG.to_le_bytes());
+    tiff.extend_from_slice(&3u16.to_le_bytes());
+    tiff.extend_from_slice(&1u32.to_le_bytes());
+    tiff.extend_from_slice(&orientation.to_le_bytes());
+    tiff.extend_from_slice(&[0, 0]);
+    // no next IFD
+    tiff.extend_from_slice(&0u32.to_le_bytes());
+
+    let len = 2 + EXIF_HEADER.len() + tiff.len();
+    let mut segment = vec![0xff, APP1];
+    segment.extend_from_slice(&(len as u16).to_be_bytes());
+    segment.extend_from_slice(EXIF_HEADER);
+    segment.extend_from_slice(&tiff);
+    segment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a JPEG segment.
+    fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xff, marker];
+        segment.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    /// Build a big-endian EXIF payload with an orientation and a GPS IFD pointer.
+    fn exif(orientation: u16) -> Vec<u8> {
+        let mut payload = EXIF_HEADER.to_vec();
+        payload.extend_from_slice(b"MM\0*\0\0\0\x08");
+        payload.extend_from_slice(&2u16.to_be_bytes());
+        payload.extend_from_slice(&[0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 0x26]);
+        payload.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1]);
+        payload.extend_from_slice(&orientation.to_be_bytes());
+        payload.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        payload
+    }
+
+    #[test]
+    fn test_strip() {
+        let jfif = segment(0xe0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+        let icc = segment(0xe2, b"ICC_PROFILE\0");
+        let scan = [
+            &segment(START_OF_SCAN, &[0; 10])[..],
+            &[1, 2, 3, 0xff, 0xd9],
+        ]
+        .concat();
+        let jpeg = [
+            &[0xff, 0xd8][..],
+            &jfif,
+            &segment(APP1, &exif(6)),
+            &segment(APP1, b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta/>"),
+            &segment(APP13, b"Photoshop 3.0\0"),
+            &icc,
+            &scan,
+        ]
+        .concat();
+
+        let stripped = strip(&jpeg).unwrap();
+        let expected = [
+            &[0xff, 0xd8][..],
+            &jfif,
+            &orientation_segment(6),
+            &icc,
+            &scan,
+        ]
+        .concat();
+        assert_eq!(stripped, expected);
+        assert_eq!(
+            read_orientation(&orientation_segment(6)[4 + EXIF_HEADER.len()..]),
+            Some(6)
+        );
+
+        // upright images need no orientation
+        let jpeg = [&[0xff, 0xd8][..], &segment(APP1, &exif(1)), &scan].concat();
+        assert_eq!(strip(&jpeg).unwrap(), [&[0xff, 0xd8][..], &scan].concat());
+        assert_eq!(strip(b"GIF89a"), None);
+    }
+}
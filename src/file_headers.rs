@@ -0,0 +1,195 @@
+//! Per-file status, headers and cache policy, declared next to the files instead of in a central
+//! configuration, so static site generators can control them page by page.
+//!
+//! They are read from a `<file>.headers.json` sidecar, or from a front-matter block of
+//! `Name: value` lines between two `---` lines at the start of HTML files, which isn't served.
+//! Sidecars take precedence over front-matter.
+
+use anyhow::{Context, Result};
+use http::{header::CACHE_CONTROL, HeaderName, StatusCode};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use crate::{backend, mock_api, settings};
+
+/// Environment variable enabling per-file headers
+pub(crate) const FILE_HEADERS_ENV: &str = "FILE_HEADERS";
+/// Suffix of sidecar files
+const SIDECAR_SUFFIX: &str = ".headers.json";
+/// Start of front-matter blocks
+const FRONT_MATTER_START: &[u8] = b"---\n";
+/// Name of the front-matter line setting the status
+const STATUS_KEY: &str = "status";
+
+/// The status, headers and cache policy declared for a file.
+#[derive(Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct FileHeaders {
+    pub status: Option<u16>,
+    pub cache_control: Option<String>,
+    pub headers: BTreeMap<String, String>,
+}
+
+impl FileHeaders {
+    /// Return the status to respond with, if one is declared.
+    pub fn status(&self) -> Option<StatusCode> {
+        self.status.and_then(|s| StatusCode::from_u16(s).ok())
+    }
+
+    /// Take the values of another declaration over these.
+    fn merge(&mut self, other: FileHeaders) {
+        self.status = other.status.or(self.status);
+        self.cache_control = other.cache_control.or(self.cache_control.take());
+        self.headers.extend(other.headers);
+    }
+
+    /// Check that the status and header names are valid.
+    fn validate(&self) -> Result<()> {
+        if let Some(status) = self.status {
+            StatusCode::from_u16(status).with_context(|| format!("invalid status {status}"))?;
+        }
+        for name in self.headers.keys() {
+            HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("invalid header name {name}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Check whether per-file headers are enabled.
+pub(crate) fn enabled() -> bool {
+    settings::var(FILE_HEADERS_ENV).is_some_and(|v| v.trim() == "true")
+}
+
+/// Check whether a path names a sidecar, which isn't served when per-file headers are enabled.
+pub(crate) fn is_sidecar(file: &Path) -> bool {
+    enabled() && file.to_string_lossy().ends_with(SIDECAR_SUFFIX)
+}
+
+/// Return the declarations for a file, if enabled and there are any.
+pub(crate) fn lookup(file: &Path) -> Result<Option<FileHeaders>> {
+    if !enabled() {
+        return Ok(None);
+    }
+    let backend = backend::current();
+    let mut declared = None::<FileHeaders>;
+    if has_front_matter(file) {
+        let html = backend
+            .read(file)
+            .with_context(|| format!("reading {}", file.display()))?;
+        let html = String::from_utf8(html)
+            .with_context(|| format!("{} is not valid UTF-8", file.display()))?;
+        let (front_matter, _) = mock_api::split_front_matter(&html)
+            .with_context(|| format!("parsing the front-matter of {}", file.display()))?;
+        declared = Some(from_front_matter(&front_matter)?);
+    }
+    let sidecar = sidecar(file);
+    if backend.is_file(&sidecar) {
+        let json = backend
+            .read(&sidecar)
+            .with_context(|| format!("reading {}", sidecar.display()))?;
+        let from_sidecar = serde_json::from_slice::<FileHeaders>(&json)
+            .with_context(|| format!("parsing {}", sidecar.display()))?;
+        declared
+            .get_or_insert_with(Default::default)
+            .merge(from_sidecar);
+    }
+    if let Some(declared) = &declared {
+        declared
+            .validate()
+            .with_context(|| format!("headers declared for {}", file.display()))?;
+    }
+    Ok(declared)
+}
+
+/// Check whether a file is an HTML document starting with a front-matter block, if enabled.
+pub(crate) fn has_front_matter(file: &Path) -> bool {
+    let is_html = file
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("html") || e.eq_ignore_ascii_case("htm"));
+    if !is_html || !enabled() {
+        return false;
+    }
+    let mut start = [0; FRONT_MATTER_START.len()];
+    backend::current()
+        .open(file)
+        .and_then(|mut f| f.read_exact(&mut start))
+        .is_ok_and(|_| start == FRONT_MATTER_START)
+}
+
+/// Remove the front-matter block of an HTML document.
+pub(crate) fn strip_front_matter(html: &str) -> Result<&str> {
+    Ok(mock_api::split_front_matter(html)?.1)
+}
+
+/// Return the path of the sidecar of a file.
+fn sidecar(file: &Path) -> PathBuf {
+    let mut sidecar = file.as_os_str().to_owned();
+    sidecar.push(SIDECAR_SUFFIX);
+    PathBuf::from(sidecar)
+}
+
+/// Build the declarations of front-matter lines, where `Status` and `Cache-Control` are the
+/// status and cache policy, and other lines headers.
+fn from_front_matter(front_matter: &[(&str, &str)]) -> Result<FileHeaders> {
+    let mut declared = FileHeaders::default();
+    for (name, value) in front_matter {
+        if name.eq_ignore_ascii_case(STATUS_KEY) {
+            declared.status = Some(
+                value
+                    .parse()
+                    .with_context(|| format!("invalid status {value}"))?,
+            );
+        } else if name.eq_ignore_ascii_case(CACHE_CONTROL.as_str()) {
+            declared.cache_control = Some(value.to_string());
+        } else {
+            declared.headers.insert(name.to_string(), value.to_string());
+        }
+    }
+    Ok(declared)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_front_matter() {
+        let declared = from_front_matter(&[
+            ("Status", "410"),
+            ("cache-control", "no-cache"),
+            ("X-Robots-Tag", "noindex"),
+        ])
+        .unwrap();
+        assert_eq!(declared.status(), Some(StatusCode::GONE));
+        assert_eq!(declared.cache_control.as_deref(), Some("no-cache"));
+        assert_eq!(
+            declared.headers,
+            BTreeMap::from([("X-Robots-Tag".to_string(), "noindex".to_string())])
+        );
+        assert!(from_front_matter(&[("Status", "gone")]).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut declared = FileHeaders {
+            status: Some(200),
+            cache_control: Some("no-cache".to_string()),
+            headers: BTreeMap::from([("a".to_string(), "1".to_string())]),
+        };
+        declared.merge(
+            serde_json::from_str(r#"{ "status": 404, "headers": { "a": "2", "b": "3" } }"#)
+                .unwrap(),
+        );
+        assert_eq!(declared.status, Some(404));
+        assert_eq!(declared.cache_control.as_deref(), Some("no-cache"));
+        assert_eq!(declared.headers["a"], "2");
+        assert_eq!(declared.headers["b"], "3");
+        assert!(serde_json::from_str::<FileHeaders>(r#"{ "stauts": 404 }"#).is_err());
+    }
+}
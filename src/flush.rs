@@ -0,0 +1,143 @@
+//! Periodic flushing of on-the-fly gzip and deflate compression, so clients start receiving very
+//! large compressible files, such as logs or SQL dumps, before the encoder has buffered a block.
+//!
+//! After every configured number of uncompressed bytes the encoder is sync-flushed, ending the
+//! current block on a byte boundary so everything read so far can be decompressed by the client.
+//! Frequent flushes cost some compression ratio.
+
+use flate2::write::{DeflateEncoder, GzEncoder};
+use std::io::{self, Read, Write};
+
+use crate::settings;
+
+/// Environment variable for the number of uncompressed bytes after which compression is flushed
+pub(crate) const COMPRESSION_FLUSH_BYTES_ENV: &str = "COMPRESSION_FLUSH_BYTES";
+
+/// Return the number of uncompressed bytes between flushes, if flushing is enabled.
+pub(crate) fn interval() -> Option<usize> {
+    settings::var(COMPRESSION_FLUSH_BYTES_ENV)?
+        .trim()
+        .parse()
+        .ok()
+        .filter(|&bytes| bytes > 0)
+}
+
+/// An encoder writing its output to a buffer.
+pub(crate) trait Encoder: Write {
+    /// Return the buffer of compressed output.
+    fn output(&mut self) -> &mut Vec<u8>;
+
+    /// Write the remaining output, such as the gzip trailer.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+impl Encoder for GzEncoder<Vec<u8>> {
+    fn output(&mut self) -> &mut Vec<u8> {
+        self.get_mut()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.try_finish()
+    }
+}
+
+impl Encoder for DeflateEncoder<Vec<u8>> {
+    fn output(&mut self) -> &mut Vec<u8> {
+        self.get_mut()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.try_finish()
+    }
+}
+
+/// Compresses a reader, flushing the encoder after every `interval` uncompressed bytes.
+pub(crate) struct FlushingEncoder<R, E> {
+    inner: R,
+    encoder: E,
+    interval: usize,
+    /// Compressed output not read yet, from `position` on.
+    pending: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: Read, E: Encoder> FlushingEncoder<R, E> {
+    pub fn new(inner: R, encoder: E, interval: usize) -> Self {
+        Self {
+            inner,
+            encoder,
+            interval,
+            pending: Vec::new(),
+            position: 0,
+            finished: false,
+        }
+    }
+
+    /// Compress the next `interval` bytes of the reader, or the rest of it.
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = Vec::with_capacity(self.interval);
+        (&mut self.inner)
+            .take(self.interval as u64)
+            .read_to_end(&mut chunk)?;
+        self.encoder.write_all(&chunk)?;
+        if chunk.len() < self.interval {
+            self.encoder.finish()?;
+            self.finished = true;
+        } else {
+            self.encoder.flush()?;
+        }
+        self.pending = std::mem::take(self.encoder.output());
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read, E: Encoder> Read for FlushingEncoder<R, E> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.pending.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let count = buf.len().min(self.pending.len() - self.position);
+        buf[..count].copy_from_slice(&self.pending[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{read::GzDecoder, Compression};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_flushing_encoder() {
+        let contents = "2024-01-01T00:00:00Z GET /index.html 200\n".repeat(1000);
+        let mut encoder = FlushingEncoder::new(
+            Cursor::new(contents.clone()),
+            GzEncoder::new(Vec::new(), Compression::fast()),
+            4096,
+        );
+
+        // the first flush makes the beginning of the file decompressible on its own
+        let mut first = vec![0; 64 * 1024];
+        let count = encoder.read(&mut first).unwrap();
+        let mut partial = vec![0; 4096];
+        GzDecoder::new(&first[..count])
+            .read_exact(&mut partial)
+            .unwrap();
+        assert_eq!(partial, contents.as_bytes()[..4096]);
+
+        let mut compressed = first[..count].to_vec();
+        encoder.read_to_end(&mut compressed).unwrap();
+        let mut decompressed = String::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, contents);
+    }
+}
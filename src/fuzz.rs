@@ -0,0 +1,43 @@
+//! Entry points into the parsers of request data for the fuzz targets in `fuzz/`, which link the
+//! crate natively. Not part of the interface of the component.
+
+use std::str::FromStr;
+
+use crate::{
+    conditional::{self, RangeOutcome},
+    languages, request_limits, ContentEncoding, FileServer, SupportedEncoding,
+};
+
+/// Resolve a request path to a file or redirect.
+pub fn resolve(path: &str) {
+    let _ = FileServer::resolve(path, &[languages::default_language()]);
+}
+
+/// Parse an element of an `Accept-Encoding` header, and negotiate the encoding of a response to
+/// a request with the whole header.
+pub fn accept_encoding(value: &[u8]) -> SupportedEncoding {
+    if let Ok(value) = std::str::from_utf8(value) {
+        for element in value.split(',') {
+            if let Ok(encoding) = ContentEncoding::from_str(element) {
+                assert!(encoding.weight.is_none_or(|w| (0.0..=1.0).contains(&w)));
+            }
+        }
+    }
+    SupportedEncoding::best_encoding(&[(http::header::ACCEPT_ENCODING.to_string(), value.to_vec())])
+}
+
+/// Evaluate the `Range`, `If-Range` and `If-None-Match` headers of a request for a
+/// representation of `len` bytes, checking that any range served is within it.
+pub fn conditional(range: &[u8], if_range: Option<&[u8]>, etag: &str, len: u64) {
+    let _ = conditional::none_match(range, etag);
+    if let RangeOutcome::Partial { start, end } =
+        conditional::evaluate_range(Some(range), if_range, etag, len)
+    {
+        assert!(start <= end && end < len, "{start}-{end} of {len} bytes");
+    }
+}
+
+/// Check the headers of a request against the limits on their size and syntax.
+pub fn request_headers(headers: &[(String, Vec<u8>)]) -> bool {
+    request_limits::check(headers).is_none()
+}
@@ -0,0 +1,252 @@
+//! Honeypot paths that no legitimate visitor requests, such as links hidden from people or the
+//! admin page of an application the site doesn't run. Requests for them are answered with
+//! `404 Not Found` like any missing file, and reported to stderr and an optional webhook as a
+//! lightweight intrusion signal.
+//!
+//! The webhook is called once the response has been sent, since Spin's outbound HTTP API takes no
+//! timeout and clients shouldn't wait for it. At most [`MAX_REPORTS_PER_MINUTE`] requests are
+//! reported per minute across all instances, so a scanner hammering honeypot paths doesn't turn
+//! into as many webhook calls. Webhook failures are logged and never fail the request.
+
+use anyhow::{bail, Context, Result};
+use http::header::{REFERER, USER_AGENT};
+use http::StatusCode;
+use spin_sdk::http::{Method, Request};
+use std::{cell::RefCell, str};
+
+use crate::{
+    access_log::strip_port, etag_cache::now, host::Store, plain_response, settings,
+    version::rfc3339, RequestContext, Response, CLIENT_ADDR_HEADER,
+};
+
+/// Environment variable for the comma-separated honeypot paths
+pub(crate) const HONEYPOT_PATHS_ENV: &str = "HONEYPOT_PATHS";
+/// Environment variable for the URL requests for honeypot paths are reported to
+pub(crate) const HONEYPOT_WEBHOOK_URL_ENV: &str = "HONEYPOT_WEBHOOK_URL";
+/// Environment variable for the `Authorization` header of webhook requests
+pub(crate) const HONEYPOT_WEBHOOK_AUTHORIZATION_ENV: &str = "HONEYPOT_WEBHOOK_AUTHORIZATION";
+/// Maximum number of requests reported to the webhook per minute
+const MAX_REPORTS_PER_MINUTE: u64 = 10;
+/// Key-value store key counting the requests reported in the current minute
+const REPORTS_KEY: &str = "spin-fileserver:honeypot-reports";
+
+thread_local! {
+    /// The request to report to the webhook once its response has been sent.
+    static PENDING: RefCell<Option<Event>> = const { RefCell::new(None) };
+}
+
+/// A request for a honeypot path.
+#[derive(Debug, PartialEq, Eq)]
+struct Event {
+    time: u64,
+    method: String,
+    path: String,
+    query: String,
+    remote_addr: Option<String>,
+    user_agent: Option<String>,
+    referer: Option<String>,
+}
+
+impl Event {
+    fn new(req: &RequestContext, path: &str, time: u64) -> Self {
+        let header = |name| {
+            req.header(name)
+                .and_then(|v| str::from_utf8(v).ok())
+                .map(str::to_string)
+        };
+        Self {
+            time,
+            method: req.method.to_string(),
+            path: path.to_string(),
+            query: req.query.clone(),
+            remote_addr: req
+                .headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(CLIENT_ADDR_HEADER))
+                .and_then(|(_, v)| str::from_utf8(v).ok())
+                .map(|addr| strip_port(addr).to_string()),
+            user_agent: header(&USER_AGENT),
+            referer: header(&REFERER),
+        }
+    }
+
+    fn body(&self) -> serde_json::Value {
+        serde_json::json!({
+            "event": "honeypot",
+            "time": rfc3339(self.time),
+            "method": self.method,
+            "path": self.path,
+            "query": self.query,
+            "remote_addr": self.remote_addr,
+            "user_agent": self.user_agent,
+            "referer": self.referer,
+        })
+    }
+}
+
+/// Answer requests for honeypot paths with `404 Not Found`, reporting them.
+pub(crate) fn check(req: &RequestContext, path: &str) -> Option<Response> {
+    let paths = settings::var(HONEYPOT_PATHS_ENV)?;
+    if !is_honeypot(&paths, path) {
+        return None;
+    }
+    let event = Event::new(req, path, now());
+    eprintln!(
+        "Honeypot {} {path} requested by {} ({})",
+        event.method,
+        event.remote_addr.as_deref().unwrap_or("-"),
+        event.user_agent.as_deref().unwrap_or("-")
+    );
+    if settings::var(HONEYPOT_WEBHOOK_URL_ENV).is_some() {
+        PENDING.set(Some(event));
+    }
+    Some(plain_response(StatusCode::NOT_FOUND))
+}
+
+/// Report the request for a honeypot path to the webhook, if there was one and the webhook
+/// wasn't called too often in the last minute. Called once the response has been sent.
+pub(crate) fn report() {
+    let Some(event) = PENDING.take() else {
+        return;
+    };
+    let Some(url) = settings::var(HONEYPOT_WEBHOOK_URL_ENV) else {
+        return;
+    };
+    let reported = throttle(event.time / 60).and_then(|allowed| {
+        if !allowed {
+            eprintln!("Not calling the honeypot webhook, called too often in the last minute");
+            return Ok(());
+        }
+        send(url.trim(), &event)
+    });
+    if let Err(e) = reported {
+        eprintln!("Error calling the honeypot webhook: {e:#}");
+    }
+}
+
+/// Count a webhook call in the default store, returning whether it is allowed this minute.
+fn throttle(minute: u64) -> Result<bool> {
+    let store = Store::open_default()
+        .context("cannot open the key-value store to throttle the honeypot webhook")?;
+    let counted = store.get(REPORTS_KEY)?.unwrap_or_default();
+    let Some(count) = next_count(&counted, minute) else {
+        return Ok(false);
+    };
+    store.set(REPORTS_KEY, format!("{minute} {count}").as_bytes())?;
+    Ok(true)
+}
+
+/// Return the count of webhook calls in a minute including one more, given the stored
+/// `<minute> <count>`, or `None` if the limit is reached.
+fn next_count(counted: &[u8], minute: u64) -> Option<u64> {
+    let count = str::from_utf8(counted)
+        .ok()
+        .and_then(|counted| counted.split_once(' '))
+        .filter(|(at, _)| at.parse() == Ok(minute))
+        .and_then(|(_, count)| count.parse::<u64>().ok())
+        .unwrap_or_default();
+    (count < MAX_REPORTS_PER_MINUTE).then_some(count + 1)
+}
+
+/// Check whether a path is one of the comma-separated honeypot paths, ignoring case. Paths ending
+/// with `/` also match everything under them.
+fn is_honeypot(paths: &str, path: &str) -> bool {
+    let path = path.trim_start_matches('/').to_lowercase();
+    paths
+        .split(',')
+        .map(|p| p.trim().trim_start_matches('/').to_lowercase())
+        .filter(|p| !p.is_empty())
+        .any(|p| {
+            path == p
+                || path == p.trim_end_matches('/')
+                || (p.ends_with('/') && path.starts_with(&p))
+        })
+}
+
+fn send(url: &str, event: &Event) -> Result<()> {
+    let mut request = Request::builder();
+    request
+        .method(Method::Post)
+        .uri(url)
+        .header("content-type", "application/json")
+        .body(event.body().to_string());
+    if let Some(authorization) = settings::var(HONEYPOT_WEBHOOK_AUTHORIZATION_ENV) {
+        request.header("authorization", authorization.trim());
+    }
+    let response: spin_sdk::http::Response =
+        spin_sdk::http::run(spin_sdk::http::send(request.build()))?;
+    let status = *response.status();
+    if !(200..300).contains(&status) {
+        bail!("{url} responded with status {status}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_honeypot() {
+        let paths = "/admin/secret-login, /trap/, backup.zip";
+        for path in [
+            "/admin/secret-login",
+            "/Admin/Secret-Login",
+            "/trap",
+            "/trap/",
+            "/trap/deeper/page.html",
+            "/backup.zip",
+        ] {
+            assert!(is_honeypot(paths, path), "{path}");
+        }
+        for path in [
+            "/admin/secret-login/page",
+            "/admin",
+            "/trapdoor",
+            "/backup.zip.html",
+            "/",
+        ] {
+            assert!(!is_honeypot(paths, path), "{path}");
+        }
+        assert!(!is_honeypot(" , ", "/"));
+    }
+
+    #[test]
+    fn test_next_count() {
+        assert_eq!(next_count(b"", 100), Some(1));
+        assert_eq!(next_count(b"100 3", 100), Some(4));
+        assert_eq!(next_count(b"99 10", 100), Some(1));
+        assert_eq!(next_count(b"100 10", 100), None);
+        assert_eq!(next_count(b"garbage", 100), Some(1));
+    }
+
+    #[test]
+    fn test_event() {
+        let req = RequestContext {
+            method: http::Method::POST,
+            query: "user=admin".to_string(),
+            headers: vec![
+                (
+                    "spin-client-addr".to_string(),
+                    b"203.0.113.7:51234".to_vec(),
+                ),
+                ("user-agent".to_string(), b"curl/8.0".to_vec()),
+            ],
+            ..Default::default()
+        };
+        let event = Event::new(&req, "/trap/login", 1_700_000_000);
+        assert_eq!(
+            event.body(),
+            serde_json::json!({
+                "event": "honeypot",
+                "time": "2023-11-14T22:13:20Z",
+                "method": "POST",
+                "path": "/trap/login",
+                "query": "user=admin",
+                "remote_addr": "203.0.113.7",
+                "user_agent": "curl/8.0",
+                "referer": null,
+            })
+        );
+    }
+}
@@ -0,0 +1,65 @@
+//! Host APIs specific to Spin, compiled out without the `spin` feature so the component only
+//! imports standard `wasi:http` 0.2 interfaces and runs under any `wasi:http` host, such as
+//! `wasmtime serve`.
+//!
+//! Without them, features keeping state in a key-value store fail to open it, and settings can't
+//! come from Spin variables. Everything else, including outbound requests, is plain `wasi:http`.
+
+#[cfg(feature = "spin")]
+pub(crate) use spin_sdk::key_value::Store;
+
+#[cfg(not(feature = "spin"))]
+pub(crate) use unavailable::Store;
+
+/// Whether the component was built with the Spin host APIs.
+pub(crate) const SPIN: bool = cfg!(feature = "spin");
+
+#[cfg(not(feature = "spin"))]
+mod unavailable {
+    use std::fmt;
+
+    /// Error opening a host API the component was built without.
+    #[derive(Debug)]
+    pub(crate) struct Unavailable(&'static str);
+
+    impl fmt::Display for Unavailable {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "{} is not available, the file server was built without the spin feature",
+                self.0
+            )
+        }
+    }
+
+    impl std::error::Error for Unavailable {}
+
+    /// Stand-in for the Spin key-value store, which can never be opened.
+    pub(crate) enum Store {}
+
+    impl Store {
+        pub fn open_default() -> Result<Self, Unavailable> {
+            Err(Unavailable("the key-value store"))
+        }
+
+        pub fn open(_label: &str) -> Result<Self, Unavailable> {
+            Err(Unavailable("the key-value store"))
+        }
+
+        pub fn get(&self, _key: &str) -> Result<Option<Vec<u8>>, Unavailable> {
+            match *self {}
+        }
+
+        pub fn set(&self, _key: &str, _value: &[u8]) -> Result<(), Unavailable> {
+            match *self {}
+        }
+
+        pub fn delete(&self, _key: &str) -> Result<(), Unavailable> {
+            match *self {}
+        }
+
+        pub fn get_keys(&self) -> Result<Vec<String>, Unavailable> {
+            match *self {}
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! Resizing of PNG and JPEG images on the fly, from `w`, `h` and `q` query parameters, so
+//! responsive images don't require pre-generating every size.
+//!
+//! Tiny blurred placeholders of images can be requested with `lqip`, so static sites can implement
+//! blur-up loading without a separate image service.
+//!
+//! Resized images are cached in the default key-value store, keyed by the ETag of the source image
+//! and the parameters, so each size is only computed once per version of an image.
+
+use anyhow::{Context, Result};
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage, ImageFormat};
+use sha2::{Digest, Sha256};
+use std::{io::Cursor, path::Path};
+
+use crate::{backend, host::Store, reproducible, settings, RequestContext};
+
+/// Environment variable enabling image resizing
+pub(crate) const IMAGE_RESIZING_ENV: &str = "IMAGE_RESIZING";
+/// Environment variable enabling image placeholders
+pub(crate) const IMAGE_PLACEHOLDERS_ENV: &str = "IMAGE_PLACEHOLDERS";
+/// Query parameter requesting a placeholder
+const PLACEHOLDER_PARAM: &str = "lqip";
+/// Largest width or height of placeholders
+const PLACEHOLDER_SIZE: u32 = 32;
+/// Standard deviation of the Gaussian blur of placeholders, in pixels
+const PLACEHOLDER_BLUR: f32 = 2.0;
+/// Prefix of the key-value store keys holding resized images
+const CACHE_PREFIX: &str = "spin-fileserver:image:";
+/// Largest width or height images can be resized to
+const MAX_DIMENSION: u32 = 4096;
+/// Quality of re-encoded JPEG images when none is requested
+const DEFAULT_QUALITY: u8 = 80;
+
+/// The requested size and quality of an image.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Params {
+    width: Option<u32>,
+    height: Option<u32>,
+    /// JPEG quality from 1 to 100, ignored for PNG images.
+    quality: Option<u8>,
+    /// Whether a blurred placeholder is requested, which has a fixed size.
+    placeholder: bool,
+}
+
+/// Check whether a request asks for a resized image or a placeholder, if enabled and responses
+/// aren't reproducible.
+pub(crate) fn requested(req: &RequestContext) -> bool {
+    let resize = enabled(IMAGE_RESIZING_ENV) && query_params(&req.query).next().is_some();
+    let placeholder = enabled(IMAGE_PLACEHOLDERS_ENV) && wants_placeholder(&req.query);
+    (resize || placeholder) && !reproducible::enabled()
+}
+
+/// Check whether a boolean setting is enabled.
+fn enabled(env: &str) -> bool {
+    settings::var(env).is_some_and(|v| v.trim() == "true")
+}
+
+/// Check whether a query string requests a placeholder.
+fn wants_placeholder(query: &str) -> bool {
+    query
+        .split('&')
+        .any(|p| p.split('=').next() == Some(PLACEHOLDER_PARAM))
+}
+
+/// Return the parameters of a request, or a description of the invalid one.
+pub(crate) fn params(req: &RequestContext) -> Result<Params, String> {
+    let mut params = Params {
+        placeholder: enabled(IMAGE_PLACEHOLDERS_ENV) && wants_placeholder(&req.query),
+        ..Default::default()
+    };
+    for (name, value) in query_params(&req.query) {
+        let parsed = value.parse::<u32>().ok();
+        match (name, parsed) {
+            ("w", Some(w @ 1..=MAX_DIMENSION)) => params.width = Some(w),
+            ("h", Some(h @ 1..=MAX_DIMENSION)) => params.height = Some(h),
+            ("q", Some(q @ 1..=100)) => params.quality = Some(q as u8),
+            ("q", _) => return Err(format!("q={value} is not a quality from 1 to 100")),
+            _ => {
+                return Err(format!(
+                    "{name}={value} is not a size from 1 to {MAX_DIMENSION} pixels"
+                ))
+            }
+        }
+    }
+    Ok(params)
+}
+
+/// Return the `w`, `h` and `q` parameters of a query string.
+fn query_params(query: &str) -> impl Iterator<Item = (&str, &str)> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .filter(|(name, _)| ["w", "h", "q"].contains(name))
+}
+
+/// Check whether a file is an image that can be resized.
+pub(crate) fn is_image(file: &Path) -> bool {
+    matches!(
+        ImageFormat::from_path(file),
+        Ok(ImageFormat::Png | ImageFormat::Jpeg)
+    )
+}
+
+/// Resize an image with the given ETag, returning the ETag and contents of the resized image.
+pub(crate) fn resize(file: &Path, etag: &str, params: &Params) -> Result<(String, Vec<u8>)> {
+    // the cache is optional, so resizing works without a key-value store
+    let store = Store::open_default().ok();
+    let key = format!("{CACHE_PREFIX}{}", cache_key(etag, params));
+    if let Some(cached) = store.as_ref().and_then(|s| s.get(&key).ok().flatten()) {
+        return Ok((hex::encode(Sha256::digest(&cached)), cached));
+    }
+    let resized = resize_file(file, params)?;
+    if let Some(store) = store {
+        if let Err(e) = store.set(&key, &resized) {
+            eprintln!("Error caching resized image {}: {e}", file.display());
+        }
+    }
+    Ok((hex::encode(Sha256::digest(&resized)), resized))
+}
+
+/// Resize an image file, re-encoding it in its format.
+fn resize_file(file: &Path, params: &Params) -> Result<Vec<u8>> {
+    let format = ImageFormat::from_path(file)?;
+    let source = backend::current()
+        .read(file)
+        .with_context(|| format!("reading {}", file.display()))?;
+    let image = image::load_from_memory_with_format(&source, format)
+        .with_context(|| format!("decoding {}", file.display()))?;
+    let (width, height) = target_size(image.width(), image.height(), params);
+    let image = if (width, height) == (image.width(), image.height()) {
+        image
+    } else {
+        image.resize_exact(width, height, FilterType::Lanczos3)
+    };
+    let image = if params.placeholder {
+        image.blur(PLACEHOLDER_BLUR)
+    } else {
+        image
+    };
+    let mut resized = Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Jpeg => {
+            let quality = params.quality.unwrap_or(DEFAULT_QUALITY);
+            JpegEncoder::new_with_quality(&mut resized, quality)
+                .encode_image(&DynamicImage::ImageRgb8(image.to_rgb8()))?;
+        }
+        _ => image.write_to(&mut resized, format)?,
+    }
+    Ok(resized.into_inner())
+}
+
+/// Return the cache key of an image resized with the given parameters.
+fn cache_key(etag: &str, params: &Params) -> String {
+    let Params {
+        width,
+        height,
+        quality,
+        placeholder,
+    } = params;
+    let dimension = |d: &Option<u32>| d.map_or("auto".to_string(), |d| d.to_string());
+    let quality = quality.map_or("auto".to_string(), |q| q.to_string());
+    let placeholder = if *placeholder { ":lqip" } else { "" };
+    format!(
+        "{etag}:{}x{}:{quality}{placeholder}",
+        dimension(width),
+        dimension(height)
+    )
+}
+
+/// Return the size an image is resized to: the largest that fits the requested width and height,
+/// or the placeholder size, keeping the aspect ratio. Images are never enlarged.
+fn target_size(width: u32, height: u32, params: &Params) -> (u32, u32) {
+    let (max_width, max_height) = if params.placeholder {
+        (Some(PLACEHOLDER_SIZE), Some(PLACEHOLDER_SIZE))
+    } else {
+        (params.width, params.height)
+    };
+    let scale = [
+        max_width.map(|w| f64::from(w) / f64::from(width)),
+        max_height.map(|h| f64::from(h) / f64::from(height)),
+    ]
+    .into_iter()
+    .flatten()
+    .fold(1.0, f64::min);
+    let scaled = |n: u32| ((f64::from(n) * scale).round() as u32).max(1);
+    (scaled(width), scaled(height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn with_query(query: &str) -> RequestContext {
+        RequestContext {
+            query: query.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_params() {
+        assert_eq!(
+            params(&with_query("w=300&q=70&v=2")),
+            Ok(Params {
+                width: Some(300),
+                height: None,
+                quality: Some(70),
+                placeholder: false,
+            })
+        );
+        assert!(wants_placeholder("lqip"));
+        assert!(wants_placeholder("v=2&lqip="));
+        assert!(!wants_placeholder("lqips"));
+        assert!(params(&with_query("w=0")).is_err());
+        assert!(params(&with_query("h=10000")).is_err());
+        assert!(params(&with_query("q=101")).is_err());
+        assert!(params(&with_query("w=wide")).is_err());
+    }
+
+    #[test]
+    fn test_target_size() {
+        let size = |width, height| Params {
+            width,
+            height,
+            ..Default::default()
+        };
+        assert_eq!(target_size(800, 600, &size(Some(400), None)), (400, 300));
+        assert_eq!(target_size(800, 600, &size(None, Some(150))), (200, 150));
+        assert_eq!(
+            target_size(800, 600, &size(Some(400), Some(100))),
+            (133, 100)
+        );
+        assert_eq!(target_size(800, 600, &size(Some(1600), None)), (800, 600));
+        assert_eq!(target_size(800, 600, &size(None, None)), (800, 600));
+        assert_eq!(target_size(1000, 1, &size(Some(10), None)), (10, 1));
+        let placeholder = Params {
+            width: Some(400),
+            placeholder: true,
+            ..Default::default()
+        };
+        assert_eq!(target_size(800, 600, &placeholder), (32, 24));
+    }
+
+    #[test]
+    fn test_resize_file() {
+        let dir = std::env::temp_dir().join("spin-fileserver-image-resize");
+        fs::create_dir_all(&dir).unwrap();
+        let png = dir.join("wide.png");
+        DynamicImage::new_rgba8(40, 20).save(&png).unwrap();
+        let jpeg = dir.join("wide.jpg");
+        DynamicImage::new_rgb8(40, 20).save(&jpeg).unwrap();
+
+        let params = Params {
+            width: Some(10),
+            ..Default::default()
+        };
+        for file in [&png, &jpeg] {
+            let resized = resize_file(file, &params).unwrap();
+            let format = ImageFormat::from_path(file).unwrap();
+            let image = image::load_from_memory_with_format(&resized, format).unwrap();
+            assert_eq!((image.width(), image.height()), (10, 5));
+        }
+        let placeholder = Params {
+            placeholder: true,
+            ..Default::default()
+        };
+        let resized = resize_file(&png, &placeholder).unwrap();
+        let image = image::load_from_memory_with_format(&resized, ImageFormat::Png).unwrap();
+        assert_eq!((image.width(), image.height()), (32, 16));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,211 @@
+//! A summary of the files the component serves, logged to help diagnose files that aren't where
+//! the configuration expects them, such as a directory that isn't mounted at all.
+//!
+//! Spin starts an instance per request, so the digest of the deployed build and configuration
+//! the summary was last logged for is kept in the default key-value store, and the summary is
+//! only logged again once they change.
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::{io, path::Path};
+
+use crate::{
+    backend::{self, Backend},
+    config::Config,
+    host::Store,
+    settings,
+    version::Version,
+    DIRECTORY_FALLBACK_PATH,
+};
+
+/// Environment variable enabling the inventory log
+pub(crate) const ASSET_INVENTORY_ENV: &str = "ASSET_INVENTORY";
+/// Number of largest files listed
+const LARGEST_FILES: usize = 5;
+/// Number of index files listed
+const INDEX_FILES: usize = 10;
+/// Depth of directories below which files aren't counted, guarding against symbolic link loops
+const MAX_DEPTH: usize = 32;
+
+/// Key-value store key holding the digest of the deployment the inventory was logged for
+const LOGGED_KEY: &str = "spin-fileserver:inventory-logged";
+
+/// A summary of the files under a directory.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Inventory {
+    /// Number of files.
+    files: u64,
+    /// Total size of the files in bytes.
+    bytes: u64,
+    /// The largest files and their sizes, largest first.
+    largest: Vec<(String, u64)>,
+    /// Paths of the index files, in the order they were found.
+    index_files: Vec<String>,
+}
+
+impl Inventory {
+    /// Walk the files under a directory of a backend.
+    pub fn walk(backend: &dyn Backend, root: &Path) -> io::Result<Self> {
+        let mut inventory = Self::default();
+        inventory.add(backend, root, "", 0)?;
+        Ok(inventory)
+    }
+
+    fn add(
+        &mut self,
+        backend: &dyn Backend,
+        dir: &Path,
+        prefix: &str,
+        depth: usize,
+    ) -> io::Result<()> {
+        let mut names = backend.list(dir)?;
+        names.sort();
+        for name in names {
+            let path = dir.join(&name);
+            let display = format!("{prefix}/{name}");
+            if backend.is_dir(&path) {
+                if depth < MAX_DEPTH {
+                    self.add(backend, &path, &display, depth + 1)?;
+                }
+                continue;
+            }
+            // special files such as pipes aren't served
+            if !backend.is_file(&path) {
+                continue;
+            }
+            let size = backend.size(&path)?;
+            self.files += 1;
+            self.bytes += size;
+            if name == DIRECTORY_FALLBACK_PATH {
+                self.index_files.push(display.clone());
+            }
+            let at = self
+                .largest
+                .partition_point(|(_, largest)| *largest >= size);
+            if at < LARGEST_FILES {
+                self.largest.insert(at, (display, size));
+                self.largest.truncate(LARGEST_FILES);
+            }
+        }
+        Ok(())
+    }
+
+    /// Format the inventory as a single log message.
+    pub fn summary(&self) -> String {
+        if self.files == 0 {
+            return "asset inventory: no files are mounted, check the files of the component"
+                .to_string();
+        }
+        let largest = self
+            .largest
+            .iter()
+            .map(|(path, size)| format!("{path} ({size} bytes)"))
+            .collect::<Vec<_>>();
+        let mut index_files =
+            self.index_files[..self.index_files.len().min(INDEX_FILES)].join(", ");
+        if self.index_files.len() > INDEX_FILES {
+            index_files += &format!(" and {} more", self.index_files.len() - INDEX_FILES);
+        }
+        if index_files.is_empty() {
+            index_files = "none".to_string();
+        }
+        format!(
+            "asset inventory: {} file(s), {} bytes\n  largest: {}\n  index files: {index_files}",
+            self.files,
+            self.bytes,
+            largest.join(", ")
+        )
+    }
+}
+
+/// Log the inventory of the served files, if enabled and not logged for this deployment yet.
+pub(crate) fn run() {
+    let enabled = settings::var(ASSET_INVENTORY_ENV).is_some_and(|v| v.trim() == "true");
+    if !enabled {
+        return;
+    }
+    match first_for_deployment() {
+        Ok(false) => return,
+        Ok(true) => {}
+        Err(e) => {
+            eprintln!("asset inventory: cannot check whether it was logged: {e:#}");
+            return;
+        }
+    }
+    match Inventory::walk(backend::current().as_ref(), Path::new("/")) {
+        Ok(inventory) => eprintln!("{}", inventory.summary()),
+        Err(e) => eprintln!("asset inventory: cannot list the files: {e}"),
+    }
+}
+
+/// Check whether the inventory wasn't logged for the deployed build and configuration yet,
+/// recording that it is.
+fn first_for_deployment() -> Result<bool> {
+    let digest = deployment_digest()?;
+    let store =
+        Store::open_default().context("cannot open the key-value store to log the inventory")?;
+    if store.get(LOGGED_KEY)?.as_deref() == Some(digest.as_bytes()) {
+        return Ok(false);
+    }
+    store.set(LOGGED_KEY, digest.as_bytes())?;
+    Ok(true)
+}
+
+/// Identify the deployment by the build metadata and the digest of the configuration file.
+fn deployment_digest() -> Result<String> {
+    let version = serde_json::to_vec(&Version::current()?)?;
+    let config = Config::digest()?.unwrap_or_default();
+    let mut hasher = Sha256::new();
+    hasher.update(&version);
+    hasher.update(config.as_bytes());
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Filesystem;
+    use std::fs;
+
+    #[test]
+    fn test_walk() {
+        let dir = std::env::temp_dir().join("spin-fileserver-inventory");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("docs/api")).unwrap();
+        fs::write(dir.join("index.html"), "home").unwrap();
+        fs::write(dir.join("app.js"), "x".repeat(100)).unwrap();
+        fs::write(dir.join("docs/index.html"), "docs").unwrap();
+        fs::write(dir.join("docs/api/v1.json"), "{}").unwrap();
+
+        let inventory = Inventory::walk(&Filesystem, &dir).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(
+            inventory,
+            Inventory {
+                files: 4,
+                bytes: 110,
+                largest: vec![
+                    ("/app.js".to_string(), 100),
+                    ("/docs/index.html".to_string(), 4),
+                    ("/index.html".to_string(), 4),
+                    ("/docs/api/v1.json".to_string(), 2),
+                ],
+                index_files: vec!["/docs/index.html".to_string(), "/index.html".to_string()],
+            }
+        );
+        assert_eq!(
+            inventory.summary(),
+            "asset inventory: 4 file(s), 110 bytes\n  \
+             largest: /app.js (100 bytes), /docs/index.html (4 bytes), /index.html (4 bytes), \
+             /docs/api/v1.json (2 bytes)\n  \
+             index files: /docs/index.html, /index.html"
+        );
+    }
+
+    #[test]
+    fn test_empty() {
+        assert!(Inventory::default()
+            .summary()
+            .contains("no files are mounted"));
+    }
+}
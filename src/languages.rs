@@ -0,0 +1,189 @@
+//! Language negotiation for localized fallback and error documents, such as `404.{lang}.html`.
+//!
+//! With a language cookie, the language chosen with a `?lang=` query parameter, or negotiated for
+//! the first localized document served, is remembered and preferred over `Accept-Language`.
+
+use http::header::ACCEPT_LANGUAGE;
+use std::{cmp::Ordering, path::Path};
+
+use crate::{oidc::query_param, settings, RequestContext, CUSTOM_404_PATH_ENV, FALLBACK_PATH_ENV};
+
+/// Environment variable for the language used when none of the accepted languages is available
+pub(crate) const DEFAULT_LANGUAGE_ENV: &str = "DEFAULT_LANGUAGE";
+/// Default language
+pub(crate) const DEFAULT_LANGUAGE: &str = "en";
+/// Placeholder replaced with a language in configured paths
+pub(crate) const LANGUAGE_PLACEHOLDER: &str = "{lang}";
+/// Environment variable for the name of the cookie remembering the language of a client
+pub(crate) const LANGUAGE_COOKIE_ENV: &str = "LANGUAGE_COOKIE";
+/// Query parameter switching the language of a client
+const LANGUAGE_PARAM: &str = "lang";
+
+/// Return the languages to try for a request, in order of preference, ending with the default
+/// language. Regional tags are followed by their primary language, e.g. `fr-ca` by `fr`.
+/// With a language cookie, the requested and remembered languages come first.
+pub(crate) fn preferred(req: &RequestContext) -> Vec<String> {
+    let accepted = req
+        .header(&ACCEPT_LANGUAGE)
+        .and_then(|v| std::str::from_utf8(v).ok())
+        .unwrap_or_default();
+    let mut languages = Vec::new();
+    if let Some(name) = cookie_name() {
+        for tag in [requested(req), remembered(req, &name)]
+            .into_iter()
+            .flatten()
+        {
+            let primary = tag.split('-').next().unwrap_or_default().to_string();
+            for language in [tag, primary] {
+                if !languages.contains(&language) {
+                    languages.push(language);
+                }
+            }
+        }
+    }
+    for language in preferred_languages(accepted, &default_language()) {
+        if !languages.contains(&language) {
+            languages.push(language);
+        }
+    }
+    languages
+}
+
+/// Return the name of the language cookie, if languages are remembered.
+pub(crate) fn cookie_name() -> Option<String> {
+    settings::var(LANGUAGE_COOKIE_ENV)
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+/// Return the name and value of the language cookie to set in the response to a request, given
+/// the language of the localized document served, if any: the language requested with the query
+/// parameter if it isn't remembered yet, or else the negotiated language if none is.
+pub(crate) fn cookie(req: &RequestContext, served: Option<&str>) -> Option<(String, String)> {
+    let name = cookie_name()?;
+    let remembered = remembered(req, &name);
+    let language = match requested(req) {
+        Some(requested) => (remembered.as_ref() != Some(&requested)).then_some(requested),
+        None if remembered.is_none() => served.map(str::to_string),
+        None => None,
+    }?;
+    Some((name, language))
+}
+
+/// Return the language of a localized fallback or custom 404 document, given the languages it
+/// was looked up in.
+pub(crate) fn served<'a>(file: &Path, languages: &'a [String]) -> Option<&'a str> {
+    let file = file.to_string_lossy();
+    [FALLBACK_PATH_ENV, CUSTOM_404_PATH_ENV]
+        .into_iter()
+        .filter_map(settings::var)
+        .filter(|path| is_template(path))
+        .find_map(|template| {
+            // under a root directory, the document is looked up within it
+            let template = template.trim_start_matches('/');
+            languages
+                .iter()
+                .find(|language| file.ends_with(&template.replace(LANGUAGE_PLACEHOLDER, language)))
+                .map(String::as_str)
+        })
+}
+
+/// Return the language requested with the query parameter.
+fn requested(req: &RequestContext) -> Option<String> {
+    query_param(&req.query, LANGUAGE_PARAM).and_then(|tag| valid_tag(&tag))
+}
+
+/// Return the language remembered in the cookie.
+fn remembered(req: &RequestContext, name: &str) -> Option<String> {
+    req.cookie(name).and_then(valid_tag)
+}
+
+/// Normalize a language tag, if it is a plain tag that can be used in file names.
+fn valid_tag(tag: &str) -> Option<String> {
+    let tag = tag.trim().to_lowercase();
+    let valid = !tag.is_empty() && tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    valid.then_some(tag)
+}
+
+/// Return the configured default language.
+pub(crate) fn default_language() -> String {
+    settings::var(DEFAULT_LANGUAGE_ENV)
+        .map(|l| l.trim().to_lowercase())
+        .unwrap_or_else(|| DEFAULT_LANGUAGE.to_string())
+}
+
+fn preferred_languages(accepted: &str, default: &str) -> Vec<String> {
+    let mut weighted = accepted
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?;
+            let weight = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            // only plain language tags can be used in file names
+            valid_tag(tag)
+                .filter(|_| weight > 0.0)
+                .map(|tag| (tag, weight))
+        })
+        .collect::<Vec<_>>();
+    // stable, so ranges of equal weight keep their order
+    weighted.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+
+    let mut languages = Vec::new();
+    for (tag, _) in weighted {
+        let primary = tag.split('-').next().unwrap_or_default().to_string();
+        for language in [tag, primary] {
+            if !languages.contains(&language) {
+                languages.push(language);
+            }
+        }
+    }
+    if !default.is_empty() && !languages.iter().any(|l| l == default) {
+        languages.push(default.to_string());
+    }
+    languages
+}
+
+/// Check whether a configured path is localized.
+pub(crate) fn is_template(path: &str) -> bool {
+    path.contains(LANGUAGE_PLACEHOLDER)
+}
+
+/// Return the candidate paths of a localized path, in order of preference.
+pub(crate) fn candidates<'a>(
+    template: &'a str,
+    languages: &'a [String],
+) -> impl Iterator<Item = String> + 'a {
+    languages
+        .iter()
+        .map(move |language| template.replace(LANGUAGE_PLACEHOLDER, language))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preferred_languages() {
+        assert_eq!(
+            preferred_languages("fr-CA, de;q=0.5, fr;q=0.9, *;q=0.1", "en"),
+            ["fr-ca", "fr", "de", "en"]
+        );
+        assert_eq!(preferred_languages("en-GB,en;q=0.8", "en"), ["en-gb", "en"]);
+        assert_eq!(preferred_languages("", "en"), ["en"]);
+        assert_eq!(
+            preferred_languages("es;q=0, ../etc, it;q=bad, nl", "de"),
+            ["nl", "de"]
+        );
+    }
+
+    #[test]
+    fn test_candidates() {
+        let languages = ["fr".to_string(), "en".to_string()];
+        assert_eq!(
+            candidates("errors/404.{lang}.html", &languages).collect::<Vec<_>>(),
+            ["errors/404.fr.html", "errors/404.en.html"]
+        );
+    }
+}
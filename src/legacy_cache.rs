@@ -0,0 +1,45 @@
+//! `Pragma: no-cache` for HTTP/1.0 caches and proxies that ignore `Cache-Control`, which some
+//! enterprise networks still run.
+
+use http::header::{CACHE_CONTROL, PRAGMA};
+
+use crate::settings;
+
+/// Environment variable enabling `Pragma: no-cache` on responses that mustn't be reused
+pub(crate) const LEGACY_PRAGMA_ENV: &str = "LEGACY_PRAGMA";
+
+/// Return the `Pragma` header to send along with the headers of a response, if enabled and its
+/// `Cache-Control` header requires revalidation or forbids storing it.
+pub(crate) fn pragma(headers: &[(String, Vec<u8>)]) -> Option<(String, Vec<u8>)> {
+    let enabled = settings::var(LEGACY_PRAGMA_ENV).is_some_and(|v| v.trim() == "true");
+    if !enabled {
+        return None;
+    }
+    let no_cache = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case(CACHE_CONTROL.as_str()))
+        .any(|(_, value)| forbids_reuse(&String::from_utf8_lossy(value)));
+    no_cache.then(|| (PRAGMA.as_str().to_string(), b"no-cache".to_vec()))
+}
+
+/// Check whether a `Cache-Control` value has a `no-cache` or `no-store` directive.
+fn forbids_reuse(cache_control: &str) -> bool {
+    cache_control.split(',').any(|directive| {
+        let name = directive.split('=').next().unwrap_or_default().trim();
+        name.eq_ignore_ascii_case("no-cache") || name.eq_ignore_ascii_case("no-store")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forbids_reuse() {
+        assert!(forbids_reuse("no-cache"));
+        assert!(forbids_reuse("private, No-Store"));
+        assert!(forbids_reuse("no-cache=\"set-cookie\", max-age=60"));
+        assert!(!forbids_reuse("max-age=60"));
+        assert!(!forbids_reuse("public, max-age=31536000, immutable"));
+    }
+}
@@ -1,20 +1,106 @@
+mod access;
+mod access_log;
+mod admin;
+mod analytics;
+mod asset_manifest;
+pub mod backend;
+#[doc(hidden)]
+pub mod bench;
+mod campaigns;
+mod checksums;
+mod conditional;
+mod config;
+mod content_addressed;
+mod content_warning;
+mod csp;
+mod deploy_window;
+mod disposition;
+mod downloads;
+mod drafts;
+mod embargo;
+mod error;
+mod etag_cache;
+mod exif;
+mod file_headers;
+mod flush;
+#[doc(hidden)]
+pub mod fuzz;
+mod honeypot;
+mod host;
+mod image_resize;
+mod inventory;
+mod languages;
+mod legacy_cache;
+mod metadata;
+mod minify;
+mod mock_api;
+mod negotiation;
+mod not_found_cache;
+mod oidc;
+mod origin;
+mod precompressed;
+mod prerender;
+mod purge_webhook;
+mod quota;
+mod redirect_table;
+mod releases;
+mod repr_digest;
+mod reproducible;
+mod request_limits;
+mod request_url;
+mod rewrite_trace;
+mod scanners;
+mod secrets;
+mod self_check;
+mod session_gate;
+mod settings;
+mod shadowing;
+mod sitemap;
+mod size_limits;
+mod subdomains;
+mod subpath;
+mod surrogate;
+mod timing;
+mod version;
+
+use access_log::{AccessLogEntry, AccessLogFormat};
 use anyhow::{anyhow, Context, Result};
+use asset_manifest::{AssetManifest, IMMUTABLE_CACHE_CONTROL};
+use backend::Backend;
+use conditional::RangeOutcome;
+use config::{same_path, Action, Config};
+use downloads::{DownloadLimiter, SlotReader};
+use error::FileServerError;
+use etag_cache::CacheStatus;
+use file_headers::FileHeaders;
+use flush::FlushingEncoder;
 use futures::SinkExt;
 use http::{
-    header::{ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, ETAG, IF_NONE_MATCH},
+    header::{
+        ACCEPT, ACCEPT_ENCODING, ACCEPT_LANGUAGE, ACCEPT_RANGES, ALLOW, CACHE_CONTROL,
+        CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_LOCATION, CONTENT_RANGE, CONTENT_TYPE, COOKIE,
+        ETAG, IF_MATCH, IF_NONE_MATCH, IF_RANGE, LOCATION, RANGE, REFERER, SET_COOKIE, USER_AGENT,
+        VARY,
+    },
     HeaderName, StatusCode, Uri,
 };
-use spin_sdk::http::{Fields, IncomingRequest, OutgoingResponse, ResponseOutparam};
+use negotiation::Negotiation;
+use request_url::RequestUrl;
+use rewrite_trace::Trace;
+use spin_sdk::http::{Fields, IncomingRequest, Method, OutgoingResponse, ResponseOutparam};
 use std::{
     cmp::Ordering,
+    collections::BTreeMap,
     fmt,
     fmt::Error,
-    fs::File,
     io::{Cursor, Read},
-    path::PathBuf,
+    ops::ControlFlow,
+    path::{Component, Path, PathBuf},
     str,
     str::FromStr,
+    time::{Instant, SystemTime},
 };
+use timing::Timings;
 
 /// The default value for the cache control header.
 const CACHE_CONTROL_DEFAULT_VALUE: &str = "max-age=60";
@@ -35,10 +121,42 @@ const DEFLATE_ENCODING: &str = "deflate";
 const PATH_INFO_HEADER: &str = "spin-path-info";
 /// The component route header
 const COMPONENT_ROUTE_HEADER: &str = "spin-component-route";
+/// The client address header
+const CLIENT_ADDR_HEADER: &str = "spin-client-addr";
 // Environment variable for the fallback path
 const FALLBACK_PATH_ENV: &str = "FALLBACK_PATH";
 /// Environment variable for the custom 404 path
 const CUSTOM_404_PATH_ENV: &str = "CUSTOM_404_PATH";
+/// Environment variable for case-insensitive path resolution (`true` or `redirect`)
+const CASE_INSENSITIVE_ENV: &str = "CASE_INSENSITIVE";
+/// Environment variable for how requests naming `index.html` are handled (`serve`, `redirect` or
+/// `not-found`)
+const INDEX_HTML_ENV: &str = "INDEX_HTML_REQUESTS";
+/// Environment variable for virtual path aliases (`/virtual/path=physical/path,...`)
+const ALIASES_ENV: &str = "ALIASES";
+/// Environment variable for the request header selecting a content variant (e.g. `X-Country`)
+const VARIANT_HEADER_ENV: &str = "VARIANT_HEADER";
+/// Environment variable for how content variants are laid out (`directory` or `suffix`)
+const VARIANT_MODE_ENV: &str = "VARIANT_MODE";
+/// Environment variable for the percentage of clients served from the `variant-b` directory
+const EXPERIMENT_SPLIT_ENV: &str = "EXPERIMENT_SPLIT";
+/// Cookie remembering the A/B experiment bucket of a client
+const EXPERIMENT_COOKIE: &str = "spin-fileserver-bucket";
+/// Environment variable for the directory of a canary site build
+const CANARY_ROOT_ENV: &str = "CANARY_ROOT";
+/// Environment variable for the percentage of clients served from the canary root
+const CANARY_PERCENT_ENV: &str = "CANARY_PERCENT";
+/// Environment variable for a request header whose hash assigns clients to the canary
+const CANARY_HEADER_ENV: &str = "CANARY_HEADER";
+/// Cookie remembering whether a client is served from the canary root
+const CANARY_COOKIE: &str = "spin-fileserver-canary";
+/// Environment variable for a header set by a trusted delegating component to scope resolution
+/// to a subdirectory
+const ROOT_HEADER_ENV: &str = "ROOT_HEADER";
+/// The methods advertised in `Allow` headers
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+/// How long clients keep their experiment bucket or canary assignment, in seconds
+const STICKY_COOKIE_MAX_AGE: u32 = 30 * 24 * 60 * 60;
 /// Directory fallback path (trying to map `/about/` -> `/about/index.html`).
 const DIRECTORY_FALLBACK_PATH: &str = "index.html";
 // FAVICON_ICO_FILENAME
@@ -51,6 +169,9 @@ const FALLBACK_FAVICON_PNG: &[u8] = include_bytes!("../spin-favicon.png");
 const FALLBACK_FAVICON_ICO: &[u8] = include_bytes!("../spin-favicon.ico");
 
 const BUFFER_SIZE: usize = 64 * 1024;
+/// Size in bytes of the largest files whose compressed contents are kept in memory when hashed for
+/// their ETag, and sent without compressing them again
+const SMALL_FILE_SIZE: u64 = 16 * 1024;
 const DEFLATE_LEVEL: flate2::Compression = flate2::Compression::fast();
 
 #[derive(PartialEq, Debug)]
@@ -127,6 +248,7 @@ impl FromStr for ContentEncoding {
             .trim()
             .parse()
             .context("failed to parse encoding weight")?;
+        anyhow::ensure!(!weight.is_nan(), "encoding weight is not a number");
         weight = weight.clamp(0.0, 1.0);
 
         Ok(ContentEncoding {
@@ -204,36 +326,67 @@ impl SupportedEncoding {
 
 #[spin_sdk::http_component]
 async fn handle_request(req: IncomingRequest, res_out: ResponseOutparam) {
+    // the request sees the settings as they were when first read
+    let _settings = settings::scope();
+    let _fetched = backend::scope();
     let headers = req.headers().entries();
-    let enc = SupportedEncoding::best_encoding(&headers);
-    let mut path = headers
+    let path_info = headers
         .iter()
-        .find_map(|(k, v)| (k.to_lowercase() == PATH_INFO_HEADER).then_some(v))
-        .expect("PATH_INFO header must be set by the Spin runtime");
-
+        .find_map(|(k, v)| (k.to_lowercase() == PATH_INFO_HEADER).then_some(v));
     let component_route = headers
         .iter()
-        .find_map(|(k, v)| (k.to_lowercase() == COMPONENT_ROUTE_HEADER).then_some(v))
-        .expect("COMPONENT_ROUTE header must be set by the Spin runtime");
-
-    let uri = req
-        .uri()
-        .parse::<Uri>()
-        .expect("URI is invalid")
-        .path()
-        .as_bytes()
-        .to_vec();
-    if &uri == component_route && path.is_empty() {
-        path = &uri;
-    }
+        .find_map(|(k, v)| (k.to_lowercase() == COMPONENT_ROUTE_HEADER).then_some(v));
+    let uri = req.uri().parse::<Uri>().ok();
+    // requests whose URI can't be parsed get `400 Bad Request` instead of trapping
+    let mut malformed = uri.is_none();
+    let uri = uri
+        .map(|uri| uri.path().as_bytes().to_vec())
+        .unwrap_or_default();
+    let (path, component_route) = request_path(
+        path_info.map(Vec::as_slice),
+        component_route.map(Vec::as_slice),
+        &uri,
+    );
 
-    let if_none_match = headers
-        .iter()
-        .find_map(|(k, v)| {
-            (HeaderName::from_bytes(k.as_bytes()).ok()? == IF_NONE_MATCH).then_some(v.as_slice())
-        })
-        .unwrap_or(b"");
-    match FileServer::make_response(path, enc, if_none_match) {
+    let target = req.path_with_query().unwrap_or_default();
+    let method = match req.method() {
+        Method::Get => http::Method::GET,
+        Method::Head => http::Method::HEAD,
+        Method::Post => http::Method::POST,
+        Method::Put => http::Method::PUT,
+        Method::Delete => http::Method::DELETE,
+        Method::Connect => http::Method::CONNECT,
+        Method::Options => http::Method::OPTIONS,
+        Method::Trace => http::Method::TRACE,
+        Method::Patch => http::Method::PATCH,
+        Method::Other(m) => match http::Method::from_bytes(m.as_bytes()) {
+            Ok(method) => method,
+            // invalid methods get `400 Bad Request` too, instead of being served like `GET`
+            Err(_) => {
+                malformed = true;
+                http::Method::default()
+            }
+        },
+    };
+    let req = RequestContext {
+        method,
+        query: target
+            .split_once('?')
+            .map(|(_, query)| query.to_string())
+            .unwrap_or_default(),
+        path,
+        route: String::from_utf8_lossy(&component_route).into_owned(),
+        headers,
+        internal: false,
+    };
+    let time = SystemTime::now();
+    let mut sent = 0;
+    let response = if malformed {
+        Ok(plain_response(StatusCode::BAD_REQUEST))
+    } else {
+        FileServer::make_response(&req)
+    };
+    let status = match response {
         Ok((status, headers, reader)) => {
             let fields = Fields::new();
             for (name, value) in headers {
@@ -244,15 +397,20 @@ async fn handle_request(req: IncomingRequest, res_out: ResponseOutparam) {
             let mut body = res.take_body();
             res_out.set(res);
             if let Some(mut reader) = reader {
-                let mut buffer = vec![0_u8; BUFFER_SIZE];
+                let start = Instant::now();
+                // the body sink takes ownership of every chunk, so each is read into its own
+                // buffer and moved into the sink rather than copied out of a shared one
                 loop {
-                    match reader.read(&mut buffer) {
+                    let mut chunk = vec![0_u8; BUFFER_SIZE];
+                    match reader.read(&mut chunk) {
                         Ok(0) => break,
                         Ok(count) => {
-                            if let Err(e) = body.send(buffer[..count].to_vec()).await {
+                            chunk.truncate(count);
+                            if let Err(e) = body.send(chunk).await {
                                 eprintln!("Error sending body: {e}");
                                 break;
                             }
+                            sent += count;
                         }
                         Err(e) => {
                             eprintln!("Error reading file: {e}");
@@ -260,7 +418,15 @@ async fn handle_request(req: IncomingRequest, res_out: ResponseOutparam) {
                         }
                     }
                 }
+                if timing::enabled() {
+                    eprintln!(
+                        "Streamed {sent} bytes for {} in {:.3}ms",
+                        String::from_utf8_lossy(&req.path),
+                        start.elapsed().as_secs_f64() * 1000.0
+                    );
+                }
             }
+            status
         }
         Err(e) => {
             eprintln!("Error building response: {e}");
@@ -268,10 +434,107 @@ async fn handle_request(req: IncomingRequest, res_out: ResponseOutparam) {
             let _ = res.set_status_code(500);
             let mut body = res.take_body();
             res_out.set(res);
-            if let Err(e) = body.send(b"Internal Server Error".to_vec()).await {
-                eprintln!("Error sending body: {e}");
+            let message = b"Internal Server Error";
+            match body.send(message.to_vec()).await {
+                Ok(()) => sent = message.len(),
+                Err(e) => eprintln!("Error sending body: {e}"),
             }
+            StatusCode::INTERNAL_SERVER_ERROR
         }
+    };
+
+    honeypot::report();
+
+    if quota::enabled() {
+        if let Err(e) = quota::record(&req, sent) {
+            eprintln!("Error accounting for bytes served: {e}");
+        }
+    }
+
+    // blocked scanner requests were logged on their own
+    let blocked = str::from_utf8(&req.path).is_ok_and(scanners::is_blocked);
+    if let Some(format) = AccessLogFormat::from_env().filter(|_| !blocked) {
+        let header_str = |name: &str| {
+            req.headers
+                .iter()
+                .find_map(|(k, v)| k.eq_ignore_ascii_case(name).then_some(v))
+                .and_then(|v| str::from_utf8(v).ok())
+        };
+        let entry = AccessLogEntry {
+            remote_addr: header_str(CLIENT_ADDR_HEADER).map(access_log::strip_port),
+            time,
+            method: req.method.as_str(),
+            target: &target,
+            status: status.as_u16(),
+            bytes: sent,
+            referer: header_str(REFERER.as_str()),
+            user_agent: header_str(USER_AGENT.as_str()),
+        };
+        println!("{}", format.format(&entry));
+    }
+}
+
+/// Return the path of the requested file and the component route, from the headers set by the
+/// Spin HTTP trigger or, when they are missing as with plain `wasi:http` hosts, from the URI path.
+fn request_path(
+    path_info: Option<&[u8]>,
+    component_route: Option<&[u8]>,
+    uri: &[u8],
+) -> (Vec<u8>, Vec<u8>) {
+    let component_route = component_route.unwrap_or_default();
+    let path = match path_info {
+        Some(path) if path.is_empty() && uri == component_route => uri,
+        Some(path) => path,
+        None => uri.strip_prefix(component_route).unwrap_or(uri),
+    };
+    (path.to_vec(), component_route.to_vec())
+}
+
+/// The status, headers and optional body of a response.
+type Response = (StatusCode, Vec<(String, Vec<u8>)>, Option<Box<dyn Read>>);
+
+/// Build a response with the canonical reason of the status as its plain text body.
+fn plain_response(status: StatusCode) -> Response {
+    let reason = status.canonical_reason().unwrap_or_default();
+    (status, Vec::new(), Some(Box::new(Cursor::new(reason))))
+}
+
+/// The parts of the incoming request used to build a response.
+#[derive(Debug, Default, Clone)]
+struct RequestContext {
+    /// The request method.
+    method: http::Method,
+    /// The path info, relative to the component route.
+    path: Vec<u8>,
+    /// The query string, without the leading `?`.
+    query: String,
+    /// The route the component is mounted at, used as the prefix of redirect locations.
+    route: String,
+    /// The request headers.
+    headers: Vec<(String, Vec<u8>)>,
+    /// Whether the file server made the request itself, so it isn't counted as a client's.
+    internal: bool,
+}
+
+impl RequestContext {
+    /// Return the value of the first header with the given name.
+    fn header(&self, name: &HeaderName) -> Option<&[u8]> {
+        self.headers.iter().find_map(|(k, v)| {
+            (HeaderName::from_bytes(k.as_bytes()).ok()? == name).then_some(v.as_slice())
+        })
+    }
+
+    /// Return the value of the cookie with the given name.
+    fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .filter(|(k, _)| HeaderName::from_bytes(k.as_bytes()).ok() == Some(COOKIE))
+            .filter_map(|(_, v)| str::from_utf8(v).ok())
+            .flat_map(|v| v.split(';'))
+            .find_map(|cookie| {
+                let (k, v) = cookie.split_once('=')?;
+                (k.trim() == name).then_some(v.trim())
+            })
     }
 }
 
@@ -279,9 +542,173 @@ async fn handle_request(req: IncomingRequest, res_out: ResponseOutparam) {
 enum FileServerPath {
     Physical(PathBuf),
     Embedded(&'static [u8]),
+    /// Redirect to the given path, relative to the component route.
+    Redirect(String),
+    /// Serve the given path, relative to the component route, which only differs from the
+    /// request path in case.
+    Recased(String),
     None,
 }
 
+/// Where the file for a content variant is found.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum VariantMode {
+    /// In a directory named after the variant (`/de/about.html`).
+    Directory,
+    /// Next to the file, with the variant before the extension (`/about.de.html`).
+    Suffix,
+}
+
+impl VariantMode {
+    fn from_env() -> Self {
+        match settings::var(VARIANT_MODE_ENV).map(|v| v.trim().to_lowercase()) {
+            Some(v) if v == "suffix" => Self::Suffix,
+            _ => Self::Directory,
+        }
+    }
+
+    /// Return the path of the given variant of a request path.
+    fn variant_path(self, path: &str, variant: &str) -> String {
+        match self {
+            Self::Directory => {
+                let rest = path.trim_start_matches('/');
+                let root = &path[..path.len() - rest.len()];
+                format!("{root}{variant}/{rest}")
+            }
+            Self::Suffix => {
+                let path = if path.is_empty() || path.ends_with('/') {
+                    format!("{path}{DIRECTORY_FALLBACK_PATH}")
+                } else {
+                    path.to_string()
+                };
+                let file_start = path.rfind('/').map(|i| i + 1).unwrap_or(0);
+                match path[file_start..].rfind('.') {
+                    Some(dot) if dot > 0 => {
+                        let dot = file_start + dot;
+                        format!("{}.{variant}{}", &path[..dot], &path[dot..])
+                    }
+                    _ => format!("{path}.{variant}"),
+                }
+            }
+        }
+    }
+}
+
+/// The canary assignment of a request.
+#[derive(Debug)]
+struct Canary {
+    /// The directory of the canary site build.
+    root: String,
+    /// Whether the request is served from the canary root.
+    active: bool,
+    /// The request header the assignment depends on.
+    vary: HeaderName,
+    /// The `Set-Cookie` header remembering a new assignment.
+    cookie: Option<(String, Vec<u8>)>,
+}
+
+/// How to treat requests that only match a file when ignoring case.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum CaseInsensitiveMode {
+    /// Only exact matches are served.
+    Off,
+    /// Serve the case-insensitive match.
+    Serve,
+    /// Redirect permanently to the canonical casing.
+    Redirect,
+}
+
+impl CaseInsensitiveMode {
+    fn from_env() -> Self {
+        match settings::var(CASE_INSENSITIVE_ENV)
+            .map(|v| v.trim().to_lowercase())
+            .as_deref()
+        {
+            Some("true") | Some("serve") => Self::Serve,
+            Some("redirect") => Self::Redirect,
+            _ => Self::Off,
+        }
+    }
+}
+
+/// How requests naming the directory fallback file, like `/docs/index.html`, are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IndexHtmlMode {
+    /// Serve the file, as for `/docs/`.
+    Serve,
+    /// Redirect permanently to the directory, so each page has one URL.
+    Redirect,
+    /// Respond with `404 Not Found`, so each page has one URL.
+    NotFound,
+}
+
+impl IndexHtmlMode {
+    fn from_env() -> Self {
+        match settings::var(INDEX_HTML_ENV)
+            .map(|v| v.trim().to_lowercase())
+            .as_deref()
+        {
+            Some("redirect") => Self::Redirect,
+            Some("not-found") => Self::NotFound,
+            _ => Self::Serve,
+        }
+    }
+}
+
+/// The file a request is routed to, and what else the response depends on.
+struct Route {
+    /// The path of the file, once rewrites, mounts, releases, rollouts and variants apply.
+    path: String,
+    assets: Option<AssetManifest>,
+    /// The status configured for the request path, if any.
+    status_override: Option<StatusCode>,
+    /// Whether the path never changes contents, because it is fingerprinted or content-addressed.
+    immutable: bool,
+    /// The `Cache-Control` header of the mount the path is under, if any.
+    mount_cache_control: Option<String>,
+    /// Whether User-Agent rules are configured.
+    user_agent_rules: bool,
+    /// Whether the path has a pre-rendered page for crawlers.
+    prerendered: bool,
+    release: Option<releases::Release>,
+    canary: Option<Canary>,
+    /// The experiment bucket of the client, and whether it was newly assigned.
+    experiment: Option<(char, bool)>,
+    /// The request header choosing content variants, if configured.
+    variant_header: Option<HeaderName>,
+    /// Whether the representation was chosen by content negotiation.
+    negotiated: bool,
+    /// The request headers configured to vary responses on for the request path.
+    vary: Vec<HeaderName>,
+    /// Whether the request path is a draft, only served with a preview token.
+    draft: bool,
+    /// The subdirectory the root header confines the request to, if any.
+    root: Option<String>,
+    /// Whether the teaser of an embargoed path is served instead.
+    embargoed: bool,
+}
+
+/// The resolved file of a request.
+struct ResolvedFile {
+    resolved: FileServerPath,
+    /// The headers declared for the file in a sidecar file or front matter.
+    declared: Option<FileHeaders>,
+    /// The slot held while a large download is sent.
+    slot: Option<downloads::Slot>,
+}
+
+/// The body of a response and its validator.
+struct Representation {
+    etag: String,
+    /// The status of the ETag cache, if it was used.
+    cache_status: Option<CacheStatus>,
+    reader: Option<Box<dyn Read>>,
+    /// Whether the file was resized or stripped, so its bytes differ from the file's.
+    transformed: bool,
+    /// The CSP nonce added to the document, which makes every response different.
+    nonce: Option<String>,
+}
+
 trait IsFavicon {
     fn is_favicon(&self) -> bool;
 }
@@ -297,53 +724,328 @@ impl IsFavicon for PathBuf {
 
 struct FileServer;
 impl FileServer {
-    /// Resolve the requested path and then try to read the file.
-    /// None should indicate that the file does not exist after attempting fallback paths.
-    fn resolve_and_read(path: &str, encoding: SupportedEncoding) -> Option<Result<Box<dyn Read>>> {
-        let reader = match Self::resolve(path) {
+    /// Try to read a resolved path.
+    /// None should indicate that there is no file to read after attempting fallback paths.
+    fn open(
+        resolved: &FileServerPath,
+        encoding: SupportedEncoding,
+        assets: Option<&AssetManifest>,
+        base: &str,
+    ) -> Option<Result<Box<dyn Read>>> {
+        let reader = match resolved {
+            // strip front-matter, rewrite references to fingerprinted assets and root-relative
+            // URLs, and minify before compressing
+            FileServerPath::Physical(path) if Self::rewritten(path, assets) => {
+                Some(Self::read_to_string(path).and_then(|contents| {
+                    let contents = if file_headers::has_front_matter(path) {
+                        file_headers::strip_front_matter(&contents)?
+                    } else {
+                        &contents
+                    };
+                    let contents = match assets.filter(|a| a.rewrites(path)) {
+                        Some(assets) => assets.rewrite(contents),
+                        None => contents.to_string(),
+                    };
+                    let contents = subpath::rewrite(path, contents, base);
+                    let contents = minify::minify(path, contents);
+                    Ok(Box::new(Cursor::new(contents.into_bytes())) as Box<dyn Read>)
+                }))
+            }
             FileServerPath::Physical(path) => {
-                Some(Self::read(&path).map(|r| Box::new(r) as Box<dyn Read>))
+                // serve precompressed files as they are
+                if let Some(sibling) = precompressed::sibling(path, encoding) {
+                    return Some(Self::read(&sibling));
+                }
+                Some(Self::read(path))
             }
             FileServerPath::Embedded(resource) => {
-                Some(Ok(Box::new(Cursor::new(resource)) as Box<dyn Read>))
+                Some(Ok(Box::new(Cursor::new(*resource)) as Box<dyn Read>))
             }
-            FileServerPath::None => None,
+            FileServerPath::Redirect(_) | FileServerPath::Recased(_) | FileServerPath::None => None,
         }?;
+        Some(reader.map(|reader| Self::encode(reader, encoding)))
+    }
 
-        Some(reader.map(|reader| match encoding {
+    /// Compress the contents of a reader in the given encoding.
+    fn encode(reader: Box<dyn Read>, encoding: SupportedEncoding) -> Box<dyn Read> {
+        match encoding {
             SupportedEncoding::Brotli => Box::new(brotli::CompressorReader::new(
                 reader,
                 BUFFER_SIZE,
                 BROTLI_LEVEL,
                 20,
             )) as Box<dyn Read>,
-            SupportedEncoding::Deflate => {
-                Box::new(flate2::read::DeflateEncoder::new(reader, DEFLATE_LEVEL))
+            SupportedEncoding::Deflate => match flush::interval() {
+                Some(interval) => Box::new(FlushingEncoder::new(
+                    reader,
+                    flate2::write::DeflateEncoder::new(Vec::new(), DEFLATE_LEVEL),
+                    interval,
+                )) as Box<dyn Read>,
+                None => Box::new(flate2::read::DeflateEncoder::new(reader, DEFLATE_LEVEL)),
+            },
+            SupportedEncoding::Gzip => match flush::interval() {
+                Some(interval) => Box::new(FlushingEncoder::new(
+                    reader,
+                    flate2::write::GzEncoder::new(Vec::new(), DEFLATE_LEVEL),
+                    interval,
+                )) as Box<dyn Read>,
+                None => Box::new(flate2::read::GzEncoder::new(reader, DEFLATE_LEVEL)),
+            },
+            SupportedEncoding::None => reader,
+        }
+    }
+
+    /// Look up the physical path configured for a virtual request path, if any.
+    /// Aliases from the environment take precedence over the config file.
+    fn alias(config: &Config, req_path: &str) -> Option<String> {
+        settings::var(ALIASES_ENV)
+            .and_then(|aliases| {
+                aliases
+                    .split(',')
+                    .filter_map(|alias| alias.split_once('='))
+                    .find_map(|(from, to)| {
+                        same_path(from.trim(), req_path).then(|| to.trim().to_string())
+                    })
+            })
+            .or_else(|| config.alias(req_path).map(str::to_string))
+    }
+
+    /// Return the path of the content variant selected by the request header `name`,
+    /// if the request carries a valid variant and a file exists for it.
+    fn variant(req: &RequestContext, name: &HeaderName, path: &str) -> Option<String> {
+        let variant = str::from_utf8(req.header(name)?)
+            .ok()?
+            .trim()
+            .to_lowercase();
+        // only plain identifiers like country or language codes can select a variant
+        if variant.is_empty()
+            || !variant
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return None;
+        }
+        let variant_path = VariantMode::from_env().variant_path(path, &variant);
+        Self::exists(&variant_path).then_some(variant_path)
+    }
+
+    /// Return the subdirectory the request is scoped to by the root header, if one is configured
+    /// and set. Values that could escape the mounted directory are errors.
+    fn root(req: &RequestContext) -> Option<Result<&str, ()>> {
+        let name = settings::var(ROOT_HEADER_ENV)?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes()).ok()?;
+        let root = req.header(&name)?;
+        let Ok(root) = str::from_utf8(root).map(|r| r.trim().trim_matches('/')) else {
+            return Some(Err(()));
+        };
+        Some(if Self::plain_path(root) {
+            Ok(root)
+        } else {
+            Err(())
+        })
+    }
+
+    /// Check whether a relative path is made of plain segments only: not empty, not `.` or `..`,
+    /// and of ASCII letters, digits, `-`, `_` and `.`.
+    fn plain_path(path: &str) -> bool {
+        !path.is_empty()
+            && path.split('/').all(|segment| {
+                !segment.is_empty()
+                    && segment != "."
+                    && segment != ".."
+                    && segment
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+            })
+    }
+
+    /// Check whether a request path names its file one way only, having no `.`, `..` or empty
+    /// segments besides a leading and a trailing slash.
+    fn canonical(path: &str) -> bool {
+        let path = path.strip_prefix('/').unwrap_or(path);
+        let path = path.strip_suffix('/').unwrap_or(path);
+        path.is_empty()
+            || path
+                .split('/')
+                .all(|segment| !segment.is_empty() && segment != "." && segment != "..")
+    }
+
+    /// Check whether a path stays within the directory it is resolved in, having no `.` or `..`
+    /// segments.
+    fn stays_within(path: &str) -> bool {
+        !path
+            .split(['/', '\\'])
+            .any(|segment| segment == "." || segment == "..")
+    }
+
+    /// Return a path resolved within a root directory as clients request it, without the root.
+    fn strip_root(path: &str, root: &str) -> String {
+        let rest = path.trim_start_matches('/');
+        let leading = &path[..path.len() - rest.len()];
+        match rest
+            .strip_prefix(root)
+            .and_then(|rest| rest.strip_prefix('/'))
+        {
+            Some(unrooted) => format!("{leading}{unrooted}"),
+            None => path.to_string(),
+        }
+    }
+
+    /// Return the A/B experiment bucket (`a` or `b`) of the request, if an experiment is
+    /// configured, and whether the bucket was newly assigned.
+    fn experiment_bucket(req: &RequestContext) -> Option<(char, bool)> {
+        let split: u32 = settings::var(EXPERIMENT_SPLIT_ENV)?.trim().parse().ok()?;
+        match req.cookie(EXPERIMENT_COOKIE) {
+            Some("a") => Some(('a', false)),
+            Some("b") => Some(('b', false)),
+            _ => Some((if Self::roll(split, None) { 'b' } else { 'a' }, true)),
+        }
+    }
+
+    /// Decide whether the request is served from the canary root, if one is configured.
+    fn canary(req: &RequestContext) -> Option<Canary> {
+        let root = settings::var(CANARY_ROOT_ENV)?;
+        let root = root.trim().trim_matches('/').to_string();
+        let percent: u32 = settings::var(CANARY_PERCENT_ENV)
+            .and_then(|p| p.trim().parse().ok())
+            .unwrap_or(0);
+        let header = settings::var(CANARY_HEADER_ENV)
+            .and_then(|h| HeaderName::from_bytes(h.trim().as_bytes()).ok());
+        if let Some(header) = header {
+            let active = Self::roll(percent, Some(req.header(&header).unwrap_or_default()));
+            return Some(Canary {
+                root,
+                active,
+                vary: header,
+                cookie: None,
+            });
+        }
+        let (active, cookie) = match req.cookie(CANARY_COOKIE) {
+            Some("1") => (true, None),
+            Some("0") => (false, None),
+            _ => {
+                let active = Self::roll(percent, None);
+                let cookie = Self::sticky_cookie(CANARY_COOKIE, if active { "1" } else { "0" });
+                (active, Some(cookie))
+            }
+        };
+        Some(Canary {
+            root,
+            active,
+            vary: COOKIE,
+            cookie,
+        })
+    }
+
+    /// Return true for `percent` percent of rolls. Rolls are random, or derived from the hash of
+    /// `seed` so the same seed always gets the same result.
+    fn roll(percent: u32, seed: Option<&[u8]>) -> bool {
+        let mut roll = [0_u8; 4];
+        match seed {
+            Some(seed) => {
+                use sha2::Digest;
+                roll.copy_from_slice(&sha2::Sha256::digest(seed)[..4]);
             }
-            SupportedEncoding::Gzip => {
-                Box::new(flate2::read::GzEncoder::new(reader, DEFLATE_LEVEL))
+            None => {
+                if let Err(e) = getrandom::getrandom(&mut roll) {
+                    eprintln!("Error rolling for a client assignment: {e}");
+                }
             }
-            SupportedEncoding::None => reader,
-        }))
+        }
+        u32::from_le_bytes(roll) % 100 < percent
+    }
+
+    /// Build the `Set-Cookie` header remembering a client assignment.
+    fn sticky_cookie(name: &str, value: &str) -> (String, Vec<u8>) {
+        let cookie =
+            format!("{name}={value}; Path=/; Max-Age={STICKY_COOKIE_MAX_AGE}; SameSite=Lax");
+        (SET_COOKIE.as_str().to_string(), cookie.into_bytes())
+    }
+
+    /// Check whether the response for a path is a localized fallback or custom 404 document, which
+    /// depends on the language of the request.
+    fn localized(path: &str) -> bool {
+        let templated = [FALLBACK_PATH_ENV, CUSTOM_404_PATH_ENV]
+            .iter()
+            .any(|env| settings::var(env).is_some_and(|p| languages::is_template(&p)));
+        templated && !Self::exists(path)
+    }
+
+    /// Check whether a path can be served, either as a file or as a directory with an index.
+    fn exists(path: &str) -> bool {
+        let backend = backend::current();
+        let path = Path::new(path);
+        backend.is_file(path) || backend.is_file(&path.join(DIRECTORY_FALLBACK_PATH))
     }
 
     /// Resolve the request path to a file path.
     /// Returns a `FileServerPath` variant.
-    fn resolve(req_path: &str) -> FileServerPath {
+    /// Localized fallback and custom 404 paths are looked up in the given languages, in order.
+    fn resolve(req_path: &str, languages: &[String]) -> FileServerPath {
+        Self::resolve_traced(req_path, None, languages, &mut Trace::default())
+    }
+
+    /// Resolve the request path to a file path like `resolve`, recording the fallbacks applied.
+    fn resolve_traced(
+        req_path: &str,
+        root: Option<&str>,
+        languages: &[String],
+        trace: &mut Trace,
+    ) -> FileServerPath {
+        let backend = backend::current();
         // fallback to index.html if the path is empty
         let mut path = if req_path.is_empty() {
+            trace.step("index", Some(DIRECTORY_FALLBACK_PATH));
             PathBuf::from(DIRECTORY_FALLBACK_PATH)
         } else {
             PathBuf::from(req_path)
         };
 
-        // if the path is a directory, try to read the fallback file relative to the directory
-        if path.is_dir() {
+        // paths recently found missing aren't searched for again until they expire, which only
+        // pays off when searching for them is costly
+        let mode = CaseInsensitiveMode::from_env();
+        let costly = mode != CaseInsensitiveMode::Off || backend.costly_listing();
+        let requested = path.clone();
+        let remembered = costly && !backend.is_file(&path) && not_found_cache::contains(&requested);
+        if remembered {
+            trace.step("not-found-cache", path.to_str());
+        } else if backend.is_dir(&path) {
+            // if the path is a directory, try to read the fallback file relative to the directory
             path.push(DIRECTORY_FALLBACK_PATH);
+            trace.step("index", path.to_str());
+        }
+        let mut found = !remembered && backend.exists(&path);
+
+        // if the path doesn't exist, optionally look for a match that only differs in case
+        if !found && !remembered {
+            if let Some(found) = (mode != CaseInsensitiveMode::Off)
+                .then(|| Self::find_case_insensitive(backend.as_ref(), &path))
+                .flatten()
+            {
+                let found_path = found.to_string_lossy();
+                // the root isn't part of the URL clients request
+                let mut location = match root {
+                    Some(root) => Self::strip_root(&found_path, root),
+                    None => found_path.into_owned(),
+                };
+                if backend.is_dir(&found) && req_path.ends_with('/') {
+                    location.push('/');
+                }
+                trace.step("case-insensitive", Some(&location));
+                return match mode {
+                    CaseInsensitiveMode::Redirect => FileServerPath::Redirect(location),
+                    _ => FileServerPath::Recased(location),
+                };
+            }
+            found = backend.exists(&path);
+            if !found && costly && !path.is_favicon() {
+                not_found_cache::insert(&requested);
+            }
         }
 
         // if path doesn't exist and a favicon is requested, return with corresponding embedded resource
-        if !path.exists() && path.is_favicon() {
+        if !found && path.is_favicon() {
+            trace.step("embedded-favicon", path.to_str());
             return match path.extension() {
                 Some(os_string) => match os_string.to_str() {
                     Some("ico") => FileServerPath::Embedded(FALLBACK_FAVICON_ICO),
@@ -354,32 +1056,107 @@ impl FileServer {
             };
         }
         // if still haven't found a file, override with the user-configured fallback path
-        if !path.exists() {
-            if let Ok(fallback_path) = std::env::var(FALLBACK_PATH_ENV) {
-                path = PathBuf::from(fallback_path);
+        if !found {
+            if let Some(fallback_path) = Self::configured_file(FALLBACK_PATH_ENV, root, languages) {
+                path = fallback_path;
+                found = true;
+                trace.step("fallback", path.to_str());
             }
         }
 
-        if path.exists() {
+        if found {
             return FileServerPath::Physical(path);
         }
 
         // check if user configured a custom 404 path
         // if so, check if that path exists and return it instead of sending a plain 404
-        if let Ok(custom_404) = std::env::var(CUSTOM_404_PATH_ENV) {
-            path = PathBuf::from(custom_404);
+        match Self::configured_file(CUSTOM_404_PATH_ENV, root, languages) {
+            Some(custom_404) => {
+                trace.step("custom-404", custom_404.to_str());
+                FileServerPath::Physical(custom_404)
+            }
+            None => FileServerPath::None,
         }
+    }
 
-        if path.exists() {
-            FileServerPath::Physical(path)
+    /// Return the file configured in an environment variable, logging why it can't be used if it
+    /// isn't a file, e.g. because it points at a missing path or a directory.
+    /// Localized paths resolve to the file of the first language it exists for. Under a root
+    /// directory, the file is looked up within it, never outside.
+    fn configured_file(env: &str, root: Option<&str>, languages: &[String]) -> Option<PathBuf> {
+        let backend = backend::current();
+        let configured = settings::var(env)?;
+        let configured = match root {
+            Some(_) if !Self::stays_within(&configured) => {
+                eprintln!("{env} {configured} leaves the root directory, ignoring it");
+                return None;
+            }
+            Some(root) => VariantMode::Directory.variant_path(&configured, root),
+            None => configured,
+        };
+        if languages::is_template(&configured) {
+            let found = languages::candidates(&configured, languages)
+                .map(PathBuf::from)
+                .find(|path| backend.is_file(path));
+            if found.is_none() {
+                eprintln!(
+                    "{env} {configured} does not exist for any of {}, ignoring it",
+                    languages.join(", ")
+                );
+            }
+            return found;
+        }
+        let path = PathBuf::from(configured);
+        if backend.is_file(&path) {
+            return Some(path);
+        }
+        let problem = if backend.exists(&path) {
+            "is not a file"
         } else {
-            FileServerPath::None
+            "does not exist"
+        };
+        eprintln!("{env} {} {problem}, ignoring it", path.display());
+        None
+    }
+
+    /// Find an existing path that matches `path` when ignoring case, one component at a time.
+    fn find_case_insensitive(backend: &dyn Backend, path: &Path) -> Option<PathBuf> {
+        let mut found = PathBuf::new();
+        for component in path.components() {
+            let Component::Normal(name) = component else {
+                found.push(component);
+                continue;
+            };
+            let candidate = found.join(name);
+            if backend.exists(&candidate) {
+                found = candidate;
+                continue;
+            }
+            let name = name.to_str()?.to_lowercase();
+            let entry = backend
+                .list(&found)
+                .ok()?
+                .into_iter()
+                .find(|entry| entry.to_lowercase() == name)?;
+            found.push(entry);
         }
+        backend.exists(&found).then_some(found)
+    }
+
+    /// Open the file given its path from the storage backend.
+    fn read(path: &Path) -> Result<Box<dyn Read>> {
+        backend::current()
+            .open(path)
+            .with_context(|| anyhow!("cannot open {}", path.display()))
     }
 
-    /// Open the file given its path and return its content and content type header.
-    fn read(path: &PathBuf) -> Result<impl Read> {
-        File::open(path).with_context(|| anyhow!("cannot open {}", path.display()))
+    /// Read the whole file given its path from the storage backend as text.
+    fn read_to_string(path: &Path) -> Result<String> {
+        let mut contents = String::new();
+        Self::read(path)?
+            .read_to_string(&mut contents)
+            .with_context(|| anyhow!("cannot read {}", path.display()))?;
+        Ok(contents)
     }
 
     /// Return the media type of the file based on the path.
@@ -393,17 +1170,25 @@ impl FileServer {
         .map(|m| m.to_string())
     }
 
-    fn make_headers(path: &str, enc: SupportedEncoding, etag: &str) -> Vec<(String, Vec<u8>)> {
+    fn make_headers(
+        path: &str,
+        enc: SupportedEncoding,
+        etag: &str,
+        declared: Option<&FileHeaders>,
+    ) -> Vec<(String, Vec<u8>)> {
         let mut headers = Vec::new();
-        let cache_control = match std::env::var(CACHE_CONTROL_ENV) {
-            Ok(c) => c,
-            Err(_) => CACHE_CONTROL_DEFAULT_VALUE.to_string(),
-        };
+        let cache_control = declared
+            .and_then(|d| d.cache_control.clone())
+            .or_else(|| settings::var(CACHE_CONTROL_ENV))
+            .unwrap_or_else(|| CACHE_CONTROL_DEFAULT_VALUE.to_string());
         headers.push((
             CACHE_CONTROL.as_str().to_string(),
             cache_control.into_bytes(),
         ));
-        headers.push((ETAG.as_str().to_string(), etag.as_bytes().to_vec()));
+        headers.push((
+            ETAG.as_str().to_string(),
+            format!("\"{etag}\"").into_bytes(),
+        ));
 
         match enc {
             SupportedEncoding::Brotli => headers.push((
@@ -424,234 +1209,3719 @@ impl FileServer {
         if let Some(mime) = Self::mime(path) {
             headers.push((CONTENT_TYPE.as_str().to_string(), mime.into_bytes()));
         };
+        headers.extend(surrogate::headers(path));
+        headers.extend(disposition::headers(path));
+        for (name, value) in declared.iter().flat_map(|d| &d.headers) {
+            headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+            headers.push((name.to_ascii_lowercase(), value.as_bytes().to_vec()));
+        }
 
         headers
     }
 
-    #[allow(clippy::type_complexity)]
-    fn make_response(
-        path: &[u8],
-        enc: SupportedEncoding,
-        if_none_match: &[u8],
-    ) -> Result<(StatusCode, Vec<(String, Vec<u8>)>, Option<Box<dyn Read>>)> {
-        let path = str::from_utf8(path)?;
-        let reader = Self::resolve_and_read(path, enc).transpose()?;
-        let etag = Self::make_etag(reader)?;
-        let mut reader = Self::resolve_and_read(path, enc).transpose()?;
-        let mut headers = Self::make_headers(path, enc, &etag);
-
-        let status = if reader.is_some() {
-            if etag.as_bytes() == if_none_match {
-                reader = None;
-                StatusCode::NOT_MODIFIED
-            } else {
-                StatusCode::OK
+    /// Build the response to a request. Errors that decide the status of the response are
+    /// answered with it, and other errors are returned.
+    fn make_response(req: &RequestContext) -> Result<Response> {
+        Self::build_response(req).or_else(|e| match error::status(&e) {
+            Some(status) => {
+                eprintln!("Responding with {status}: {e:#}");
+                Ok(plain_response(status))
             }
-        } else {
-            reader = Some(Box::new(Cursor::new(b"Not Found")));
-            headers = Vec::new();
-            StatusCode::NOT_FOUND
-        };
-
-        Ok((status, headers, reader))
+            None => Err(e),
+        })
     }
 
-    fn make_etag(body: Option<Box<dyn Read>>) -> Result<String> {
-        use sha2::Digest;
-        let mut hasher = sha2::Sha256::new();
-        if let Some(mut reader) = body {
-            let mut buffer = vec![0_u8; BUFFER_SIZE];
-            loop {
-                match reader.read(&mut buffer)? {
-                    0 => break,
-                    count => {
-                        hasher.update(&buffer[..count]);
+    /// Build the response to a request in stages, each of which can answer the request itself:
+    /// checking the request, routing it to a path, resolving the file, choosing its
+    /// representation, adding the response headers, and evaluating the request's conditions.
+    fn build_response(req: &RequestContext) -> Result<Response> {
+        let mut trace = Trace::from_env();
+        macro_rules! stage {
+            ($stage:expr) => {
+                match $stage? {
+                    ControlFlow::Continue(next) => next,
+                    ControlFlow::Break(mut response) => {
+                        response.1.extend(trace.header());
+                        return Ok(response);
                     }
                 }
-            }
+            };
         }
-        Ok(hex::encode(hasher.finalize()))
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use http::header::ACCEPT_ENCODING;
-    use scopeguard::defer;
-    use std::{fs, path::Path, sync::Mutex};
-
-    static TEST_ENV_MUTEX: Mutex<()> = Mutex::new(());
 
-    #[test]
-    fn test_best_encoding_none() {
-        let enc = SupportedEncoding::best_encoding(&[]);
-        assert_eq!(enc, SupportedEncoding::None);
-    }
+        let path = stage!(Self::check_request(req));
+        let route = stage!(Self::route(req, path, &mut trace));
+        let mut timings = Timings::from_env(req);
+        let file = stage!(Self::resolve_file(req, &route, &mut timings, &mut trace));
+        let enc = Self::encoding(req, &file.resolved, route.assets.as_ref());
+        let representation = stage!(Self::represent(req, &route, &file, enc, &mut timings));
+        let cache_status = representation.cache_status;
+        let headers = Self::response_headers(req, &route, &file, enc, &representation)?;
+        let (status, mut headers, mut reader) =
+            Self::evaluate_conditions(req, &route, &file, enc, representation, headers)?;
 
-    #[test]
-    fn test_best_encoding_with_unknown() {
-        let enc = SupportedEncoding::best_encoding(&[(
-            ACCEPT_ENCODING.to_string(),
-            b"some-weird-encoding".to_vec(),
-        )]);
-        assert_eq!(enc, SupportedEncoding::None);
-    }
+        timings.log(&route.path);
+        headers.extend(timings.header());
+        headers.extend(trace.header());
+        if status != StatusCode::NOT_FOUND {
+            headers.extend(cache_status.into_iter().flat_map(CacheStatus::headers));
+        }
 
-    #[test]
-    fn test_best_encoding_with_weights() {
-        let enc = SupportedEncoding::best_encoding(&[(
-            ACCEPT_ENCODING.to_string(),
-            b"gzip;br;q=0.1".to_vec(),
-        )]);
-        assert_eq!(enc, SupportedEncoding::Gzip);
-    }
+        // requests of the link checker aren't page views or downloads
+        if !req.internal {
+            // views are counted by the file served, so fallback documents are counted once
+            if let FileServerPath::Physical(served) = &file.resolved {
+                if analytics::enabled() && analytics::is_page_view(req, status, &headers) {
+                    let served = served.to_string_lossy();
+                    let served = format!("/{}", served.trim_start_matches('/'));
+                    if let Err(e) = analytics::record(req, &served) {
+                        eprintln!("Error recording page view: {e}");
+                    }
+                }
+            }
+            if downloads::is_download(req, status) && downloads::counter_enabled() {
+                if let Err(e) = downloads::count_download(path) {
+                    eprintln!("Error counting download: {e}");
+                }
+            }
+        }
 
-    #[test]
-    fn test_best_encoding_with_multiple_headers() {
-        let enc = SupportedEncoding::best_encoding(&[
-            (ACCEPT_ENCODING.to_string(), b"gzip".to_vec()),
-            (ACCEPT_ENCODING.to_string(), b"br".to_vec()),
-        ]);
-        assert_eq!(enc, SupportedEncoding::Brotli);
-    }
+        // hold on to the download slot until the body has been sent
+        if let Some(slot) = file.slot {
+            reader = reader.map(|inner| Box::new(SlotReader::new(inner, slot)) as Box<dyn Read>);
+        }
 
-    #[test]
-    fn test_best_encoding_with_gzip() {
-        let enc =
-            SupportedEncoding::best_encoding(&[(ACCEPT_ENCODING.to_string(), b"gzip".to_vec())]);
-        assert_eq!(enc, SupportedEncoding::Gzip);
+        Ok((status, headers, reader))
     }
 
-    #[test]
-    fn test_best_encoding_with_deflate() {
-        let enc =
-            SupportedEncoding::best_encoding(&[(ACCEPT_ENCODING.to_string(), b"deflate".to_vec())]);
-        assert_eq!(enc, SupportedEncoding::Deflate);
+    /// Check that the request can be served, and answer the requests that aren't for a file:
+    /// disallowed methods, mock APIs, `OPTIONS`, logins, admin routes and `index.html` requests.
+    /// Returns the request path otherwise.
+    fn check_request(req: &RequestContext) -> Result<ControlFlow<Response, &str>> {
+        if let Some(response) = request_limits::check(&req.headers) {
+            return Ok(ControlFlow::Break(response));
+        }
+        // reject paths that can never map to a file instead of failing with an internal error,
+        // and paths naming a file in more than one way, which rules for paths wouldn't match
+        let path = match str::from_utf8(&req.path) {
+            Ok(path) if !path.chars().any(char::is_control) && Self::canonical(path) => path,
+            _ => {
+                let path = String::from_utf8_lossy(&req.path);
+                return Err(FileServerError::BadRequest(format!("invalid path {path:?}")).into());
+            }
+        };
+        // honeypots are reported even if they're also blocked as scanner paths
+        if let Some(response) = honeypot::check(req, path) {
+            return Ok(ControlFlow::Break(response));
+        }
+        // scanners probing for well-known paths get no fallback document
+        if let Some(response) = scanners::check(req, path) {
+            return Ok(ControlFlow::Break(response));
+        }
+        // methods configured for the path apply to every response, except the admin routes' own
+        let allowed = if path.starts_with(admin::ADMIN_PREFIX) {
+            None
+        } else {
+            Config::load()?.allowed_methods(path)
+        };
+        if let Some(allowed) = &allowed {
+            let listed = allowed.split(", ").any(|m| m == req.method.as_str());
+            if !listed {
+                let (status, mut headers, body) = plain_response(StatusCode::METHOD_NOT_ALLOWED);
+                headers.push((ALLOW.as_str().to_string(), allowed.as_bytes().to_vec()));
+                return Ok(ControlFlow::Break((status, headers, body)));
+            }
+        }
+        // fixtures may answer any method, including OPTIONS
+        if let Some(response) = mock_api::handle(req, path) {
+            return response.map(ControlFlow::Break);
+        }
+        // answer OPTIONS and TRACE requests instead of treating them as file requests
+        if req.method == http::Method::OPTIONS || req.method == http::Method::TRACE {
+            return Ok(ControlFlow::Break(Self::allow(req, allowed.as_deref())));
+        }
+        // the login callback is under the admin prefix, but needs no token
+        if let Some(response) = oidc::handle(req, path) {
+            return response.map(ControlFlow::Break);
+        }
+        // the version route is under the admin prefix, but may be served without a token
+        if let Some(response) = version::handle(path) {
+            return response.map(ControlFlow::Break);
+        }
+        if let Some(response) = session_gate::handle(req, path) {
+            return response.map(ControlFlow::Break);
+        }
+        if let Some(response) = content_warning::handle(req, path) {
+            return response.map(ControlFlow::Break);
+        }
+        if let Some(response) = drafts::handle(req, path) {
+            return Ok(ControlFlow::Break(response));
+        }
+        if let Some(response) = admin::handle(req, path) {
+            return response.map(ControlFlow::Break);
+        }
+        // the admin routes stay available, so the totals can be checked
+        if let Some(response) = quota::check(req)? {
+            return Ok(ControlFlow::Break(response));
+        }
+        inventory::run();
+        if let Some(response) = self_check::run() {
+            return Ok(ControlFlow::Break(response));
+        }
+        // the directory is the canonical URL of its index
+        if let Some(dir) = path
+            .strip_suffix(DIRECTORY_FALLBACK_PATH)
+            .filter(|dir| dir.is_empty() || dir.ends_with('/'))
+        {
+            match IndexHtmlMode::from_env() {
+                IndexHtmlMode::Serve => {}
+                IndexHtmlMode::Redirect => {
+                    let mut location = if dir.is_empty() { "/" } else { dir }.to_string();
+                    if !req.query.is_empty() {
+                        location = format!("{location}?{}", req.query);
+                    }
+                    return Ok(ControlFlow::Break(Self::redirect(
+                        req,
+                        StatusCode::MOVED_PERMANENTLY,
+                        &location,
+                    )));
+                }
+                IndexHtmlMode::NotFound => {
+                    return Ok(ControlFlow::Break(plain_response(StatusCode::NOT_FOUND)))
+                }
+            }
+        }
+        Ok(ControlFlow::Continue(path))
     }
 
-    #[test]
-    fn test_best_encoding_with_br() {
-        let enc =
-            SupportedEncoding::best_encoding(&[(ACCEPT_ENCODING.to_string(), b"gzip,br".to_vec())]);
+    /// Apply access rules, then map the request path to the path of the file to serve through
+    /// rewrites, redirects, aliases, fingerprints, mounts, roots, releases, rollouts, variants
+    /// and content negotiation, fetching files missing locally from the origin.
+    fn route(
+        req: &RequestContext,
+        path: &str,
+        trace: &mut Trace,
+    ) -> Result<ControlFlow<Response, Route>> {
+        let config = Config::load()?;
+        // invalid names are reported by the configuration check
+        let mut vary: Vec<_> = config
+            .vary(path)
+            .into_iter()
+            .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+            .collect();
+        if campaigns::varies_on_referer(&config.campaigns, path) {
+            vary.push(REFERER);
+        }
+        let draft = drafts::applies(path);
+        if let Some(response) = access::check(&config.access, req, path) {
+            return Ok(ControlFlow::Break(response));
+        }
+        if let Some(response) = campaigns::check(&config.campaigns, req, path) {
+            return Ok(ControlFlow::Break(response));
+        }
+        let embargo = embargo::check(&config.embargoes, path, etag_cache::now() as i64);
+        let teaser = match embargo {
+            Some(embargo::Embargo::Teaser(teaser)) => Some(teaser),
+            Some(embargo::Embargo::NotFound) => {
+                let (status, mut headers, body) = plain_response(StatusCode::NOT_FOUND);
+                headers.push((CACHE_CONTROL.as_str().to_string(), b"no-cache".to_vec()));
+                return Ok(ControlFlow::Break((status, headers, body)));
+            }
+            None => None,
+        };
+        trace.step("embargo", teaser);
+        let path = teaser.unwrap_or(path);
+        let status_override = config.status(path)?;
+        let user_agent = req
+            .header(&USER_AGENT)
+            .and_then(|ua| str::from_utf8(ua).ok())
+            .unwrap_or_default();
+        let rewrite = match config.user_agent_action(user_agent)? {
+            // don't redirect requests for the redirect target itself
+            Some(Action::Redirect(location)) if !same_path(location, path) => {
+                trace.step("user-agent", Some(location));
+                return Ok(ControlFlow::Break(Self::redirect(
+                    req,
+                    StatusCode::FOUND,
+                    location,
+                )));
+            }
+            Some(Action::Rewrite(rewrite)) => Some(rewrite.as_str()),
+            _ => None,
+        };
+        trace.step("user-agent", rewrite);
+        let path = rewrite.unwrap_or(path);
+        // crawlers get the pre-rendered page of client-rendered apps instead of their shell
+        let prerendered = prerender::page(path);
+        let path = match prerendered.as_deref() {
+            Some(page) if prerender::is_crawler(req) => {
+                trace.step("prerender", Some(page));
+                page
+            }
+            _ => path,
+        };
+        let table_rewrite = match redirect_table::lookup(path) {
+            Ok(Some(redirect_table::Entry::Redirect(status, location))) => {
+                trace.step("redirect-table", Some(&location));
+                return Ok(ControlFlow::Break(Self::redirect(req, status, &location)));
+            }
+            Ok(Some(redirect_table::Entry::Rewrite(rewrite))) => Some(rewrite),
+            Ok(None) => None,
+            Err(e) if e.is::<redirect_table::LoopError>() => {
+                eprintln!("Error resolving {path}: {e}");
+                return Ok(ControlFlow::Break(plain_response(
+                    StatusCode::LOOP_DETECTED,
+                )));
+            }
+            Err(e) => return Err(e),
+        };
+        trace.step("redirect-table", table_rewrite.as_deref());
+        let path = table_rewrite.as_deref().unwrap_or(path);
+        let alias = Self::alias(&config, path);
+        trace.step("alias", alias.as_deref());
+        let path = alias.as_deref().unwrap_or(path);
+        let assets = AssetManifest::from_env()?;
+        let immutable = assets.as_ref().is_some_and(|a| a.is_hashed(path));
+        let hashed = assets.as_ref().and_then(|a| a.hashed(path));
+        trace.step("asset-manifest", hashed.as_deref());
+        if let Some(hashed) = hashed.as_deref() {
+            if assets.as_ref().is_some_and(|a| a.redirect) {
+                return Ok(ControlFlow::Break(Self::redirect(
+                    req,
+                    StatusCode::FOUND,
+                    hashed,
+                )));
+            }
+        }
+        let path = hashed.as_deref().unwrap_or(path);
+        // content-addressed paths name a file of the ETag manifest, which no other rule applies to
+        let addressed = match content_addressed::digest(path) {
+            Some(digest) => match content_addressed::file(&digest)? {
+                Some(file) => Some(file),
+                None => {
+                    let problem = format!("no file has the digest {digest}");
+                    return Err(FileServerError::NotFound(problem).into());
+                }
+            },
+            None => None,
+        };
+        let immutable = immutable || addressed.is_some();
+        trace.step("content-addressed", addressed.as_deref());
+        let path = addressed.as_deref().unwrap_or(path);
+        // once mounts are declared, only paths under a mount are served
+        let mount = config.mount(path).filter(|_| addressed.is_none());
+        let mounted = match mount {
+            Some((mount, _))
+                if mount
+                    .token
+                    .as_ref()
+                    .is_some_and(|t| !admin::is_authorized(req, t)) =>
+            {
+                return Ok(ControlFlow::Break(admin::unauthorized()));
+            }
+            Some((mount, rest)) => match mount.path(rest) {
+                Some(mounted) if path.starts_with('/') => Some(format!("/{mounted}")),
+                Some(mounted) => Some(mounted),
+                None => {
+                    let problem = format!("{path} leaves the mount {}", mount.prefix);
+                    return Err(FileServerError::BadRequest(problem).into());
+                }
+            },
+            None if !config.mounts.is_empty() && addressed.is_none() => {
+                return Ok(ControlFlow::Break(plain_response(StatusCode::NOT_FOUND)))
+            }
+            None => None,
+        };
+        trace.step("mount", mounted.as_deref());
+        let path = mounted.as_deref().unwrap_or(path);
+        let root_dir = match Self::root(req).filter(|_| addressed.is_none()) {
+            Some(Ok(root)) => Some(root.to_string()),
+            Some(Err(())) => {
+                let problem = "the root header names an invalid directory".to_string();
+                return Err(FileServerError::BadRequest(problem).into());
+            }
+            // sites of wildcard subdomains are isolated like those of the root header
+            None => match subdomains::root(req).filter(|_| addressed.is_none()) {
+                Some(Ok(root)) => Some(root),
+                Some(Err(problem)) => return Err(FileServerError::NotFound(problem).into()),
+                None => None,
+            },
+        };
+        // rewrites and aliases mustn't reach the files of another root
+        if root_dir.is_some() && !Self::stays_within(path) {
+            let problem = format!("{path} leaves the root directory");
+            return Err(FileServerError::BadRequest(problem).into());
+        }
+        let root = root_dir
+            .as_deref()
+            .map(|root| VariantMode::Directory.variant_path(path, root));
+        trace.step("root", root.as_deref());
+        let path = root.as_deref().unwrap_or(path);
+        // mounts and content-addressed paths name directories outside of the releases
+        let release = match (mount, &addressed) {
+            (None, None) => releases::select(req)?,
+            _ => None,
+        };
+        let released = release
+            .as_ref()
+            .map(|release| VariantMode::Directory.variant_path(path, &release.dir));
+        trace.step("release", released.as_deref());
+        let path = released.as_deref().unwrap_or(path);
+        let canary = Self::canary(req).filter(|_| addressed.is_none());
+        let canary_path = canary.as_ref().filter(|c| c.active).and_then(|c| {
+            let candidate = VariantMode::Directory.variant_path(path, &c.root);
+            Self::exists(&candidate).then_some(candidate)
+        });
+        trace.step("canary", canary_path.as_deref());
+        let path = canary_path.as_deref().unwrap_or(path);
+        let experiment = Self::experiment_bucket(req).filter(|_| addressed.is_none());
+        let experiment_path = experiment.as_ref().and_then(|(bucket, _)| {
+            let root = format!("variant-{bucket}");
+            let candidate = VariantMode::Directory.variant_path(path, &root);
+            Self::exists(&candidate).then_some(candidate)
+        });
+        trace.step("experiment", experiment_path.as_deref());
+        let path = experiment_path.as_deref().unwrap_or(path);
+        let variant_header = settings::var(VARIANT_HEADER_ENV)
+            .and_then(|h| HeaderName::from_bytes(h.trim().as_bytes()).ok())
+            .filter(|_| addressed.is_none());
+        let variant = variant_header
+            .as_ref()
+            .and_then(|name| Self::variant(req, name, path));
+        trace.step("variant", variant.as_deref());
+        let path = variant.as_deref().unwrap_or(path);
+        let negotiated = match negotiation::negotiate(req, path) {
+            Some(Negotiation::Chosen(file)) => Some(file),
+            Some(Negotiation::NotAcceptable) => {
+                let (status, mut headers, body) = plain_response(StatusCode::NOT_ACCEPTABLE);
+                headers.push((
+                    VARY.as_str().to_string(),
+                    ACCEPT.as_str().as_bytes().to_vec(),
+                ));
+                return Ok(ControlFlow::Break((status, headers, body)));
+            }
+            None => None,
+        };
+        trace.step("negotiation", negotiated.as_deref());
+        let path = negotiated.as_deref().unwrap_or(path);
+        // files that don't exist locally may be served by the origin
+        if origin::enabled() && !Self::exists(path) {
+            match origin::fetch(req, path) {
+                Ok(Some(response)) => return Ok(ControlFlow::Break(response)),
+                Ok(None) => {}
+                Err(e) => {
+                    eprintln!("Error fetching {path} from the origin: {e:#}");
+                    return Ok(ControlFlow::Break(origin::unavailable()));
+                }
+            }
+        }
+        Ok(ControlFlow::Continue(Route {
+            path: path.to_string(),
+            assets,
+            status_override,
+            immutable,
+            mount_cache_control: mount.and_then(|(mount, _)| mount.cache_control.clone()),
+            user_agent_rules: !config.user_agent.is_empty(),
+            prerendered: prerendered.is_some(),
+            release,
+            canary,
+            experiment,
+            variant_header,
+            negotiated: negotiated.is_some(),
+            vary,
+            draft,
+            root: root_dir,
+            embargoed: teaser.is_some(),
+        }))
+    }
+
+    /// Resolve the routed path to a file, answering redirects, sidecar and metadata requests,
+    /// and requests for large downloads while the download limit is reached.
+    fn resolve_file(
+        req: &RequestContext,
+        route: &Route,
+        timings: &mut Timings,
+        trace: &mut Trace,
+    ) -> Result<ControlFlow<Response, ResolvedFile>> {
+        let path = route.path.as_str();
+        // only localized documents depend on the language, and vary on where it comes from
+        let languages = if Self::localized(path) {
+            languages::preferred(req)
+        } else {
+            vec![languages::default_language()]
+        };
+        let root = route.root.as_deref();
+        let resolved = timings.measure("resolve", || {
+            Self::resolve_traced(path, root, &languages, trace)
+        });
+        if let FileServerPath::Redirect(location) = resolved {
+            return Ok(ControlFlow::Break(Self::redirect(
+                req,
+                StatusCode::MOVED_PERMANENTLY,
+                &location,
+            )));
+        }
+        // the request is handled again for the casing of the file, so the rules for its path
+        // apply whatever the casing requested
+        if let FileServerPath::Recased(location) = resolved {
+            let recased = RequestContext {
+                path: location.into_bytes(),
+                ..req.clone()
+            };
+            return Self::make_response(&recased).map(ControlFlow::Break);
+        }
+        let declared = match &resolved {
+            FileServerPath::Physical(file) if file_headers::is_sidecar(file) => {
+                return Ok(ControlFlow::Break(plain_response(StatusCode::NOT_FOUND)));
+            }
+            FileServerPath::Physical(file) => file_headers::lookup(file)?,
+            _ => None,
+        };
+        if metadata::requested(req) {
+            let url = RequestUrl::of(req);
+            return match metadata::describe(path, &resolved, route.assets.as_ref(), url.base())? {
+                Some(metadata) => admin::json_response(&metadata).map(ControlFlow::Break),
+                None => Ok(ControlFlow::Break(plain_response(StatusCode::NOT_FOUND))),
+            };
+        }
+        if let FileServerPath::Physical(file) = &resolved {
+            if let Some(response) = size_limits::check(backend::current().size(file)?) {
+                eprintln!("Refusing to serve {path}: the file is larger than the maximum size");
+                return Ok(ControlFlow::Break(response));
+            }
+        }
+        let slot = match (DownloadLimiter::from_env(), &resolved) {
+            (Some(limiter), FileServerPath::Physical(file))
+                if limiter.applies_to(backend::current().size(file)?) =>
+            {
+                match limiter.acquire()? {
+                    Some(slot) => Some(slot),
+                    None => return Ok(ControlFlow::Break(limiter.unavailable())),
+                }
+            }
+            _ => None,
+        };
+        Ok(ControlFlow::Continue(ResolvedFile {
+            resolved,
+            declared,
+            slot,
+        }))
+    }
+
+    /// Choose the encoding of the response. Ranges, resized images, images stripped of their
+    /// metadata and reproducible responses are only served unencoded, and files too large to be
+    /// compressed on the fly only if they were precompressed.
+    fn encoding(
+        req: &RequestContext,
+        resolved: &FileServerPath,
+        assets: Option<&AssetManifest>,
+    ) -> SupportedEncoding {
+        let identity_only = image_resize::requested(req)
+            || reproducible::enabled()
+            || matches!(resolved, FileServerPath::Physical(file) if exif::applies(file));
+        if identity_only {
+            return SupportedEncoding::None;
+        }
+        // ranges are only served from stored bytes, never from compression on the fly
+        let ranged = req.header(&RANGE).is_some();
+        let enc = SupportedEncoding::best_encoding(&req.headers);
+        let FileServerPath::Physical(file) = resolved else {
+            return if ranged { SupportedEncoding::None } else { enc };
+        };
+        if enc == SupportedEncoding::None {
+            return enc;
+        }
+        let sibling = precompressed::sibling(file, enc).filter(|_| !Self::rewritten(file, assets));
+        if ranged && sibling.is_none() {
+            return SupportedEncoding::None;
+        }
+        match backend::current().size(file) {
+            // encoding empty files would only add bytes
+            Ok(0) => SupportedEncoding::None,
+            Ok(len) if !size_limits::compresses(len) && sibling.is_none() => {
+                SupportedEncoding::None
+            }
+            _ => enc,
+        }
+    }
+
+    /// Compute the ETag of the file in the chosen encoding and open it, resizing or stripping
+    /// images if needed. Reproducible responses whose contents don't match are refused.
+    fn represent(
+        req: &RequestContext,
+        route: &Route,
+        file: &ResolvedFile,
+        enc: SupportedEncoding,
+        timings: &mut Timings,
+    ) -> Result<ControlFlow<Response, Representation>> {
+        let resolved = &file.resolved;
+        let assets = route.assets.as_ref();
+        let url = RequestUrl::of(req);
+        let (etag, cache_status, body) = timings.measure("etag", || {
+            Self::etag_and_body(resolved, enc, assets, url.base())
+        })?;
+        if cache_status == Some(CacheStatus::Changed) && purge_webhook::enabled() {
+            purge_webhook::notify(&purge_webhook::Purge::changed(req));
+        }
+        // the manifest ETag isn't trusted to match the served bytes, which are hashed every time
+        if let (FileServerPath::Physical(file), true) = (resolved, reproducible::enabled()) {
+            let digest = Self::make_etag(Self::open(resolved, enc, None, "").transpose()?)?;
+            if let Err(problem) = reproducible::verify(file, &digest) {
+                eprintln!("Refusing to serve {}: {problem}", route.path);
+                return Ok(ControlFlow::Break(plain_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )));
+            }
+        }
+        let nonce = match resolved {
+            FileServerPath::Physical(file) if csp::uses_nonce(file) => Some(csp::nonce()?),
+            _ => None,
+        };
+        let reader = match &nonce {
+            // the nonce goes into the document before it is compressed
+            Some(nonce) => {
+                let mut html = String::new();
+                if let Some(mut reader) =
+                    Self::open(resolved, SupportedEncoding::None, assets, url.base()).transpose()?
+                {
+                    reader.read_to_string(&mut html)?;
+                }
+                let html = csp::inject(&html, nonce);
+                Some(Self::encode(Box::new(Cursor::new(html.into_bytes())), enc))
+            }
+            None => match body {
+                Some(body) => Some(Box::new(Cursor::new(body)) as Box<dyn Read>),
+                None => Self::open(resolved, enc, assets, url.base()).transpose()?,
+            },
+        };
+        let transformed = match resolved {
+            FileServerPath::Physical(file)
+                if image_resize::requested(req) && image_resize::is_image(file) =>
+            {
+                match image_resize::params(req) {
+                    Ok(params) => Some(
+                        timings.measure("resize", || image_resize::resize(file, &etag, &params))?,
+                    ),
+                    Err(problem) => {
+                        let body = Box::new(Cursor::new(problem.into_bytes()));
+                        return Ok(ControlFlow::Break((
+                            StatusCode::BAD_REQUEST,
+                            Vec::new(),
+                            Some(body),
+                        )));
+                    }
+                }
+            }
+            FileServerPath::Physical(file) if exif::applies(file) => {
+                Some(timings.measure("strip", || exif::strip_file(file, &etag))?)
+            }
+            _ => None,
+        };
+        Ok(ControlFlow::Continue(match transformed {
+            Some((etag, image)) => Representation {
+                etag,
+                cache_status,
+                reader: Some(Box::new(Cursor::new(image))),
+                transformed: true,
+                nonce: None,
+            },
+            None => Representation {
+                etag,
+                cache_status,
+                reader,
+                transformed: false,
+                nonce,
+            },
+        }))
+    }
+
+    /// Build the headers of a successful response: the caching policy, the content location and
+    /// the request headers the response varies on.
+    fn response_headers(
+        req: &RequestContext,
+        route: &Route,
+        file: &ResolvedFile,
+        enc: SupportedEncoding,
+        representation: &Representation,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        let path = route.path.as_str();
+        let resolved = &file.resolved;
+        let declared = file.declared.as_ref();
+        let mut headers = Self::make_headers(path, enc, &representation.etag, declared);
+        // the cache policy declared for a file takes precedence over rules for many files
+        let pinned = declared.is_some_and(|d| d.cache_control.is_some());
+        if let Some(cache_control) = route.mount_cache_control.as_ref().filter(|_| !pinned) {
+            headers.retain(|(k, _)| k != CACHE_CONTROL.as_str());
+            headers.push((
+                CACHE_CONTROL.as_str().to_string(),
+                cache_control.as_bytes().to_vec(),
+            ));
+        }
+        let fingerprinted = matches!(resolved, FileServerPath::Physical(file) if asset_manifest::is_fingerprinted(file));
+        if (route.immutable || fingerprinted) && !pinned {
+            headers.retain(|(k, _)| k != CACHE_CONTROL.as_str());
+            headers.push((
+                CACHE_CONTROL.as_str().to_string(),
+                IMMUTABLE_CACHE_CONTROL.as_bytes().to_vec(),
+            ));
+        }
+        // documents are revalidated sooner while a release is deployed
+        let config = Config::load()?;
+        if let Some(max_age) =
+            deploy_window::max_age(&config.deploy_windows, etag_cache::now() as i64)
+        {
+            let is_html = headers
+                .iter()
+                .any(|(k, v)| k == CONTENT_TYPE.as_str() && v.starts_with(b"text/html"));
+            for (_, value) in headers
+                .iter_mut()
+                .filter(|(k, _)| is_html && k == CACHE_CONTROL.as_str())
+            {
+                let cache_control = String::from_utf8_lossy(value);
+                *value = deploy_window::tighten(&cache_control, max_age).into_bytes();
+            }
+        }
+        // identical files point caches at one copy, unless their contents are rewritten
+        if let FileServerPath::Physical(file) = resolved {
+            if !Self::rewritten(file, route.assets.as_ref()) && !representation.transformed {
+                if let Some(canonical) = content_addressed::canonical(file)? {
+                    let location = RequestUrl::of(req).path(&canonical);
+                    headers.push((CONTENT_LOCATION.as_str().to_string(), location.into_bytes()));
+                }
+            }
+        }
+        // the encoding depends on Accept-Encoding, even for ranges, unless it is always identity
+        let identity_only = image_resize::requested(req)
+            || reproducible::enabled()
+            || matches!(resolved, FileServerPath::Physical(file) if exif::applies(file));
+        if !identity_only {
+            headers.push((
+                VARY.as_str().to_string(),
+                ACCEPT_ENCODING.as_str().as_bytes().to_vec(),
+            ));
+        }
+        if let Some(name) = &route.variant_header {
+            headers.push((VARY.as_str().to_string(), name.as_str().as_bytes().to_vec()));
+        }
+        if route.negotiated {
+            headers.push((
+                VARY.as_str().to_string(),
+                ACCEPT.as_str().as_bytes().to_vec(),
+            ));
+        }
+        let localized = Self::localized(path);
+        if localized {
+            headers.push((
+                VARY.as_str().to_string(),
+                ACCEPT_LANGUAGE.as_str().as_bytes().to_vec(),
+            ));
+            if languages::cookie_name().is_some() {
+                headers.push((
+                    VARY.as_str().to_string(),
+                    COOKIE.as_str().as_bytes().to_vec(),
+                ));
+            }
+        }
+        // clients stay with the language they switched to, or were first served, and shared
+        // caches must not hand the cookie to other clients
+        if localized {
+            let languages = languages::preferred(req);
+            let served = match resolved {
+                FileServerPath::Physical(file) => languages::served(file, &languages),
+                _ => None,
+            };
+            if let Some((name, language)) = languages::cookie(req, served) {
+                headers.push(Self::sticky_cookie(&name, &language));
+                headers.retain(|(k, _)| k != CACHE_CONTROL.as_str());
+                headers.push((
+                    CACHE_CONTROL.as_str().to_string(),
+                    b"private, no-cache".to_vec(),
+                ));
+            }
+        }
+        if matches!(resolved, FileServerPath::Physical(file) if subpath::varies(file)) {
+            headers.push((
+                VARY.as_str().to_string(),
+                request_url::FORWARDED_PREFIX_HEADER.as_bytes().to_vec(),
+            ));
+        }
+        if route.user_agent_rules || route.prerendered {
+            headers.push((
+                VARY.as_str().to_string(),
+                USER_AGENT.as_str().as_bytes().to_vec(),
+            ));
+        }
+        if let Some(canary) = &route.canary {
+            headers.push((
+                VARY.as_str().to_string(),
+                canary.vary.as_str().as_bytes().to_vec(),
+            ));
+            headers.extend(canary.cookie.clone());
+        }
+        if content_warning::applies(path) || route.draft {
+            headers.push((
+                VARY.as_str().to_string(),
+                COOKIE.as_str().as_bytes().to_vec(),
+            ));
+        }
+        for name in route.release.iter().flat_map(|release| &release.vary) {
+            headers.push((VARY.as_str().to_string(), name.as_str().as_bytes().to_vec()));
+        }
+        for name in &route.vary {
+            let listed = headers.iter().any(|(k, v)| {
+                k == VARY.as_str() && v.eq_ignore_ascii_case(name.as_str().as_bytes())
+            });
+            if !listed {
+                headers.push((VARY.as_str().to_string(), name.as_str().as_bytes().to_vec()));
+            }
+        }
+        if let Some((bucket, is_new)) = route.experiment {
+            headers.push((
+                VARY.as_str().to_string(),
+                COOKIE.as_str().as_bytes().to_vec(),
+            ));
+            if is_new {
+                headers.push(Self::sticky_cookie(EXPERIMENT_COOKIE, &bucket.to_string()));
+            }
+        }
+        if let FileServerPath::Physical(file) = resolved {
+            headers.extend(csp::header(file, representation.nonce.as_deref()));
+        }
+        // every response has its own nonce, so it can't be stored or revalidated
+        if representation.nonce.is_some() {
+            headers.retain(|(k, _)| k != CACHE_CONTROL.as_str() && k != ETAG.as_str());
+            headers.push((CACHE_CONTROL.as_str().to_string(), b"no-store".to_vec()));
+        }
+        // teasers are replaced by the content as soon as it is released
+        if route.embargoed {
+            headers.retain(|(k, _)| k != CACHE_CONTROL.as_str());
+            headers.push((CACHE_CONTROL.as_str().to_string(), b"no-cache".to_vec()));
+        }
+        // drafts are only for the holders of a preview token, and aren't published yet
+        if route.draft {
+            headers.retain(|(k, _)| k != CACHE_CONTROL.as_str());
+            headers.push((
+                CACHE_CONTROL.as_str().to_string(),
+                b"private, no-store".to_vec(),
+            ));
+            headers.push(("x-robots-tag".to_string(), b"noindex".to_vec()));
+        }
+        headers.extend(legacy_cache::pragma(&headers));
+        Ok(headers)
+    }
+
+    /// Decide the status of the response from the configured status, the preconditions and the
+    /// range of the request, and drop the body of `HEAD` requests.
+    fn evaluate_conditions(
+        req: &RequestContext,
+        route: &Route,
+        file: &ResolvedFile,
+        enc: SupportedEncoding,
+        representation: Representation,
+        mut headers: Vec<(String, Vec<u8>)>,
+    ) -> Result<Response> {
+        let Representation {
+            etag,
+            mut reader,
+            transformed,
+            nonce,
+            ..
+        } = representation;
+        let if_none_match = req.header(&IF_NONE_MATCH).unwrap_or(b"");
+        let mut status = if reader.is_some() {
+            if let Some(status) = file
+                .declared
+                .as_ref()
+                .and_then(FileHeaders::status)
+                .or(route.status_override)
+            {
+                status
+            } else if conditional::match_fails(req.header(&IF_MATCH), &etag) {
+                let (status, plain_headers, body) = plain_response(StatusCode::PRECONDITION_FAILED);
+                (headers, reader) = (plain_headers, body);
+                status
+            } else if nonce.is_none() && conditional::none_match(if_none_match, &etag) {
+                reader = None;
+                StatusCode::NOT_MODIFIED
+            } else {
+                StatusCode::OK
+            }
+        } else {
+            reader = Some(Box::new(Cursor::new(b"Not Found")));
+            headers = Vec::new();
+            StatusCode::NOT_FOUND
+        };
+        // a single range of the stored bytes of a file, unencoded or precompressed, can be served
+        // once conditions are evaluated, but not of a file compressed on the fly
+        let rangeable = match &file.resolved {
+            FileServerPath::Physical(file)
+                if !Self::rewritten(file, route.assets.as_ref())
+                    && !transformed
+                    && nonce.is_none() =>
+            {
+                match enc {
+                    SupportedEncoding::None => Some(file.clone()),
+                    enc => precompressed::sibling(file, enc),
+                }
+            }
+            _ => None,
+        };
+        if let (StatusCode::OK, Some(file)) = (status, rangeable.as_deref()) {
+            headers.push((ACCEPT_RANGES.as_str().to_string(), b"bytes".to_vec()));
+            let backend = backend::current();
+            let len = backend.size(file)?;
+            match conditional::evaluate_range(req.header(&RANGE), req.header(&IF_RANGE), &etag, len)
+            {
+                // hosts can't tell an empty body from one they have to wait for
+                RangeOutcome::Full if len == 0 => {
+                    headers.push((CONTENT_LENGTH.as_str().to_string(), b"0".to_vec()));
+                }
+                RangeOutcome::Full => {}
+                RangeOutcome::Partial { start, end } => {
+                    reader = Some(backend.open_range(file, start, end - start + 1)?);
+                    headers.push((
+                        CONTENT_RANGE.as_str().to_string(),
+                        format!("bytes {start}-{end}/{len}").into_bytes(),
+                    ));
+                    status = StatusCode::PARTIAL_CONTENT;
+                }
+                RangeOutcome::Unsatisfiable => {
+                    let (unsatisfiable, _, body) =
+                        plain_response(StatusCode::RANGE_NOT_SATISFIABLE);
+                    status = unsatisfiable;
+                    headers = vec![(
+                        CONTENT_RANGE.as_str().to_string(),
+                        format!("bytes */{len}").into_bytes(),
+                    )];
+                    reader = body;
+                }
+            }
+        }
+        // the digest of a document with a nonce isn't known before it is sent
+        if matches!(status, StatusCode::OK | StatusCode::PARTIAL_CONTENT)
+            && repr_digest::wanted(req)
+            && nonce.is_none()
+        {
+            headers.extend(repr_digest::header(&etag));
+        }
+        // responses to HEAD requests have the headers of a GET response, without the body
+        if req.method == http::Method::HEAD {
+            reader = None;
+        }
+        Ok((status, headers, reader))
+    }
+
+    /// Respond to an `OPTIONS` request with the supported methods, or the methods configured for
+    /// the path if any, or reject a `TRACE` request.
+    fn allow(req: &RequestContext, allowed: Option<&str>) -> Response {
+        let allow = (
+            ALLOW.as_str().to_string(),
+            allowed.unwrap_or(ALLOWED_METHODS).as_bytes().to_vec(),
+        );
+        if req.method == http::Method::TRACE {
+            let (status, mut headers, body) = plain_response(StatusCode::METHOD_NOT_ALLOWED);
+            headers.push(allow);
+            return (status, headers, body);
+        }
+        (StatusCode::NO_CONTENT, vec![allow], None)
+    }
+
+    /// Build a redirect response. Locations starting with `/` are relative to the component route.
+    fn redirect(req: &RequestContext, status: StatusCode, location: &str) -> Response {
+        let location = if location.starts_with('/') {
+            RequestUrl::of(req).redirect(location)
+        } else {
+            location.to_string()
+        };
+        (
+            status,
+            vec![(LOCATION.as_str().to_string(), location.into_bytes())],
+            None,
+        )
+    }
+
+    /// Check whether the contents of a file are changed when served, so they can't be served from
+    /// precompressed siblings, precomputed ETags or byte ranges of the file.
+    fn rewritten(file: &Path, assets: Option<&AssetManifest>) -> bool {
+        let rewrites = assets.is_some_and(|a| a.rewrites(file))
+            || subpath::applies(file)
+            || file_headers::has_front_matter(file)
+            || minify::applies(file);
+        rewrites && !reproducible::enabled()
+    }
+
+    /// Return the ETag of a resolved path, from the ETag manifest or the ETag cache if they are
+    /// enabled, along with the status of the ETag cache if it was used. Rewritten documents are
+    /// always hashed, since their contents depend on the asset manifest and route too.
+    fn etag(
+        resolved: &FileServerPath,
+        enc: SupportedEncoding,
+        assets: Option<&AssetManifest>,
+        base: &str,
+    ) -> Result<(String, Option<CacheStatus>)> {
+        Self::etag_and_body(resolved, enc, assets, base).map(|(etag, status, _)| (etag, status))
+    }
+
+    /// Return the ETag of a resolved path like `etag`, along with the compressed contents of
+    /// small files if they had to be compressed to hash them, so they are only compressed once,
+    /// or if they are cached along with the ETag. This reuses the buffered output, not the
+    /// encoder: every request sets up its own encoder, as instances don't outlive a request.
+    fn etag_and_body(
+        resolved: &FileServerPath,
+        enc: SupportedEncoding,
+        assets: Option<&AssetManifest>,
+        base: &str,
+    ) -> Result<(String, Option<CacheStatus>, Option<Vec<u8>>)> {
+        // compressed bodies are cached along with the ETags, up to a larger size
+        let max_kept = if etag_cache::enabled() {
+            etag_cache::MAX_CACHED_BODY
+        } else {
+            SMALL_FILE_SIZE
+        };
+        let keep = enc != SupportedEncoding::None
+            && matches!(resolved, FileServerPath::Physical(file)
+                if backend::current().size(file).is_ok_and(|len| len <= max_kept));
+        let mut body = None;
+        let mut compute = || {
+            use sha2::Digest;
+            let reader = Self::open(resolved, enc, assets, base).transpose()?;
+            if !keep {
+                return Self::make_etag(reader);
+            }
+            let mut contents = Vec::new();
+            if let Some(mut reader) = reader {
+                reader.read_to_end(&mut contents)?;
+            }
+            let etag = hex::encode(sha2::Sha256::digest(&contents));
+            body = Some(contents);
+            Ok(etag)
+        };
+        let FileServerPath::Physical(file) = resolved else {
+            return Ok((compute()?, None, body));
+        };
+        let (etag, status) = if Self::rewritten(file, assets) {
+            (compute()?, None)
+        } else if let Some(etag) = precompressed::manifest_etag(file, enc)? {
+            (etag, None)
+        } else if etag_cache::enabled() {
+            let (etag, status) = etag_cache::get_or_insert(file, enc, compute)?;
+            if keep {
+                body = Some(match body.take() {
+                    Some(computed) => {
+                        etag_cache::insert_body(file, enc, &etag, &computed);
+                        computed
+                    }
+                    None => match etag_cache::body(file, enc, &etag) {
+                        Some(cached) => cached,
+                        None => {
+                            let mut contents = Vec::new();
+                            if let Some(mut reader) =
+                                Self::open(resolved, enc, assets, base).transpose()?
+                            {
+                                reader.read_to_end(&mut contents)?;
+                            }
+                            etag_cache::insert_body(file, enc, &etag, &contents);
+                            contents
+                        }
+                    },
+                });
+            }
+            (etag, Some(status))
+        } else {
+            (compute()?, None)
+        };
+        Ok((etag, status, body))
+    }
+
+    /// Populate the ETag and compressed body caches for every encoding of the files listed in the
+    /// `paths` query parameter, or of all files under `root`.
+    /// Returns the number of files warmed and the errors encountered by path.
+    fn warm(req: &RequestContext, root: &Path) -> Result<(usize, BTreeMap<String, String>)> {
+        let paths = req.query.split('&').find_map(|p| p.strip_prefix("paths="));
+        let languages = [languages::default_language()];
+        let files = match paths {
+            Some(paths) => paths
+                .split(',')
+                .filter(|p| !p.is_empty())
+                .filter_map(|p| match Self::resolve(p, &languages) {
+                    FileServerPath::Physical(file) => Some(file),
+                    _ => None,
+                })
+                .collect(),
+            None => Self::walk(backend::current().as_ref(), root)?,
+        };
+
+        let assets = AssetManifest::from_env()?;
+        let url = RequestUrl::of(req);
+        let mut errors = BTreeMap::new();
+        for file in &files {
+            let resolved = FileServerPath::Physical(file.clone());
+            for enc in [
+                SupportedEncoding::None,
+                SupportedEncoding::Brotli,
+                SupportedEncoding::Deflate,
+                SupportedEncoding::Gzip,
+            ] {
+                if let Err(e) = Self::etag(&resolved, enc, assets.as_ref(), url.base()) {
+                    errors.insert(file.display().to_string(), e.to_string());
+                    break;
+                }
+            }
+        }
+        Ok((files.len() - errors.len(), errors))
+    }
+
+    /// List all files under a directory of a backend, recursively.
+    fn walk(backend: &dyn Backend, dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for name in backend.list(dir)? {
+            let path = dir.join(name);
+            if backend.is_dir(&path) {
+                files.extend(Self::walk(backend, &path)?);
+            } else if backend.is_file(&path) {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    fn make_etag(body: Option<Box<dyn Read>>) -> Result<String> {
+        use sha2::Digest;
+        let mut hasher = sha2::Sha256::new();
+        if let Some(mut reader) = body {
+            let mut buffer = vec![0_u8; BUFFER_SIZE];
+            loop {
+                match reader.read(&mut buffer)? {
+                    0 => break,
+                    count => {
+                        hasher.update(&buffer[..count]);
+                    }
+                }
+            }
+        }
+        Ok(hex::encode(hasher.finalize()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::ACCEPT_ENCODING;
+    use scopeguard::defer;
+    use std::{fs, path::Path, sync::Mutex};
+
+    static TEST_ENV_MUTEX: Mutex<()> = Mutex::new(());
+
+    fn request(path: &[u8]) -> RequestContext {
+        RequestContext {
+            path: path.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_best_encoding_none() {
+        let enc = SupportedEncoding::best_encoding(&[]);
+        assert_eq!(enc, SupportedEncoding::None);
+    }
+
+    #[test]
+    fn test_best_encoding_with_unknown() {
+        let enc = SupportedEncoding::best_encoding(&[(
+            ACCEPT_ENCODING.to_string(),
+            b"some-weird-encoding".to_vec(),
+        )]);
+        assert_eq!(enc, SupportedEncoding::None);
+    }
+
+    #[test]
+    fn test_best_encoding_with_weights() {
+        let enc = SupportedEncoding::best_encoding(&[(
+            ACCEPT_ENCODING.to_string(),
+            b"gzip;br;q=0.1".to_vec(),
+        )]);
+        assert_eq!(enc, SupportedEncoding::Gzip);
+
+        let enc = SupportedEncoding::best_encoding(&[(
+            ACCEPT_ENCODING.to_string(),
+            b"br;q=NaN, gzip;q=0.5".to_vec(),
+        )]);
+        assert_eq!(enc, SupportedEncoding::Gzip);
+    }
+
+    #[test]
+    fn test_best_encoding_with_multiple_headers() {
+        let enc = SupportedEncoding::best_encoding(&[
+            (ACCEPT_ENCODING.to_string(), b"gzip".to_vec()),
+            (ACCEPT_ENCODING.to_string(), b"br".to_vec()),
+        ]);
         assert_eq!(enc, SupportedEncoding::Brotli);
     }
 
     #[test]
-    fn test_serve_file_found() {
-        let (status, ..) =
-            FileServer::make_response(b"./hello-test.txt", SupportedEncoding::None, b"").unwrap();
+    fn test_best_encoding_with_gzip() {
+        let enc =
+            SupportedEncoding::best_encoding(&[(ACCEPT_ENCODING.to_string(), b"gzip".to_vec())]);
+        assert_eq!(enc, SupportedEncoding::Gzip);
+    }
+
+    #[test]
+    fn test_best_encoding_with_deflate() {
+        let enc =
+            SupportedEncoding::best_encoding(&[(ACCEPT_ENCODING.to_string(), b"deflate".to_vec())]);
+        assert_eq!(enc, SupportedEncoding::Deflate);
+    }
+
+    #[test]
+    fn test_best_encoding_with_br() {
+        let enc =
+            SupportedEncoding::best_encoding(&[(ACCEPT_ENCODING.to_string(), b"gzip,br".to_vec())]);
+        assert_eq!(enc, SupportedEncoding::Brotli);
+    }
+
+    #[test]
+    fn test_serve_file_found() {
+        let (status, ..) = FileServer::make_response(&request(b"hello-test.txt")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_serve_with_etag() {
+        let mut req = request(b"hello-test.txt");
+        req.headers.push((
+            IF_NONE_MATCH.to_string(),
+            b"4dca0fd5f424a31b03ab807cbae77eb32bf2d089eed1cee154b3afed458de0dc".to_vec(),
+        ));
+        let (status, _, reader) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert!(reader.is_none());
+    }
+
+    #[test]
+    fn test_ranges_and_compression() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let dir =
+            std::env::temp_dir().join(format!("spin-fileserver-ranges-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let contents = "hello, world!\n".repeat(100);
+        let mut gzipped = Vec::new();
+        flate2::read::GzEncoder::new(contents.as_bytes(), flate2::Compression::best())
+            .read_to_end(&mut gzipped)
+            .unwrap();
+        fs::write(dir.join("data.txt"), &contents).unwrap();
+        fs::write(dir.join("data.txt.gz"), &gzipped).unwrap();
+        std::env::set_var(precompressed::PRECOMPRESSED_ENV, "true");
+        defer! {
+            std::env::remove_var(precompressed::PRECOMPRESSED_ENV);
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        let path = dir.join("data.txt");
+        let respond = |accept_encoding: &[u8], range: Option<&[u8]>| {
+            let mut req = request(path.to_str().unwrap().as_bytes());
+            req.headers
+                .push((ACCEPT_ENCODING.to_string(), accept_encoding.to_vec()));
+            if let Some(range) = range {
+                req.headers.push((RANGE.to_string(), range.to_vec()));
+            }
+            let (status, headers, reader) = FileServer::make_response(&req).unwrap();
+            let header = |name: HeaderName| {
+                headers
+                    .iter()
+                    .find(|(k, _)| *k == name.as_str())
+                    .map(|(_, v)| String::from_utf8_lossy(v).into_owned())
+            };
+            let mut body = Vec::new();
+            reader.unwrap().read_to_end(&mut body).unwrap();
+            (
+                status,
+                header(ACCEPT_RANGES),
+                header(CONTENT_ENCODING),
+                header(CONTENT_RANGE),
+                body,
+            )
+        };
+        let bytes = Some("bytes".to_string());
+        let gzip = Some("gzip".to_string());
+
+        // ranges of precompressed files are ranges of their compressed bytes
+        assert_eq!(
+            respond(b"gzip", None),
+            (
+                StatusCode::OK,
+                bytes.clone(),
+                gzip.clone(),
+                None,
+                gzipped.clone()
+            )
+        );
+        assert_eq!(
+            respond(b"gzip", Some(b"bytes=0-9")),
+            (
+                StatusCode::PARTIAL_CONTENT,
+                bytes.clone(),
+                gzip.clone(),
+                Some(format!("bytes 0-9/{}", gzipped.len())),
+                gzipped[..10].to_vec()
+            )
+        );
+        let (status, ranges, encoding, range, _) = respond(b"gzip", Some(b"bytes=100000-"));
+        assert_eq!(
+            (status, ranges, encoding, range),
+            (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                None,
+                None,
+                Some(format!("bytes */{}", gzipped.len()))
+            )
+        );
+
+        // files compressed on the fly don't support ranges, which are served unencoded instead
+        let (status, ranges, encoding, range, _) = respond(b"br", None);
+        assert_eq!(
+            (status, ranges, encoding, range),
+            (StatusCode::OK, None, Some("br".to_string()), None)
+        );
+        assert_eq!(
+            respond(b"br", Some(b"bytes=0-4")),
+            (
+                StatusCode::PARTIAL_CONTENT,
+                bytes.clone(),
+                None,
+                Some(format!("bytes 0-4/{}", contents.len())),
+                b"hello".to_vec()
+            )
+        );
+
+        // without precompressed files, every range is served unencoded
+        std::env::remove_var(precompressed::PRECOMPRESSED_ENV);
+        let (status, ranges, encoding, range, body) = respond(b"gzip", Some(b"bytes=7-11"));
+        assert_eq!(
+            (status, ranges, encoding, range, body),
+            (
+                StatusCode::PARTIAL_CONTENT,
+                bytes,
+                None,
+                Some(format!("bytes 7-11/{}", contents.len())),
+                b"world".to_vec()
+            )
+        );
+        let (_, ranges, encoding, ..) = respond(b"gzip", None);
+        assert_eq!((ranges, encoding), (None, gzip));
+    }
+
+    #[test]
+    fn test_conditional_head_and_range_requests() {
+        const ETAG: &[u8] = b"4dca0fd5f424a31b03ab807cbae77eb32bf2d089eed1cee154b3afed458de0dc";
+        let respond = |method: http::Method, headers: &[(HeaderName, &[u8])]| {
+            let mut req = request(b"hello-test.txt");
+            req.method = method;
+            req.headers
+                .push((ACCEPT_ENCODING.to_string(), b"gzip".to_vec()));
+            req.headers.extend(
+                headers
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_vec())),
+            );
+            let (status, headers, reader) = FileServer::make_response(&req).unwrap();
+            let body = reader.map(|mut reader| {
+                let mut body = String::new();
+                reader.read_to_string(&mut body).unwrap();
+                body
+            });
+            let header = |name: HeaderName| {
+                headers
+                    .iter()
+                    .find(|(k, _)| *k == name.as_str())
+                    .map(|(_, v)| String::from_utf8_lossy(v).into_owned())
+            };
+            (
+                status,
+                header(CONTENT_RANGE),
+                header(CONTENT_ENCODING),
+                body,
+            )
+        };
+        let get = |headers| respond(http::Method::GET, headers);
+
+        // ranges are served unencoded
+        assert_eq!(
+            get(&[(RANGE, b"bytes=0-4")]),
+            (
+                StatusCode::PARTIAL_CONTENT,
+                Some("bytes 0-4/14".to_string()),
+                None,
+                Some("hello".to_string())
+            )
+        );
+        // a matching If-None-Match wins over the range, and a failed If-Match over both
+        let (status, _, _, body) = get(&[(RANGE, b"bytes=0-4"), (IF_NONE_MATCH, ETAG)]);
+        assert_eq!((status, body), (StatusCode::NOT_MODIFIED, None));
+        assert_eq!(
+            get(&[
+                (RANGE, b"bytes=0-4"),
+                (IF_NONE_MATCH, ETAG),
+                (IF_MATCH, b"\"stale\"")
+            ]),
+            (
+                StatusCode::PRECONDITION_FAILED,
+                None,
+                None,
+                Some("Precondition Failed".to_string())
+            )
+        );
+        let (status, ..) = get(&[(RANGE, b"bytes=0-4"), (IF_MATCH, ETAG)]);
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        // the range is only served if If-Range matches
+        let (status, range, _, body) = get(&[(RANGE, b"bytes=7-"), (IF_RANGE, ETAG)]);
+        assert_eq!(
+            (status, range, body.as_deref()),
+            (
+                StatusCode::PARTIAL_CONTENT,
+                Some("bytes 7-13/14".to_string()),
+                Some("world!\n")
+            )
+        );
+        let (status, range, _, body) = get(&[(RANGE, b"bytes=7-"), (IF_RANGE, b"\"stale\"")]);
+        assert_eq!(
+            (status, range, body.as_deref()),
+            (StatusCode::OK, None, Some("hello, world!\n"))
+        );
+        assert_eq!(
+            get(&[(RANGE, b"bytes=20-")]),
+            (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                Some("bytes */14".to_string()),
+                None,
+                Some("Range Not Satisfiable".to_string())
+            )
+        );
+        // malformed headers are rejected or ignored, never trap
+        for (name, value, expected) in [
+            (RANGE, &b"bytes=0-4\x00"[..], StatusCode::BAD_REQUEST),
+            (RANGE, b"bytes=18446744073709551616-", StatusCode::OK),
+            (
+                RANGE,
+                b"bytes=-18446744073709551615",
+                StatusCode::PARTIAL_CONTENT,
+            ),
+            (RANGE, b"bytes=0-4,5-6,", StatusCode::OK),
+            (RANGE, b"bytes=\xe2\x80\x94", StatusCode::BAD_REQUEST),
+            (IF_RANGE, b"\"", StatusCode::OK),
+            (IF_NONE_MATCH, b",,,W/", StatusCode::OK),
+            (ACCEPT_ENCODING, b";q=;;,gzip;q=1e309", StatusCode::OK),
+            (ACCEPT_ENCODING, b"br\r\n", StatusCode::BAD_REQUEST),
+        ] {
+            assert_eq!(
+                respond(http::Method::HEAD, &[(name.clone(), value)]).0,
+                expected,
+                "{name}: {}",
+                String::from_utf8_lossy(value)
+            );
+        }
+
+        // HEAD responses have the status and headers of GET responses without the body
+        let head = |headers| respond(http::Method::HEAD, headers);
+        assert_eq!(
+            head(&[]),
+            (StatusCode::OK, None, Some("gzip".to_string()), None)
+        );
+        assert_eq!(head(&[(IF_NONE_MATCH, b"*")]).0, StatusCode::NOT_MODIFIED);
+        let (status, range, _, body) = head(&[(RANGE, b"bytes=0-4")]);
+        assert_eq!(
+            (status, range, body),
+            (
+                StatusCode::PARTIAL_CONTENT,
+                Some("bytes 0-4/14".to_string()),
+                None
+            )
+        );
+    }
+
+    #[test]
+    fn test_repr_digest() {
+        let digest = (
+            "repr-digest".to_string(),
+            b"sha-256=:TcoP1fQkoxsDq4B8uud+syvy0Inu0c7hVLOv7UWN4Nw=:".to_vec(),
+        );
+        let (_, headers, _) = FileServer::make_response(&request(b"hello-test.txt")).unwrap();
+        assert!(!headers.contains(&digest));
+
+        // partial responses carry the digest of the whole file
+        let mut req = request(b"hello-test.txt");
+        req.headers.extend([
+            ("want-repr-digest".to_string(), b"sha-256=1".to_vec()),
+            (RANGE.to_string(), b"bytes=0-4".to_vec()),
+        ]);
+        let (status, headers, _) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert!(headers.contains(&digest));
+    }
+
+    #[test]
+    fn test_serve_file_not_found() {
+        let (status, _, reader) =
+            FileServer::make_response(&request(b"non-exisitent-file")).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let mut actual_body = Vec::new();
+        reader.unwrap().read_to_end(&mut actual_body).unwrap();
+        assert_eq!(actual_body.as_slice(), b"Not Found");
+    }
+
+    #[test]
+    fn test_serve_invalid_utf8_path() {
+        let (status, _, reader) = FileServer::make_response(&request(b"hello-\xff.txt")).unwrap();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let mut actual_body = Vec::new();
+        reader.unwrap().read_to_end(&mut actual_body).unwrap();
+        assert_eq!(actual_body.as_slice(), b"Bad Request");
+    }
+
+    #[test]
+    fn test_serve_path_with_control_characters() {
+        for path in [
+            &b"hello-test.txt\0"[..],
+            b"hello\n-test.txt",
+            b"hello-test\x7f.txt",
+        ] {
+            let (status, ..) = FileServer::make_response(&request(path)).unwrap();
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn test_serve_custom_404() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        // reuse existing asset as custom 404 doc
+        let custom_404_path = "hello-test.txt";
+        let expected_body =
+            fs::read(Path::new(custom_404_path)).expect("Could not read custom 404 file");
+
+        std::env::set_var(CUSTOM_404_PATH_ENV, custom_404_path);
+        defer! {
+            std::env::remove_var(CUSTOM_404_PATH_ENV);
+        }
+
+        let (status, _, reader) =
+            FileServer::make_response(&request(b"non-exisitent-file")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let mut actual_body = Vec::new();
+        reader.unwrap().read_to_end(&mut actual_body).unwrap();
+        assert_eq!(actual_body, expected_body);
+    }
+
+    #[test]
+    fn test_serve_localized_custom_404() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let dir = std::env::temp_dir().join(format!("spin-fileserver-404-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("404.en.html"), "Not found").unwrap();
+        fs::write(dir.join("404.fr.html"), "Introuvable").unwrap();
+        std::env::set_var(
+            CUSTOM_404_PATH_ENV,
+            dir.join("404.{lang}.html").to_str().unwrap(),
+        );
+        defer! {
+            std::env::remove_var(CUSTOM_404_PATH_ENV);
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        let body = |accept_language: &str| {
+            let mut req = request(b"non-existent-file");
+            req.headers.push((
+                "accept-language".to_string(),
+                accept_language.as_bytes().to_vec(),
+            ));
+            let (_, headers, reader) = FileServer::make_response(&req).unwrap();
+            assert!(headers.contains(&("vary".to_string(), b"accept-language".to_vec())));
+            let mut body = String::new();
+            reader.unwrap().read_to_string(&mut body).unwrap();
+            body
+        };
+        assert_eq!(body("fr-CA,fr;q=0.9"), "Introuvable");
+        assert_eq!(body("de"), "Not found");
+
+        // existing files don't depend on the language
+        let (_, headers, _) = FileServer::make_response(&request(b"hello-test.txt")).unwrap();
+        assert!(!headers.contains(&("vary".to_string(), b"accept-language".to_vec())));
+    }
+
+    #[test]
+    fn test_language_cookie() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let dir = std::env::temp_dir().join(format!("spin-fileserver-lang-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("404.en.html"), "Not found").unwrap();
+        fs::write(dir.join("404.fr.html"), "Introuvable").unwrap();
+        fs::write(dir.join("404.de.html"), "Nicht gefunden").unwrap();
+        std::env::set_var(
+            CUSTOM_404_PATH_ENV,
+            dir.join("404.{lang}.html").to_str().unwrap(),
+        );
+        std::env::set_var(languages::LANGUAGE_COOKIE_ENV, "lang");
+        defer! {
+            std::env::remove_var(CUSTOM_404_PATH_ENV);
+            std::env::remove_var(languages::LANGUAGE_COOKIE_ENV);
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        let respond = |path: &[u8], query: &str, cookie: Option<&str>| {
+            let mut req = request(path);
+            req.query = query.to_string();
+            req.headers
+                .push(("accept-language".to_string(), b"fr-CA,fr;q=0.9".to_vec()));
+            if let Some(cookie) = cookie {
+                req.headers
+                    .push(("cookie".to_string(), cookie.as_bytes().to_vec()));
+            }
+            let (_, headers, reader) = FileServer::make_response(&req).unwrap();
+            let cookie = headers
+                .iter()
+                .find(|(k, _)| k == SET_COOKIE.as_str())
+                .map(|(_, v)| String::from_utf8(v.clone()).unwrap());
+            if cookie.is_some() {
+                assert!(
+                    headers.contains(&(CACHE_CONTROL.to_string(), b"private, no-cache".to_vec()))
+                );
+            }
+            let mut body = String::new();
+            reader.unwrap().read_to_string(&mut body).unwrap();
+            (body, cookie)
+        };
+
+        // the negotiated language is remembered
+        let (body, cookie) = respond(b"missing", "", None);
+        assert_eq!(body, "Introuvable");
+        assert!(cookie.unwrap().starts_with("lang=fr; Path=/;"));
+
+        // the remembered language wins over Accept-Language
+        assert_eq!(
+            respond(b"missing", "", Some("lang=de")),
+            ("Nicht gefunden".to_string(), None)
+        );
+
+        // and switching with the query parameter replaces it
+        let (body, cookie) = respond(b"missing", "lang=en", Some("lang=de"));
+        assert_eq!(body, "Not found");
+        assert!(cookie.unwrap().starts_with("lang=en;"));
+        assert_eq!(respond(b"missing", "lang=de", Some("lang=de")).1, None);
+        assert_eq!(respond(b"missing", "lang=../x", Some("lang=fr")).1, None);
+
+        // files that don't depend on the language never set it, so they stay cacheable
+        let (_, cookie) = respond(b"hello-test.txt", "lang=DE", None);
+        assert_eq!(cookie, None);
+    }
+
+    #[test]
+    fn test_serve_non_existing_custom_404() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        // provide a invalid path
+        let custom_404_path = "non-existing-404.html";
+
+        std::env::set_var(CUSTOM_404_PATH_ENV, custom_404_path);
+        defer! {
+            std::env::remove_var(CUSTOM_404_PATH_ENV);
+        }
+
+        let (status, _, reader) =
+            FileServer::make_response(&request(b"non-exisitent-file")).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let mut actual_body = Vec::new();
+        reader.unwrap().read_to_end(&mut actual_body).unwrap();
+        assert_eq!(actual_body.as_slice(), b"Not Found");
+    }
+
+    #[test]
+    fn test_serve_file_not_found_with_fallback_path() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        // reuse existing asset as fallback
+        let fallback_path = "hello-test.txt";
+        let expected_body =
+            fs::read(Path::new(fallback_path)).expect("Could not read fallback file");
+
+        std::env::set_var(FALLBACK_PATH_ENV, fallback_path);
+        defer! {
+            std::env::remove_var(FALLBACK_PATH_ENV);
+        }
+
+        let (status, _, reader) =
+            FileServer::make_response(&request(b"non-exisitent-file")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let mut actual_body = Vec::new();
+        reader.unwrap().read_to_end(&mut actual_body).unwrap();
+        assert_eq!(actual_body, expected_body);
+    }
+
+    #[test]
+    fn test_serve_index() {
+        // Test against path with trailing slash
+        let (status, ..) =
+            FileServer::make_response(&request(b"tests/fixtures/subdirectory/")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+
+        // Test against empty path
+        let (status, ..) = FileServer::make_response(&request(b"")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_block_scanners() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+        std::env::set_var(FALLBACK_PATH_ENV, "index.html");
+        defer! {
+            std::env::remove_var(FALLBACK_PATH_ENV);
+            std::env::remove_var(scanners::BLOCK_SCANNERS_ENV);
+        }
+
+        // without the blocklist, scanners get the fallback document like everyone else
+        let (status, ..) = FileServer::make_response(&request(b"/wp-admin/install.php")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+
+        std::env::set_var(scanners::BLOCK_SCANNERS_ENV, "true");
+        for path in [&b"/wp-admin/install.php"[..], b"/.env", b"/.git/config"] {
+            let (status, headers, reader) = FileServer::make_response(&request(path)).unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert!(headers.is_empty());
+            let mut body = String::new();
+            reader.unwrap().read_to_string(&mut body).unwrap();
+            assert_eq!(body, "Not Found");
+        }
+        let (status, ..) = FileServer::make_response(&request(b"/missing/page")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_honeypot() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+        std::env::set_var(FALLBACK_PATH_ENV, "index.html");
+        std::env::set_var(
+            honeypot::HONEYPOT_PATHS_ENV,
+            "/hidden-admin/, /old-login.php",
+        );
+        defer! {
+            std::env::remove_var(FALLBACK_PATH_ENV);
+            std::env::remove_var(honeypot::HONEYPOT_PATHS_ENV);
+        }
+
+        for path in [&b"/hidden-admin/users"[..], b"/old-login.php"] {
+            let (status, headers, _) = FileServer::make_response(&request(path)).unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert!(headers.is_empty());
+        }
+        let (status, ..) = FileServer::make_response(&request(b"/hidden")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_serve_fallback_favicon() {
+        let (status, _, reader) =
+            FileServer::make_response(&request(FAVICON_PNG_FILENAME.as_bytes())).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let mut actual_body = Vec::new();
+        reader.unwrap().read_to_end(&mut actual_body).unwrap();
+        assert_eq!(actual_body, FALLBACK_FAVICON_PNG);
+    }
+
+    #[test]
+    fn test_serve_case_insensitive() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let (status, ..) = FileServer::make_response(&request(b"Hello-Test.TXT")).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        std::env::set_var(CASE_INSENSITIVE_ENV, "true");
+        defer! {
+            std::env::remove_var(CASE_INSENSITIVE_ENV);
+        }
+
+        let (status, _, reader) = FileServer::make_response(&request(b"Hello-Test.TXT")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let mut actual_body = Vec::new();
+        reader.unwrap().read_to_end(&mut actual_body).unwrap();
+        assert_eq!(actual_body, fs::read("hello-test.txt").unwrap());
+    }
+
+    #[test]
+    fn test_redirect_case_insensitive() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        std::env::set_var(CASE_INSENSITIVE_ENV, "redirect");
+        defer! {
+            std::env::remove_var(CASE_INSENSITIVE_ENV);
+        }
+
+        let (status, headers, reader) =
+            FileServer::make_response(&request(b"HELLO-test.txt")).unwrap();
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            headers,
+            vec![(LOCATION.to_string(), b"hello-test.txt".to_vec())]
+        );
+        assert!(reader.is_none());
+    }
+
+    #[test]
+    fn test_index_html_requests() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+        defer! {
+            std::env::remove_var(INDEX_HTML_ENV);
+            std::env::remove_var(request_url::ABSOLUTE_REDIRECTS_ENV);
+            std::env::remove_var(request_url::TRUST_FORWARDED_HEADERS_ENV);
+        }
+
+        let (status, ..) = FileServer::make_response(&request(b"/index.html")).unwrap();
+        assert_ne!(status, StatusCode::MOVED_PERMANENTLY);
+
+        std::env::set_var(INDEX_HTML_ENV, "redirect");
+        let mut req = request(b"/docs/index.html");
+        req.route = "/site".to_string();
+        req.query = "v=2".to_string();
+        let (status, headers, _) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            headers,
+            vec![(LOCATION.to_string(), b"/site/docs/?v=2".to_vec())]
+        );
+        // redirects keep the prefix of trusted proxies and can name the host clients requested
+        std::env::set_var(request_url::ABSOLUTE_REDIRECTS_ENV, "true");
+        req.headers = vec![
+            (
+                request_url::FULL_URL_HEADER.to_string(),
+                b"https://example.com/site/docs/index.html?v=2".to_vec(),
+            ),
+            ("x-forwarded-prefix".to_string(), b"/blog".to_vec()),
+        ];
+        let (_, headers, _) = FileServer::make_response(&req).unwrap();
+        assert_eq!(
+            headers,
+            vec![(
+                LOCATION.to_string(),
+                b"https://example.com/site/docs/?v=2".to_vec()
+            )]
+        );
+        std::env::set_var(request_url::TRUST_FORWARDED_HEADERS_ENV, "true");
+        let (_, headers, _) = FileServer::make_response(&req).unwrap();
+        assert_eq!(
+            headers,
+            vec![(
+                LOCATION.to_string(),
+                b"https://example.com/blog/site/docs/?v=2".to_vec()
+            )]
+        );
+        let (status, ..) = FileServer::make_response(&request(b"/docs/my-index.html")).unwrap();
+        assert_ne!(status, StatusCode::MOVED_PERMANENTLY);
+
+        std::env::set_var(INDEX_HTML_ENV, "not-found");
+        let (status, ..) = FileServer::make_response(&request(b"/index.html")).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_serve_alias() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        std::env::set_var(
+            ALIASES_ENV,
+            "/downloads/latest.txt=hello-test.txt, /other=missing.txt",
+        );
+        defer! {
+            std::env::remove_var(ALIASES_ENV);
+        }
+
+        let (status, headers, reader) =
+            FileServer::make_response(&request(b"/downloads/latest.txt")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains(&(CONTENT_TYPE.to_string(), b"text/plain".to_vec())));
+        let mut actual_body = Vec::new();
+        reader.unwrap().read_to_end(&mut actual_body).unwrap();
+        assert_eq!(actual_body, fs::read("hello-test.txt").unwrap());
+
+        let (status, ..) = FileServer::make_response(&request(b"/other")).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_serve_status_override() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let config_path = std::env::temp_dir().join("spin-fileserver-status-override.toml");
+        fs::write(
+            &config_path,
+            "[status]\n\"/coming-soon\" = 503\n\n[aliases]\n\"/coming-soon\" = \"hello-test.txt\"\n",
+        )
+        .unwrap();
+        std::env::set_var(config::CONFIG_FILE_ENV, &config_path);
+        defer! {
+            std::env::remove_var(config::CONFIG_FILE_ENV);
+            let _ = fs::remove_file(&config_path);
+        }
+
+        let (status, _, reader) = FileServer::make_response(&request(b"/coming-soon")).unwrap();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        let mut actual_body = Vec::new();
+        reader.unwrap().read_to_end(&mut actual_body).unwrap();
+        assert_eq!(actual_body, fs::read("hello-test.txt").unwrap());
+
+        let (status, ..) = FileServer::make_response(&request(b"hello-test.txt")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_embargo() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let config_path = std::env::temp_dir().join("spin-fileserver-embargo.toml");
+        fs::write(
+            &config_path,
+            r#"
+            [aliases]
+            "/launch" = "hello-test.txt"
+            "/soon" = "index.html"
+            "/press-kit" = "hello-test.txt"
+
+            [[embargo]]
+            path = "/launch"
+            not-before = 9999-01-01T00:00:00Z
+            teaser = "/soon"
+
+            [[embargo]]
+            path = "/press-kit"
+            not-before = 2000-01-01
+            not-after = 2000-02-01
+            "#,
+        )
+        .unwrap();
+        std::env::set_var(config::CONFIG_FILE_ENV, &config_path);
+        defer! {
+            std::env::remove_var(config::CONFIG_FILE_ENV);
+            let _ = fs::remove_file(&config_path);
+        }
+
+        // the teaser is served until the release, and isn't cached
+        let (status, headers, reader) = FileServer::make_response(&request(b"/launch")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains(&(CACHE_CONTROL.to_string(), b"no-cache".to_vec())));
+        let mut actual_body = Vec::new();
+        reader.unwrap().read_to_end(&mut actual_body).unwrap();
+        assert_eq!(actual_body, fs::read("index.html").unwrap());
+
+        let (status, headers, _) = FileServer::make_response(&request(b"/press-kit")).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(headers.contains(&(CACHE_CONTROL.to_string(), b"no-cache".to_vec())));
+
+        let (status, ..) = FileServer::make_response(&request(b"/soon")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_deploy_window() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let config_path = std::env::temp_dir().join("spin-fileserver-deploy-window.toml");
+        fs::write(
+            &config_path,
+            r#"
+            [[deploy-window]]
+            start = 2000-01-01T00:00:00Z
+            end = 9999-01-01T00:00:00Z
+            max-age = 5
+            "#,
+        )
+        .unwrap();
+        std::env::set_var(config::CONFIG_FILE_ENV, &config_path);
+        defer! {
+            std::env::remove_var(config::CONFIG_FILE_ENV);
+            let _ = fs::remove_file(&config_path);
+        }
+
+        let cache_control = |path: &[u8]| {
+            let (_, headers, _) = FileServer::make_response(&request(path)).unwrap();
+            headers
+                .into_iter()
+                .find(|(k, _)| k == CACHE_CONTROL.as_str())
+                .map(|(_, v)| v)
+        };
+        // only documents are cached for less time
+        assert_eq!(cache_control(b"index.html"), Some(b"max-age=5".to_vec()));
+        assert_eq!(
+            cache_control(b"hello-test.txt"),
+            Some(b"max-age=60".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_variant_path() {
+        let dir = VariantMode::Directory;
+        assert_eq!(dir.variant_path("/about.html", "de"), "/de/about.html");
+        assert_eq!(dir.variant_path("/", "de"), "/de/");
+        assert_eq!(dir.variant_path("", "de"), "de/");
+
+        let suffix = VariantMode::Suffix;
+        assert_eq!(suffix.variant_path("/about.html", "de"), "/about.de.html");
+        assert_eq!(suffix.variant_path("/v1.0/about", "de"), "/v1.0/about.de");
+        assert_eq!(suffix.variant_path("/.env", "de"), "/.env.de");
+        assert_eq!(suffix.variant_path("/docs/", "de"), "/docs/index.de.html");
+    }
+
+    #[test]
+    fn test_serve_header_variant() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let dir = std::env::temp_dir().join("spin-fileserver-variants");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("page.txt"), "default").unwrap();
+        fs::write(dir.join("page.de.txt"), "german").unwrap();
+        std::env::set_var(VARIANT_HEADER_ENV, "X-Country");
+        std::env::set_var(VARIANT_MODE_ENV, "suffix");
+        defer! {
+            std::env::remove_var(VARIANT_HEADER_ENV);
+            std::env::remove_var(VARIANT_MODE_ENV);
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        let path = dir.join("page.txt").to_string_lossy().into_owned();
+        for (country, expected_body) in [("DE", "german"), ("us", "default"), ("../x", "default")] {
+            let mut req = request(path.as_bytes());
+            req.headers
+                .push(("x-country".to_string(), country.as_bytes().to_vec()));
+            let (status, headers, reader) = FileServer::make_response(&req).unwrap();
+            assert_eq!(status, StatusCode::OK);
+            assert!(headers.contains(&(VARY.to_string(), b"x-country".to_vec())));
+            let mut actual_body = String::new();
+            reader.unwrap().read_to_string(&mut actual_body).unwrap();
+            assert_eq!(actual_body, expected_body);
+        }
+    }
+
+    #[test]
+    fn test_user_agent_rules() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let config_path = std::env::temp_dir().join("spin-fileserver-user-agent.toml");
+        fs::write(
+            &config_path,
+            r#"
+            [[user-agent]]
+            pattern = "MSIE"
+            redirect = "/unsupported.html"
+
+            [[user-agent]]
+            pattern = "(?i)bot"
+            rewrite = "./hello-test.txt"
+            "#,
+        )
+        .unwrap();
+        std::env::set_var(config::CONFIG_FILE_ENV, &config_path);
+        defer! {
+            std::env::remove_var(config::CONFIG_FILE_ENV);
+            let _ = fs::remove_file(&config_path);
+        }
+
+        let with_user_agent = |path: &[u8], user_agent: &str| {
+            let mut req = request(path);
+            req.route = "/site".to_string();
+            req.headers
+                .push((USER_AGENT.to_string(), user_agent.as_bytes().to_vec()));
+            FileServer::make_response(&req).unwrap()
+        };
+
+        let (status, headers, _) = with_user_agent(b"/index.html", "Mozilla/4.0 (MSIE 6.0)");
+        assert_eq!(status, StatusCode::FOUND);
+        assert_eq!(
+            headers,
+            vec![(LOCATION.to_string(), b"/site/unsupported.html".to_vec())]
+        );
+
+        let (status, ..) = with_user_agent(b"/unsupported.html", "Mozilla/4.0 (MSIE 6.0)");
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, headers, reader) = with_user_agent(b"/index.html", "Googlebot/2.1");
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains(&(VARY.to_string(), b"user-agent".to_vec())));
+        let mut actual_body = Vec::new();
+        reader.unwrap().read_to_end(&mut actual_body).unwrap();
+        assert_eq!(actual_body, fs::read("hello-test.txt").unwrap());
+    }
+
+    #[test]
+    fn test_prerendered_pages() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let dir = std::env::temp_dir().join(format!(
+            "spin-fileserver-prerendered-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(dir.join("blog")).unwrap();
+        fs::write(dir.join("blog").join("post-1.html"), "<h1>Post 1</h1>").unwrap();
+        std::env::set_var(prerender::PRERENDERED_DIR_ENV, &dir);
+        std::env::set_var(FALLBACK_PATH_ENV, "hello-test.txt");
+        defer! {
+            std::env::remove_var(prerender::PRERENDERED_DIR_ENV);
+            std::env::remove_var(FALLBACK_PATH_ENV);
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        let with_user_agent = |path: &[u8], user_agent: &str| {
+            let mut req = request(path);
+            req.headers
+                .push((USER_AGENT.to_string(), user_agent.as_bytes().to_vec()));
+            FileServer::make_response(&req).unwrap()
+        };
+
+        let (status, headers, reader) = with_user_agent(
+            b"/blog/post-1",
+            "Mozilla/5.0 (compatible; bingbot/2.0; +http://www.bing.com/bingbot.htm)",
+        );
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains(&(VARY.to_string(), b"user-agent".to_vec())));
+        let mut actual_body = Vec::new();
+        reader.unwrap().read_to_end(&mut actual_body).unwrap();
+        assert_eq!(actual_body, b"<h1>Post 1</h1>");
+
+        // other clients get the shell of the app
+        let (status, headers, reader) =
+            with_user_agent(b"/blog/post-1", "Mozilla/5.0 Firefox/130.0");
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains(&(VARY.to_string(), b"user-agent".to_vec())));
+        let mut actual_body = Vec::new();
+        reader.unwrap().read_to_end(&mut actual_body).unwrap();
+        assert_eq!(actual_body, fs::read("hello-test.txt").unwrap());
+
+        // paths without a page don't depend on the client
+        let (status, headers, _) = with_user_agent(b"hello-test.txt", "Googlebot/2.1");
+        assert_eq!(status, StatusCode::OK);
+        assert!(!headers.contains(&(VARY.to_string(), b"user-agent".to_vec())));
+    }
+
+    #[test]
+    fn test_sitemap_check() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let dir =
+            std::env::temp_dir().join(format!("spin-fileserver-sitemap-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        defer! {
+            let _ = fs::remove_dir_all(&dir);
+        }
+        let dir = dir.to_str().unwrap();
+        fs::write(format!("{dir}/page.html"), "<h1>Page</h1>").unwrap();
+        fs::write(
+            format!("{dir}/sitemap.xml"),
+            format!(
+                r#"<urlset>
+                <url><loc>https://example.com/docs{dir}/page.html</loc></url>
+                <url><loc>https://example.com/docs{dir}/missing.html</loc></url>
+                <url><loc>https://example.com/blog/</loc></url>
+                </urlset>"#
+            ),
+        )
+        .unwrap();
+
+        let req = RequestContext {
+            query: format!("sitemap={dir}/sitemap.xml"),
+            route: "/docs".to_string(),
+            ..Default::default()
+        };
+        let report = sitemap::check(&req).unwrap().unwrap();
+        assert_eq!(report.checked, 2);
+        assert_eq!(
+            report.broken,
+            BTreeMap::from([(format!("https://example.com/docs{dir}/missing.html"), 404)])
+        );
+        assert!(report.redirects.is_empty());
+        assert_eq!(report.outside, ["https://example.com/blog/"]);
+
+        let req = RequestContext {
+            query: format!("sitemap={dir}/missing.xml"),
+            ..Default::default()
+        };
+        assert!(sitemap::check(&req).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_experiment_bucket() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        std::env::set_var(EXPERIMENT_SPLIT_ENV, "100");
+        defer! {
+            std::env::remove_var(EXPERIMENT_SPLIT_ENV);
+        }
+
+        // new clients are assigned a bucket, and files missing from it are served from the root
+        let (status, headers, _) = FileServer::make_response(&request(b"hello-test.txt")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains(&(VARY.to_string(), b"cookie".to_vec())));
+        let set_cookie = headers
+            .iter()
+            .find_map(|(k, v)| (k == SET_COOKIE.as_str()).then_some(v))
+            .unwrap();
+        assert!(set_cookie.starts_with(b"spin-fileserver-bucket=b;"));
+
+        // returning clients keep their bucket
+        let mut req = request(b"hello-test.txt");
+        req.headers.push((
+            COOKIE.to_string(),
+            b"theme=dark; spin-fileserver-bucket=a".to_vec(),
+        ));
+        assert_eq!(req.cookie(EXPERIMENT_COOKIE), Some("a"));
+        let (_, headers, _) = FileServer::make_response(&req).unwrap();
+        assert!(!headers.iter().any(|(k, _)| k == SET_COOKIE.as_str()));
+    }
+
+    #[test]
+    fn test_canary_assignment() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        std::env::set_var(CANARY_ROOT_ENV, "/next/");
+        defer! {
+            std::env::remove_var(CANARY_ROOT_ENV);
+            std::env::remove_var(CANARY_PERCENT_ENV);
+            std::env::remove_var(CANARY_HEADER_ENV);
+        }
+
+        std::env::set_var(CANARY_PERCENT_ENV, "0");
+        let canary = FileServer::canary(&request(b"")).unwrap();
+        assert_eq!(canary.root, "next");
+        assert!(!canary.active);
+
+        std::env::set_var(CANARY_PERCENT_ENV, "100");
+        let canary = FileServer::canary(&request(b"")).unwrap();
+        assert!(canary.active);
+        assert_eq!(canary.vary, COOKIE);
+        assert_eq!(
+            canary.cookie.unwrap().1,
+            b"spin-fileserver-canary=1; Path=/; Max-Age=2592000; SameSite=Lax"
+        );
+
+        // the cookie keeps clients on their assignment
+        let mut req = request(b"");
+        req.headers
+            .push((COOKIE.to_string(), b"spin-fileserver-canary=0".to_vec()));
+        let canary = FileServer::canary(&req).unwrap();
+        assert!(!canary.active);
+        assert!(canary.cookie.is_none());
+
+        // hashing a header is deterministic and doesn't need a cookie
+        std::env::set_var(CANARY_PERCENT_ENV, "50");
+        std::env::set_var(CANARY_HEADER_ENV, "X-Client-Id");
+        let mut req = request(b"");
+        req.headers
+            .push(("x-client-id".to_string(), b"client-42".to_vec()));
+        let first = FileServer::canary(&req).unwrap();
+        assert!(first.cookie.is_none());
+        assert_eq!(first.vary.as_str(), "x-client-id");
+        for _ in 0..10 {
+            assert_eq!(FileServer::canary(&req).unwrap().active, first.active);
+        }
+    }
+
+    #[test]
+    fn test_admin_routes_require_token() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        // admin routes are regular (missing) paths unless a token is configured
+        let (status, ..) = FileServer::make_response(&request(b"/__fileserver/downloads")).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        std::env::set_var(admin::ADMIN_TOKEN_ENV, "s3cret");
+        defer! {
+            std::env::remove_var(admin::ADMIN_TOKEN_ENV);
+        }
+
+        let (status, headers, _) =
+            FileServer::make_response(&request(b"/__fileserver/downloads")).unwrap();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            headers,
+            vec![("www-authenticate".to_string(), b"Bearer".to_vec())]
+        );
+
+        let mut req = request(b"/__fileserver/unknown");
+        req.headers
+            .push(("authorization".to_string(), b"Bearer s3cret".to_vec()));
+        let (status, ..) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // purging the ETag cache must be explicit
+        req.path = b"/__fileserver/etag-cache".to_vec();
+        let (status, headers, _) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert!(headers.contains(&("allow".to_string(), b"DELETE".to_vec())));
+    }
+
+    #[test]
+    fn test_version_route() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let (status, ..) = FileServer::make_response(&request(b"/__fileserver/version")).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        std::env::set_var(version::VERSION_ROUTE_ENV, "true");
+        defer! {
+            std::env::remove_var(version::VERSION_ROUTE_ENV);
+        }
+
+        let (status, _, body) =
+            FileServer::make_response(&request(b"/__fileserver/version")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let mut body_str = String::new();
+        body.unwrap().read_to_string(&mut body_str).unwrap();
+        let version: serde_json::Value = serde_json::from_str(&body_str).unwrap();
+        assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(version["backend"], "filesystem");
+        assert_eq!(version["asset_manifest_sha256"], serde_json::Value::Null);
+        assert!(version["built_at"].as_str().unwrap().ends_with('Z'));
+    }
+
+    #[test]
+    fn test_small_files_compressed_once() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let small = FileServerPath::Physical(PathBuf::from("hello-test.txt"));
+        let (etag, _, body) =
+            FileServer::etag_and_body(&small, SupportedEncoding::Gzip, None, "").unwrap();
+        let body = body.unwrap();
+        assert_eq!(
+            etag,
+            hex::encode(<sha2::Sha256 as sha2::Digest>::digest(&body))
+        );
+        assert_eq!(
+            FileServer::etag(&small, SupportedEncoding::Gzip, None, "")
+                .unwrap()
+                .0,
+            etag
+        );
+        let mut contents = Vec::new();
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, fs::read("hello-test.txt").unwrap());
+
+        // unencoded and larger files are read again when sent
+        let (_, _, body) =
+            FileServer::etag_and_body(&small, SupportedEncoding::None, None, "").unwrap();
+        assert_eq!(body, None);
+        let large = std::env::temp_dir().join("spin-fileserver-large-file.txt");
+        fs::write(&large, "x".repeat(SMALL_FILE_SIZE as usize + 1)).unwrap();
+        defer! {
+            let _ = fs::remove_file(&large);
+        }
+        let (_, _, body) = FileServer::etag_and_body(
+            &FileServerPath::Physical(large.clone()),
+            SupportedEncoding::Gzip,
+            None,
+            "",
+        )
+        .unwrap();
+        assert_eq!(body, None);
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+        defer! {
+            std::env::remove_var(size_limits::MAX_FILE_SIZE_ENV);
+            std::env::remove_var(size_limits::MAX_FILE_SIZE_STATUS_ENV);
+            std::env::remove_var(size_limits::COMPRESSION_MAX_SIZE_ENV);
+        }
+
+        let respond = || {
+            let mut req = request(b"hello-test.txt");
+            req.headers
+                .push((ACCEPT_ENCODING.to_string(), b"gzip".to_vec()));
+            let (status, headers, _) = FileServer::make_response(&req).unwrap();
+            let encoding = headers
+                .iter()
+                .find(|(k, _)| k == CONTENT_ENCODING.as_str())
+                .map(|(_, v)| v.clone());
+            (status, encoding)
+        };
+
+        // the file has 14 bytes
+        std::env::set_var(size_limits::MAX_FILE_SIZE_ENV, "14");
+        assert_eq!(respond(), (StatusCode::OK, Some(b"gzip".to_vec())));
+        std::env::set_var(size_limits::MAX_FILE_SIZE_ENV, "13");
+        assert_eq!(respond(), (StatusCode::FORBIDDEN, None));
+        std::env::set_var(size_limits::MAX_FILE_SIZE_STATUS_ENV, "413");
+        assert_eq!(respond(), (StatusCode::PAYLOAD_TOO_LARGE, None));
+        std::env::remove_var(size_limits::MAX_FILE_SIZE_ENV);
+
+        std::env::set_var(size_limits::COMPRESSION_MAX_SIZE_ENV, "13");
+        assert_eq!(respond(), (StatusCode::OK, None));
+        std::env::set_var(size_limits::COMPRESSION_MAX_SIZE_ENV, "14");
+        assert_eq!(respond(), (StatusCode::OK, Some(b"gzip".to_vec())));
+    }
+
+    #[test]
+    fn test_empty_files() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let dir = std::env::temp_dir().join("spin-fileserver-empty-files");
+        fs::create_dir_all(&dir).unwrap();
+        let empty = dir.join("empty.txt").to_string_lossy().into_owned();
+        fs::write(&empty, "").unwrap();
+        defer! {
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        let respond = |headers: &[(HeaderName, &[u8])]| {
+            let mut req = request(empty.as_bytes());
+            req.headers.extend(
+                headers
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_vec())),
+            );
+            let (status, headers, reader) = FileServer::make_response(&req).unwrap();
+            let mut body = Vec::new();
+            if let Some(mut reader) = reader {
+                reader.read_to_end(&mut body).unwrap();
+            }
+            (status, headers, body)
+        };
+        let header = |headers: &[(String, Vec<u8>)], name: HeaderName| {
+            headers
+                .iter()
+                .find(|(k, _)| *k == name.as_str())
+                .map(|(_, v)| v.clone())
+        };
+
+        // empty files aren't compressed, which would only add bytes
+        let empty_etag = hex::encode(<sha2::Sha256 as sha2::Digest>::digest(b""));
+        let (status, headers, body) = respond(&[(ACCEPT_ENCODING, b"gzip, br")]);
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, b"");
+        assert_eq!(header(&headers, CONTENT_ENCODING), None);
+        assert_eq!(header(&headers, CONTENT_LENGTH), Some(b"0".to_vec()));
+        assert_eq!(
+            header(&headers, ETAG),
+            Some(format!("\"{empty_etag}\"").into_bytes())
+        );
+
+        let (status, headers, body) = respond(&[(IF_NONE_MATCH, empty_etag.as_bytes())]);
+        assert_eq!((status, body), (StatusCode::NOT_MODIFIED, Vec::new()));
+        assert_eq!(header(&headers, CONTENT_LENGTH), None);
+
+        let (status, headers, _) = respond(&[(RANGE, b"bytes=0-0")]);
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(header(&headers, CONTENT_RANGE), Some(b"bytes */0".to_vec()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_special_files() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let dir = std::env::temp_dir().join("spin-fileserver-special-files");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("regular.txt"), "regular").unwrap();
+        let fifo = dir.join("pipe");
+        let created = std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .is_ok_and(|status| status.success());
+        defer! {
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        // special files are never opened, since reading them could block or never end
+        let mut special = vec![PathBuf::from("/dev/null"), PathBuf::from("/dev/zero")];
+        if created {
+            special.push(fifo.clone());
+        }
+        for path in &special {
+            let req = request(path.to_string_lossy().as_bytes());
+            let (status, ..) = FileServer::make_response(&req).unwrap();
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", path.display());
+        }
+        assert_eq!(
+            FileServer::walk(&backend::Filesystem, &dir).unwrap(),
+            vec![dir.join("regular.txt")]
+        );
+    }
+
+    #[test]
+    fn test_minify() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let dir = std::env::temp_dir().join("spin-fileserver-minify");
+        fs::create_dir_all(&dir).unwrap();
+        let css = dir.join("site.css").to_string_lossy().into_owned();
+        fs::write(&css, "/* layout */\nbody {\n  margin: 0;\n}\n").unwrap();
+        std::env::set_var(minify::MINIFY_ENV, "text/css");
+        defer! {
+            std::env::remove_var(minify::MINIFY_ENV);
+            std::env::remove_var(minify::MINIFY_MAX_SIZE_ENV);
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        let body = || {
+            let (_, _, reader) = FileServer::make_response(&request(css.as_bytes())).unwrap();
+            let mut body = String::new();
+            reader.unwrap().read_to_string(&mut body).unwrap();
+            body
+        };
+        assert_eq!(body(), "body{margin: 0;}");
+        std::env::set_var(minify::MINIFY_MAX_SIZE_ENV, "10");
+        assert!(body().starts_with("/* layout */"));
+    }
+
+    #[test]
+    fn test_rewrite_root_urls() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let dir = std::env::temp_dir().join("spin-fileserver-rewrite-root-urls");
+        fs::create_dir_all(&dir).unwrap();
+        let page = dir.join("page.html").to_string_lossy().into_owned();
+        fs::write(&page, r#"<a href="/guide/">Guide</a>"#).unwrap();
+        std::env::set_var(subpath::REWRITE_ROOT_URLS_ENV, "true");
+        defer! {
+            std::env::remove_var(subpath::REWRITE_ROOT_URLS_ENV);
+            std::env::remove_var(request_url::TRUST_FORWARDED_HEADERS_ENV);
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        let vary_prefix = (VARY.to_string(), b"x-forwarded-prefix".to_vec());
+        let response = |prefix: &str| {
+            let mut req = request(page.as_bytes());
+            req.route = "/docs".to_string();
+            req.headers
+                .push(("x-forwarded-prefix".to_string(), prefix.as_bytes().to_vec()));
+            let (_, headers, reader) = FileServer::make_response(&req).unwrap();
+            let mut body = String::new();
+            reader.unwrap().read_to_string(&mut body).unwrap();
+            (headers, body)
+        };
+        // the prefix of untrusted clients is ignored
+        let (headers, body) = response("/blog");
+        assert_eq!(body, r#"<a href="/docs/guide/">Guide</a>"#);
+        assert!(!headers.contains(&vary_prefix));
+
+        std::env::set_var(request_url::TRUST_FORWARDED_HEADERS_ENV, "true");
+        let (headers, body) = response("/blog");
+        assert_eq!(body, r#"<a href="/blog/docs/guide/">Guide</a>"#);
+        assert!(headers.contains(&vary_prefix));
+        let (_, body) = response(r#"x"><script>alert(1)</script>"#);
+        assert_eq!(body, r#"<a href="/docs/guide/">Guide</a>"#);
+    }
+
+    #[test]
+    fn test_file_headers() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let dir = std::env::temp_dir().join("spin-fileserver-file-headers");
+        fs::create_dir_all(&dir).unwrap();
+        let gone = dir.join("gone.html").to_string_lossy().into_owned();
+        let data = dir.join("data.txt").to_string_lossy().into_owned();
+        let sidecar = format!("{data}.headers.json");
+        fs::write(
+            &gone,
+            "---\nStatus: 410\nX-Robots-Tag: noindex\n---\n<p>gone</p>",
+        )
+        .unwrap();
+        fs::write(&data, "data").unwrap();
+        fs::write(
+            &sidecar,
+            r#"{ "cache-control": "no-cache", "headers": { "X-Frame-Options": "DENY" } }"#,
+        )
+        .unwrap();
+        defer! {
+            std::env::remove_var(file_headers::FILE_HEADERS_ENV);
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        let (_, _, reader) = FileServer::make_response(&request(gone.as_bytes())).unwrap();
+        let mut body = String::new();
+        reader.unwrap().read_to_string(&mut body).unwrap();
+        assert!(body.starts_with("---"));
+
+        std::env::set_var(file_headers::FILE_HEADERS_ENV, "true");
+        let (status, headers, reader) =
+            FileServer::make_response(&request(gone.as_bytes())).unwrap();
+        assert_eq!(status, StatusCode::GONE);
+        assert!(headers.contains(&("x-robots-tag".to_string(), b"noindex".to_vec())));
+        let mut body = String::new();
+        reader.unwrap().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "<p>gone</p>");
+
+        let (status, headers, _) = FileServer::make_response(&request(data.as_bytes())).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains(&(CACHE_CONTROL.to_string(), b"no-cache".to_vec())));
+        assert!(headers.contains(&("x-frame-options".to_string(), b"DENY".to_vec())));
+        let (status, ..) = FileServer::make_response(&request(sidecar.as_bytes())).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_mock_api() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let dir = std::env::temp_dir().join("spin-fileserver-mock-api");
+        fs::create_dir_all(dir.join("users")).unwrap();
+        fs::write(dir.join("users/42.GET.json"), r#"{"id": 42}"#).unwrap();
+        fs::write(dir.join("users/42.DELETE.204.json"), "").unwrap();
+        fs::write(
+            dir.join("users.POST.json"),
+            "---\nStatus: 201\nLocation: /api/users/43\n---\n{\"id\": 43}",
+        )
+        .unwrap();
+        std::env::set_var(mock_api::MOCK_API_PREFIX_ENV, &dir);
+        defer! {
+            std::env::remove_var(mock_api::MOCK_API_PREFIX_ENV);
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        let respond = |method: http::Method, path: &str| {
+            let mut req = request(dir.join(path).to_string_lossy().as_bytes());
+            req.method = method;
+            let (status, headers, reader) = FileServer::make_response(&req).unwrap();
+            let mut body = String::new();
+            if let Some(mut reader) = reader {
+                reader.read_to_string(&mut body).unwrap();
+            }
+            (status, headers, body)
+        };
+        let (status, headers, body) = respond(http::Method::GET, "users/42");
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains(&(CONTENT_TYPE.to_string(), b"application/json".to_vec())));
+        assert_eq!(body, r#"{"id": 42}"#);
+        let (status, ..) = respond(http::Method::DELETE, "users/42");
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, headers, _) = respond(http::Method::PUT, "users/42");
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert!(headers.contains(&(ALLOW.to_string(), b"DELETE, GET".to_vec())));
+        let (status, headers, body) = respond(http::Method::POST, "users/");
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(headers.contains(&(LOCATION.to_string(), b"/api/users/43".to_vec())));
+        assert_eq!(body, r#"{"id": 43}"#);
+        let (status, ..) = respond(http::Method::GET, "users/43");
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_negotiate_formats() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let dir = std::env::temp_dir().join("spin-fileserver-negotiation");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("report.html"), "<p>report</p>").unwrap();
+        fs::write(dir.join("report.json"), "{}").unwrap();
+        let report = dir.join("report").to_string_lossy().into_owned();
+        std::env::set_var(negotiation::NEGOTIATE_FORMATS_ENV, "true");
+        defer! {
+            std::env::remove_var(negotiation::NEGOTIATE_FORMATS_ENV);
+            let _ = fs::remove_dir_all(&dir);
+        }
+        let vary = (VARY.to_string(), b"accept".to_vec());
+
+        let respond = |accept: &[u8]| {
+            let mut req = request(report.as_bytes());
+            req.headers.push((ACCEPT.to_string(), accept.to_vec()));
+            let (status, headers, _) = FileServer::make_response(&req).unwrap();
+            let content_type = headers
+                .iter()
+                .find(|(k, _)| k == CONTENT_TYPE.as_str())
+                .map(|(_, v)| v.clone());
+            (status, headers, content_type)
+        };
+        let (status, headers, content_type) = respond(b"text/html,*/*;q=0.8");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type.as_deref(), Some(&b"text/html"[..]));
+        assert!(headers.contains(&vary));
+        let (_, _, content_type) = respond(b"application/json");
+        assert_eq!(content_type.as_deref(), Some(&b"application/json"[..]));
+        let (status, headers, _) = respond(b"image/png");
+        assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+        assert!(headers.contains(&vary));
+
+        // files requested by their full name aren't negotiated
+        let html = dir.join("report.html").to_string_lossy().into_owned();
+        let (_, headers, _) = FileServer::make_response(&request(html.as_bytes())).unwrap();
+        assert!(!headers.contains(&vary));
+    }
+
+    #[test]
+    fn test_file_metadata() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let mut req = request(b"hello-test.txt");
+        req.query = "meta".to_string();
+        let (_, headers, _) = FileServer::make_response(&req).unwrap();
+        assert!(headers.contains(&(CONTENT_TYPE.to_string(), b"text/plain".to_vec())));
+
+        std::env::set_var(metadata::FILE_METADATA_ENV, "true");
+        defer! {
+            std::env::remove_var(metadata::FILE_METADATA_ENV);
+        }
+        let (status, _, body) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let mut body_str = String::new();
+        body.unwrap().read_to_string(&mut body_str).unwrap();
+        let metadata: serde_json::Value = serde_json::from_str(&body_str).unwrap();
+        assert_eq!(metadata["path"], "/hello-test.txt");
+        assert_eq!(
+            metadata["size"],
+            fs::metadata("hello-test.txt").unwrap().len()
+        );
+        assert_eq!(metadata["content_type"], "text/plain");
+        assert_eq!(metadata["encodings"][1], "br");
+
+        req.path = b"missing.txt".to_vec();
+        let (status, ..) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_server_timing() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        std::env::set_var(timing::SERVER_TIMING_ENV, "true");
+        std::env::set_var(admin::ADMIN_TOKEN_ENV, "s3cret");
+        defer! {
+            std::env::remove_var(timing::SERVER_TIMING_ENV);
+            std::env::remove_var(admin::ADMIN_TOKEN_ENV);
+        }
+
+        let server_timing = |req: &RequestContext| {
+            let (_, headers, _) = FileServer::make_response(req).unwrap();
+            headers
+                .into_iter()
+                .find_map(|(k, v)| (k == "server-timing").then_some(v))
+        };
+        let mut req = request(b"hello-test.txt");
+        assert_eq!(server_timing(&req), None);
+        req.headers = vec![("authorization".to_string(), b"Bearer s3cret".to_vec())];
+        assert!(server_timing(&req).unwrap().starts_with(b"resolve;dur="));
+    }
+
+    #[test]
+    fn test_walk_lists_files_recursively() {
+        let files = FileServer::walk(&backend::Filesystem, Path::new("src")).unwrap();
+        assert!(files.contains(&PathBuf::from("src/lib.rs")));
+        assert!(files.iter().all(|f| f.is_file()));
+        assert!(files.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn test_immutable_fingerprints() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let dir = std::env::temp_dir().join("spin-fileserver-fingerprints");
+        fs::create_dir_all(&dir).unwrap();
+        let hashed = dir.join("app.3f2a9c1d.js").to_string_lossy().into_owned();
+        fs::write(&hashed, "hashed").unwrap();
+        std::env::set_var(asset_manifest::IMMUTABLE_FINGERPRINTS_ENV, "true");
+        defer! {
+            std::env::remove_var(asset_manifest::IMMUTABLE_FINGERPRINTS_ENV);
+            let _ = fs::remove_dir_all(&dir);
+        }
+        let immutable = (
+            CACHE_CONTROL.to_string(),
+            IMMUTABLE_CACHE_CONTROL.as_bytes().to_vec(),
+        );
+
+        let (_, headers, _) = FileServer::make_response(&request(hashed.as_bytes())).unwrap();
+        assert!(headers.contains(&immutable));
+        let (_, headers, _) = FileServer::make_response(&request(b"hello-test.txt")).unwrap();
+        assert!(!headers.contains(&immutable));
+    }
+
+    #[test]
+    fn test_content_addressed() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let dir = std::env::temp_dir().join("spin-fileserver-cas");
+        fs::create_dir_all(&dir).unwrap();
+        let digest = FileServer::make_etag(Some(Box::new(Cursor::new("hello")))).unwrap();
+        let manifest = dir.join("etags.json");
+        fs::write(
+            &manifest,
+            serde_json::json!({ "hello-test.txt": { "identity": digest } }).to_string(),
+        )
+        .unwrap();
+        std::env::set_var(precompressed::ETAG_MANIFEST_ENV, &manifest);
+        std::env::set_var(content_addressed::CONTENT_ADDRESSED_ENV, "true");
+        defer! {
+            std::env::remove_var(precompressed::ETAG_MANIFEST_ENV);
+            std::env::remove_var(content_addressed::CONTENT_ADDRESSED_ENV);
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        let path = format!("/_cas/{}", digest.to_uppercase());
+        let (status, headers, _) = FileServer::make_response(&request(path.as_bytes())).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains(&(
+            CACHE_CONTROL.to_string(),
+            IMMUTABLE_CACHE_CONTROL.as_bytes().to_vec()
+        )));
+        assert!(headers.contains(&(ETAG.to_string(), format!("\"{digest}\"").into_bytes())));
+
+        let path = format!("/_cas/{}", "0".repeat(64));
+        let (status, ..) = FileServer::make_response(&request(path.as_bytes())).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // duplicates point at the first of the identical files
+        fs::write(
+            &manifest,
+            serde_json::json!({
+                "hello-test.txt": { "identity": digest },
+                "README.md": { "identity": digest },
+            })
+            .to_string(),
+        )
+        .unwrap();
+        std::env::set_var(content_addressed::CANONICAL_LOCATION_ENV, "true");
+        defer! {
+            std::env::remove_var(content_addressed::CANONICAL_LOCATION_ENV);
+        }
+        let (_, headers, _) = FileServer::make_response(&request(b"hello-test.txt")).unwrap();
+        assert!(headers.contains(&(CONTENT_LOCATION.to_string(), b"/README.md".to_vec())));
+        let (_, headers, _) = FileServer::make_response(&request(b"README.md")).unwrap();
+        assert!(!headers.iter().any(|(k, _)| k == CONTENT_LOCATION.as_str()));
+    }
+
+    #[test]
+    fn test_asset_manifest() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let dir = std::env::temp_dir().join("spin-fileserver-assets");
+        fs::create_dir_all(&dir).unwrap();
+        let logical = dir.join("app.js").to_string_lossy().into_owned();
+        let hashed = dir.join("app.3f2a9.js").to_string_lossy().into_owned();
+        let page = dir.join("page.html").to_string_lossy().into_owned();
+        fs::write(&hashed, "hashed").unwrap();
+        fs::write(&page, format!(r#"<script src="{logical}"></script>"#)).unwrap();
+        let manifest = dir.join("asset-manifest.json");
+        fs::write(
+            &manifest,
+            serde_json::json!({ logical.clone(): hashed.clone() }).to_string(),
+        )
+        .unwrap();
+        std::env::set_var(asset_manifest::ASSET_MANIFEST_ENV, &manifest);
+        std::env::set_var(asset_manifest::ASSET_MANIFEST_REWRITE_ENV, "true");
+        defer! {
+            std::env::remove_var(asset_manifest::ASSET_MANIFEST_ENV);
+            std::env::remove_var(asset_manifest::ASSET_MANIFEST_REWRITE_ENV);
+            std::env::remove_var(asset_manifest::ASSET_MANIFEST_MODE_ENV);
+            let _ = fs::remove_dir_all(&dir);
+        }
+        let immutable = (
+            CACHE_CONTROL.to_string(),
+            IMMUTABLE_CACHE_CONTROL.as_bytes().to_vec(),
+        );
+
+        let (status, headers, reader) =
+            FileServer::make_response(&request(logical.as_bytes())).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(!headers.contains(&immutable));
+        let mut actual_body = String::new();
+        reader.unwrap().read_to_string(&mut actual_body).unwrap();
+        assert_eq!(actual_body, "hashed");
+
+        let (status, headers, _) = FileServer::make_response(&request(hashed.as_bytes())).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains(&immutable));
+
+        let (_, _, reader) = FileServer::make_response(&request(page.as_bytes())).unwrap();
+        let mut actual_body = String::new();
+        reader.unwrap().read_to_string(&mut actual_body).unwrap();
+        assert_eq!(actual_body, format!(r#"<script src="{hashed}"></script>"#));
+
+        std::env::set_var(asset_manifest::ASSET_MANIFEST_MODE_ENV, "redirect");
+        let (status, headers, _) = FileServer::make_response(&request(logical.as_bytes())).unwrap();
+        assert_eq!(status, StatusCode::FOUND);
+        assert_eq!(headers, vec![(LOCATION.to_string(), hashed.into_bytes())]);
+    }
+
+    #[test]
+    fn test_root_header() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let with_root = |root: &str| {
+            let mut req = request(b"/index.html");
+            req.headers
+                .push(("spin-fileserver-root".to_string(), root.as_bytes().to_vec()));
+            req
+        };
+        // the header is ignored unless configured
+        assert_eq!(FileServer::root(&with_root("docs")), None);
+
+        std::env::set_var(ROOT_HEADER_ENV, "spin-fileserver-root");
+        defer! {
+            std::env::remove_var(ROOT_HEADER_ENV);
+        }
+        assert_eq!(
+            FileServer::root(&with_root("/sites/docs/")),
+            Some(Ok("sites/docs"))
+        );
+        assert_eq!(FileServer::root(&with_root("v1.2")), Some(Ok("v1.2")));
+        assert_eq!(FileServer::root(&request(b"/index.html")), None);
+        for root in ["..", "docs/../..", "a//b", "", "docs\\..", "%2e%2e"] {
+            assert_eq!(FileServer::root(&with_root(root)), Some(Err(())), "{root}");
+        }
+
+        let (status, ..) = FileServer::make_response(&with_root("../etc")).unwrap();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let mut req = with_root("src");
+        req.path = b"lib.rs".to_vec();
+        let (status, ..) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_mounts() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let config_path = std::env::temp_dir().join("spin-fileserver-mounts.toml");
+        fs::write(
+            &config_path,
+            r#"
+            [[mount]]
+            prefix = "/code"
+            directory = "src"
+            cache-control = "no-store"
+
+            [[mount]]
+            prefix = "/private"
+            directory = "src"
+            token = "secret"
+            "#,
+        )
+        .unwrap();
+        std::env::set_var(config::CONFIG_FILE_ENV, &config_path);
+        defer! {
+            std::env::remove_var(config::CONFIG_FILE_ENV);
+            let _ = fs::remove_file(&config_path);
+        }
+
+        let (status, headers, _) = FileServer::make_response(&request(b"code/lib.rs")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains(&("cache-control".to_string(), b"no-store".to_vec())));
+
+        let (status, ..) = FileServer::make_response(&request(b"hello-test.txt")).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, ..) = FileServer::make_response(&request(b"code/../hello-test.txt")).unwrap();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, ..) = FileServer::make_response(&request(b"private/lib.rs")).unwrap();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let mut req = request(b"private/lib.rs");
+        req.headers
+            .push(("authorization".to_string(), b"Bearer secret".to_vec()));
+        let (status, ..) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_options_and_trace() {
+        let allow = (ALLOW.to_string(), ALLOWED_METHODS.as_bytes().to_vec());
+
+        for path in [&b"*"[..], b"/hello-test.txt"] {
+            let mut req = request(path);
+            req.method = http::Method::OPTIONS;
+            let (status, headers, body) = FileServer::make_response(&req).unwrap();
+            assert_eq!(status, StatusCode::NO_CONTENT);
+            assert_eq!(headers, vec![allow.clone()]);
+            assert!(body.is_none());
+        }
+
+        let mut req = request(b"hello-test.txt");
+        req.method = http::Method::TRACE;
+        let (status, headers, _) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert!(headers.contains(&allow));
+    }
+
+    #[test]
+    fn test_unusable_fallback_paths_are_ignored() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        std::env::set_var(FALLBACK_PATH_ENV, "src");
+        std::env::set_var(CUSTOM_404_PATH_ENV, "does-not-exist.html");
+        defer! {
+            std::env::remove_var(FALLBACK_PATH_ENV);
+            std::env::remove_var(CUSTOM_404_PATH_ENV);
+        }
+        let (status, ..) = FileServer::make_response(&request(b"missing.txt")).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        std::env::set_var(CUSTOM_404_PATH_ENV, "hello-test.txt");
+        assert!(matches!(
+            FileServer::resolve("missing.txt", &[]),
+            FileServerPath::Physical(path) if path == Path::new("hello-test.txt")
+        ));
+    }
+
+    #[test]
+    fn test_reproducible() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let dir = std::env::temp_dir().join("spin-fileserver-reproducible");
+        fs::create_dir_all(&dir).unwrap();
+        let css = dir.join("site.css").to_string_lossy().into_owned();
+        let contents = "/* layout */\nbody {\n  margin: 0;\n}\n";
+        fs::write(&css, contents).unwrap();
+        let digest = FileServer::make_etag(Some(Box::new(Cursor::new(contents)))).unwrap();
+        let manifest = dir.join("etags.json");
+        fs::write(
+            &manifest,
+            serde_json::json!({ &css: { "identity": digest } }).to_string(),
+        )
+        .unwrap();
+        std::env::set_var(precompressed::ETAG_MANIFEST_ENV, &manifest);
+        std::env::set_var(reproducible::REPRODUCIBLE_ENV, "true");
+        std::env::set_var(minify::MINIFY_ENV, "text/css");
+        defer! {
+            std::env::remove_var(precompressed::ETAG_MANIFEST_ENV);
+            std::env::remove_var(reproducible::REPRODUCIBLE_ENV);
+            std::env::remove_var(minify::MINIFY_ENV);
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        let mut req = request(css.as_bytes());
+        req.headers
+            .push((ACCEPT_ENCODING.to_string(), b"gzip".to_vec()));
+        let (status, headers, reader) = FileServer::make_response(&req).unwrap();
         assert_eq!(status, StatusCode::OK);
+        assert!(!headers.iter().any(|(k, _)| k == CONTENT_ENCODING.as_str()));
+        let mut body = String::new();
+        reader.unwrap().read_to_string(&mut body).unwrap();
+        assert_eq!(body, contents);
+
+        // changed files aren't served
+        fs::write(&css, "body{margin:0}").unwrap();
+        let (status, ..) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
     }
 
     #[test]
-    fn test_serve_with_etag() {
-        let (status, _, reader) = FileServer::make_response(
-            b"./hello-test.txt",
-            SupportedEncoding::None,
-            b"4dca0fd5f424a31b03ab807cbae77eb32bf2d089eed1cee154b3afed458de0dc",
+    fn test_access_rules() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let config_path = std::env::temp_dir().join("spin-fileserver-access.toml");
+        fs::write(
+            &config_path,
+            r#"
+            [[access]]
+            path = "/src/public/**"
+            action = "allow"
+
+            [[access]]
+            path = "/src/**"
+            action = "auth-required"
+            token = "secret"
+
+            [[access]]
+            path = "/**/*.txt"
+            methods = ["POST", "PUT"]
+            action = "deny"
+
+            [[access]]
+            path = "/**"
+            headers = { "x-legacy-client" = "*" }
+            action = "redirect"
+            location = "/legacy.html"
+            "#,
         )
         .unwrap();
-        assert_eq!(status, StatusCode::NOT_MODIFIED);
-        assert!(reader.is_none());
+        std::env::set_var(config::CONFIG_FILE_ENV, &config_path);
+        defer! {
+            std::env::remove_var(config::CONFIG_FILE_ENV);
+            std::env::remove_var(CASE_INSENSITIVE_ENV);
+            let _ = fs::remove_file(&config_path);
+        }
+
+        let status = |req: &RequestContext| FileServer::make_response(req).unwrap().0;
+        assert_eq!(status(&request(b"src/lib.rs")), StatusCode::UNAUTHORIZED);
+        // other spellings of the path don't escape the rules
+        for path in [
+            "/./src/lib.rs",
+            "src/public/../lib.rs",
+            "//src/lib.rs",
+            "src//lib.rs",
+        ] {
+            assert_eq!(
+                status(&request(path.as_bytes())),
+                StatusCode::BAD_REQUEST,
+                "{path}"
+            );
+        }
+        std::env::set_var(CASE_INSENSITIVE_ENV, "serve");
+        assert_eq!(status(&request(b"SRC/lib.rs")), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(&request(b"Src/Lib.rs")), StatusCode::UNAUTHORIZED);
+        std::env::remove_var(CASE_INSENSITIVE_ENV);
+        let mut req = request(b"src/lib.rs");
+        req.headers
+            .push(("authorization".to_string(), b"Bearer secret".to_vec()));
+        assert_eq!(status(&req), StatusCode::OK);
+        assert_eq!(status(&request(b"src/public/x")), StatusCode::NOT_FOUND);
+
+        let mut req = request(b"hello-test.txt");
+        assert_eq!(status(&req), StatusCode::OK);
+        req.method = http::Method::POST;
+        assert_eq!(status(&req), StatusCode::FORBIDDEN);
+
+        let mut req = request(b"hello-test.txt");
+        req.headers
+            .push(("x-legacy-client".to_string(), b"1".to_vec()));
+        let (status, headers, _) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::FOUND);
+        assert!(headers.contains(&(LOCATION.to_string(), b"/legacy.html".to_vec())));
     }
 
     #[test]
-    fn test_serve_file_not_found() {
-        let (status, _, reader) =
-            FileServer::make_response(b"non-exisitent-file", SupportedEncoding::None, b"").unwrap();
-        assert_eq!(status, StatusCode::NOT_FOUND);
-        let mut actual_body = Vec::new();
-        reader.unwrap().read_to_end(&mut actual_body).unwrap();
-        assert_eq!(actual_body.as_slice(), b"Not Found");
+    fn test_oidc_login_required() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        std::env::set_var(oidc::OIDC_ISSUER_ENV, "https://id.example.com");
+        std::env::set_var(oidc::OIDC_PREFIXES_ENV, "/src");
+        defer! {
+            std::env::remove_var(oidc::OIDC_ISSUER_ENV);
+            std::env::remove_var(oidc::OIDC_PREFIXES_ENV);
+            std::env::remove_var(oidc::OIDC_SESSION_SECRET_ENV);
+            std::env::remove_var(CASE_INSENSITIVE_ENV);
+        }
+
+        let status = |req: &RequestContext| FileServer::make_response(req).unwrap().0;
+        // sessions can't be checked without a secret
+        assert_eq!(
+            status(&request(b"src/lib.rs")),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        std::env::set_var(oidc::OIDC_SESSION_SECRET_ENV, "s3cret");
+        assert_eq!(status(&request(b"hello-test.txt")), StatusCode::OK);
+
+        let mut req = request(b"src/lib.rs");
+        req.method = http::Method::POST;
+        req.headers.push((
+            COOKIE.to_string(),
+            b"spin-fileserver-session=forged.cookie".to_vec(),
+        ));
+        assert_eq!(status(&req), StatusCode::UNAUTHORIZED);
+        // other spellings of a protected path don't skip the login
+        req.path = b"/./src/lib.rs".to_vec();
+        assert_eq!(status(&req), StatusCode::BAD_REQUEST);
+        req.path = b"hello/../src/lib.rs".to_vec();
+        assert_eq!(status(&req), StatusCode::BAD_REQUEST);
+        std::env::set_var(CASE_INSENSITIVE_ENV, "serve");
+        req.path = b"SRC/lib.rs".to_vec();
+        assert_eq!(status(&req), StatusCode::UNAUTHORIZED);
+        std::env::remove_var(CASE_INSENSITIVE_ENV);
+
+        let mut req = request(b"/__fileserver/oidc/callback");
+        req.query = "code=abc&state=forged.state".to_string();
+        assert_eq!(status(&req), StatusCode::BAD_REQUEST);
     }
 
     #[test]
-    fn test_serve_custom_404() {
+    fn test_session_gate() {
         let _lock = TEST_ENV_MUTEX.lock().unwrap();
 
-        // reuse existing asset as custom 404 doc
-        let custom_404_path = "hello-test.txt";
-        let expected_body =
-            fs::read(Path::new(custom_404_path)).expect("Could not read custom 404 file");
+        std::env::set_var(session_gate::SESSION_COOKIE_ENV, "sid");
+        std::env::set_var(session_gate::SESSION_PREFIXES_ENV, "/src");
+        defer! {
+            std::env::remove_var(session_gate::SESSION_COOKIE_ENV);
+            std::env::remove_var(session_gate::SESSION_PREFIXES_ENV);
+            std::env::remove_var(session_gate::SESSION_LOGIN_URL_ENV);
+            std::env::remove_var(CASE_INSENSITIVE_ENV);
+        }
 
-        std::env::set_var(CUSTOM_404_PATH_ENV, custom_404_path);
+        let (status, ..) = FileServer::make_response(&request(b"hello-test.txt")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let mut req = request(b"src/lib.rs");
+        req.headers
+            .push((COOKIE.to_string(), b"sid=not a session".to_vec()));
+        let (status, ..) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        // other spellings of a gated path need a session too
+        for path in ["/./src/lib.rs", "hello/../src/lib.rs", "//src/lib.rs"] {
+            req.path = path.as_bytes().to_vec();
+            let (status, ..) = FileServer::make_response(&req).unwrap();
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{path}");
+        }
+        std::env::set_var(CASE_INSENSITIVE_ENV, "serve");
+        req.path = b"Src/lib.rs".to_vec();
+        let (status, ..) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        std::env::remove_var(CASE_INSENSITIVE_ENV);
+        req.path = b"src/lib.rs".to_vec();
+
+        std::env::set_var(session_gate::SESSION_LOGIN_URL_ENV, "/login?app=docs");
+        req.query = "v=1".to_string();
+        let (status, headers, _) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::FOUND);
+        assert!(headers.contains(&(
+            LOCATION.to_string(),
+            b"/login?app=docs&return_to=/src/lib.rs%3Fv%3D1".to_vec()
+        )));
+    }
+
+    #[test]
+    fn test_method_rules() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let config_path = std::env::temp_dir().join("spin-fileserver-methods.toml");
+        fs::write(
+            &config_path,
+            r#"
+            [[methods]]
+            path = "/src/**"
+            allow = ["GET", "HEAD"]
+
+            [[methods]]
+            path = "/**/*.txt"
+            allow = ["GET", "OPTIONS", "POST"]
+            "#,
+        )
+        .unwrap();
+        std::env::set_var(config::CONFIG_FILE_ENV, &config_path);
         defer! {
-            std::env::remove_var(CUSTOM_404_PATH_ENV);
+            std::env::remove_var(config::CONFIG_FILE_ENV);
+            let _ = fs::remove_file(&config_path);
         }
 
-        let (status, _, reader) =
-            FileServer::make_response(b"non-exisitent-file", SupportedEncoding::None, b"").unwrap();
+        let respond = |method, path: &[u8]| {
+            let mut req = request(path);
+            req.method = method;
+            FileServer::make_response(&req).unwrap()
+        };
+        let (status, headers, _) = respond(http::Method::OPTIONS, b"src/lib.rs");
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert!(headers.contains(&(ALLOW.to_string(), b"GET, HEAD".to_vec())));
+        let (status, headers, _) = respond(http::Method::OPTIONS, b"hello-test.txt");
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(headers.contains(&(ALLOW.to_string(), b"GET, OPTIONS, POST".to_vec())));
+        let (status, ..) = respond(http::Method::HEAD, b"hello-test.txt");
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        let (status, ..) = respond(http::Method::GET, b"hello-test.txt");
         assert_eq!(status, StatusCode::OK);
-        let mut actual_body = Vec::new();
-        reader.unwrap().read_to_end(&mut actual_body).unwrap();
-        assert_eq!(actual_body, expected_body);
+        // paths without a rule keep the default methods
+        let (status, headers, _) = respond(http::Method::OPTIONS, b"README.md");
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(headers.contains(&(ALLOW.to_string(), ALLOWED_METHODS.as_bytes().to_vec())));
     }
 
     #[test]
-    fn test_serve_non_existing_custom_404() {
+    fn test_request_path() {
+        let path = |path_info: Option<&[u8]>, route: Option<&[u8]>, uri: &[u8]| {
+            let (path, route) = request_path(path_info, route, uri);
+            (
+                String::from_utf8(path).unwrap(),
+                String::from_utf8(route).unwrap(),
+            )
+        };
+        let owned = |path: &str, route: &str| (path.to_string(), route.to_string());
+        assert_eq!(
+            path(Some(b"/a.txt"), Some(b"/static"), b"/static/a.txt"),
+            owned("/a.txt", "/static")
+        );
+        assert_eq!(
+            path(Some(b""), Some(b"/static"), b"/static"),
+            owned("/static", "/static")
+        );
+        // outside the Spin HTTP trigger the URI path is all there is
+        assert_eq!(path(None, None, b"/docs/a.txt"), owned("/docs/a.txt", ""));
+        assert_eq!(
+            path(None, Some(b"/static"), b"/static/a.txt"),
+            owned("/a.txt", "/static")
+        );
+    }
+
+    /// Render the status and headers of a response, in order, for a snapshot.
+    fn head(response: Response) -> String {
+        let (status, headers, _) = response;
+        let mut head = format!("{status}\n");
+        for (name, value) in headers {
+            head += &format!("{name}: {}\n", String::from_utf8_lossy(&value));
+        }
+        head
+    }
+
+    #[test]
+    fn test_snapshot_compressed() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+        let req = RequestContext {
+            headers: vec![(ACCEPT_ENCODING.to_string(), b"br".to_vec())],
+            ..request(b"hello-test.txt")
+        };
+        insta::assert_snapshot!(head(FileServer::make_response(&req).unwrap()));
+    }
+
+    #[test]
+    fn test_snapshot_fallback() {
         let _lock = TEST_ENV_MUTEX.lock().unwrap();
+        std::env::set_var(FALLBACK_PATH_ENV, "hello-test.txt");
+        defer! {
+            std::env::remove_var(FALLBACK_PATH_ENV);
+        }
+        let response = FileServer::make_response(&request(b"no-such-file.txt")).unwrap();
+        insta::assert_snapshot!(head(response));
+    }
 
-        // provide a invalid path
-        let custom_404_path = "non-existing-404.html";
+    #[test]
+    fn test_snapshot_not_found() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+        let response = FileServer::make_response(&request(b"no-such-file.txt")).unwrap();
+        insta::assert_snapshot!(head(response));
+    }
 
-        std::env::set_var(CUSTOM_404_PATH_ENV, custom_404_path);
+    #[test]
+    fn test_snapshot_favicon() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+        let response = FileServer::make_response(&request(b"favicon.ico")).unwrap();
+        insta::assert_snapshot!(head(response));
+    }
+
+    #[test]
+    fn test_snapshot_immutable() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+        let dir = std::env::temp_dir().join("spin-fileserver-snapshot");
+        fs::create_dir_all(&dir).unwrap();
+        let hashed = dir.join("app.3f2a9c1d.js").to_string_lossy().into_owned();
+        fs::write(&hashed, "hashed").unwrap();
+        std::env::set_var(asset_manifest::IMMUTABLE_FINGERPRINTS_ENV, "true");
         defer! {
-            std::env::remove_var(CUSTOM_404_PATH_ENV);
+            std::env::remove_var(asset_manifest::IMMUTABLE_FINGERPRINTS_ENV);
+            let _ = fs::remove_dir_all(&dir);
         }
+        let response = FileServer::make_response(&request(hashed.as_bytes())).unwrap();
+        insta::assert_snapshot!(head(response));
+    }
 
-        let (status, _, reader) =
-            FileServer::make_response(b"non-exisitent-file", SupportedEncoding::None, b"").unwrap();
-        assert_eq!(status, StatusCode::NOT_FOUND);
-        let mut actual_body = Vec::new();
-        reader.unwrap().read_to_end(&mut actual_body).unwrap();
-        assert_eq!(actual_body.as_slice(), b"Not Found");
+    #[test]
+    fn test_legacy_pragma() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+        std::env::set_var(legacy_cache::LEGACY_PRAGMA_ENV, "true");
+        std::env::set_var(CACHE_CONTROL_ENV, "no-cache");
+        defer! {
+            std::env::remove_var(legacy_cache::LEGACY_PRAGMA_ENV);
+            std::env::remove_var(CACHE_CONTROL_ENV);
+        }
+        let pragma = (http::header::PRAGMA.to_string(), b"no-cache".to_vec());
+
+        let (_, headers, _) = FileServer::make_response(&request(b"hello-test.txt")).unwrap();
+        assert!(headers.contains(&pragma));
+
+        // a 304 has the headers a 200 would have
+        let mut req = request(b"hello-test.txt");
+        req.headers.push((
+            IF_NONE_MATCH.to_string(),
+            b"\"4dca0fd5f424a31b03ab807cbae77eb32bf2d089eed1cee154b3afed458de0dc\"".to_vec(),
+        ));
+        let (status, headers, _) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert!(headers.contains(&pragma));
+
+        std::env::set_var(CACHE_CONTROL_ENV, "max-age=60");
+        let (_, headers, _) = FileServer::make_response(&request(b"hello-test.txt")).unwrap();
+        assert!(!headers.iter().any(|(k, _)| k == "pragma"));
     }
 
     #[test]
-    fn test_serve_file_not_found_with_fallback_path() {
+    fn test_content_warning() {
         let _lock = TEST_ENV_MUTEX.lock().unwrap();
 
-        // reuse existing asset as fallback
-        let fallback_path = "hello-test.txt";
-        let expected_body =
-            fs::read(Path::new(fallback_path)).expect("Could not read fallback file");
+        std::env::set_var(content_warning::CONTENT_WARNING_PREFIXES_ENV, "/src");
+        defer! {
+            std::env::remove_var(content_warning::CONTENT_WARNING_PREFIXES_ENV);
+            std::env::remove_var(content_warning::CONTENT_WARNING_PAGE_ENV);
+        }
 
-        std::env::set_var(FALLBACK_PATH_ENV, fallback_path);
+        let (status, headers, _) = FileServer::make_response(&request(b"hello-test.txt")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(!headers.contains(&(VARY.to_string(), b"cookie".to_vec())));
+
+        let (status, headers, body) = FileServer::make_response(&request(b"src/lib.rs")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains(&(CACHE_CONTROL.to_string(), b"no-store".to_vec())));
+        let mut page = String::new();
+        body.unwrap().read_to_string(&mut page).unwrap();
+        assert!(page.contains(r#"<form method="post">"#));
+
+        // acknowledging sets the cookie and goes back to the page
+        let mut req = request(b"src/lib.rs");
+        req.method = http::Method::POST;
+        req.query = "v=1".to_string();
+        let (status, headers, _) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert!(headers.contains(&(LOCATION.to_string(), b"/src/lib.rs?v=1".to_vec())));
+        let (_, cookie) = headers
+            .iter()
+            .find(|(k, _)| k == SET_COOKIE.as_str())
+            .unwrap();
+        assert!(cookie.starts_with(b"content_warning=acknowledged; Path=/; Max-Age=86400;"));
+
+        let mut req = request(b"src/lib.rs");
+        req.headers
+            .push((COOKIE.to_string(), b"content_warning=acknowledged".to_vec()));
+        let (status, headers, _) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains(&(VARY.to_string(), b"cookie".to_vec())));
+        assert!(!headers.contains(&(CACHE_CONTROL.to_string(), b"no-store".to_vec())));
+
+        std::env::set_var(content_warning::CONTENT_WARNING_PAGE_ENV, "hello-test.txt");
+        let (_, _, body) = FileServer::make_response(&request(b"src/lib.rs")).unwrap();
+        let mut page = String::new();
+        body.unwrap().read_to_string(&mut page).unwrap();
+        assert_eq!(page, "hello, world!\n");
+    }
+
+    #[test]
+    fn test_rewrite_trace() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        std::env::set_var(rewrite_trace::REWRITE_TRACE_ENV, "true");
+        std::env::set_var(ALIASES_ENV, "/greeting=missing.txt");
+        std::env::set_var(FALLBACK_PATH_ENV, "hello-test.txt");
         defer! {
+            std::env::remove_var(rewrite_trace::REWRITE_TRACE_ENV);
+            std::env::remove_var(ALIASES_ENV);
             std::env::remove_var(FALLBACK_PATH_ENV);
         }
+        let trace = |path: &[u8]| {
+            let (_, headers, _) = FileServer::make_response(&request(path)).unwrap();
+            headers
+                .into_iter()
+                .find(|(k, _)| k == "x-rewrite-trace")
+                .map(|(_, v)| String::from_utf8(v).unwrap())
+        };
 
-        let (status, _, reader) =
-            FileServer::make_response(b"non-exisitent-file", SupportedEncoding::None, b"").unwrap();
-        assert_eq!(status, StatusCode::OK);
-        let mut actual_body = Vec::new();
-        reader.unwrap().read_to_end(&mut actual_body).unwrap();
-        assert_eq!(actual_body, expected_body);
+        assert_eq!(
+            trace(b"/greeting").as_deref(),
+            Some("alias missing.txt, fallback hello-test.txt")
+        );
+        assert_eq!(trace(b"hello-test.txt").as_deref(), Some("none"));
+
+        std::env::remove_var(rewrite_trace::REWRITE_TRACE_ENV);
+        assert_eq!(trace(b"/greeting"), None);
     }
 
     #[test]
-    fn test_serve_index() {
-        // Test against path with trailing slash
-        let (status, ..) = FileServer::make_response(b"./", SupportedEncoding::None, b"").unwrap();
+    fn test_campaign_redirects() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let config_path = std::env::temp_dir().join("spin-fileserver-campaign.toml");
+        fs::write(
+            &config_path,
+            r#"
+            [[campaign]]
+            path = "/"
+            param = "ref"
+            value = "newsletter"
+            location = "/hello-test.txt"
+
+            [[campaign]]
+            path = "/index.html"
+            referer = "^https://news\\.example\\.com/"
+            location = "/hello-test.txt"
+            "#,
+        )
+        .unwrap();
+        std::env::set_var(config::CONFIG_FILE_ENV, &config_path);
+        defer! {
+            std::env::remove_var(config::CONFIG_FILE_ENV);
+            let _ = fs::remove_file(&config_path);
+        }
+
+        let mut req = request(b"");
+        req.query = "ref=newsletter&utm_medium=email".to_string();
+        let (status, headers, _) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::FOUND);
+        assert!(headers.contains(&(
+            LOCATION.to_string(),
+            b"/hello-test.txt?ref=newsletter&utm_medium=email".to_vec()
+        )));
+        req.query = "ref=ads".to_string();
+        let (status, ..) = FileServer::make_response(&req).unwrap();
         assert_eq!(status, StatusCode::OK);
 
-        // Test against empty path
-        let (status, ..) = FileServer::make_response(b"", SupportedEncoding::None, b"").unwrap();
+        let mut req = request(b"index.html");
+        let (status, headers, _) = FileServer::make_response(&req).unwrap();
         assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains(&(VARY.to_string(), b"referer".to_vec())));
+        req.headers = vec![(
+            "referer".to_string(),
+            b"https://news.example.com/today".to_vec(),
+        )];
+        let (status, headers, _) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::FOUND);
+        assert!(headers.contains(&(VARY.to_string(), b"referer".to_vec())));
     }
 
     #[test]
-    fn test_serve_fallback_favicon() {
-        let (status, _, reader) = FileServer::make_response(
-            FAVICON_PNG_FILENAME.as_bytes(),
-            SupportedEncoding::None,
-            b"",
+    fn test_vary_rules() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let config_path = std::env::temp_dir().join("spin-fileserver-vary.toml");
+        fs::write(
+            &config_path,
+            r#"
+            [[vary]]
+            path = "/**/*.txt"
+            headers = ["Origin", "accept-encoding"]
+            "#,
         )
         .unwrap();
+        std::env::set_var(config::CONFIG_FILE_ENV, &config_path);
+        defer! {
+            std::env::remove_var(config::CONFIG_FILE_ENV);
+            let _ = fs::remove_file(&config_path);
+        }
+
+        let (_, headers, _) = FileServer::make_response(&request(b"hello-test.txt")).unwrap();
+        let vary = headers
+            .iter()
+            .filter(|(k, _)| k == VARY.as_str())
+            .map(|(_, v)| v.as_slice())
+            .collect::<Vec<_>>();
+        assert_eq!(vary, [&b"accept-encoding"[..], b"origin"]);
+
+        let (_, headers, _) = FileServer::make_response(&request(b"index.html")).unwrap();
+        assert!(!headers.contains(&(VARY.to_string(), b"origin".to_vec())));
+    }
+
+    #[test]
+    fn test_csp_nonce() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let dir = std::env::temp_dir().join("spin-fileserver-csp");
+        fs::create_dir_all(&dir).unwrap();
+        let page = dir.join("page.html").to_string_lossy().into_owned();
+        fs::write(&page, "<script>run()</script>").unwrap();
+        std::env::set_var(
+            csp::CONTENT_SECURITY_POLICY_ENV,
+            "script-src 'nonce-{nonce}' 'strict-dynamic'",
+        );
+        defer! {
+            std::env::remove_var(csp::CONTENT_SECURITY_POLICY_ENV);
+            let _ = fs::remove_dir_all(&dir);
+        }
+        use http::header::CONTENT_SECURITY_POLICY;
+        let header = |headers: &[(String, Vec<u8>)], name: &HeaderName| {
+            headers
+                .iter()
+                .find(|(k, _)| k == name.as_str())
+                .map(|(_, v)| String::from_utf8(v.clone()).unwrap())
+        };
+
+        let mut req = request(page.as_bytes());
+        req.headers.push((IF_NONE_MATCH.to_string(), b"*".to_vec()));
+        let (status, headers, body) = FileServer::make_response(&req).unwrap();
         assert_eq!(status, StatusCode::OK);
-        let mut actual_body = Vec::new();
-        reader.unwrap().read_to_end(&mut actual_body).unwrap();
-        assert_eq!(actual_body, FALLBACK_FAVICON_PNG);
+        let policy = header(&headers, &CONTENT_SECURITY_POLICY).unwrap();
+        let nonce = policy
+            .strip_prefix("script-src 'nonce-")
+            .and_then(|p| p.strip_suffix("' 'strict-dynamic'"))
+            .unwrap();
+        let mut html = String::new();
+        body.unwrap().read_to_string(&mut html).unwrap();
+        assert_eq!(html, format!("<script nonce=\"{nonce}\">run()</script>"));
+        assert_eq!(header(&headers, &ETAG), None);
+        assert_eq!(
+            header(&headers, &CACHE_CONTROL).as_deref(),
+            Some("no-store")
+        );
+
+        let (_, headers, _) = FileServer::make_response(&request(page.as_bytes())).unwrap();
+        assert_ne!(header(&headers, &CONTENT_SECURITY_POLICY).unwrap(), policy);
+
+        // policies without a nonce are sent as they are, and other files don't get one
+        std::env::set_var(csp::CONTENT_SECURITY_POLICY_ENV, "default-src 'self'");
+        let (_, headers, _) = FileServer::make_response(&request(page.as_bytes())).unwrap();
+        assert_eq!(
+            header(&headers, &CONTENT_SECURITY_POLICY).as_deref(),
+            Some("default-src 'self'")
+        );
+        assert!(header(&headers, &ETAG).is_some());
+        let (_, headers, _) = FileServer::make_response(&request(b"hello-test.txt")).unwrap();
+        assert_eq!(header(&headers, &CONTENT_SECURITY_POLICY), None);
+    }
+
+    #[test]
+    fn test_drafts() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        std::env::set_var(ALIASES_ENV, "/drafts/launch=hello-test.txt");
+        std::env::set_var(drafts::DRAFTS_SECRET_ENV, "s3cret");
+        std::env::set_var(admin::ADMIN_TOKEN_ENV, "t0ken");
+        defer! {
+            std::env::remove_var(ALIASES_ENV);
+            std::env::remove_var(drafts::DRAFTS_SECRET_ENV);
+            std::env::remove_var(admin::ADMIN_TOKEN_ENV);
+        }
+
+        let (status, _, _) = FileServer::make_response(&request(b"/drafts/launch")).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        // other spellings of a draft aren't served without a token either
+        for path in ["/./drafts/launch", "/x/../drafts/launch", "//drafts/launch"] {
+            let (status, _, _) = FileServer::make_response(&request(path.as_bytes())).unwrap();
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{path}");
+        }
+        assert!(drafts::applies("drafts//launch"));
+        assert!(drafts::applies("/x/../drafts/launch"));
+
+        // the admin route issues a link to the drafts under a path
+        let mut req = request(b"/__fileserver/preview/launch");
+        req.method = http::Method::POST;
+        req.headers
+            .push(("authorization".to_string(), b"Bearer t0ken".to_vec()));
+        let (status, _, body) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let issued: serde_json::Value = serde_json::from_reader(body.unwrap()).unwrap();
+        let url = issued["url"].as_str().unwrap();
+        let token = url.strip_prefix("/drafts/launch?preview=").unwrap();
+
+        // the link sets the token as a cookie, and redirects to the draft without it
+        let mut req = request(b"/drafts/launch");
+        req.query = format!("v=2&preview={token}");
+        let (status, headers, _) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert!(headers.contains(&(LOCATION.to_string(), b"/drafts/launch?v=2".to_vec())));
+        let (_, cookie) = headers
+            .iter()
+            .find(|(k, _)| k == SET_COOKIE.as_str())
+            .unwrap();
+        let cookie = String::from_utf8(cookie.clone()).unwrap();
+        assert!(cookie.starts_with(&format!("spin-fileserver-preview={token}; Path=/drafts;")));
+
+        let mut req = request(b"/drafts/launch");
+        req.headers.push((
+            COOKIE.to_string(),
+            format!("spin-fileserver-preview={token}").into_bytes(),
+        ));
+        let (status, headers, _) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains(&(VARY.to_string(), b"cookie".to_vec())));
+        assert!(headers.contains(&(CACHE_CONTROL.to_string(), b"private, no-store".to_vec())));
+        assert!(headers.contains(&("x-robots-tag".to_string(), b"noindex".to_vec())));
+
+        // tokens only give access to the drafts they were issued for
+        let mut req = request(b"/drafts/other");
+        req.query = format!("preview={token}");
+        let (status, _, _) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let mut req = request(b"/drafts/launch");
+        req.headers.push((
+            COOKIE.to_string(),
+            format!("spin-fileserver-preview={token}x").into_bytes(),
+        ));
+        let (status, _, _) = FileServer::make_response(&req).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_root_isolation() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        // the roots of two tenants, "src" and "benches"
+        let with_root = |root: &str, path: &str| {
+            let mut req = request(path.as_bytes());
+            req.headers
+                .push(("spin-fileserver-root".to_string(), root.as_bytes().to_vec()));
+            req
+        };
+        std::env::set_var(ROOT_HEADER_ENV, "spin-fileserver-root");
+        defer! {
+            std::env::remove_var(ROOT_HEADER_ENV);
+            std::env::remove_var(ALIASES_ENV);
+            std::env::remove_var(FALLBACK_PATH_ENV);
+            std::env::remove_var(CUSTOM_404_PATH_ENV);
+            std::env::remove_var(CASE_INSENSITIVE_ENV);
+        }
+
+        let (status, ..) = FileServer::make_response(&with_root("benches", "pipeline.rs")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        for path in ["../src/lib.rs", "./../src/lib.rs", "x/../../src/lib.rs"] {
+            let (status, ..) = FileServer::make_response(&with_root("benches", path)).unwrap();
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{path}");
+        }
+
+        // rewrites are resolved within the root too
+        std::env::set_var(ALIASES_ENV, "/shared=../src/lib.rs,/bench=pipeline.rs");
+        let (status, ..) = FileServer::make_response(&with_root("benches", "shared")).unwrap();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, ..) = FileServer::make_response(&with_root("benches", "bench")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        std::env::remove_var(ALIASES_ENV);
+
+        // fallback and custom 404 documents come from the root of the request, or nowhere
+        std::env::set_var(FALLBACK_PATH_ENV, "lib.rs");
+        let (status, _, body) = FileServer::make_response(&with_root("src", "missing")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let mut served = String::new();
+        body.unwrap().read_to_string(&mut served).unwrap();
+        assert!(served.contains("fn resolve_traced"));
+        let (status, ..) = FileServer::make_response(&with_root("benches", "missing")).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        std::env::set_var(FALLBACK_PATH_ENV, "../src/lib.rs");
+        let (status, ..) = FileServer::make_response(&with_root("benches", "missing")).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        std::env::remove_var(FALLBACK_PATH_ENV);
+
+        std::env::set_var(CUSTOM_404_PATH_ENV, "pipeline.rs");
+        let (status, _, body) =
+            FileServer::make_response(&with_root("benches", "missing")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let mut served = String::new();
+        body.unwrap().read_to_string(&mut served).unwrap();
+        assert!(served.contains("criterion_main!"));
+        let (status, _, body) = FileServer::make_response(&with_root("src", "missing")).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let mut served = String::new();
+        body.unwrap().read_to_string(&mut served).unwrap();
+        assert_eq!(served, "Not Found");
+        std::env::remove_var(CUSTOM_404_PATH_ENV);
+
+        // redirects point at the path clients request, without the root
+        std::env::set_var(CASE_INSENSITIVE_ENV, "redirect");
+        let (status, headers, _) =
+            FileServer::make_response(&with_root("benches", "Pipeline.rs")).unwrap();
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+        assert!(headers.contains(&(LOCATION.to_string(), b"pipeline.rs".to_vec())));
+    }
+
+    #[test]
+    fn test_subdomain_roots() {
+        let _lock = TEST_ENV_MUTEX.lock().unwrap();
+
+        let on_host = |host: &str, path: &str| {
+            let mut req = request(path.as_bytes());
+            req.headers
+                .push(("host".to_string(), host.as_bytes().to_vec()));
+            req
+        };
+        std::env::set_var(subdomains::SUBDOMAIN_ROOTS_ENV, "*.preview.example.com=src");
+        defer! {
+            std::env::remove_var(subdomains::SUBDOMAIN_ROOTS_ENV);
+            std::env::remove_var(subdomains::SUBDOMAIN_PATTERN_ENV);
+        }
+        let snapshot = "spin_static_fs__tests__snapshot_fallback.snap";
+
+        let (status, ..) =
+            FileServer::make_response(&on_host("snapshots.preview.example.com", snapshot)).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        // other hosts are served as usual, and subdomains only from their own directory
+        let (status, ..) =
+            FileServer::make_response(&on_host("example.com", "hello-test.txt")).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let (status, ..) =
+            FileServer::make_response(&on_host("other.preview.example.com", snapshot)).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, ..) =
+            FileServer::make_response(&on_host("snapshots.preview.example.com", "../lib.rs"))
+                .unwrap();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        std::env::set_var(subdomains::SUBDOMAIN_PATTERN_ENV, "^pr-[0-9]+$");
+        let (status, ..) =
+            FileServer::make_response(&on_host("snapshots.preview.example.com", snapshot)).unwrap();
+        assert_eq!(status, StatusCode::NOT_FOUND);
     }
 }
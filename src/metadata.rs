@@ -0,0 +1,91 @@
+//! File metadata as JSON, for deployment verification scripts and download pages that show file
+//! sizes. Served for `?meta` requests when `FILE_METADATA` is enabled, and by the `stat` admin
+//! route.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{collections::BTreeMap, time::UNIX_EPOCH};
+
+use crate::{
+    asset_manifest::AssetManifest, backend, precompressed, settings, FileServer, FileServerPath,
+    RequestContext, SupportedEncoding, BROTLI_ENCODING, DEFLATE_ENCODING, GZIP_ENCODING,
+};
+
+/// Environment variable enabling the `?meta` query
+pub(crate) const FILE_METADATA_ENV: &str = "FILE_METADATA";
+/// Query parameter requesting the metadata of a file instead of its content
+const META_QUERY: &str = "meta";
+/// Name of the unencoded representation
+const IDENTITY_ENCODING: &str = "identity";
+
+/// Metadata of a served file.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct FileMetadata {
+    /// The request path.
+    path: String,
+    size: u64,
+    /// Last modification time, in seconds since the Unix epoch.
+    modified: Option<u64>,
+    content_type: Option<String>,
+    /// ETag of the unencoded file.
+    etag: String,
+    /// Content encodings the file can be served with.
+    encodings: Vec<&'static str>,
+    /// Sizes of the precompressed files, by encoding.
+    precompressed: BTreeMap<&'static str, u64>,
+}
+
+/// Check whether the request asks for the metadata of a file through the `?meta` query.
+pub(crate) fn requested(req: &RequestContext) -> bool {
+    settings::var(FILE_METADATA_ENV).is_some_and(|v| v.trim() == "true")
+        && req
+            .query
+            .split('&')
+            .any(|p| p.split('=').next() == Some(META_QUERY))
+}
+
+/// Describe the file a request path resolves to, for a component route whose path is `base`.
+/// Returns `None` if it doesn't resolve to a file.
+pub(crate) fn describe(
+    path: &str,
+    resolved: &FileServerPath,
+    assets: Option<&AssetManifest>,
+    base: &str,
+) -> Result<Option<FileMetadata>> {
+    let FileServerPath::Physical(file) = resolved else {
+        return Ok(None);
+    };
+    let backend = backend::current();
+    let size = backend
+        .size(file)
+        .with_context(|| format!("cannot read {}", file.display()))?;
+    let modified = backend
+        .modified(file)?
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+    let (etag, _) = FileServer::etag(resolved, SupportedEncoding::None, assets, base)?;
+    let mut precompressed = BTreeMap::new();
+    for (encoding, name) in [
+        (SupportedEncoding::Brotli, BROTLI_ENCODING),
+        (SupportedEncoding::Deflate, DEFLATE_ENCODING),
+        (SupportedEncoding::Gzip, GZIP_ENCODING),
+    ] {
+        if let Some(sibling) = precompressed::sibling(file, encoding) {
+            precompressed.insert(name, backend.size(&sibling)?);
+        }
+    }
+    Ok(Some(FileMetadata {
+        path: format!("/{}", path.trim_start_matches('/')),
+        size,
+        modified,
+        content_type: FileServer::mime(&file.to_string_lossy()),
+        etag,
+        encodings: vec![
+            IDENTITY_ENCODING,
+            BROTLI_ENCODING,
+            DEFLATE_ENCODING,
+            GZIP_ENCODING,
+        ],
+        precompressed,
+    }))
+}
@@ -0,0 +1,462 @@
+//! Minification of HTML, CSS and JavaScript responses, for sites whose build doesn't minify.
+//!
+//! The minifiers are conservative: they strip comments and collapse whitespace, and leave
+//! everything else alone, including the contents of strings, template literals, regular
+//! expressions and `<pre>`, `<textarea>`, `<script>` and `<style>` elements. Comments starting
+//! with `/*!` are kept, since they usually hold licenses. Line breaks are kept where whitespace
+//! held one, so automatic semicolon insertion works the same in scripts.
+
+use std::path::Path;
+
+use crate::{backend, settings};
+
+/// Environment variable for the comma-separated media types to minify
+pub(crate) const MINIFY_ENV: &str = "MINIFY";
+/// Environment variable for the size of the largest files to minify, in bytes
+pub(crate) const MINIFY_MAX_SIZE_ENV: &str = "MINIFY_MAX_SIZE";
+/// Size of the largest files minified by default, since files are minified in memory
+pub(crate) const DEFAULT_MINIFY_MAX_SIZE: &str = "1048576";
+/// Elements whose contents are kept as they are
+const RAW_ELEMENTS: [&str; 4] = ["pre", "textarea", "script", "style"];
+/// Keywords after which a `/` starts a regular expression rather than a division
+const REGEX_KEYWORDS: [&str; 9] = [
+    "return",
+    "typeof",
+    "instanceof",
+    "in",
+    "of",
+    "new",
+    "delete",
+    "void",
+    "case",
+];
+
+/// The languages that can be minified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Language {
+    Html,
+    Css,
+    Js,
+}
+
+impl Language {
+    /// Return the language of a media type.
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        let essence = media_type.split(';').next().unwrap_or_default().trim();
+        match essence.to_ascii_lowercase().as_str() {
+            "text/html" => Some(Self::Html),
+            "text/css" => Some(Self::Css),
+            "text/javascript" | "application/javascript" => Some(Self::Js),
+            _ => None,
+        }
+    }
+}
+
+/// Check whether files of a media type can be minified.
+pub(crate) fn is_minifiable(media_type: &str) -> bool {
+    Language::from_media_type(media_type).is_some()
+}
+
+/// Return the language to minify a file as, if its media type is configured and it isn't too
+/// large.
+fn language(file: &Path) -> Option<Language> {
+    let configured = settings::var(MINIFY_ENV)?;
+    let language = Language::from_media_type(mime_guess::from_path(file).first()?.as_ref())?;
+    let max_size = settings::var(MINIFY_MAX_SIZE_ENV)
+        .unwrap_or_else(|| DEFAULT_MINIFY_MAX_SIZE.to_string())
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    let enabled = configured
+        .split(',')
+        .any(|m| Language::from_media_type(m) == Some(language));
+    (enabled
+        && backend::current()
+            .size(file)
+            .is_ok_and(|size| size <= max_size))
+    .then_some(language)
+}
+
+/// Check whether a file is minified when served.
+pub(crate) fn applies(file: &Path) -> bool {
+    language(file).is_some()
+}
+
+/// Minify the contents of a file, if it is minified when served.
+pub(crate) fn minify(file: &Path, contents: String) -> String {
+    match language(file) {
+        Some(Language::Html) => html(&contents),
+        Some(Language::Css) => css(&contents),
+        Some(Language::Js) => js(&contents),
+        None => contents,
+    }
+}
+
+/// Minify an HTML document.
+fn html(src: &str) -> String {
+    let bytes = src.as_bytes();
+    let lower = src.to_ascii_lowercase();
+    let mut out = String::with_capacity(src.len());
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let rest = &src[pos..];
+        // conditional comments are instructions for old browsers
+        if rest.starts_with("<!--") && !rest.starts_with("<!--[if") {
+            match rest.find("-->") {
+                Some(end) => pos += end + 3,
+                None => {
+                    out.push_str(rest);
+                    pos = bytes.len();
+                }
+            }
+        } else if rest.starts_with('<') {
+            let end = tag_end(rest);
+            let tag = &rest[..end];
+            push_collapsed(&mut out, tag, true);
+            pos += end;
+            if let Some(name) = raw_element(tag) {
+                let close = lower[pos..]
+                    .find(&format!("</{name}"))
+                    .map_or(bytes.len(), |i| pos + i);
+                out.push_str(&src[pos..close]);
+                pos = close;
+            }
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            push_collapsed(&mut out, &rest[..end], false);
+            pos += end;
+        }
+    }
+    out
+}
+
+/// Return the length of the tag at the start of `src`, up to and including its `>`.
+fn tag_end(src: &str) -> usize {
+    let mut quote = None;
+    for (i, b) in src.bytes().enumerate().skip(1) {
+        match (quote, b) {
+            (Some(q), _) if b == q => quote = None,
+            (None, b'"' | b'\'') => quote = Some(b),
+            (None, b'>') => return i + 1,
+            _ => {}
+        }
+    }
+    src.len()
+}
+
+/// Return the name of the element a tag opens, if its contents are kept as they are.
+fn raw_element(tag: &str) -> Option<&'static str> {
+    let name = tag
+        .strip_prefix('<')?
+        .split(|c: char| c.is_ascii_whitespace() || c == '>' || c == '/')
+        .next()?;
+    RAW_ELEMENTS
+        .into_iter()
+        .find(|raw| raw.eq_ignore_ascii_case(name))
+}
+
+/// Append text with whitespace runs collapsed to a line break if they hold one, or else a space.
+/// Whitespace in quoted attribute values is kept if `in_tag`.
+fn push_collapsed(out: &mut String, text: &str, in_tag: bool) {
+    let mut quote = None;
+    let mut whitespace = None;
+    for c in text.chars() {
+        if quote.is_none() && c.is_ascii_whitespace() {
+            whitespace = Some(whitespace == Some(true) || c == '\n');
+            continue;
+        }
+        if let Some(newline) = whitespace.take() {
+            push_whitespace(out, newline);
+        }
+        if in_tag {
+            match quote {
+                Some(q) if c == q => quote = None,
+                None if c == '"' || c == '\'' => quote = Some(c),
+                _ => {}
+            }
+        }
+        out.push(c);
+    }
+    if let Some(newline) = whitespace {
+        push_whitespace(out, newline);
+    }
+}
+
+/// Append collapsed whitespace, merging it with whitespace the output ends with, e.g. around a
+/// removed comment.
+fn push_whitespace(out: &mut String, newline: bool) {
+    match out.chars().last() {
+        Some('\n') => {}
+        Some(' ') if newline => {
+            out.pop();
+            out.push('\n');
+        }
+        Some(' ') => {}
+        _ => out.push(if newline { '\n' } else { ' ' }),
+    }
+}
+
+/// Minify a style sheet.
+fn css(src: &str) -> String {
+    let bytes = src.as_bytes();
+    let mut out = String::with_capacity(src.len());
+    let mut pos = 0;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'/' if src[pos..].starts_with("/*") => {
+                let end = src[pos + 2..]
+                    .find("*/")
+                    .map_or(bytes.len(), |i| pos + i + 4);
+                if src[pos..].starts_with("/*!") {
+                    out.push_str(&src[pos..end]);
+                }
+                pos = end;
+            }
+            quote @ (b'"' | b'\'') => {
+                let end = string_end(bytes, pos, quote);
+                out.push_str(&src[pos..end]);
+                pos = end;
+            }
+            b if b.is_ascii_whitespace() => {
+                while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+                    pos += 1;
+                }
+                // whitespace around punctuation is never significant
+                let next = bytes.get(pos).copied();
+                let previous = out.bytes().last();
+                let around = |b: Option<u8>| matches!(b, None | Some(b'{' | b'}' | b';' | b','));
+                if !around(previous) && !around(next) {
+                    out.push(' ');
+                }
+            }
+            _ => {
+                let end = pos
+                    + src[pos..]
+                        .find(|c: char| "/\"'".contains(c) || c.is_ascii_whitespace())
+                        .unwrap_or(bytes.len() - pos)
+                        .max(1);
+                out.push_str(&src[pos..end]);
+                pos = end;
+            }
+        }
+    }
+    out
+}
+
+/// Minify a script.
+fn js(src: &str) -> String {
+    let bytes = src.as_bytes();
+    let mut out = String::with_capacity(src.len());
+    let mut pos = 0;
+    // open braces of each `${` substitution of the template literals being scanned
+    let mut substitutions = Vec::<usize>::new();
+    // whitespace to write before the next token, and whether it holds a line break
+    let mut pending = None::<bool>;
+    while pos < bytes.len() {
+        let b = bytes[pos];
+        let comment = src[pos..].starts_with("//") || src[pos..].starts_with("/*");
+        if b.is_ascii_whitespace() || comment && !src[pos..].starts_with("/*!") {
+            let end = if b.is_ascii_whitespace() {
+                pos + 1
+            } else if src[pos..].starts_with("//") {
+                src[pos..].find('\n').map_or(bytes.len(), |i| pos + i)
+            } else {
+                src[pos + 2..]
+                    .find("*/")
+                    .map_or(bytes.len(), |i| pos + i + 4)
+            };
+            // comments holding a line break still end statements
+            pending = Some(pending == Some(true) || src[pos..end].contains('\n'));
+            pos = end;
+            continue;
+        }
+        if let Some(newline) = pending.take().filter(|_| !out.is_empty()) {
+            out.push(if newline { '\n' } else { ' ' });
+        }
+        match b {
+            b'/' if src[pos..].starts_with("/*!") => {
+                let end = src[pos + 2..]
+                    .find("*/")
+                    .map_or(bytes.len(), |i| pos + i + 4);
+                out.push_str(&src[pos..end]);
+                pos = end;
+            }
+            b'/' if starts_regex(&out) => {
+                let end = regex_end(bytes, pos);
+                out.push_str(&src[pos..end]);
+                pos = end;
+            }
+            quote @ (b'"' | b'\'') => {
+                let end = string_end(bytes, pos, quote);
+                out.push_str(&src[pos..end]);
+                pos = end;
+            }
+            b'`' => {
+                let end = template_end(bytes, pos + 1);
+                out.push_str(&src[pos..end]);
+                pos = end;
+                if src[..end].ends_with("${") {
+                    substitutions.push(0);
+                }
+            }
+            b'{' => {
+                if let Some(depth) = substitutions.last_mut() {
+                    *depth += 1;
+                }
+                out.push('{');
+                pos += 1;
+            }
+            b'}' if substitutions.last() == Some(&0) => {
+                // the end of a substitution resumes its template literal
+                substitutions.pop();
+                let end = template_end(bytes, pos + 1);
+                out.push_str(&src[pos..end]);
+                pos = end;
+                if src[..end].ends_with("${") {
+                    substitutions.push(0);
+                }
+            }
+            b'}' => {
+                if let Some(depth) = substitutions.last_mut() {
+                    *depth -= 1;
+                }
+                out.push('}');
+                pos += 1;
+            }
+            _ => {
+                let end = pos
+                    + src[pos..]
+                        .find(|c: char| "/\"'`{}".contains(c) || c.is_ascii_whitespace())
+                        .unwrap_or(bytes.len() - pos)
+                        .max(1);
+                out.push_str(&src[pos..end]);
+                pos = end;
+            }
+        }
+    }
+    out
+}
+
+/// Check whether a `/` following the minified output starts a regular expression.
+fn starts_regex(out: &str) -> bool {
+    let before = out.trim_end();
+    let Some(last) = before.chars().last() else {
+        return true;
+    };
+    if "(,=:[!&|?{};+-*%<>~^".contains(last) {
+        return true;
+    }
+    let word = before
+        .rsplit(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '$'))
+        .next()
+        .unwrap_or_default();
+    REGEX_KEYWORDS.contains(&word)
+}
+
+/// Return the end of the string starting with the quote at `start`, past its closing quote.
+fn string_end(bytes: &[u8], start: usize, quote: u8) -> usize {
+    let mut pos = start + 1;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'\\' => pos += 2,
+            b if b == quote => return pos + 1,
+            // unterminated strings end with their line
+            b'\n' => return pos,
+            _ => pos += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// Return the end of the part of a template literal starting at `start`, past its closing
+/// backtick or the `${` of its next substitution.
+fn template_end(bytes: &[u8], start: usize) -> usize {
+    let mut pos = start;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'\\' => pos += 2,
+            b'`' => return pos + 1,
+            b'$' if bytes.get(pos + 1) == Some(&b'{') => return pos + 2,
+            _ => pos += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// Return the end of the regular expression starting at `start`, past its flags.
+fn regex_end(bytes: &[u8], start: usize) -> usize {
+    let mut pos = start + 1;
+    let mut in_class = false;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'\\' => pos += 2,
+            b'[' => {
+                in_class = true;
+                pos += 1;
+            }
+            b']' => {
+                in_class = false;
+                pos += 1;
+            }
+            b'/' if !in_class => {
+                pos += 1;
+                while pos < bytes.len() && bytes[pos].is_ascii_alphabetic() {
+                    pos += 1;
+                }
+                return pos;
+            }
+            b'\n' => return pos,
+            _ => pos += 1,
+        }
+    }
+    bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_html() {
+        assert_eq!(
+            html("<!doctype html>\n<html>\n  <!-- nav -->\n  <p   class=\"a  b\">Hello,   world</p>\n</html>\n"),
+            "<!doctype html>\n<html>\n<p class=\"a  b\">Hello, world</p>\n</html>\n"
+        );
+        assert_eq!(
+            html("<PRE>  keep\n   this </PRE>  <script>if (a  <  b) {}</script>"),
+            "<PRE>  keep\n   this </PRE> <script>if (a  <  b) {}</script>"
+        );
+        assert_eq!(
+            html("<!--[if IE]>x<![endif]-->"),
+            "<!--[if IE]>x<![endif]-->"
+        );
+    }
+
+    #[test]
+    fn test_css() {
+        assert_eq!(
+            css("/* reset */\nbody ,  p {\n  margin : 0;\n  font-family: \"Open  Sans\";\n}\na :hover { }\n/*! MIT */"),
+            "body,p{margin : 0;font-family: \"Open  Sans\";}a :hover{}/*! MIT */"
+        );
+    }
+
+    #[test]
+    fn test_js() {
+        assert_eq!(
+            js("// setup\nconst a = 1;   /* one */\nconst b = a / 2 // half\n"),
+            "const a = 1;\nconst b = a / 2"
+        );
+        assert_eq!(
+            js("const url = 'http://example.com'; /*! MIT */"),
+            "const url = 'http://example.com'; /*! MIT */"
+        );
+        assert_eq!(
+            js("const re = /\\/\\/ [/*]/g;\nreturn   /a  b/;"),
+            "const re = /\\/\\/ [/*]/g;\nreturn /a  b/;"
+        );
+        assert_eq!(
+            js("const t = `a  ${b ? `c  ${d}` : {e: 1}}  // f`;"),
+            "const t = `a  ${b ? `c  ${d}` : {e: 1}}  // f`;"
+        );
+    }
+}
@@ -0,0 +1,196 @@
+//! Mock API mode: requests under a path prefix are answered from JSON fixture files, so frontend
+//! teams can host a complete mock backend with just the file server.
+//!
+//! `GET /api/users/42` is answered from `/api/users/42.GET.json`. The status can be put in the
+//! file name, as in `42.POST.201.json`, and a front-matter block of `Name: value` lines between
+//! two `---` lines at the start of a fixture sets the status and headers of its response.
+
+use anyhow::{bail, Context, Result};
+use http::{
+    header::{ALLOW, CACHE_CONTROL, CONTENT_TYPE},
+    HeaderName, Method, StatusCode,
+};
+use std::{io::Cursor, path::Path};
+
+use crate::{backend, plain_response, settings, RequestContext, Response};
+
+/// Environment variable for the path prefix of the mocked API, e.g. `/api`
+pub(crate) const MOCK_API_PREFIX_ENV: &str = "MOCK_API_PREFIX";
+/// Extension of fixture files
+const FIXTURE_EXTENSION: &str = "json";
+/// Line delimiting the front-matter block of a fixture
+const FRONT_MATTER_DELIMITER: &str = "---";
+
+/// A fixture file matching a request path.
+#[derive(Debug, PartialEq, Eq)]
+struct Fixture {
+    method: Method,
+    /// The status given in the file name, if any.
+    status: Option<StatusCode>,
+}
+
+/// Answer a request for a path under the mocked API prefix from its fixture.
+/// Returns `None` if the mode isn't enabled or the path isn't under the prefix.
+pub(crate) fn handle(req: &RequestContext, path: &str) -> Option<Result<Response>> {
+    let prefix = settings::var(MOCK_API_PREFIX_ENV)?;
+    let prefix = prefix.trim().trim_matches('/');
+    let rest = path.trim_start_matches('/').strip_prefix(prefix)?;
+    if !(rest.is_empty() || rest.starts_with('/')) || prefix.is_empty() {
+        return None;
+    }
+    Some(respond(req, path.trim_end_matches('/')))
+}
+
+/// Respond with the fixture of a request path for the request method.
+fn respond(req: &RequestContext, path: &str) -> Result<Response> {
+    let path = Path::new(path);
+    let (Some(dir), Some(stem)) = (path.parent(), path.file_name().and_then(|n| n.to_str())) else {
+        return Ok(plain_response(StatusCode::NOT_FOUND));
+    };
+    let backend = backend::current();
+    let mut fixtures = Vec::new();
+    if backend.is_dir(dir) {
+        for name in backend.list(dir)? {
+            if let Some(fixture) = parse_name(&name, stem) {
+                fixtures.push((name, fixture));
+            }
+        }
+    }
+    // HEAD requests are answered like GET requests, without a body
+    let method = if req.method == Method::HEAD {
+        Method::GET
+    } else {
+        req.method.clone()
+    };
+    let Some((name, fixture)) = fixtures.iter().find(|(_, f)| f.method == method) else {
+        if fixtures.is_empty() {
+            return Ok(plain_response(StatusCode::NOT_FOUND));
+        }
+        let mut allowed = fixtures
+            .iter()
+            .map(|(_, f)| f.method.as_str())
+            .collect::<Vec<_>>();
+        allowed.sort_unstable();
+        allowed.dedup();
+        let (status, mut headers, body) = plain_response(StatusCode::METHOD_NOT_ALLOWED);
+        headers.push((ALLOW.as_str().to_string(), allowed.join(", ").into_bytes()));
+        return Ok((status, headers, body));
+    };
+    let file = dir.join(name);
+    let contents = backend
+        .read(&file)
+        .with_context(|| format!("reading {}", file.display()))?;
+    let contents = String::from_utf8(contents)
+        .with_context(|| format!("{} is not valid UTF-8", file.display()))?;
+    let (front_matter, body) =
+        split_front_matter(&contents).with_context(|| format!("parsing {}", file.display()))?;
+    let mut status = fixture.status.unwrap_or(StatusCode::OK);
+    let mut headers = vec![
+        (
+            CONTENT_TYPE.as_str().to_string(),
+            b"application/json".to_vec(),
+        ),
+        (CACHE_CONTROL.as_str().to_string(), b"no-store".to_vec()),
+    ];
+    for (name, value) in front_matter {
+        if name.eq_ignore_ascii_case("status") {
+            status = value
+                .parse()
+                .with_context(|| format!("invalid status {value} in {}", file.display()))?;
+            continue;
+        }
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("invalid header {name} in {}", file.display()))?;
+        headers.retain(|(k, _)| k != name.as_str());
+        headers.push((name.as_str().to_string(), value.as_bytes().to_vec()));
+    }
+    let body = (req.method != Method::HEAD)
+        .then(|| Box::new(Cursor::new(body.as_bytes().to_vec())) as Box<dyn std::io::Read>);
+    Ok((status, headers, body))
+}
+
+/// Parse the name of a fixture file of the request path ending in `stem`, like
+/// `42.GET.json` or `42.POST.201.json`.
+fn parse_name(name: &str, stem: &str) -> Option<Fixture> {
+    let rest = name
+        .strip_prefix(stem)?
+        .strip_prefix('.')?
+        .strip_suffix(FIXTURE_EXTENSION)?
+        .strip_suffix('.')?;
+    let (method, status) = match rest.split_once('.') {
+        Some((method, status)) => (method, Some(status.parse().ok()?)),
+        None => (rest, None),
+    };
+    // only upper-case names, so that `42.json` next to `42.GET.json` isn't mistaken for a fixture
+    if method.is_empty() || !method.bytes().all(|b| b.is_ascii_uppercase()) {
+        return None;
+    }
+    Some(Fixture {
+        method: Method::from_bytes(method.as_bytes()).ok()?,
+        status,
+    })
+}
+
+/// Split a fixture into the `Name: value` pairs of its front-matter block, if any, and its body.
+pub(crate) fn split_front_matter(contents: &str) -> Result<(Vec<(&str, &str)>, &str)> {
+    let Some(rest) = contents
+        .strip_prefix(FRONT_MATTER_DELIMITER)
+        .and_then(|rest| {
+            rest.strip_prefix('\n')
+                .or_else(|| rest.strip_prefix("\r\n"))
+        })
+    else {
+        return Ok((Vec::new(), contents));
+    };
+    let mut front_matter = Vec::new();
+    let mut consumed = 0;
+    for line in rest.split_inclusive('\n') {
+        consumed += line.len();
+        let line = line.trim();
+        if line == FRONT_MATTER_DELIMITER {
+            return Ok((front_matter, &rest[consumed..]));
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .with_context(|| format!("front-matter line {line:?} isn't `Name: value`"))?;
+        front_matter.push((name.trim(), value.trim()));
+    }
+    bail!("front-matter block isn't closed with {FRONT_MATTER_DELIMITER}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_name() {
+        let fixture = |method, status| Some(Fixture { method, status });
+        assert_eq!(parse_name("42.GET.json", "42"), fixture(Method::GET, None));
+        assert_eq!(
+            parse_name("42.POST.201.json", "42"),
+            fixture(Method::POST, Some(StatusCode::CREATED))
+        );
+        assert_eq!(parse_name("42.json", "42"), None);
+        assert_eq!(parse_name("42.get.json", "42"), None);
+        assert_eq!(parse_name("420.GET.json", "42"), None);
+        assert_eq!(parse_name("42.GET.abc.json", "42"), None);
+        assert_eq!(parse_name("42.GET.txt", "42"), None);
+    }
+
+    #[test]
+    fn test_split_front_matter() {
+        assert_eq!(
+            split_front_matter("{\"id\": 42}").unwrap(),
+            (vec![], "{\"id\": 42}")
+        );
+        assert_eq!(
+            split_front_matter("---\nStatus: 404\nX-Mock: yes\n---\n{}\n").unwrap(),
+            (vec![("Status", "404"), ("X-Mock", "yes")], "{}\n")
+        );
+        assert!(split_front_matter("---\nStatus: 404\n{}").is_err());
+        assert!(split_front_matter("---\nnot a header\n---\n").is_err());
+    }
+}
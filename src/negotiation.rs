@@ -0,0 +1,119 @@
+//! Content negotiation between representations stored next to each other, so that a static data
+//! directory can serve both humans and scripts at one URL: `/data/report` is served from
+//! `report.html` or `report.json`, whichever the `Accept` header of the request prefers.
+
+use http::header::ACCEPT;
+use std::cmp::Ordering;
+
+use crate::{settings, FileServer, RequestContext};
+
+/// Environment variable enabling content negotiation
+pub(crate) const NEGOTIATE_FORMATS_ENV: &str = "NEGOTIATE_FORMATS";
+/// The negotiated representations as extension and media type, preferred in this order when
+/// accepted equally, e.g. by `*/*`.
+const REPRESENTATIONS: [(&str, &str); 2] = [("html", "text/html"), ("json", "application/json")];
+
+/// The outcome of negotiating the representation of a path.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Negotiation {
+    /// Serve the file at this path.
+    Chosen(String),
+    /// There are representations, but the request accepts none of them.
+    NotAcceptable,
+}
+
+/// Negotiate the representation of a path that doesn't exist as is.
+/// Returns `None` if negotiation isn't enabled or there is no representation of the path.
+pub(crate) fn negotiate(req: &RequestContext, path: &str) -> Option<Negotiation> {
+    if settings::var(NEGOTIATE_FORMATS_ENV)
+        .as_deref()
+        .map(str::trim)
+        != Some("true")
+        || path.is_empty()
+        || path.ends_with('/')
+        || FileServer::exists(path)
+    {
+        return None;
+    }
+    let mut available = REPRESENTATIONS
+        .iter()
+        .map(|(extension, media_type)| (format!("{path}.{extension}"), *media_type))
+        .filter(|(file, _)| FileServer::exists(file))
+        .collect::<Vec<_>>();
+    if available.is_empty() {
+        return None;
+    }
+    let accept = req
+        .header(&ACCEPT)
+        .and_then(|v| std::str::from_utf8(v).ok())
+        .unwrap_or("*/*");
+    let media_types = available.iter().map(|(_, m)| *m).collect::<Vec<_>>();
+    Some(match choose(accept, &media_types) {
+        Some(index) => Negotiation::Chosen(available.swap_remove(index).0),
+        None => Negotiation::NotAcceptable,
+    })
+}
+
+/// Return the index of the media type an `Accept` header prefers, the first one on ties.
+/// Returns `None` if none is acceptable.
+fn choose(accept: &str, media_types: &[&str]) -> Option<usize> {
+    media_types
+        .iter()
+        .map(|media_type| quality(accept, media_type))
+        .enumerate()
+        .filter(|(_, q)| *q > 0.0)
+        // `max_by` returns the last maximum, so compare in reverse to keep the first
+        .min_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(Ordering::Equal))
+        .map(|(index, _)| index)
+}
+
+/// Return the quality an `Accept` header gives a media type, from its most specific matching
+/// range: `text/html` over `text/*` over `*/*`.
+fn quality(accept: &str, media_type: &str) -> f32 {
+    let (kind, _) = media_type.split_once('/').unwrap_or((media_type, ""));
+    accept
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let range = parts.next()?.trim().to_ascii_lowercase();
+            let specificity = if range == media_type {
+                2
+            } else if range == format!("{kind}/*") {
+                1
+            } else if range == "*/*" {
+                0
+            } else {
+                return None;
+            };
+            let q = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            Some((specificity, q))
+        })
+        .max_by_key(|(specificity, _)| *specificity)
+        .map_or(0.0, |(_, q)| q)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose() {
+        let both = ["text/html", "application/json"];
+        for (accept, expected) in [
+            ("*/*", Some(0)),
+            ("application/json", Some(1)),
+            ("text/html,application/xhtml+xml,*/*;q=0.8", Some(0)),
+            ("application/json, */*;q=0.1", Some(1)),
+            ("text/*;q=0.5, application/*", Some(1)),
+            ("*/*, text/html;q=0", Some(1)),
+            ("image/png", None),
+            ("application/json;q=0", None),
+        ] {
+            assert_eq!(choose(accept, &both), expected, "{accept}");
+        }
+        assert_eq!(choose("text/html", &["application/json"]), None);
+        assert_eq!(choose("*/*", &["application/json"]), Some(0));
+    }
+}
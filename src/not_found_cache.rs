@@ -0,0 +1,151 @@
+//! Paths that resolved to no file, remembered in the default key-value store for a while, so that
+//! clients repeatedly requesting missing paths (like bots probing for `/wp-login.php`) don't make
+//! the file server search the storage backend for them on every request.
+//!
+//! Only the request path itself is remembered: the fallback and custom 404 documents are still
+//! looked up on every request, so they follow the language of the request and configuration
+//! changes. A file added since a path was remembered is found once the entry expires.
+//!
+//! The paths are kept under a single key, pruned of expired entries whenever a path is added and
+//! capped at [`MAX_ENTRIES`], so probes for ever new paths don't grow the store without bound.
+
+use anyhow::{Context, Result};
+use std::{path::Path, str};
+
+use crate::{etag_cache::now, host::Store, settings};
+
+/// Environment variable for the number of seconds missing paths are remembered
+pub(crate) const NOT_FOUND_CACHE_TTL_ENV: &str = "NOT_FOUND_CACHE_TTL";
+/// Key-value store key holding the remembered paths
+const NOT_FOUND_KEY: &str = "spin-fileserver:not-found";
+/// Maximum number of paths remembered, dropping the oldest ones first
+const MAX_ENTRIES: usize = 1000;
+
+/// Return how many seconds missing paths are remembered, or `None` if they aren't.
+fn ttl() -> Option<u64> {
+    settings::var(NOT_FOUND_CACHE_TTL_ENV).and_then(|ttl| parse_ttl(&ttl))
+}
+
+/// Parse a number of seconds, where 0 disables remembering missing paths.
+fn parse_ttl(ttl: &str) -> Option<u64> {
+    ttl.trim().parse().ok().filter(|ttl| *ttl > 0)
+}
+
+/// Check whether a path was remembered as missing and hasn't expired yet.
+pub(crate) fn contains(path: &Path) -> bool {
+    let Some(ttl) = ttl() else {
+        return false;
+    };
+    match load() {
+        Ok((_, entries)) => entries.contains(&path.to_string_lossy(), now(), ttl),
+        Err(e) => {
+            eprintln!("Error looking up missing path {}: {e:#}", path.display());
+            false
+        }
+    }
+}
+
+/// Remember that a path is missing, if missing paths are remembered.
+pub(crate) fn insert(path: &Path) {
+    let Some(ttl) = ttl() else {
+        return;
+    };
+    let stored = load().and_then(|(store, mut entries)| {
+        entries.insert(&path.to_string_lossy(), now(), ttl);
+        Ok(store.set(NOT_FOUND_KEY, &entries.to_bytes())?)
+    });
+    if let Err(e) = stored {
+        eprintln!("Error remembering missing path {}: {e:#}", path.display());
+    }
+}
+
+/// Open the default store and read the remembered paths.
+fn load() -> Result<(Store, Entries)> {
+    let store =
+        Store::open_default().context("cannot open the key-value store of missing paths")?;
+    let entries = Entries::parse(&store.get(NOT_FOUND_KEY)?.unwrap_or_default());
+    Ok((store, entries))
+}
+
+/// Remembered paths, oldest first, stored as lines of `<remembered-at> <path>`.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Entries(Vec<(u64, String)>);
+
+impl Entries {
+    /// Parse the stored paths. Lines that can't be parsed are dropped.
+    fn parse(bytes: &[u8]) -> Self {
+        let entries = str::from_utf8(bytes)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| {
+                let (at, path) = line.split_once(' ')?;
+                Some((at.parse().ok()?, path.to_string()))
+            })
+            .collect();
+        Self(entries)
+    }
+
+    fn contains(&self, path: &str, now: u64, ttl: u64) -> bool {
+        self.0
+            .iter()
+            .any(|(at, remembered)| remembered == path && now.saturating_sub(*at) < ttl)
+    }
+
+    /// Remember a path, dropping expired entries and the oldest ones beyond [`MAX_ENTRIES`].
+    fn insert(&mut self, path: &str, now: u64, ttl: u64) {
+        self.0
+            .retain(|(at, remembered)| remembered != path && now.saturating_sub(*at) < ttl);
+        self.0.push((now, path.to_string()));
+        let excess = self.0.len().saturating_sub(MAX_ENTRIES);
+        self.0.drain(..excess);
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0
+            .iter()
+            .map(|(at, path)| format!("{at} {path}\n"))
+            .collect::<String>()
+            .into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries() {
+        let mut entries = Entries::parse(b"100 wp-login.php\n150 old page.html\nbroken\n");
+        assert_eq!(entries.0.len(), 2);
+        assert!(entries.contains("old page.html", 200, 60));
+        assert!(!entries.contains("wp-login.php", 200, 60));
+        assert!(!entries.contains("index.html", 200, 60));
+
+        // expired entries are dropped, and paths remembered again move to the end
+        entries.insert("old page.html", 200, 60);
+        entries.insert(".env", 201, 60);
+        assert_eq!(
+            Entries::parse(&entries.to_bytes()),
+            Entries(vec![
+                (200, "old page.html".to_string()),
+                (201, ".env".to_string())
+            ])
+        );
+
+        for i in 0..MAX_ENTRIES as u64 {
+            entries.insert(&format!("probe-{i}"), 300 + i, 3600);
+        }
+        assert_eq!(entries.0.len(), MAX_ENTRIES);
+        assert!(!entries.contains(".env", 300, 3600));
+        assert!(entries.contains("probe-0", 300, 3600));
+    }
+
+    #[test]
+    fn test_parse_ttl() {
+        assert_eq!(parse_ttl("30"), Some(30));
+        assert_eq!(parse_ttl(" 5 "), Some(5));
+        assert_eq!(parse_ttl("0"), None);
+        assert_eq!(parse_ttl("-1"), None);
+        assert_eq!(parse_ttl("soon"), None);
+    }
+}
@@ -0,0 +1,483 @@
+//! OpenID Connect login for protected static sites, such as internal documentation, without a
+//! separate authenticating gateway.
+//!
+//! Unauthenticated requests under the protected prefixes are redirected to the identity provider
+//! with the authorization code flow. Its callback exchanges the code for an ID token and sets a
+//! session cookie signed with HMAC-SHA256, which later requests are checked against without
+//! contacting the identity provider.
+//!
+//! The ID token comes straight from the token endpoint over TLS, authenticated with the client
+//! secret, so its claims are validated but not its signature, as OpenID Connect Core 1.0 allows
+//! for the code flow.
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use http::{
+    header::{CACHE_CONTROL, CONTENT_TYPE, LOCATION, SET_COOKIE},
+    StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use spin_sdk::http::{Method, Request};
+use std::str;
+
+use crate::{
+    admin::ADMIN_PREFIX, config, error::FileServerError, etag_cache::now, plain_response,
+    request_url::RequestUrl, secrets, settings, RequestContext, Response,
+};
+
+/// Environment variable for the issuer URL of the identity provider, enabling the login
+pub(crate) const OIDC_ISSUER_ENV: &str = "OIDC_ISSUER";
+/// Environment variable for the client ID registered with the identity provider
+pub(crate) const OIDC_CLIENT_ID_ENV: &str = "OIDC_CLIENT_ID";
+/// Environment variable for the client secret registered with the identity provider
+pub(crate) const OIDC_CLIENT_SECRET_ENV: &str = "OIDC_CLIENT_SECRET";
+/// Environment variable for the secret session cookies are signed with
+pub(crate) const OIDC_SESSION_SECRET_ENV: &str = "OIDC_SESSION_SECRET";
+/// Environment variable for the comma-separated request path prefixes requiring a session
+pub(crate) const OIDC_PREFIXES_ENV: &str = "OIDC_PREFIXES";
+/// Environment variable for the callback URL registered with the identity provider
+pub(crate) const OIDC_REDIRECT_URL_ENV: &str = "OIDC_REDIRECT_URL";
+/// Environment variable for the seconds sessions last
+pub(crate) const OIDC_SESSION_MAX_AGE_ENV: &str = "OIDC_SESSION_MAX_AGE";
+/// Default seconds sessions last
+pub(crate) const DEFAULT_SESSION_MAX_AGE: u64 = 8 * 60 * 60;
+/// Route of the callback, after the admin prefix
+const CALLBACK_ROUTE: &str = "oidc/callback";
+/// Cookie holding the session
+const SESSION_COOKIE: &str = "spin-fileserver-session";
+/// Cookie binding a login in progress to the browser that started it
+const NONCE_COOKIE: &str = "spin-fileserver-oidc-nonce";
+/// Seconds users have to log in at the identity provider
+const LOGIN_TTL: u64 = 10 * 60;
+/// Scopes requested from the identity provider
+const SCOPES: &str = "openid email profile";
+
+/// A logged-in user, stored in the session cookie.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Session {
+    /// The subject identifier of the user at the identity provider.
+    sub: String,
+    email: Option<String>,
+    /// When the session expires, in seconds since the Unix epoch.
+    exp: u64,
+}
+
+/// A login in progress, passed through the identity provider as the `state` parameter.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct LoginState {
+    /// The path and query the user requested, relative to the host.
+    return_to: String,
+    /// Random value the ID token and the nonce cookie must both carry.
+    nonce: String,
+    /// When the login expires, in seconds since the Unix epoch.
+    exp: u64,
+}
+
+/// The endpoints of the identity provider, from its discovery document.
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+/// The claims of an ID token that are checked.
+#[derive(Debug, Deserialize)]
+struct Claims {
+    iss: String,
+    aud: Audience,
+    exp: u64,
+    nonce: Option<String>,
+    sub: String,
+    email: Option<String>,
+}
+
+/// The audience of an ID token, a single client ID or several.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Handle requests for the callback and requests requiring a session.
+/// Returns `None` if the login isn't enabled, or the request may be served.
+pub(crate) fn handle(req: &RequestContext, path: &str) -> Option<Result<Response>> {
+    let issuer = settings::var(OIDC_ISSUER_ENV)?;
+    let issuer = issuer.trim().trim_end_matches('/');
+    let is_callback = path.strip_prefix(ADMIN_PREFIX) == Some(CALLBACK_ROUTE);
+    if !is_callback && !protects(path) {
+        return None;
+    }
+    // without a secret sessions can't be trusted, so nobody gets in
+    let Some(secret) = settings::var(OIDC_SESSION_SECRET_ENV).filter(|s| !s.trim().is_empty())
+    else {
+        eprintln!("{OIDC_SESSION_SECRET_ENV} is not set, refusing to serve {path}");
+        return Some(Ok(plain_response(StatusCode::INTERNAL_SERVER_ERROR)));
+    };
+    let secret = secret.trim();
+    if is_callback {
+        return Some(callback(req, issuer, secret));
+    }
+    let session = req
+        .cookie(SESSION_COOKIE)
+        .and_then(|cookie| verify::<Session>(secret, cookie))
+        .filter(|session| session.exp > now());
+    if session.is_some() {
+        return None;
+    }
+    // only navigations can be sent to the identity provider
+    if req.method != http::Method::GET && req.method != http::Method::HEAD {
+        return Some(Ok(plain_response(StatusCode::UNAUTHORIZED)));
+    }
+    Some(login(req, path, issuer, secret))
+}
+
+/// Check whether a request path requires a session.
+fn protects(path: &str) -> bool {
+    let prefixes = settings::var(OIDC_PREFIXES_ENV).unwrap_or_else(|| "/".to_string());
+    config::under_prefixes(&prefixes, path)
+}
+
+/// Redirect to the identity provider, remembering the requested path.
+fn login(req: &RequestContext, path: &str, issuer: &str, secret: &str) -> Result<Response> {
+    let discovery = discover(issuer).map_err(|e| {
+        FileServerError::BackendUnavailable(format!("cannot start the login: {e:#}"))
+    })?;
+    let mut nonce = [0_u8; 16];
+    getrandom::getrandom(&mut nonce).map_err(|e| anyhow!("cannot generate a nonce: {e}"))?;
+    let nonce = hex::encode(nonce);
+    let mut return_to = RequestUrl::of(req).path(path);
+    if !req.query.is_empty() {
+        return_to = format!("{return_to}?{}", req.query);
+    }
+    let state = sign(
+        secret,
+        &LoginState {
+            return_to,
+            nonce: nonce.clone(),
+            exp: now() + LOGIN_TTL,
+        },
+    )?;
+    let client_id = settings::var(OIDC_CLIENT_ID_ENV).unwrap_or_default();
+    let params = [
+        ("response_type", "code"),
+        ("client_id", client_id.trim()),
+        ("redirect_uri", &redirect_url(req)?),
+        ("scope", SCOPES),
+        ("state", &state),
+        ("nonce", &nonce),
+    ];
+    let separator = if discovery.authorization_endpoint.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    let location = format!(
+        "{}{separator}{}",
+        discovery.authorization_endpoint,
+        form_encode(&params)
+    );
+    Ok(redirect(&location, cookie(NONCE_COOKIE, &nonce, LOGIN_TTL)))
+}
+
+/// Handle the redirect back from the identity provider, starting a session.
+fn callback(req: &RequestContext, issuer: &str, secret: &str) -> Result<Response> {
+    if let Some(error) = query_param(&req.query, "error") {
+        let problem = format!("the identity provider refused the login: {error}");
+        return Err(FileServerError::AccessDenied(problem).into());
+    }
+    let login = query_param(&req.query, "state")
+        .and_then(|state| verify::<LoginState>(secret, &state))
+        .filter(|login| login.exp > now())
+        .filter(|login| req.cookie(NONCE_COOKIE) == Some(login.nonce.as_str()));
+    let (Some(login), Some(code)) = (login, query_param(&req.query, "code")) else {
+        let problem = "the login state or code is missing, invalid or expired".to_string();
+        return Err(FileServerError::BadRequest(problem).into());
+    };
+    let client_id = settings::var(OIDC_CLIENT_ID_ENV).unwrap_or_default();
+    let claims = exchange(req, issuer, client_id.trim(), &code).map_err(|e| {
+        FileServerError::BackendUnavailable(format!("cannot complete the login: {e:#}"))
+    })?;
+    validate(&claims, issuer, client_id.trim(), &login.nonce, now())
+        .map_err(|e| FileServerError::AccessDenied(format!("rejecting the ID token: {e}")))?;
+    let max_age = settings::var(OIDC_SESSION_MAX_AGE_ENV)
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(DEFAULT_SESSION_MAX_AGE);
+    let session = sign(
+        secret,
+        &Session {
+            sub: claims.sub,
+            email: claims.email,
+            exp: now() + max_age,
+        },
+    )?;
+    // only redirect within the site
+    let return_to = Some(login.return_to)
+        .filter(|r| r.starts_with('/') && !r.starts_with("//"))
+        .unwrap_or_else(|| "/".to_string());
+    let (status, mut headers, body) =
+        redirect(&return_to, cookie(SESSION_COOKIE, &session, max_age));
+    headers.push(cookie(NONCE_COOKIE, "", 0));
+    Ok((status, headers, body))
+}
+
+/// Fetch the discovery document of the identity provider.
+fn discover(issuer: &str) -> Result<Discovery> {
+    let url = format!("{issuer}/.well-known/openid-configuration");
+    let mut request = Request::builder();
+    request.method(Method::Get).uri(&url);
+    let response: spin_sdk::http::Response =
+        spin_sdk::http::run(spin_sdk::http::send(request.build()))
+            .with_context(|| format!("cannot fetch {url}"))?;
+    let status = *response.status();
+    if !(200..300).contains(&status) {
+        bail!("{url} responded with status {status}");
+    }
+    serde_json::from_slice(response.body())
+        .with_context(|| format!("invalid discovery document {url}"))
+}
+
+/// Exchange an authorization code for the claims of an ID token.
+fn exchange(req: &RequestContext, issuer: &str, client_id: &str, code: &str) -> Result<Claims> {
+    #[derive(Deserialize)]
+    struct TokenResponse {
+        id_token: String,
+    }
+
+    let discovery = discover(issuer)?;
+    let client_secret = settings::var(OIDC_CLIENT_SECRET_ENV)
+        .and_then(|s| secrets::current(&s))
+        .unwrap_or_default();
+    let body = form_encode(&[
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", &redirect_url(req)?),
+        ("client_id", client_id),
+        ("client_secret", &client_secret),
+    ]);
+    let mut request = Request::builder();
+    request
+        .method(Method::Post)
+        .uri(&discovery.token_endpoint)
+        .header("content-type", "application/x-www-form-urlencoded")
+        .header("accept", "application/json")
+        .body(body);
+    let response: spin_sdk::http::Response =
+        spin_sdk::http::run(spin_sdk::http::send(request.build()))
+            .with_context(|| format!("cannot reach {}", discovery.token_endpoint))?;
+    let status = *response.status();
+    if !(200..300).contains(&status) {
+        bail!(
+            "{} responded with status {status}",
+            discovery.token_endpoint
+        );
+    }
+    let token: TokenResponse =
+        serde_json::from_slice(response.body()).context("invalid token response")?;
+    let payload = token
+        .id_token
+        .split('.')
+        .nth(1)
+        .context("the ID token isn't a JWT")?;
+    let payload = URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .context("the ID token isn't a JWT")?;
+    serde_json::from_slice(&payload).context("invalid ID token claims")
+}
+
+/// Check that an ID token was issued by the identity provider, for this client and this login,
+/// and hasn't expired.
+fn validate(claims: &Claims, issuer: &str, client_id: &str, nonce: &str, now: u64) -> Result<()> {
+    if claims.iss.trim_end_matches('/') != issuer {
+        bail!("issued by {}, not {issuer}", claims.iss);
+    }
+    let audience = match &claims.aud {
+        Audience::One(aud) => std::slice::from_ref(aud),
+        Audience::Many(aud) => aud.as_slice(),
+    };
+    if !audience.iter().any(|aud| aud == client_id) {
+        bail!("not issued for client {client_id}");
+    }
+    if claims.exp <= now {
+        bail!("expired");
+    }
+    if claims.nonce.as_deref() != Some(nonce) {
+        bail!("issued for another login");
+    }
+    Ok(())
+}
+
+/// Return the callback URL: the configured one, or the callback route on the requested host.
+fn redirect_url(req: &RequestContext) -> Result<String> {
+    if let Some(url) = settings::var(OIDC_REDIRECT_URL_ENV) {
+        return Ok(url.trim().to_string());
+    }
+    RequestUrl::of(req)
+        .absolute(&format!("{ADMIN_PREFIX}{CALLBACK_ROUTE}"))
+        .with_context(|| format!("requests have no Host header, set {OIDC_REDIRECT_URL_ENV}"))
+}
+
+/// Build an uncacheable `302 Found` response setting a cookie.
+fn redirect(location: &str, cookie: (String, Vec<u8>)) -> Response {
+    (
+        StatusCode::FOUND,
+        vec![
+            (LOCATION.as_str().to_string(), location.as_bytes().to_vec()),
+            (CACHE_CONTROL.as_str().to_string(), b"no-store".to_vec()),
+            (CONTENT_TYPE.as_str().to_string(), b"text/plain".to_vec()),
+            cookie,
+        ],
+        None,
+    )
+}
+
+/// Build the `Set-Cookie` header of a cookie only sent back over HTTPS and hidden from scripts.
+fn cookie(name: &str, value: &str, max_age: u64) -> (String, Vec<u8>) {
+    let cookie =
+        format!("{name}={value}; Path=/; Max-Age={max_age}; HttpOnly; Secure; SameSite=Lax");
+    (SET_COOKIE.as_str().to_string(), cookie.into_bytes())
+}
+
+/// Serialize a value as JSON, signed with the current value of the secret: the Base64 JSON and
+/// its Base64 HMAC, separated by a dot.
+pub(crate) fn sign(secret: &str, value: &impl Serialize) -> Result<String> {
+    let key = secrets::current(secret).context("the signing secret is empty")?;
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(value)?);
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes())?;
+    mac.update(payload.as_bytes());
+    let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+    Ok(format!("{payload}.{signature}"))
+}
+
+/// Return the value of a token signed with an accepted value of the secret, if the signature is
+/// valid.
+pub(crate) fn verify<T: DeserializeOwned>(secret: &str, token: &str) -> Option<T> {
+    secrets::accepted(secret)
+        .iter()
+        .find_map(|key| verify_with(key, token))
+}
+
+/// Return the value of a token signed with a key, if the signature is valid.
+fn verify_with<T: DeserializeOwned>(key: &str, token: &str) -> Option<T> {
+    let (payload, signature) = token.split_once('.')?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).ok()?;
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&URL_SAFE_NO_PAD.decode(signature).ok()?)
+        .ok()?;
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()
+}
+
+/// Encode parameters as `application/x-www-form-urlencoded`.
+fn form_encode(params: &[(&str, &str)]) -> String {
+    let encode = |value: &str| {
+        value
+            .bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    (b as char).to_string()
+                }
+                _ => format!("%{b:02X}"),
+            })
+            .collect::<String>()
+    };
+    params
+        .iter()
+        .map(|(name, value)| format!("{}={}", encode(name), encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Return the decoded value of a query string parameter.
+pub(crate) fn query_param(query: &str, name: &str) -> Option<String> {
+    let value = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))?;
+    let mut decoded = Vec::new();
+    let mut bytes = value.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                decoded.push(u8::from_str_radix(str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b => decoded.push(b),
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        let session = Session {
+            sub: "user-1".to_string(),
+            email: Some("ada@example.com".to_string()),
+            exp: 1_900_000_000,
+        };
+        let token = sign("s3cret", &session).unwrap();
+        let rotated = sign("n3w s3cret", &session).unwrap();
+        assert_eq!(verify::<Session>("s3cret", &token), Some(session));
+        assert_eq!(verify::<Session>("other", &token), None);
+        // sessions signed before a rotation stay valid, new ones use the new secret
+        assert!(verify::<Session>("n3w s3cret", &token).is_some());
+        assert!(verify::<Session>("n3w", &rotated).is_some());
+        assert_eq!(verify::<Session>("s3cret", &rotated), None);
+
+        let (payload, signature) = token.split_once('.').unwrap();
+        let forged = URL_SAFE_NO_PAD.encode(
+            String::from_utf8(URL_SAFE_NO_PAD.decode(payload).unwrap())
+                .unwrap()
+                .replace("user-1", "admin"),
+        );
+        assert_eq!(
+            verify::<Session>("s3cret", &format!("{forged}.{signature}")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let claims = |iss: &str, aud: Audience, exp, nonce: &str| Claims {
+            iss: iss.to_string(),
+            aud,
+            exp,
+            nonce: Some(nonce.to_string()),
+            sub: "user-1".to_string(),
+            email: None,
+        };
+        let issuer = "https://id.example.com";
+        let one = || Audience::One("docs".to_string());
+        assert!(validate(&claims(issuer, one(), 200, "n"), issuer, "docs", "n", 100).is_ok());
+        let many = Audience::Many(vec!["api".to_string(), "docs".to_string()]);
+        assert!(validate(&claims(issuer, many, 200, "n"), issuer, "docs", "n", 100).is_ok());
+        let other_issuer = claims("https://evil.example.com", one(), 200, "n");
+        assert!(validate(&other_issuer, issuer, "docs", "n", 100).is_err());
+        let other_client = claims(issuer, Audience::One("api".to_string()), 200, "n");
+        assert!(validate(&other_client, issuer, "docs", "n", 100).is_err());
+        assert!(validate(&claims(issuer, one(), 100, "n"), issuer, "docs", "n", 100).is_err());
+        assert!(validate(&claims(issuer, one(), 200, "m"), issuer, "docs", "n", 100).is_err());
+    }
+
+    #[test]
+    fn test_query_param() {
+        assert_eq!(
+            query_param("code=a%2Fb+c&state=xyz", "code"),
+            Some("a/b c".to_string())
+        );
+        assert_eq!(query_param("codes=1", "code"), None);
+        assert_eq!(query_param("code=%zz", "code"), None);
+        assert_eq!(
+            form_encode(&[
+                ("redirect_uri", "https://docs.example.com/cb"),
+                ("scope", "a b")
+            ]),
+            "redirect_uri=https%3A%2F%2Fdocs.example.com%2Fcb&scope=a%20b"
+        );
+    }
+}
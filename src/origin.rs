@@ -0,0 +1,496 @@
+//! Proxy-to-origin mode: files that don't exist locally are fetched from an origin server, and
+//! cached in the default key-value store following the caching rules of the origin.
+//!
+//! Cached responses are served as long as they are fresh according to their `Cache-Control`
+//! header, and revalidated with `If-None-Match` and `If-Modified-Since` once they are stale.
+//!
+//! Requests to the origin are retried with exponential backoff when they fail or the origin is
+//! temporarily unavailable. After repeated failures a circuit breaker, shared by all instances
+//! through the key-value store, stops contacting the origin for a while; stale cached responses
+//! are served in the meantime.
+
+use anyhow::{anyhow, bail, Context, Result};
+use http::{
+    header::{
+        AGE, CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+    },
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use spin_sdk::{
+    http::{Method, Request},
+    key_value::Store,
+};
+use std::{
+    io::Cursor,
+    time::{Duration, Instant},
+};
+
+use crate::{etag_cache::now, plain_response, settings, RequestContext, Response};
+
+/// Environment variable for the base URL of the origin
+pub(crate) const ORIGIN_URL_ENV: &str = "ORIGIN_URL";
+/// Environment variable for the time in milliseconds after which an origin response is discarded
+pub(crate) const ORIGIN_TIMEOUT_ENV: &str = "ORIGIN_TIMEOUT";
+/// Environment variable for the number of retries of failed origin requests
+pub(crate) const ORIGIN_RETRIES_ENV: &str = "ORIGIN_RETRIES";
+/// Environment variable for the delay in milliseconds before the first retry, doubled every retry
+pub(crate) const ORIGIN_RETRY_BACKOFF_ENV: &str = "ORIGIN_RETRY_BACKOFF";
+/// Environment variable for the number of consecutive failures that open the circuit breaker
+pub(crate) const ORIGIN_BREAKER_THRESHOLD_ENV: &str = "ORIGIN_BREAKER_THRESHOLD";
+/// Environment variable for the seconds the circuit breaker stays open
+pub(crate) const ORIGIN_BREAKER_COOLDOWN_ENV: &str = "ORIGIN_BREAKER_COOLDOWN";
+/// Environment variable for the page served when the origin is unavailable
+pub(crate) const ORIGIN_ERROR_PAGE_ENV: &str = "ORIGIN_ERROR_PAGE";
+/// Default timeout in milliseconds
+const DEFAULT_TIMEOUT: u64 = 10_000;
+/// Default number of retries
+const DEFAULT_RETRIES: u32 = 2;
+/// Default delay in milliseconds before the first retry
+const DEFAULT_RETRY_BACKOFF: u64 = 100;
+/// Default number of consecutive failures that open the circuit breaker
+const DEFAULT_BREAKER_THRESHOLD: u32 = 5;
+/// Default seconds the circuit breaker stays open
+const DEFAULT_BREAKER_COOLDOWN: u64 = 30;
+/// Key-value store key prefix of cached origin responses
+const ORIGIN_PREFIX: &str = "spin-fileserver:origin:";
+/// Key-value store key holding the state of the circuit breaker
+const BREAKER_KEY: &str = "spin-fileserver:origin-breaker";
+/// Origin statuses worth retrying, as the origin may recover
+const RETRYABLE_STATUSES: [u16; 3] = [502, 503, 504];
+/// The `X-Cache` header, which the `http` crate doesn't define
+const X_CACHE: &str = "x-cache";
+/// Headers of origin responses that are cached and passed on to clients
+const FORWARDED_HEADERS: [&str; 4] = ["cache-control", "content-type", "etag", "last-modified"];
+
+/// A cached origin response.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    /// When the response was received or last revalidated, in seconds since the Unix epoch.
+    stored_at: u64,
+    /// How many seconds the response stays fresh.
+    max_age: u64,
+    headers: Vec<(String, String)>,
+    #[serde(skip)]
+    body: Vec<u8>,
+}
+
+impl Entry {
+    /// Encode the entry as its metadata in JSON, a newline, and the body.
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut encoded = serde_json::to_vec(self)?;
+        encoded.push(b'\n');
+        encoded.extend_from_slice(&self.body);
+        Ok(encoded)
+    }
+
+    fn decode(encoded: &[u8]) -> Option<Self> {
+        let newline = encoded.iter().position(|b| *b == b'\n')?;
+        let mut entry: Self = serde_json::from_slice(&encoded[..newline]).ok()?;
+        entry.body = encoded[newline + 1..].to_vec();
+        Some(entry)
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find_map(|(k, v)| k.eq_ignore_ascii_case(name).then_some(v.as_str()))
+    }
+
+    fn is_fresh(&self, now: u64) -> bool {
+        now.saturating_sub(self.stored_at) < self.max_age
+    }
+}
+
+/// How requests to the origin are retried, and when to stop sending them.
+#[derive(Debug, PartialEq, Eq)]
+struct Policy {
+    timeout: Duration,
+    retries: u32,
+    /// Delay before the first retry.
+    backoff: Duration,
+    /// Consecutive failures that open the circuit breaker.
+    threshold: u32,
+    /// Seconds the circuit breaker stays open.
+    cooldown: u64,
+}
+
+impl Policy {
+    fn from_env() -> Self {
+        fn number<T: std::str::FromStr>(name: &str, default: T) -> T {
+            settings::var(name)
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(default)
+        }
+        Self {
+            timeout: Duration::from_millis(number(ORIGIN_TIMEOUT_ENV, DEFAULT_TIMEOUT)),
+            retries: number(ORIGIN_RETRIES_ENV, DEFAULT_RETRIES),
+            backoff: Duration::from_millis(number(ORIGIN_RETRY_BACKOFF_ENV, DEFAULT_RETRY_BACKOFF)),
+            threshold: number(ORIGIN_BREAKER_THRESHOLD_ENV, DEFAULT_BREAKER_THRESHOLD).max(1),
+            cooldown: number(ORIGIN_BREAKER_COOLDOWN_ENV, DEFAULT_BREAKER_COOLDOWN),
+        }
+    }
+
+    /// Return the delay before a retry, starting from 0 for the first one.
+    fn backoff(&self, retry: u32) -> Duration {
+        self.backoff.saturating_mul(1 << retry.min(16))
+    }
+}
+
+/// State of the circuit breaker.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Breaker {
+    /// Consecutive failed origin requests.
+    failures: u32,
+    /// When the breaker opened, in seconds since the Unix epoch.
+    opened_at: Option<u64>,
+}
+
+impl Breaker {
+    fn load(store: &Store) -> Result<Self> {
+        Ok(store
+            .get(BREAKER_KEY)?
+            .and_then(|state| serde_json::from_slice(&state).ok())
+            .unwrap_or_default())
+    }
+
+    /// Check whether the origin must not be contacted. Once the cooldown is over, requests are
+    /// let through again, and the next failure opens the breaker for another cooldown.
+    fn is_open(&self, cooldown: u64, now: u64) -> bool {
+        self.opened_at
+            .is_some_and(|opened_at| now.saturating_sub(opened_at) < cooldown)
+    }
+
+    /// Record the outcome of an origin request, returning whether the state changed.
+    fn record(&mut self, success: bool, threshold: u32, now: u64) -> bool {
+        let previous = (self.failures, self.opened_at);
+        if success {
+            *self = Self::default();
+        } else {
+            self.failures = self.failures.saturating_add(1);
+            if self.failures >= threshold {
+                self.opened_at = Some(now);
+            }
+        }
+        previous != (self.failures, self.opened_at)
+    }
+}
+
+/// A response of the origin.
+struct OriginResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/// Check whether the proxy-to-origin mode is enabled.
+pub(crate) fn enabled() -> bool {
+    settings::var(ORIGIN_URL_ENV).is_some()
+}
+
+/// Serve a request path from the cache or the origin.
+/// Returns `None` if the origin doesn't have the file either.
+pub(crate) fn fetch(req: &RequestContext, path: &str) -> Result<Option<Response>> {
+    let Some(base) = settings::var(ORIGIN_URL_ENV) else {
+        return Ok(None);
+    };
+    let url = format!(
+        "{}/{}",
+        base.trim().trim_end_matches('/'),
+        path.trim_start_matches('/')
+    );
+    let store =
+        Store::open_default().context("cannot open the key-value store to cache the origin")?;
+    let key = format!("{ORIGIN_PREFIX}/{}", path.trim_start_matches('/'));
+    let cached = store.get(&key)?.and_then(|entry| Entry::decode(&entry));
+    let now = now();
+
+    let (entry, cache) = match cached {
+        Some(entry) if entry.is_fresh(now) => (entry, "HIT"),
+        cached => {
+            let policy = Policy::from_env();
+            let mut breaker = Breaker::load(&store)?;
+            let result = if breaker.is_open(policy.cooldown, now) {
+                Err(anyhow!(
+                    "the circuit breaker is open after repeated failures"
+                ))
+            } else {
+                let result = request_with_retries(&url, cached.as_ref(), &policy);
+                let success = result.as_ref().is_ok_and(|r| r.status < 500);
+                if breaker.record(success, policy.threshold, now) {
+                    store.set(BREAKER_KEY, &serde_json::to_vec(&breaker)?)?;
+                }
+                result
+            };
+            let response = match result {
+                Ok(response) if response.status < 500 => response,
+                result => {
+                    let error = match result {
+                        Ok(response) => anyhow!("{url} responded with status {}", response.status),
+                        Err(e) => e,
+                    };
+                    // a stale response is better than none while the origin is down
+                    let Some(entry) = cached else {
+                        return Err(error);
+                    };
+                    eprintln!("Serving a stale copy of {path}: {error:#}");
+                    return Ok(Some(respond(req, entry, "STALE", now)));
+                }
+            };
+            match (response.status, cached) {
+                (304, Some(mut entry)) => {
+                    // the revalidation response may update the caching rules
+                    if let Some((_, cache_control)) = response
+                        .headers
+                        .iter()
+                        .find(|(k, _)| k == CACHE_CONTROL.as_str())
+                    {
+                        entry.max_age = max_age(cache_control).unwrap_or_default();
+                    }
+                    entry.stored_at = now;
+                    store.set(&key, &entry.encode()?)?;
+                    (entry, "REVALIDATED")
+                }
+                (200, _) => {
+                    let cache_control = response
+                        .headers
+                        .iter()
+                        .find_map(|(k, v)| (k == CACHE_CONTROL.as_str()).then_some(v.as_str()));
+                    let max_age = max_age(cache_control.unwrap_or_default());
+                    let entry = Entry {
+                        stored_at: now,
+                        max_age: max_age.unwrap_or_default(),
+                        headers: response.headers,
+                        body: response.body,
+                    };
+                    if max_age.is_some() {
+                        store.set(&key, &entry.encode()?)?;
+                    } else {
+                        store.delete(&key)?;
+                    }
+                    (entry, "MISS")
+                }
+                (404, _) => {
+                    store.delete(&key)?;
+                    return Ok(None);
+                }
+                (status, _) => bail!("{url} responded with status {status}"),
+            }
+        }
+    };
+    Ok(Some(respond(req, entry, cache, now)))
+}
+
+/// Send a GET request to the origin, retrying failures and retryable statuses with backoff.
+fn request_with_retries(
+    url: &str,
+    cached: Option<&Entry>,
+    policy: &Policy,
+) -> Result<OriginResponse> {
+    let mut retry = 0;
+    loop {
+        let result = request(url, cached, policy.timeout);
+        let retryable = match &result {
+            Ok(response) => RETRYABLE_STATUSES.contains(&response.status),
+            Err(_) => true,
+        };
+        if !retryable || retry >= policy.retries {
+            return result;
+        }
+        std::thread::sleep(policy.backoff(retry));
+        retry += 1;
+    }
+}
+
+/// Send a GET request to the origin, conditional on the validators of the cached response.
+/// Spin's outbound HTTP API takes no timeout, so responses arriving late are discarded instead.
+fn request(url: &str, cached: Option<&Entry>, timeout: Duration) -> Result<OriginResponse> {
+    let mut request = Request::builder();
+    request.method(Method::Get).uri(url);
+    if let Some(etag) = cached.and_then(|entry| entry.header(ETAG.as_str())) {
+        request.header(IF_NONE_MATCH.as_str(), etag);
+    }
+    if let Some(modified) = cached.and_then(|entry| entry.header(LAST_MODIFIED.as_str())) {
+        request.header(IF_MODIFIED_SINCE.as_str(), modified);
+    }
+    let start = Instant::now();
+    let response: spin_sdk::http::Response =
+        spin_sdk::http::run(spin_sdk::http::send(request.build()))
+            .with_context(|| format!("cannot fetch {url}"))?;
+    if start.elapsed() > timeout {
+        bail!(
+            "{url} took longer than {}ms to respond",
+            timeout.as_millis()
+        );
+    }
+    let headers = FORWARDED_HEADERS
+        .iter()
+        .filter_map(|name| {
+            let value = response.header(name)?.as_str()?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect();
+    Ok(OriginResponse {
+        status: *response.status(),
+        headers,
+        body: response.body().to_vec(),
+    })
+}
+
+/// Build the response sent when the origin is unavailable: the configured error page, or a plain
+/// `502 Bad Gateway`.
+pub(crate) fn unavailable() -> Response {
+    let Some(page) = settings::var(ORIGIN_ERROR_PAGE_ENV) else {
+        return plain_response(StatusCode::BAD_GATEWAY);
+    };
+    let page = page.trim();
+    match std::fs::read(page) {
+        Ok(body) => {
+            let content_type = mime_guess::from_path(page).first_or_octet_stream();
+            (
+                StatusCode::BAD_GATEWAY,
+                vec![(
+                    CONTENT_TYPE.as_str().to_string(),
+                    content_type.as_ref().as_bytes().to_vec(),
+                )],
+                Some(Box::new(Cursor::new(body))),
+            )
+        }
+        Err(e) => {
+            eprintln!("{ORIGIN_ERROR_PAGE_ENV} {page} cannot be read, ignoring it: {e}");
+            plain_response(StatusCode::BAD_GATEWAY)
+        }
+    }
+}
+
+/// Build the response to the client from a cached or fetched origin response.
+fn respond(req: &RequestContext, entry: Entry, cache: &str, now: u64) -> Response {
+    let mut headers: Vec<(String, Vec<u8>)> = entry
+        .headers
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone().into_bytes()))
+        .collect();
+    headers.push((X_CACHE.to_string(), cache.as_bytes().to_vec()));
+    headers.push((
+        AGE.as_str().to_string(),
+        now.saturating_sub(entry.stored_at).to_string().into_bytes(),
+    ));
+    let not_modified = entry
+        .header(ETAG.as_str())
+        .is_some_and(|etag| req.header(&IF_NONE_MATCH) == Some(etag.as_bytes()));
+    if not_modified {
+        return (StatusCode::NOT_MODIFIED, headers, None);
+    }
+    (
+        StatusCode::OK,
+        headers,
+        Some(Box::new(Cursor::new(entry.body))),
+    )
+}
+
+/// Return how long a response with the given `Cache-Control` header stays fresh in a shared
+/// cache, or `None` if it must not be stored.
+fn max_age(cache_control: &str) -> Option<u64> {
+    let directives = cache_control
+        .split(',')
+        .map(|d| d.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    if directives.iter().any(|d| d == "no-store" || d == "private") {
+        return None;
+    }
+    if directives.iter().any(|d| d == "no-cache") {
+        return Some(0);
+    }
+    let seconds = |name: &str| {
+        directives.iter().find_map(|d| {
+            d.strip_prefix(name)?
+                .strip_prefix('=')?
+                .trim_matches('"')
+                .parse()
+                .ok()
+        })
+    };
+    Some(
+        seconds("s-maxage")
+            .or_else(|| seconds("max-age"))
+            .unwrap_or_default(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_max_age() {
+        assert_eq!(max_age("public, max-age=60"), Some(60));
+        assert_eq!(max_age("max-age=60, s-maxage=600"), Some(600));
+        assert_eq!(max_age("no-cache, max-age=60"), Some(0));
+        assert_eq!(max_age("max-age=60, private"), None);
+        assert_eq!(max_age("no-store"), None);
+        assert_eq!(max_age(""), Some(0));
+    }
+
+    #[test]
+    fn test_entry() {
+        let entry = Entry {
+            stored_at: 1_700_000_000,
+            max_age: 60,
+            headers: vec![("etag".to_string(), "\"abc\"".to_string())],
+            body: b"line 1\nline 2".to_vec(),
+        };
+        let decoded = Entry::decode(&entry.encode().unwrap()).unwrap();
+        assert_eq!(decoded, entry);
+        assert_eq!(decoded.header("ETag"), Some("\"abc\""));
+        assert!(entry.is_fresh(1_700_000_059));
+        assert!(!entry.is_fresh(1_700_000_060));
+        assert_eq!(Entry::decode(b"garbage"), None);
+    }
+
+    #[test]
+    fn test_respond() {
+        let entry = || Entry {
+            stored_at: 100,
+            max_age: 60,
+            headers: vec![("etag".to_string(), "\"abc\"".to_string())],
+            body: b"hello".to_vec(),
+        };
+        let (status, headers, body) = respond(&RequestContext::default(), entry(), "HIT", 130);
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains(&("age".to_string(), b"30".to_vec())));
+        assert!(headers.contains(&("x-cache".to_string(), b"HIT".to_vec())));
+        assert!(body.is_some());
+
+        let req = RequestContext {
+            headers: vec![("if-none-match".to_string(), b"\"abc\"".to_vec())],
+            ..Default::default()
+        };
+        let (status, _, body) = respond(&req, entry(), "HIT", 130);
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert!(body.is_none());
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = Policy::from_env();
+        assert_eq!(policy.retries, DEFAULT_RETRIES);
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_breaker() {
+        let mut breaker = Breaker::default();
+        assert!(!breaker.record(true, 2, 100));
+        assert!(breaker.record(false, 2, 100));
+        assert!(!breaker.is_open(30, 100));
+        assert!(breaker.record(false, 2, 110));
+        assert!(breaker.is_open(30, 139));
+        // let through after the cooldown, and opened again by the next failure
+        assert!(!breaker.is_open(30, 140));
+        assert!(breaker.record(false, 2, 140));
+        assert!(breaker.is_open(30, 169));
+        assert!(breaker.record(true, 2, 170));
+        assert_eq!(breaker, Breaker::default());
+    }
+}
@@ -0,0 +1,109 @@
+//! Assets precompressed at build time, e.g. by `spin-fileserver-precompress`.
+//!
+//! A precompressed file sits next to the original with an extra `.br` or `.gz` extension and is
+//! served as-is instead of compressing the original on the fly. An optional JSON manifest maps
+//! each file path to the ETags of its encodings (`identity`, `br` and `gz`), so they don't have
+//! to be computed by hashing the file.
+
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{backend, settings, SupportedEncoding};
+
+/// Environment variable enabling precompressed siblings
+pub(crate) const PRECOMPRESSED_ENV: &str = "PRECOMPRESSED";
+/// Environment variable for the path of the ETag manifest
+pub(crate) const ETAG_MANIFEST_ENV: &str = "ETAG_MANIFEST";
+
+/// ETags of a file by encoding name.
+type Etags = HashMap<String, String>;
+
+/// Return the precompressed sibling of a file for the given encoding, if there is one.
+pub(crate) fn sibling(file: &Path, encoding: SupportedEncoding) -> Option<PathBuf> {
+    if settings::var(PRECOMPRESSED_ENV).as_deref().map(str::trim) != Some("true") {
+        return None;
+    }
+    let mut sibling = file.as_os_str().to_owned();
+    sibling.push(".");
+    sibling.push(extension(encoding)?);
+    let sibling = PathBuf::from(sibling);
+    backend::current().is_file(&sibling).then_some(sibling)
+}
+
+/// Look up the ETag of a file in the manifest.
+/// ETags of compressed encodings are only used when the precompressed sibling they were computed
+/// from is being served.
+pub(crate) fn manifest_etag(file: &Path, encoding: SupportedEncoding) -> Result<Option<String>> {
+    let Some(manifest) = settings::var(ETAG_MANIFEST_ENV) else {
+        return Ok(None);
+    };
+    if encoding != SupportedEncoding::None && sibling(file, encoding).is_none() {
+        return Ok(None);
+    }
+    Ok(lookup(&read_manifest(&manifest)?, file, encoding))
+}
+
+/// Read and parse the ETag manifest at `path`.
+pub(crate) fn read_manifest(path: &str) -> Result<HashMap<String, Etags>> {
+    let contents =
+        std::fs::read(path).with_context(|| format!("cannot read ETag manifest {path}"))?;
+    serde_json::from_slice(&contents).with_context(|| format!("invalid ETag manifest {path}"))
+}
+
+fn lookup(
+    manifest: &HashMap<String, Etags>,
+    file: &Path,
+    encoding: SupportedEncoding,
+) -> Option<String> {
+    let name = match encoding {
+        SupportedEncoding::None => "identity",
+        encoding => extension(encoding)?,
+    };
+    manifest
+        .get(file.to_str()?)
+        .and_then(|etags| etags.get(name))
+        .cloned()
+}
+
+/// The file extension of precompressed files, for the encodings that can be precompressed.
+fn extension(encoding: SupportedEncoding) -> Option<&'static str> {
+    match encoding {
+        SupportedEncoding::Brotli => Some("br"),
+        SupportedEncoding::Gzip => Some("gz"),
+        SupportedEncoding::Deflate | SupportedEncoding::None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let manifest = HashMap::from([(
+            "/index.html".to_string(),
+            HashMap::from([
+                ("identity".to_string(), "abc".to_string()),
+                ("br".to_string(), "def".to_string()),
+            ]),
+        )]);
+        let file = Path::new("/index.html");
+        assert_eq!(
+            lookup(&manifest, file, SupportedEncoding::None),
+            Some("abc".to_string())
+        );
+        assert_eq!(
+            lookup(&manifest, file, SupportedEncoding::Brotli),
+            Some("def".to_string())
+        );
+        assert_eq!(lookup(&manifest, file, SupportedEncoding::Gzip), None);
+        assert_eq!(lookup(&manifest, file, SupportedEncoding::Deflate), None);
+        assert_eq!(
+            lookup(&manifest, Path::new("/other.html"), SupportedEncoding::None),
+            None
+        );
+    }
+}
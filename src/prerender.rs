@@ -0,0 +1,101 @@
+//! Pre-rendered pages for crawlers, so client-rendered apps are indexed from their content rather
+//! than their empty shell, without a third-party prerendering service.
+//!
+//! When a directory of pre-rendered pages is configured, requests from crawlers for a path with a
+//! page there, such as `/about` for `prerendered/about.html`, get the page instead. Other clients
+//! get the app as usual.
+
+use regex::Regex;
+use std::path::Path;
+
+use http::header::USER_AGENT;
+
+use crate::{backend, settings, RequestContext};
+
+/// Environment variable for the directory of pre-rendered pages, enabling them
+pub(crate) const PRERENDERED_DIR_ENV: &str = "PRERENDERED_DIR";
+/// Environment variable for the regular expression matching the `User-Agent` of crawlers
+pub(crate) const PRERENDER_CRAWLERS_ENV: &str = "PRERENDER_CRAWLERS";
+/// Crawlers of search engines and link previews matched by default
+pub(crate) const DEFAULT_CRAWLERS: &str = "(?i)googlebot|bingbot|yandex|baiduspider|duckduckbot|\
+    slurp|applebot|facebookexternalhit|twitterbot|linkedinbot|slackbot|discordbot|whatsapp|\
+    telegrambot|pinterest|redditbot|embedly";
+
+/// Return the pre-rendered page of a request path, if there is one.
+pub(crate) fn page(path: &str) -> Option<String> {
+    let dir = settings::var(PRERENDERED_DIR_ENV)?;
+    let backend = backend::current();
+    candidates(dir.trim(), path)
+        .into_iter()
+        .find(|candidate| backend.is_file(Path::new(candidate)))
+}
+
+/// Check whether a request comes from a crawler. An invalid pattern matches nothing.
+pub(crate) fn is_crawler(req: &RequestContext) -> bool {
+    let Some(user_agent) = req
+        .header(&USER_AGENT)
+        .and_then(|ua| std::str::from_utf8(ua).ok())
+    else {
+        return false;
+    };
+    let pattern = settings::var(PRERENDER_CRAWLERS_ENV);
+    let pattern = pattern.as_deref().map_or(DEFAULT_CRAWLERS, str::trim);
+    match Regex::new(pattern) {
+        Ok(pattern) => pattern.is_match(user_agent),
+        Err(e) => {
+            eprintln!("{PRERENDER_CRAWLERS_ENV} {pattern:?} is invalid, ignoring it: {e}");
+            false
+        }
+    }
+}
+
+/// Return the files that can hold the pre-rendered page of a request path, in order.
+fn candidates(dir: &str, path: &str) -> Vec<String> {
+    let dir = dir.trim_end_matches('/');
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        return vec![format!("{dir}/index.html")];
+    }
+    if path.ends_with(".html") {
+        return vec![format!("{dir}/{path}")];
+    }
+    vec![
+        format!("{dir}/{path}.html"),
+        format!("{dir}/{path}/index.html"),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates() {
+        assert_eq!(
+            candidates("/prerendered/", "/"),
+            ["/prerendered/index.html"]
+        );
+        assert_eq!(
+            candidates("/prerendered", "/blog/post-1/"),
+            [
+                "/prerendered/blog/post-1.html",
+                "/prerendered/blog/post-1/index.html"
+            ]
+        );
+        assert_eq!(
+            candidates("prerendered", "/about.html"),
+            ["prerendered/about.html"]
+        );
+    }
+
+    #[test]
+    fn test_default_crawlers() {
+        let pattern = Regex::new(DEFAULT_CRAWLERS).unwrap();
+        assert!(pattern
+            .is_match("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)"));
+        assert!(pattern.is_match("facebookexternalhit/1.1"));
+        assert!(!pattern.is_match(
+            "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 Safari/605.1.15"
+        ));
+    }
+}
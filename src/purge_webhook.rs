@@ -0,0 +1,146 @@
+//! Outbound webhook notifying a CDN when cached responses need to be purged, so that updates to
+//! the files served reach visitors without waiting for the CDN cache to expire.
+//!
+//! The webhook is called when the ETag cache is purged through the admin route, and when a file
+//! is served whose ETag differs from the one cached for an earlier version of it. Failures are
+//! logged and never fail the request.
+
+use anyhow::{bail, Result};
+use spin_sdk::http::{Method, Request};
+
+use crate::{request_url, settings, RequestContext};
+
+/// Environment variable for the URL the webhook posts to
+pub(crate) const PURGE_WEBHOOK_URL_ENV: &str = "PURGE_WEBHOOK_URL";
+/// Environment variable for the `Authorization` header of webhook requests
+pub(crate) const PURGE_WEBHOOK_AUTHORIZATION_ENV: &str = "PURGE_WEBHOOK_AUTHORIZATION";
+/// Environment variable for the body format of webhook requests (`json` or `cloudflare`)
+pub(crate) const PURGE_WEBHOOK_FORMAT_ENV: &str = "PURGE_WEBHOOK_FORMAT";
+
+/// What needs to be purged.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Purge {
+    /// Everything served by the component.
+    All,
+    /// The response for a request path, along with its full URL.
+    Changed { path: String, url: String },
+}
+
+impl Purge {
+    /// Build the purge of the response to a request.
+    pub fn changed(req: &RequestContext) -> Self {
+        Self::Changed {
+            path: String::from_utf8_lossy(&req.path).into_owned(),
+            url: request_url::requested(req).unwrap_or_default(),
+        }
+    }
+}
+
+/// Body formats of webhook requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// `{"event": "purge-all"}` or `{"event": "changed", "paths": [...], "urls": [...]}`
+    Json,
+    /// The body of the Cloudflare purge API, purging everything or the changed URL.
+    Cloudflare,
+}
+
+/// Check whether a webhook is configured.
+pub(crate) fn enabled() -> bool {
+    settings::var(PURGE_WEBHOOK_URL_ENV).is_some()
+}
+
+/// Call the webhook, if configured, logging failures.
+pub(crate) fn notify(purge: &Purge) {
+    let Some(url) = settings::var(PURGE_WEBHOOK_URL_ENV) else {
+        return;
+    };
+    if let Err(e) = send(url.trim(), purge) {
+        eprintln!("Error calling the purge webhook: {e:#}");
+    }
+}
+
+fn send(url: &str, purge: &Purge) -> Result<()> {
+    let format = match settings::var(PURGE_WEBHOOK_FORMAT_ENV)
+        .as_deref()
+        .map(str::trim)
+    {
+        Some("cloudflare") => Format::Cloudflare,
+        _ => Format::Json,
+    };
+    let mut request = Request::builder();
+    request
+        .method(Method::Post)
+        .uri(url)
+        .header("content-type", "application/json")
+        .body(body(format, purge).to_string());
+    if let Some(authorization) = settings::var(PURGE_WEBHOOK_AUTHORIZATION_ENV) {
+        request.header("authorization", authorization.trim());
+    }
+    let response: spin_sdk::http::Response =
+        spin_sdk::http::run(spin_sdk::http::send(request.build()))?;
+    let status = *response.status();
+    if !(200..300).contains(&status) {
+        bail!("{url} responded with status {status}");
+    }
+    Ok(())
+}
+
+fn body(format: Format, purge: &Purge) -> serde_json::Value {
+    match (format, purge) {
+        (Format::Json, Purge::All) => serde_json::json!({ "event": "purge-all" }),
+        (Format::Json, Purge::Changed { path, url }) => {
+            serde_json::json!({ "event": "changed", "paths": [path], "urls": [url] })
+        }
+        (Format::Cloudflare, Purge::All) => serde_json::json!({ "purge_everything": true }),
+        (Format::Cloudflare, Purge::Changed { url, .. }) => serde_json::json!({ "files": [url] }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changed() {
+        let req = RequestContext {
+            path: b"/index.html".to_vec(),
+            headers: vec![(
+                "Spin-Full-Url".to_string(),
+                b"https://example.com/docs/index.html?v=2".to_vec(),
+            )],
+            ..Default::default()
+        };
+        assert_eq!(
+            Purge::changed(&req),
+            Purge::Changed {
+                path: "/index.html".to_string(),
+                url: "https://example.com/docs/index.html".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_body() {
+        let changed = Purge::Changed {
+            path: "/index.html".to_string(),
+            url: "https://example.com/index.html".to_string(),
+        };
+        assert_eq!(
+            body(Format::Json, &changed),
+            serde_json::json!({
+                "event": "changed",
+                "paths": ["/index.html"],
+                "urls": ["https://example.com/index.html"]
+            })
+        );
+        assert_eq!(
+            body(Format::Cloudflare, &changed),
+            serde_json::json!({ "files": ["https://example.com/index.html"] })
+        );
+        assert_eq!(
+            body(Format::Cloudflare, &Purge::All),
+            serde_json::json!({ "purge_everything": true })
+        );
+    }
+}
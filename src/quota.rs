@@ -0,0 +1,191 @@
+//! Bytes served per site and month, for multi-tenant hosting where many sites are host names
+//! pointed at the same component, with an optional monthly quota per site.
+//!
+//! Sites are told apart by the host clients sent their requests to. The bytes of response bodies
+//! are added up in the default key-value store once they have been sent. The store has no atomic
+//! updates, so totals are best-effort under heavy contention, and the request that crosses the
+//! quota is still served in full.
+
+use anyhow::{Context, Result};
+use http::{header::RETRY_AFTER, StatusCode};
+use std::{
+    collections::BTreeMap,
+    io::Cursor,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    access_log::{civil_time, days_from_civil},
+    downloads::parse_count,
+    host::Store,
+    request_url::RequestUrl,
+    settings, RequestContext, Response,
+};
+
+/// Environment variable enabling the accounting of bytes served per site
+pub(crate) const BANDWIDTH_ACCOUNTING_ENV: &str = "BANDWIDTH_ACCOUNTING";
+/// Environment variable for the bytes each site may serve per month, enabling the accounting
+pub(crate) const BANDWIDTH_QUOTA_ENV: &str = "BANDWIDTH_QUOTA";
+/// Environment variable for the status of responses once the quota is exceeded, 429 or 509
+pub(crate) const BANDWIDTH_QUOTA_STATUS_ENV: &str = "BANDWIDTH_QUOTA_STATUS";
+/// Statuses responses can have once the quota is exceeded
+pub(crate) const QUOTA_STATUSES: [u16; 2] = [429, 509];
+/// Key-value store key prefix of the byte counters
+const BANDWIDTH_PREFIX: &str = "spin-fileserver:bandwidth:";
+/// Site of requests without a known host
+const UNKNOWN_SITE: &str = "unknown";
+
+/// Check whether bytes served are accounted for.
+pub(crate) fn enabled() -> bool {
+    settings::var(BANDWIDTH_ACCOUNTING_ENV).is_some_and(|v| v.trim() == "true") || quota().is_some()
+}
+
+/// Return the bytes each site may serve per month, if limited.
+fn quota() -> Option<u64> {
+    settings::var(BANDWIDTH_QUOTA_ENV).and_then(|v| v.trim().parse().ok())
+}
+
+/// Answer requests of sites that exceeded their quota this month.
+pub(crate) fn check(req: &RequestContext) -> Result<Option<Response>> {
+    let Some(quota) = quota() else {
+        return Ok(None);
+    };
+    let store = open()?;
+    let now = SystemTime::now();
+    let key = key(&month(now), &site(req));
+    if parse_count(&store.get(&key)?.unwrap_or_default()) < quota {
+        return Ok(None);
+    }
+    let status = settings::var(BANDWIDTH_QUOTA_STATUS_ENV)
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|status| QUOTA_STATUSES.contains(status))
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .unwrap_or(StatusCode::TOO_MANY_REQUESTS);
+    Ok(Some((
+        status,
+        vec![(
+            RETRY_AFTER.as_str().to_string(),
+            until_next_month(now).as_secs().to_string().into_bytes(),
+        )],
+        Some(Box::new(Cursor::new(b"Monthly bandwidth quota exceeded"))),
+    )))
+}
+
+/// Add the bytes of a response body to the total of the site of its request.
+pub(crate) fn record(req: &RequestContext, bytes: usize) -> Result<()> {
+    if bytes == 0 {
+        return Ok(());
+    }
+    let store = open()?;
+    let key = key(&month(SystemTime::now()), &site(req));
+    let total = parse_count(&store.get(&key)?.unwrap_or_default());
+    store.set(&key, (total + bytes as u64).to_string().as_bytes())?;
+    Ok(())
+}
+
+/// Return the bytes served by every site, by month.
+pub(crate) fn report() -> Result<BTreeMap<String, BTreeMap<String, u64>>> {
+    let store = open()?;
+    let mut report = BTreeMap::<String, BTreeMap<String, u64>>::new();
+    for key in store.get_keys()? {
+        let Some((month, site)) = parse_key(&key) else {
+            continue;
+        };
+        let bytes = parse_count(&store.get(&key)?.unwrap_or_default());
+        report
+            .entry(month.to_string())
+            .or_default()
+            .insert(site.to_string(), bytes);
+    }
+    Ok(report)
+}
+
+fn open() -> Result<Store> {
+    Store::open_default().context("cannot open the key-value store to account for bytes served")
+}
+
+/// Return the site of a request: the lowercase host it was sent to, with its port if any.
+fn site(req: &RequestContext) -> String {
+    RequestUrl::of(req)
+        .host()
+        .unwrap_or_else(|| UNKNOWN_SITE.to_string())
+}
+
+fn key(month: &str, site: &str) -> String {
+    format!("{BANDWIDTH_PREFIX}{month}:{site}")
+}
+
+/// Split a counter key into its month and site.
+fn parse_key(key: &str) -> Option<(&str, &str)> {
+    key.strip_prefix(BANDWIDTH_PREFIX)?.split_once(':')
+}
+
+/// The UTC month of a time as `YYYY-MM`.
+fn month(time: SystemTime) -> String {
+    let ((year, month, _), _) = civil_time(time);
+    format!("{year:04}-{month:02}")
+}
+
+/// Return the time left until the UTC month after the one of a time starts, when quotas reset.
+fn until_next_month(time: SystemTime) -> Duration {
+    let ((year, month, _), _) = civil_time(time);
+    let (year, month) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    let start = UNIX_EPOCH + Duration::from_secs(days_from_civil(year, month, 1) as u64 * 86400);
+    start.duration_since(time).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_months() {
+        // 2024-02-29T12:00:00Z
+        let leap_day = at(1709208000);
+        assert_eq!(month(leap_day), "2024-02");
+        assert_eq!(until_next_month(leap_day), Duration::from_secs(12 * 3600));
+        // 2023-12-31T23:59:59Z
+        assert_eq!(month(at(1704067199)), "2023-12");
+        assert_eq!(until_next_month(at(1704067199)), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_site() {
+        let req = |headers: &[(&str, &str)]| RequestContext {
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.as_bytes().to_vec()))
+                .collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            site(&req(&[("host", "Example.com:8080")])),
+            "example.com:8080"
+        );
+        assert_eq!(
+            site(&req(&[
+                ("host", "fs.internal"),
+                ("spin-full-url", "https://blog.example.com/a.txt")
+            ])),
+            "blog.example.com"
+        );
+        assert_eq!(site(&req(&[])), UNKNOWN_SITE);
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(
+            parse_key(&key("2024-02", "example.com:8080")),
+            Some(("2024-02", "example.com:8080"))
+        );
+        assert_eq!(parse_key("spin-fileserver:downloads:/a"), None);
+    }
+}
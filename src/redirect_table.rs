@@ -0,0 +1,239 @@
+//! Redirects and rewrites looked up in a Spin SQLite table, for sites with too many legacy URLs
+//! to list in the configuration file.
+//!
+//! The table is queried by primary key on every request. Spin instantiates the component for every
+//! request, so entries aren't cached in memory between requests.
+//!
+//! Rewrites are followed until a path without an entry is reached, and chains of redirects to
+//! local paths are collapsed into a single redirect, up to [`MAX_HOPS`] entries. Cycles are
+//! reported as a [`LoopError`].
+
+use anyhow::{bail, Result};
+use http::StatusCode;
+use std::fmt;
+#[cfg(feature = "spin")]
+use {
+    anyhow::Context,
+    spin_sdk::sqlite::{Connection, Value},
+};
+
+use crate::settings;
+
+/// Environment variable for the label of the SQLite database holding the table
+pub(crate) const REDIRECT_DATABASE_ENV: &str = "REDIRECT_DATABASE";
+/// Environment variable for the name of the table
+pub(crate) const REDIRECT_TABLE_ENV: &str = "REDIRECT_TABLE";
+/// Default name of the table
+pub(crate) const DEFAULT_TABLE: &str = "redirects";
+/// Status of entries that rewrite the request path instead of redirecting
+const REWRITE_STATUS: i64 = 200;
+/// Maximum number of entries followed for a single request
+const MAX_HOPS: usize = 10;
+
+/// What to do with a request path listed in the table.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Entry {
+    /// Redirect to the target with the given status.
+    Redirect(StatusCode, String),
+    /// Serve the target instead.
+    Rewrite(String),
+}
+
+impl Entry {
+    fn target(&self) -> &str {
+        match self {
+            Self::Redirect(_, target) | Self::Rewrite(target) => target,
+        }
+    }
+}
+
+/// Entries that point back at each other, or too many entries chained together.
+#[derive(Debug)]
+pub(crate) struct LoopError(Vec<String>);
+
+impl fmt::Display for LoopError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "redirect table loop: {}", self.0.join(" -> "))
+    }
+}
+
+impl std::error::Error for LoopError {}
+
+/// Look up a request path, if a redirect table is configured.
+pub(crate) fn lookup(path: &str) -> Result<Option<Entry>> {
+    let Some(database) = settings::var(REDIRECT_DATABASE_ENV) else {
+        return Ok(None);
+    };
+    let table = settings::var(REDIRECT_TABLE_ENV);
+    let table = table_name(table.as_deref())?;
+    query(database.trim(), table, path)
+}
+
+/// Follow the entries of a path in the table of a Spin SQLite database.
+#[cfg(feature = "spin")]
+fn query(database: &str, table: &str, path: &str) -> Result<Option<Entry>> {
+    let connection = Connection::open(database)
+        .with_context(|| format!("cannot open SQLite database {database}"))?;
+    follow(path, |path| lookup_one(&connection, table, path))
+}
+
+/// SQLite databases are only available to the component built with the Spin host APIs.
+#[cfg(not(feature = "spin"))]
+fn query(_database: &str, _table: &str, _path: &str) -> Result<Option<Entry>> {
+    bail!("SQLite is not available, the file server was built without the spin feature")
+}
+
+/// Follow the entries starting at `path`, returning the resulting redirect or rewrite.
+#[cfg_attr(not(feature = "spin"), allow(dead_code))]
+fn follow(
+    path: &str,
+    mut lookup: impl FnMut(&str) -> Result<Option<Entry>>,
+) -> Result<Option<Entry>> {
+    let mut hops = vec![path.to_string()];
+    let mut current = path.to_string();
+    let mut result: Option<Entry> = None;
+    while let Some(entry) = lookup(&current)? {
+        // a redirect is only collapsed with further redirects, since the client requests its
+        // target again anyway
+        if let (Some(Entry::Redirect(..)), Entry::Rewrite(_)) = (&result, &entry) {
+            break;
+        }
+        current = entry.target().to_string();
+        let seen = hops.contains(&current);
+        hops.push(current.clone());
+        if seen || hops.len() > MAX_HOPS {
+            return Err(LoopError(hops).into());
+        }
+        result = Some(entry);
+        // stop at absolute URLs, which can't be in the table
+        if !current.starts_with('/') {
+            break;
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(feature = "spin")]
+fn lookup_one(connection: &Connection, table: &str, path: &str) -> Result<Option<Entry>> {
+    let result = connection
+        .execute(
+            &format!("SELECT target, status FROM {table} WHERE path = ? LIMIT 1"),
+            &[Value::Text(path.to_string())],
+        )
+        .with_context(|| format!("cannot query redirect table {table}"))?;
+    let Some(row) = result.rows().next() else {
+        return Ok(None);
+    };
+    let target = row
+        .get::<&str>("target")
+        .with_context(|| format!("target of {path} in {table} is not text"))?;
+    let status = row.get::<i64>("status").unwrap_or(301);
+    entry(target, status).map(Some)
+}
+
+#[cfg_attr(not(feature = "spin"), allow(dead_code))]
+fn entry(target: &str, status: i64) -> Result<Entry> {
+    match status {
+        REWRITE_STATUS => Ok(Entry::Rewrite(target.to_string())),
+        301 | 302 | 303 | 307 | 308 => Ok(Entry::Redirect(
+            StatusCode::from_u16(status as u16)?,
+            target.to_string(),
+        )),
+        _ => bail!("invalid redirect status {status} for {target}"),
+    }
+}
+
+/// Validate the configured table name, since it can't be passed as a query parameter.
+pub(crate) fn table_name(name: Option<&str>) -> Result<&str> {
+    let name = name.map(str::trim).unwrap_or(DEFAULT_TABLE);
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        bail!("invalid redirect table name {name:?}");
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry() {
+        assert_eq!(
+            entry("/new", 200).unwrap(),
+            Entry::Rewrite("/new".to_string())
+        );
+        assert_eq!(
+            entry("https://example.com/", 308).unwrap(),
+            Entry::Redirect(
+                StatusCode::PERMANENT_REDIRECT,
+                "https://example.com/".to_string()
+            )
+        );
+        assert!(entry("/new", 404).is_err());
+    }
+
+    #[test]
+    fn test_table_name() {
+        assert_eq!(table_name(None).unwrap(), "redirects");
+        assert_eq!(table_name(Some(" legacy_urls ")).unwrap(), "legacy_urls");
+        assert!(table_name(Some("redirects; DROP TABLE x")).is_err());
+        assert!(table_name(Some("1st")).is_err());
+    }
+
+    #[test]
+    fn test_follow() {
+        let table = |path: &str| {
+            Ok(match path {
+                "/a" => Some(Entry::Rewrite("/b".to_string())),
+                "/b" => Some(Entry::Redirect(StatusCode::FOUND, "/c".to_string())),
+                "/c" => Some(Entry::Redirect(
+                    StatusCode::MOVED_PERMANENTLY,
+                    "/d".to_string(),
+                )),
+                "/d" => Some(Entry::Rewrite("/e".to_string())),
+                "/x" => Some(Entry::Redirect(
+                    StatusCode::FOUND,
+                    "https://example.com/x".to_string(),
+                )),
+                "/loop1" => Some(Entry::Rewrite("/loop2".to_string())),
+                "/loop2" => Some(Entry::Rewrite("/loop1".to_string())),
+                path => path
+                    .strip_prefix("/chain")
+                    .map(|n| Entry::Rewrite(format!("/chain{}", n.parse::<u32>().unwrap() + 1))),
+            })
+        };
+
+        assert_eq!(
+            follow("/a", table).unwrap(),
+            Some(Entry::Redirect(
+                StatusCode::MOVED_PERMANENTLY,
+                "/d".to_string()
+            ))
+        );
+        assert_eq!(
+            follow("/d", table).unwrap(),
+            Some(Entry::Rewrite("/e".to_string()))
+        );
+        assert_eq!(
+            follow("/x", table).unwrap(),
+            Some(Entry::Redirect(
+                StatusCode::FOUND,
+                "https://example.com/x".to_string()
+            ))
+        );
+        assert_eq!(follow("/e", table).unwrap(), None);
+
+        let err = follow("/loop1", table).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "redirect table loop: /loop1 -> /loop2 -> /loop1"
+        );
+        assert!(err.is::<LoopError>());
+        assert!(follow("/chain1", table).unwrap_err().is::<LoopError>());
+    }
+}
@@ -0,0 +1,192 @@
+//! Versioned site roots: every release is deployed next to the others as `<RELEASES_DIR>/<version>/`
+//! and a pointer selects the one being served, so deploying or rolling back is a single admin call
+//! that doesn't touch the Spin manifest.
+//!
+//! The pointer is a key of the default key-value store, set through the `releases` admin route.
+//! Until it is set, the version named in the `current` file of the releases directory is served.
+//! Requests carrying the configured preview header or cookie are served from the release it names
+//! instead, so a deploy can be previewed on the production URL before it is activated.
+
+use anyhow::{bail, Result};
+use http::{header::COOKIE, HeaderName};
+use serde::Serialize;
+use std::{fs, path::Path, str};
+
+use crate::{host::Store, settings, RequestContext};
+
+/// Environment variable for the directory holding one subdirectory per release
+pub(crate) const RELEASES_DIR_ENV: &str = "RELEASES_DIR";
+/// Environment variable for the request header naming a release to preview
+pub(crate) const RELEASE_PREVIEW_HEADER_ENV: &str = "RELEASE_PREVIEW_HEADER";
+/// Environment variable for the cookie naming a release to preview
+pub(crate) const RELEASE_PREVIEW_COOKIE_ENV: &str = "RELEASE_PREVIEW_COOKIE";
+/// Key-value store key holding the active version
+const CURRENT_KEY: &str = "spin-fileserver:current-release";
+/// File of the releases directory naming the version to serve until the pointer is set
+const CURRENT_FILE: &str = "current";
+
+/// The releases and the active one.
+#[derive(Debug, Serialize)]
+pub(crate) struct Releases {
+    current: Option<String>,
+    releases: Vec<String>,
+}
+
+/// The release a request is served from.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Release {
+    /// The directory of the release, e.g. `releases/v2`.
+    pub dir: String,
+    /// The request headers the choice of release depends on.
+    pub vary: Vec<HeaderName>,
+}
+
+/// Return the configured releases directory, relative to the root of the served files.
+fn releases_dir() -> Option<String> {
+    settings::var(RELEASES_DIR_ENV).map(|dir| dir.trim().trim_matches('/').to_string())
+}
+
+/// Return the release a request is served from, if releases are enabled: the release it asks to
+/// preview if there is one, or else the active release.
+pub(crate) fn select(req: &RequestContext) -> Result<Option<Release>> {
+    let Some(dir) = releases_dir() else {
+        return Ok(None);
+    };
+    let header = settings::var(RELEASE_PREVIEW_HEADER_ENV)
+        .and_then(|h| HeaderName::from_bytes(h.trim().as_bytes()).ok());
+    let cookie = settings::var(RELEASE_PREVIEW_COOKIE_ENV).map(|c| c.trim().to_string());
+    let vary = header
+        .iter()
+        .cloned()
+        .chain(cookie.is_some().then_some(COOKIE))
+        .collect();
+    let preview = preview(req, header.as_ref(), cookie.as_deref())
+        .filter(|version| Path::new("/").join(&dir).join(version).is_dir());
+    let Some(version) = preview.or_else(|| current(&dir)) else {
+        bail!("no release is active, and {dir}/{CURRENT_FILE} names none");
+    };
+    Ok(Some(Release {
+        dir: format!("{dir}/{version}"),
+        vary,
+    }))
+}
+
+/// Return the release a request asks to preview, from the header first, then the cookie.
+fn preview(
+    req: &RequestContext,
+    header: Option<&HeaderName>,
+    cookie: Option<&str>,
+) -> Option<String> {
+    let from_header = header
+        .and_then(|name| req.header(name))
+        .and_then(|value| str::from_utf8(value).ok());
+    let from_cookie = cookie.and_then(|name| req.cookie(name));
+    from_header
+        .or(from_cookie)
+        .map(str::trim)
+        .filter(|version| is_version(version))
+        .map(str::to_string)
+}
+
+/// Return the active version, from the pointer or the `current` file.
+fn current(dir: &str) -> Option<String> {
+    // the pointer is optional, so deployments using the file don't need a key-value store
+    let pointer = Store::open_default()
+        .ok()
+        .and_then(|store| store.get(CURRENT_KEY).ok().flatten())
+        .and_then(|version| String::from_utf8(version).ok());
+    let version =
+        pointer.or_else(|| fs::read_to_string(Path::new("/").join(dir).join(CURRENT_FILE)).ok())?;
+    let version = version.trim();
+    is_version(version).then(|| version.to_string())
+}
+
+/// List the releases, in alphabetical order.
+pub(crate) fn list() -> Result<Option<Releases>> {
+    let Some(dir) = releases_dir() else {
+        return Ok(None);
+    };
+    let mut releases = Vec::new();
+    for entry in fs::read_dir(Path::new("/").join(&dir))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if entry.path().is_dir() && is_version(&name) {
+            releases.push(name);
+        }
+    }
+    releases.sort();
+    Ok(Some(Releases {
+        current: current(&dir),
+        releases,
+    }))
+}
+
+/// Make a release the active one.
+/// Returns `false` if releases aren't enabled or there is no such release.
+pub(crate) fn activate(version: &str) -> Result<bool> {
+    let Some(dir) = releases_dir() else {
+        return Ok(false);
+    };
+    if !is_version(version) || !Path::new("/").join(dir).join(version).is_dir() {
+        return Ok(false);
+    }
+    Store::open_default()?.set(CURRENT_KEY, version.as_bytes())?;
+    Ok(true)
+}
+
+/// Check whether a name can be a release, which rules out paths escaping the releases directory.
+fn is_version(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_version() {
+        assert!(is_version("v1.2.0"));
+        assert!(is_version("2024-06-01_build-7"));
+        assert!(!is_version(""));
+        assert!(!is_version(".."));
+        assert!(!is_version("v1/../v2"));
+        assert!(!is_version("v1 "));
+    }
+
+    #[test]
+    fn test_preview() {
+        let header = HeaderName::from_static("x-preview");
+        let req = |headers: &[(&str, &str)]| RequestContext {
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.as_bytes().to_vec()))
+                .collect(),
+            ..Default::default()
+        };
+        let preview = |req: &RequestContext| preview(req, Some(&header), Some("preview"));
+
+        assert_eq!(preview(&req(&[])), None);
+        assert_eq!(
+            preview(&req(&[("X-Preview", " v3 ")])),
+            Some("v3".to_string())
+        );
+        assert_eq!(
+            preview(&req(&[("cookie", "theme=dark; preview=v3")])),
+            Some("v3".to_string())
+        );
+        assert_eq!(
+            preview(&req(&[("x-preview", "v4"), ("cookie", "preview=v3")])),
+            Some("v4".to_string())
+        );
+        assert_eq!(preview(&req(&[("x-preview", "../secrets")])), None);
+        assert_eq!(
+            super::preview(&req(&[("x-preview", "v3")]), None, None),
+            None
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! `Repr-Digest` headers (RFC 9530), sent when requested with `Want-Repr-Digest`, so clients can
+//! verify the integrity of downloads end to end.
+//!
+//! ETags are SHA-256 digests of the representation, whether computed, cached or read from the
+//! ETag manifest, so the digest comes for free.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::RequestContext;
+
+/// Name of the header requesting digests
+const WANT_REPR_DIGEST: &str = "want-repr-digest";
+/// Name of the header carrying digests
+const REPR_DIGEST: &str = "repr-digest";
+/// The only supported digest algorithm
+const SHA_256: &str = "sha-256";
+
+/// Check whether the request asks for a SHA-256 digest: `Want-Repr-Digest` lists `sha-256`
+/// with a non-zero preference.
+pub(crate) fn wanted(req: &RequestContext) -> bool {
+    req.headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case(WANT_REPR_DIGEST))
+        .filter_map(|(_, v)| std::str::from_utf8(v).ok())
+        .flat_map(|v| v.split(','))
+        .any(|member| {
+            let (algorithm, preference) = member.split_once('=').unwrap_or((member, "1"));
+            algorithm.trim().eq_ignore_ascii_case(SHA_256)
+                && preference
+                    .split(';')
+                    .next()
+                    .and_then(|p| p.trim().parse::<u8>().ok())
+                    .is_some_and(|p| p > 0)
+        })
+}
+
+/// Build the `Repr-Digest` header of a response from its ETag.
+/// Returns `None` if the ETag isn't a SHA-256 digest.
+pub(crate) fn header(etag: &str) -> Option<(String, Vec<u8>)> {
+    let digest = hex::decode(etag).ok().filter(|d| d.len() == 32)?;
+    let value = format!("{SHA_256}=:{}:", STANDARD.encode(digest));
+    Some((REPR_DIGEST.to_string(), value.into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wanted() {
+        let wanted = |value: &[u8]| {
+            wanted(&RequestContext {
+                headers: vec![("Want-Repr-Digest".to_string(), value.to_vec())],
+                ..Default::default()
+            })
+        };
+        assert!(wanted(b"sha-256=1"));
+        assert!(wanted(b"sha-512=3, sha-256=10"));
+        assert!(wanted(b"SHA-256=5"));
+        assert!(!wanted(b"sha-256=0"));
+        assert!(!wanted(b"sha-512=3"));
+        assert!(!wanted(b"sha-256=high"));
+        assert!(!super::wanted(&RequestContext::default()));
+    }
+
+    #[test]
+    fn test_header() {
+        // the SHA-256 digest of "hello, world!\n"
+        assert_eq!(
+            header("4dca0fd5f424a31b03ab807cbae77eb32bf2d089eed1cee154b3afed458de0dc"),
+            Some((
+                "repr-digest".to_string(),
+                b"sha-256=:TcoP1fQkoxsDq4B8uud+syvy0Inu0c7hVLOv7UWN4Nw=:".to_vec()
+            ))
+        );
+        assert_eq!(header("not a digest"), None);
+        assert_eq!(header("abcd"), None);
+    }
+}
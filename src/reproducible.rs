@@ -0,0 +1,41 @@
+//! Byte-for-byte reproducible responses, for deployments whose files are signed or audited: files
+//! are served exactly as they are on disk, and only if their contents match the ETag manifest.
+//!
+//! Compression, asset rewriting, front-matter stripping, minification and image transformations
+//! are all disabled, so the bytes a client receives are the bytes that were hashed at build time.
+
+use std::path::Path;
+
+use crate::{
+    precompressed::{self, ETAG_MANIFEST_ENV},
+    settings,
+};
+
+/// Environment variable enabling reproducible responses
+pub(crate) const REPRODUCIBLE_ENV: &str = "REPRODUCIBLE";
+
+/// Check whether reproducible responses are enabled.
+pub(crate) fn enabled() -> bool {
+    settings::var(REPRODUCIBLE_ENV).is_some_and(|v| v.trim() == "true")
+}
+
+/// Check that the digest of the contents of a file matches its entry in the ETag manifest,
+/// returning a description of the mismatch otherwise. Files missing from the manifest can't be
+/// vouched for, so they don't match either.
+pub(crate) fn verify(file: &Path, digest: &str) -> Result<(), String> {
+    let manifest = settings::var(ETAG_MANIFEST_ENV)
+        .ok_or_else(|| format!("{ETAG_MANIFEST_ENV} is not set"))?;
+    let manifest = precompressed::read_manifest(manifest.trim()).map_err(|e| format!("{e:#}"))?;
+    let expected = file
+        .to_str()
+        .and_then(|f| manifest.get(f))
+        .and_then(|etags| etags.get("identity"))
+        .ok_or_else(|| format!("{} is not listed in the ETag manifest", file.display()))?;
+    if !expected.eq_ignore_ascii_case(digest) {
+        return Err(format!(
+            "{} has digest {digest}, the ETag manifest lists {expected}",
+            file.display()
+        ));
+    }
+    Ok(())
+}
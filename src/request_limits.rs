@@ -0,0 +1,133 @@
+//! Limits on the size and shape of request headers, checked before any of them is parsed, so
+//! oversized or malformed values are rejected up front rather than reaching the parsers of
+//! `Accept-Encoding`, `Range` and conditional headers.
+
+use http::{
+    header::{
+        ACCEPT_ENCODING, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, IF_UNMODIFIED_SINCE,
+        RANGE,
+    },
+    HeaderName, StatusCode,
+};
+
+use crate::{plain_response, Response};
+
+/// Largest value of a single header
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+/// Largest size of all headers together
+const MAX_HEADERS_SIZE: usize = 64 * 1024;
+/// Most comma-separated elements of a parsed header, such as ranges or entity tags
+const MAX_LIST_ELEMENTS: usize = 64;
+/// Headers the file server parses, whose values must be printable ASCII
+const PARSED_HEADERS: &[HeaderName] = &[
+    ACCEPT_ENCODING,
+    RANGE,
+    IF_RANGE,
+    IF_NONE_MATCH,
+    IF_MATCH,
+    IF_MODIFIED_SINCE,
+    IF_UNMODIFIED_SINCE,
+];
+
+/// Check the headers of a request, returning the response rejecting it if they are too large
+/// (`431 Request Header Fields Too Large`) or a parsed header is malformed (`400 Bad Request`).
+pub(crate) fn check(headers: &[(String, Vec<u8>)]) -> Option<Response> {
+    let problem = problem(headers)?;
+    eprintln!("Rejecting request: {}", problem.1);
+    Some(plain_response(problem.0))
+}
+
+/// Return the status and description of the first problem with the headers of a request.
+fn problem(headers: &[(String, Vec<u8>)]) -> Option<(StatusCode, String)> {
+    let total = headers
+        .iter()
+        .map(|(name, value)| name.len() + value.len())
+        .sum::<usize>();
+    if total > MAX_HEADERS_SIZE {
+        return Some((
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            format!("headers take {total} bytes, more than {MAX_HEADERS_SIZE}"),
+        ));
+    }
+    for (name, value) in headers {
+        if value.len() > MAX_HEADER_SIZE {
+            return Some((
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                format!(
+                    "{name} takes {} bytes, more than {MAX_HEADER_SIZE}",
+                    value.len()
+                ),
+            ));
+        }
+        let parsed = HeaderName::from_bytes(name.as_bytes())
+            .is_ok_and(|name| PARSED_HEADERS.contains(&name));
+        if !parsed {
+            continue;
+        }
+        // field values are visible ASCII, spaces and tabs for all the parsed headers
+        if !value
+            .iter()
+            .all(|&b| b == b'\t' || (b' '..=b'~').contains(&b))
+        {
+            return Some((
+                StatusCode::BAD_REQUEST,
+                format!("{name} has bytes other than printable ASCII"),
+            ));
+        }
+        let elements = value.split(|&b| b == b',').count();
+        if elements > MAX_LIST_ELEMENTS {
+            return Some((
+                StatusCode::BAD_REQUEST,
+                format!("{name} lists {elements} elements, more than {MAX_LIST_ELEMENTS}"),
+            ));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_problem() {
+        let many_ranges = format!("bytes={}", vec!["0-0"; MAX_LIST_ELEMENTS + 1].join(","));
+        let many_etags = vec!["\"a\""; MAX_LIST_ELEMENTS + 1].join(", ");
+        let long = "a".repeat(MAX_HEADER_SIZE + 1);
+        for (name, value, expected) in [
+            (ACCEPT_ENCODING, &b"gzip;q=0.5, br;q=NaN, *;q=-1"[..], None),
+            (RANGE, b"bytes=--, =-, 5-a", None),
+            (IF_RANGE, b"Wed, 21 Oct 2015 07:28:00 GMT", None),
+            (IF_NONE_MATCH, b"W/\"\", \"\"\"", None),
+            (ACCEPT_ENCODING, b"gzip\x00", Some(StatusCode::BAD_REQUEST)),
+            (RANGE, b"bytes=0-\r\n4", Some(StatusCode::BAD_REQUEST)),
+            (IF_NONE_MATCH, b"\"\xff\"", Some(StatusCode::BAD_REQUEST)),
+            (RANGE, many_ranges.as_bytes(), Some(StatusCode::BAD_REQUEST)),
+            (
+                IF_NONE_MATCH,
+                many_etags.as_bytes(),
+                Some(StatusCode::BAD_REQUEST),
+            ),
+            (
+                HeaderName::from_static("x-anything"),
+                long.as_bytes(),
+                Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            ),
+            // only parsed headers need to be ASCII
+            (HeaderName::from_static("x-name"), "Zoë".as_bytes(), None),
+        ] {
+            let headers = [(name.to_string(), value.to_vec())];
+            assert_eq!(
+                problem(&headers).map(|(status, _)| status),
+                expected,
+                "{name}: {}",
+                String::from_utf8_lossy(value)
+            );
+        }
+        let headers = vec![("x-filler".to_string(), vec![b'a'; MAX_HEADER_SIZE]); 9];
+        assert_eq!(
+            problem(&headers).map(|(status, _)| status),
+            Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! The URL clients sent a request to, so redirects and URLs built by the file server point at the
+//! site as clients see it rather than as the component does.
+//!
+//! The scheme and host come from the `spin-full-url` header Spin sets or, under other hosts, from
+//! the `X-Forwarded-Proto` and `X-Forwarded-Host` headers of proxies, then the `Host` header. The
+//! `X-Forwarded-Prefix` header of proxies mounting the site under a path is prepended to the
+//! component route, if it is made of plain path segments.
+//!
+//! Clients can send `X-Forwarded-*` headers of their own, so they are only used when the file
+//! server is configured to run behind a proxy that sets them.
+
+use http::{header::HOST, HeaderName, Uri};
+use std::str;
+
+use crate::{settings, FileServer, RequestContext};
+
+/// Header set by Spin to the full URL of the request
+pub(crate) const FULL_URL_HEADER: &str = "spin-full-url";
+/// Environment variable making redirect locations absolute URLs
+pub(crate) const ABSOLUTE_REDIRECTS_ENV: &str = "ABSOLUTE_REDIRECTS";
+/// Environment variable trusting the `X-Forwarded-*` headers of requests
+pub(crate) const TRUST_FORWARDED_HEADERS_ENV: &str = "TRUST_FORWARDED_HEADERS";
+/// Header of proxies for the scheme of the original request
+const FORWARDED_PROTO_HEADER: &str = "x-forwarded-proto";
+/// Header of proxies for the host of the original request
+const FORWARDED_HOST_HEADER: &str = "x-forwarded-host";
+/// Header of proxies for the path prefix they strip
+pub(crate) const FORWARDED_PREFIX_HEADER: &str = "x-forwarded-prefix";
+
+/// The public URL of the component route.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct RequestUrl {
+    /// The scheme and host, such as `https://example.com`, if known.
+    origin: Option<String>,
+    /// The path prefix of the component route, without a trailing slash.
+    base: String,
+}
+
+/// Check whether the `X-Forwarded-*` headers of requests are set by a trusted proxy.
+pub(crate) fn trusts_forwarded_headers() -> bool {
+    settings::var(TRUST_FORWARDED_HEADERS_ENV).is_some_and(|v| v.trim() == "true")
+}
+
+impl RequestUrl {
+    /// Determine the public URL of the route of a request.
+    pub fn of(req: &RequestContext) -> Self {
+        Self::resolve(req, trusts_forwarded_headers())
+    }
+
+    /// Determine the public URL of the route of a request, using the `X-Forwarded-*` headers
+    /// only if they are trusted.
+    fn resolve(req: &RequestContext, trust_forwarded: bool) -> Self {
+        let header = |name: &str| {
+            let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+            let value = str::from_utf8(req.header(&name)?).ok()?;
+            // proxies append to lists, the first element is the client's
+            let value = value.split(',').next()?.trim();
+            (!value.is_empty()).then_some(value)
+        };
+        let from_full_url = header(FULL_URL_HEADER)
+            .and_then(|url| url.parse::<Uri>().ok())
+            .and_then(|url| Some(format!("{}://{}", url.scheme_str()?, url.authority()?)));
+        let forwarded = |name: &str| header(name).filter(|_| trust_forwarded);
+        let origin = from_full_url.or_else(|| {
+            let host = forwarded(FORWARDED_HOST_HEADER).or_else(|| header(HOST.as_str()))?;
+            // TLS is terminated in front of most deployments
+            let scheme = forwarded(FORWARDED_PROTO_HEADER).unwrap_or("https");
+            Some(format!("{scheme}://{host}"))
+        });
+        let prefix = forwarded(FORWARDED_PREFIX_HEADER)
+            .map(|p| p.trim_matches('/'))
+            .filter(|p| FileServer::plain_path(p))
+            .map(|p| format!("/{p}"))
+            .unwrap_or_default();
+        let base = format!("{prefix}{}", req.route);
+        Self {
+            origin,
+            base: base.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Return the lowercase host clients sent the request to, with its port if any.
+    pub fn host(&self) -> Option<String> {
+        let (_, host) = self.origin.as_ref()?.split_once("://")?;
+        Some(host.to_ascii_lowercase())
+    }
+
+    /// Return the path of the component route, without a trailing slash.
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+
+    /// Return the path clients request for a path relative to the component route.
+    pub fn path(&self, path: &str) -> String {
+        format!("{}/{}", self.base, path.trim_start_matches('/'))
+    }
+
+    /// Return the absolute URL of a path relative to the component route, if the host is known.
+    pub fn absolute(&self, path: &str) -> Option<String> {
+        Some(format!("{}{}", self.origin.as_ref()?, self.path(path)))
+    }
+
+    /// Return the location of a redirect to a path relative to the component route: an absolute
+    /// URL if enabled and the host is known, the path otherwise.
+    pub fn redirect(&self, path: &str) -> String {
+        let absolute = settings::var(ABSOLUTE_REDIRECTS_ENV).is_some_and(|v| v.trim() == "true");
+        absolute
+            .then(|| self.absolute(path))
+            .flatten()
+            .unwrap_or_else(|| self.path(path))
+    }
+}
+
+/// Return the full URL of a request, without its query string, if the host is known.
+pub(crate) fn requested(req: &RequestContext) -> Option<String> {
+    let url = req
+        .headers
+        .iter()
+        .find_map(|(k, v)| k.eq_ignore_ascii_case(FULL_URL_HEADER).then_some(v))
+        .map(|url| String::from_utf8_lossy(url).into_owned())
+        .or_else(|| RequestUrl::of(req).absolute(&String::from_utf8_lossy(&req.path)))?;
+    // the query string doesn't select a different file
+    Some(url.split('?').next().unwrap_or_default().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_headers(route: &str, headers: &[(&str, &str)]) -> RequestContext {
+        RequestContext {
+            route: route.to_string(),
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.as_bytes().to_vec()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let url = RequestUrl::resolve(
+            &with_headers(
+                "/static",
+                &[
+                    ("spin-full-url", "http://localhost:3000/static/a.txt?v=1"),
+                    ("x-forwarded-host", "example.com"),
+                ],
+            ),
+            true,
+        );
+        assert_eq!(url.path("/docs/"), "/static/docs/");
+        assert_eq!(
+            url.absolute("docs/"),
+            Some("http://localhost:3000/static/docs/".to_string())
+        );
+
+        // other hosts only have the headers of proxies
+        let proxied = with_headers(
+            "",
+            &[
+                ("host", "10.0.0.1"),
+                ("x-forwarded-host", "example.com, proxy.internal"),
+                ("x-forwarded-proto", "http"),
+                ("x-forwarded-prefix", "/site/"),
+            ],
+        );
+        let url = RequestUrl::resolve(&proxied, true);
+        assert_eq!(
+            url.absolute("/docs/"),
+            Some("http://example.com/site/docs/".to_string())
+        );
+        // unless they are trusted, clients could spoof them
+        let url = RequestUrl::resolve(&proxied, false);
+        assert_eq!(
+            url.absolute("/docs/"),
+            Some("https://10.0.0.1/docs/".to_string())
+        );
+        let url = RequestUrl::resolve(&with_headers("", &[("host", "example.com")]), false);
+        assert_eq!(url.absolute("/"), Some("https://example.com/".to_string()));
+        let url = RequestUrl::resolve(&with_headers("", &[("host", "Example.com:8080")]), false);
+        assert_eq!(url.host(), Some("example.com:8080".to_string()));
+        let url = RequestUrl::resolve(&RequestContext::default(), false);
+        assert_eq!(
+            (url.path("/a"), url.absolute("/a")),
+            ("/a".to_string(), None)
+        );
+        assert_eq!(url.host(), None);
+
+        // prefixes that aren't plain path segments are ignored
+        for prefix in ["x\"><script>", "/a/../b", "a b", "/"] {
+            let url = RequestUrl::resolve(
+                &with_headers("/docs", &[("x-forwarded-prefix", prefix)]),
+                true,
+            );
+            assert_eq!(url.base(), "/docs", "{prefix}");
+        }
+    }
+}
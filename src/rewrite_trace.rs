@@ -0,0 +1,88 @@
+//! The rules that changed the path of a request, in the order they applied, reported in the
+//! `X-Rewrite-Trace` response header to debug why a path resolved the way it did.
+
+use http::header::HeaderName;
+
+use crate::settings;
+
+/// Environment variable enabling the `X-Rewrite-Trace` header
+pub(crate) const REWRITE_TRACE_ENV: &str = "REWRITE_TRACE";
+/// The `X-Rewrite-Trace` header
+const REWRITE_TRACE: HeaderName = HeaderName::from_static("x-rewrite-trace");
+
+/// The rewrites, redirects and fallbacks applied to the path of a request.
+#[derive(Debug, Default)]
+pub(crate) struct Trace {
+    enabled: bool,
+    steps: Vec<(&'static str, String)>,
+}
+
+impl Trace {
+    pub fn from_env() -> Self {
+        Self {
+            enabled: settings::var(REWRITE_TRACE_ENV).is_some_and(|v| v.trim() == "true"),
+            steps: Vec::new(),
+        }
+    }
+
+    /// Record that `rule` changed the path to `path`, if it applied.
+    pub fn step(&mut self, rule: &'static str, path: Option<&str>) {
+        if let (true, Some(path)) = (self.enabled, path) {
+            self.steps.push((rule, path.to_string()));
+        }
+    }
+
+    /// Return the `X-Rewrite-Trace` header listing the recorded steps as `rule path`, or `none`
+    /// if no rule applied.
+    pub fn header(&self) -> Option<(String, Vec<u8>)> {
+        if !self.enabled {
+            return None;
+        }
+        let value = if self.steps.is_empty() {
+            "none".to_string()
+        } else {
+            self.steps
+                .iter()
+                .map(|(rule, path)| format!("{rule} {}", encode(path)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        Some((REWRITE_TRACE.as_str().to_string(), value.into_bytes()))
+    }
+}
+
+/// Percent-encode the bytes of a path that can't appear in a header value or would be mistaken
+/// for separators of the list.
+fn encode(path: &str) -> String {
+    path.bytes()
+        .map(|b| match b {
+            b',' | b'%' => format!("%{b:02X}"),
+            b'!'..=b'~' => (b as char).to_string(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_header() {
+        let mut trace = Trace {
+            enabled: true,
+            steps: Vec::new(),
+        };
+        assert_eq!(trace.header().unwrap().1, b"none");
+        trace.step("alias", Some("/docs/v2"));
+        trace.step("mount", None);
+        trace.step("fallback", Some("index, all.html"));
+        let (name, value) = trace.header().unwrap();
+        assert_eq!(name, "x-rewrite-trace");
+        assert_eq!(value, b"alias /docs/v2, fallback index%2C%20all.html");
+
+        let mut trace = Trace::default();
+        trace.step("alias", Some("/docs/v2"));
+        assert!(trace.header().is_none());
+    }
+}
@@ -0,0 +1,113 @@
+//! A preset of paths probed by vulnerability scanners and bots, answered with a plain
+//! `404 Not Found` before any other processing, so that single-page apps don't send their
+//! fallback document for each of them.
+//!
+//! Blocked requests are logged to stderr on their own and left out of the access log.
+
+use http::{header::USER_AGENT, StatusCode};
+use std::str;
+
+use crate::{plain_response, settings, RequestContext, Response};
+
+/// Environment variable enabling the scanner path blocklist
+pub(crate) const BLOCK_SCANNERS_ENV: &str = "BLOCK_SCANNERS";
+/// Path segments that are blocked wherever they appear, such as configuration files, secrets and
+/// version control metadata
+const BLOCKED_SEGMENTS: &[&str] = &[
+    ".env",
+    ".git",
+    ".svn",
+    ".hg",
+    ".aws",
+    ".ssh",
+    ".htaccess",
+    ".htpasswd",
+    ".ds_store",
+];
+/// First path segments that are blocked, naming the admin pages and scripts of common web
+/// applications
+const BLOCKED_PREFIXES: &[&str] = &[
+    "wp-admin",
+    "wp-includes",
+    "wp-login.php",
+    "xmlrpc.php",
+    "phpmyadmin",
+    "cgi-bin",
+];
+
+/// Check whether the blocklist is enabled.
+fn enabled() -> bool {
+    settings::var(BLOCK_SCANNERS_ENV).is_some_and(|v| v.trim() == "true")
+}
+
+/// Check whether a request path is blocked.
+pub(crate) fn is_blocked(path: &str) -> bool {
+    enabled() && is_scanner_path(path)
+}
+
+/// Answer requests for blocked paths with `404 Not Found`, logging them.
+pub(crate) fn check(req: &RequestContext, path: &str) -> Option<Response> {
+    if !is_blocked(path) {
+        return None;
+    }
+    let user_agent = req
+        .header(&USER_AGENT)
+        .and_then(|ua| str::from_utf8(ua).ok())
+        .unwrap_or("-");
+    eprintln!(
+        "Blocked scanner request {} {path} ({user_agent})",
+        req.method
+    );
+    Some(plain_response(StatusCode::NOT_FOUND))
+}
+
+/// Check whether a path is one probed by scanners, ignoring case.
+fn is_scanner_path(path: &str) -> bool {
+    let path = path.to_lowercase();
+    let mut segments = path.split('/').filter(|s| !s.is_empty()).peekable();
+    if segments
+        .peek()
+        .is_some_and(|first| BLOCKED_PREFIXES.contains(first))
+    {
+        return true;
+    }
+    // `.env.local`, `.env.production` and the like are as secret as `.env`
+    segments.any(|segment| BLOCKED_SEGMENTS.contains(&segment) || segment.starts_with(".env."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_scanner_path() {
+        for path in [
+            "/wp-admin",
+            "/wp-admin/setup-config.php",
+            "/WP-Login.php",
+            "/xmlrpc.php",
+            "/.env",
+            "/api/.env",
+            "/.env.production",
+            "/.git/config",
+            "/static/.DS_Store",
+            "/cgi-bin/luci",
+            "phpmyadmin/index.php",
+        ] {
+            assert!(is_scanner_path(path), "{path}");
+        }
+        for path in [
+            "/",
+            "/index.html",
+            "/blog/wp-admin-tips.html",
+            "/docs/wp-admin/index.html",
+            "/wp-content/uploads/logo.png",
+            "/.well-known/security.txt",
+            "/environment.html",
+            "/.envelope",
+            "/github/.gitignore",
+        ] {
+            assert!(!is_scanner_path(path), "{path}");
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Secrets of the authentication features: bearer tokens of the admin routes, mounts and access
+//! rules, and the secret OpenID Connect session cookies are signed with.
+//!
+//! A secret may list several whitespace-separated values, the current one first, so it can be
+//! rotated without locking clients out: previous values are accepted until the deadline set by
+//! `SECRET_ROTATION_UNTIL`, if any, while only the current one signs new cookies. Secrets of the
+//! configuration file can reference a Spin variable with `{{ name }}` rather than being written
+//! out in the file. Candidates are compared in constant time.
+
+use crate::{etag_cache::now, settings};
+
+/// Environment variable for the time in seconds since the Unix epoch after which only the current
+/// value of rotated secrets is accepted
+pub(crate) const SECRET_ROTATION_UNTIL_ENV: &str = "SECRET_ROTATION_UNTIL";
+
+/// Return the values of a secret accepted now, the current one first.
+pub(crate) fn accepted(secret: &str) -> Vec<String> {
+    let until = settings::var(SECRET_ROTATION_UNTIL_ENV).and_then(|v| v.trim().parse().ok());
+    accepted_at(&resolve(secret), until, now())
+}
+
+/// Return the current value of a secret, which signs new values.
+pub(crate) fn current(secret: &str) -> Option<String> {
+    resolve(secret)
+        .split_whitespace()
+        .next()
+        .map(str::to_string)
+}
+
+/// Check whether a candidate, such as a bearer token, is an accepted value of a secret.
+pub(crate) fn matches(candidate: &[u8], secret: &str) -> bool {
+    // every value is compared, so the time taken doesn't tell which one matched
+    accepted(secret).iter().fold(false, |matched, value| {
+        constant_time_eq(candidate, value.as_bytes()) | matched
+    })
+}
+
+/// Compare two byte strings in a time depending only on their lengths, which aren't secret.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a.iter().zip(b).fold(0, |d, (x, y)| d | (x ^ y));
+    std::hint::black_box(difference) == 0
+}
+
+/// Replace a `{{ name }}` reference with the value of the Spin variable.
+fn resolve(secret: &str) -> String {
+    let reference = secret
+        .trim()
+        .strip_prefix("{{")
+        .and_then(|s| s.strip_suffix("}}"));
+    match reference {
+        Some(name) => settings::variable(name.trim()).unwrap_or_default(),
+        None => secret.to_string(),
+    }
+}
+
+/// Return the values of a secret accepted at a time, given the rotation deadline.
+fn accepted_at(secret: &str, until: Option<u64>, now: u64) -> Vec<String> {
+    let mut values = secret.split_whitespace().map(str::to_string);
+    let current = values.next();
+    let rotating = until.is_none_or(|until| now < until);
+    current
+        .into_iter()
+        .chain(values.filter(|_| rotating))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cret", b"s3creT"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cre"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn test_accepted_at() {
+        assert_eq!(accepted_at(" new  old ", None, 100), ["new", "old"]);
+        assert_eq!(accepted_at("new old", Some(200), 100), ["new", "old"]);
+        assert_eq!(accepted_at("new old", Some(100), 100), ["new"]);
+        assert!(accepted_at("  ", None, 100).is_empty());
+        assert_eq!(resolve("s3cret"), "s3cret");
+        // Spin variables aren't available outside Spin
+        assert_eq!(resolve("{{ docs_token }}"), "");
+    }
+}
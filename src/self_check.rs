@@ -0,0 +1,717 @@
+//! Validation of the whole configuration, so that mistakes are reported together instead of
+//! settings being silently ignored.
+//!
+//! Spin has no startup hook, so the check runs on every request when `SELF_CHECK` is set, and
+//! on demand through the `check` admin route.
+
+use http::{HeaderName, HeaderValue, Method, StatusCode};
+use regex::Regex;
+use serde::Serialize;
+use std::{fmt, path::Path};
+
+use crate::{
+    admin::ADMIN_TOKEN_ENV,
+    analytics::ANALYTICS_ENV,
+    asset_manifest::{
+        AssetManifest, ASSET_MANIFEST_ENV, ASSET_MANIFEST_MODE_ENV, ASSET_MANIFEST_REWRITE_ENV,
+        FINGERPRINT_PATTERN_ENV, IMMUTABLE_FINGERPRINTS_ENV,
+    },
+    backend::{
+        self, KEY_VALUE_BACKEND, STORAGE_BACKEND_ENV, STORAGE_CHECKSUMS_ENV, STORAGE_STORE_ENV,
+    },
+    config::{Config, CONFIG_FILE_ENV},
+    content_addressed::{CANONICAL_LOCATION_ENV, CONTENT_ADDRESSED_ENV},
+    content_warning::{
+        CONTENT_WARNING_MAX_AGE_ENV, CONTENT_WARNING_PAGE_ENV, CONTENT_WARNING_PREFIXES_ENV,
+    },
+    csp::CONTENT_SECURITY_POLICY_ENV,
+    disposition::{self, CONTENT_DISPOSITION_ENV},
+    downloads::{
+        DOWNLOAD_COUNTER_ENV, LARGE_DOWNLOAD_LIMIT_ENV, LARGE_DOWNLOAD_RETRY_AFTER_ENV,
+        LARGE_DOWNLOAD_SIZE_ENV,
+    },
+    drafts::{DRAFTS_PREVIEW_MAX_AGE_ENV, DRAFTS_SECRET_ENV},
+    etag_cache::ETAG_CACHE_ENV,
+    flush::COMPRESSION_FLUSH_BYTES_ENV,
+    honeypot::{HONEYPOT_PATHS_ENV, HONEYPOT_WEBHOOK_AUTHORIZATION_ENV, HONEYPOT_WEBHOOK_URL_ENV},
+    host,
+    languages::{self, DEFAULT_LANGUAGE, DEFAULT_LANGUAGE_ENV, LANGUAGE_PLACEHOLDER},
+    minify::{self, MINIFY_ENV, MINIFY_MAX_SIZE_ENV},
+    not_found_cache::NOT_FOUND_CACHE_TTL_ENV,
+    oidc::{
+        OIDC_CLIENT_ID_ENV, OIDC_CLIENT_SECRET_ENV, OIDC_ISSUER_ENV, OIDC_PREFIXES_ENV,
+        OIDC_REDIRECT_URL_ENV, OIDC_SESSION_MAX_AGE_ENV, OIDC_SESSION_SECRET_ENV,
+    },
+    origin::{
+        ORIGIN_BREAKER_COOLDOWN_ENV, ORIGIN_BREAKER_THRESHOLD_ENV, ORIGIN_ERROR_PAGE_ENV,
+        ORIGIN_RETRIES_ENV, ORIGIN_RETRY_BACKOFF_ENV, ORIGIN_TIMEOUT_ENV, ORIGIN_URL_ENV,
+    },
+    precompressed::{self, ETAG_MANIFEST_ENV},
+    purge_webhook::{
+        PURGE_WEBHOOK_AUTHORIZATION_ENV, PURGE_WEBHOOK_FORMAT_ENV, PURGE_WEBHOOK_URL_ENV,
+    },
+    quota::{
+        BANDWIDTH_ACCOUNTING_ENV, BANDWIDTH_QUOTA_ENV, BANDWIDTH_QUOTA_STATUS_ENV, QUOTA_STATUSES,
+    },
+    redirect_table::{self, REDIRECT_DATABASE_ENV, REDIRECT_TABLE_ENV},
+    releases::{RELEASES_DIR_ENV, RELEASE_PREVIEW_COOKIE_ENV, RELEASE_PREVIEW_HEADER_ENV},
+    reproducible::REPRODUCIBLE_ENV,
+    secrets::SECRET_ROTATION_UNTIL_ENV,
+    session_gate::{
+        SESSION_COOKIE_ENV, SESSION_KEY_PREFIX_ENV, SESSION_LOGIN_URL_ENV, SESSION_PREFIXES_ENV,
+        SESSION_STORE_ENV,
+    },
+    settings::{self, SETTINGS},
+    shadowing,
+    size_limits::{
+        COMPRESSION_MAX_SIZE_ENV, MAX_FILE_SIZE_ENV, MAX_FILE_SIZE_STATUSES,
+        MAX_FILE_SIZE_STATUS_ENV,
+    },
+    subdomains::{SubdomainRoots, SUBDOMAIN_PATTERN_ENV, SUBDOMAIN_ROOTS_ENV},
+    surrogate::SURROGATE_CONTROL_ENV,
+    FileServer, ALIASES_ENV, CACHE_CONTROL_ENV, CANARY_HEADER_ENV, CANARY_PERCENT_ENV,
+    CANARY_ROOT_ENV, CUSTOM_404_PATH_ENV, EXPERIMENT_SPLIT_ENV, FALLBACK_PATH_ENV, ROOT_HEADER_ENV,
+    VARIANT_HEADER_ENV, VARIANT_MODE_ENV,
+};
+
+/// Environment variable enabling the check on every request (`log` or `strict`)
+pub(crate) const SELF_CHECK_ENV: &str = "SELF_CHECK";
+
+/// Settings that have no effect without another one, and the setting they depend on
+const DEPENDENT_SETTINGS: [(&str, &str); 43] = [
+    (ASSET_MANIFEST_MODE_ENV, ASSET_MANIFEST_ENV),
+    (ASSET_MANIFEST_REWRITE_ENV, ASSET_MANIFEST_ENV),
+    (FINGERPRINT_PATTERN_ENV, IMMUTABLE_FINGERPRINTS_ENV),
+    (CANARY_PERCENT_ENV, CANARY_ROOT_ENV),
+    (CANARY_HEADER_ENV, CANARY_ROOT_ENV),
+    (VARIANT_MODE_ENV, VARIANT_HEADER_ENV),
+    (LARGE_DOWNLOAD_SIZE_ENV, LARGE_DOWNLOAD_LIMIT_ENV),
+    (LARGE_DOWNLOAD_RETRY_AFTER_ENV, LARGE_DOWNLOAD_LIMIT_ENV),
+    (REDIRECT_TABLE_ENV, REDIRECT_DATABASE_ENV),
+    (MINIFY_MAX_SIZE_ENV, MINIFY_ENV),
+    (CONTENT_ADDRESSED_ENV, ETAG_MANIFEST_ENV),
+    (CANONICAL_LOCATION_ENV, ETAG_MANIFEST_ENV),
+    (REPRODUCIBLE_ENV, ETAG_MANIFEST_ENV),
+    (OIDC_CLIENT_ID_ENV, OIDC_ISSUER_ENV),
+    (OIDC_CLIENT_SECRET_ENV, OIDC_ISSUER_ENV),
+    (OIDC_SESSION_SECRET_ENV, OIDC_ISSUER_ENV),
+    (OIDC_PREFIXES_ENV, OIDC_ISSUER_ENV),
+    (OIDC_REDIRECT_URL_ENV, OIDC_ISSUER_ENV),
+    (OIDC_SESSION_MAX_AGE_ENV, OIDC_ISSUER_ENV),
+    (SESSION_STORE_ENV, SESSION_COOKIE_ENV),
+    (SESSION_KEY_PREFIX_ENV, SESSION_COOKIE_ENV),
+    (SESSION_PREFIXES_ENV, SESSION_COOKIE_ENV),
+    (SESSION_LOGIN_URL_ENV, SESSION_COOKIE_ENV),
+    (CONTENT_WARNING_PAGE_ENV, CONTENT_WARNING_PREFIXES_ENV),
+    (CONTENT_WARNING_MAX_AGE_ENV, CONTENT_WARNING_PREFIXES_ENV),
+    (DRAFTS_PREVIEW_MAX_AGE_ENV, DRAFTS_SECRET_ENV),
+    (BANDWIDTH_QUOTA_STATUS_ENV, BANDWIDTH_QUOTA_ENV),
+    (MAX_FILE_SIZE_STATUS_ENV, MAX_FILE_SIZE_ENV),
+    (SUBDOMAIN_PATTERN_ENV, SUBDOMAIN_ROOTS_ENV),
+    (RELEASE_PREVIEW_HEADER_ENV, RELEASES_DIR_ENV),
+    (RELEASE_PREVIEW_COOKIE_ENV, RELEASES_DIR_ENV),
+    (PURGE_WEBHOOK_AUTHORIZATION_ENV, PURGE_WEBHOOK_URL_ENV),
+    (PURGE_WEBHOOK_FORMAT_ENV, PURGE_WEBHOOK_URL_ENV),
+    (HONEYPOT_WEBHOOK_URL_ENV, HONEYPOT_PATHS_ENV),
+    (HONEYPOT_WEBHOOK_AUTHORIZATION_ENV, HONEYPOT_WEBHOOK_URL_ENV),
+    (STORAGE_STORE_ENV, STORAGE_BACKEND_ENV),
+    (STORAGE_CHECKSUMS_ENV, STORAGE_BACKEND_ENV),
+    (ORIGIN_TIMEOUT_ENV, ORIGIN_URL_ENV),
+    (ORIGIN_RETRIES_ENV, ORIGIN_URL_ENV),
+    (ORIGIN_RETRY_BACKOFF_ENV, ORIGIN_URL_ENV),
+    (ORIGIN_BREAKER_THRESHOLD_ENV, ORIGIN_URL_ENV),
+    (ORIGIN_BREAKER_COOLDOWN_ENV, ORIGIN_URL_ENV),
+    (ORIGIN_ERROR_PAGE_ENV, ORIGIN_URL_ENV),
+];
+
+/// What to do with the problems found on every request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SelfCheckMode {
+    /// Log the problems and keep serving.
+    Log,
+    /// Log the problems and fail every request until they are fixed.
+    Strict,
+}
+
+impl SelfCheckMode {
+    /// Return the configured mode, if the check runs on every request.
+    pub fn from_env() -> Option<Self> {
+        match settings::var(SELF_CHECK_ENV)?
+            .trim()
+            .to_lowercase()
+            .as_str()
+        {
+            "log" => Some(Self::Log),
+            "strict" => Some(Self::Strict),
+            _ => None,
+        }
+    }
+}
+
+/// A setting that is invalid or has no effect.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Problem {
+    pub setting: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.setting, self.message)
+    }
+}
+
+/// Check the configuration in the environment.
+pub(crate) fn check() -> Vec<Problem> {
+    check_with(settings::var)
+}
+
+/// Run the check if enabled, logging the problems found.
+/// Returns the response to fail the request with in strict mode.
+pub(crate) fn run() -> Option<crate::Response> {
+    let mode = SelfCheckMode::from_env()?;
+    let problems = check();
+    if problems.is_empty() {
+        return None;
+    }
+    eprintln!("{}", diagnostic(&problems));
+    (mode == SelfCheckMode::Strict)
+        .then(|| crate::plain_response(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+/// Format the problems as a single log message.
+pub(crate) fn diagnostic(problems: &[Problem]) -> String {
+    problems.iter().fold(
+        format!("{} configuration problem(s) found:", problems.len()),
+        |message, problem| format!("{message}\n  {problem}"),
+    )
+}
+
+/// Check the configuration returned by `var`.
+fn check_with(var: impl Fn(&str) -> Option<String>) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut problem =
+        |setting: &'static str, message: String| problems.push(Problem { setting, message });
+
+    for setting in [
+        CACHE_CONTROL_ENV,
+        SURROGATE_CONTROL_ENV,
+        CONTENT_SECURITY_POLICY_ENV,
+    ] {
+        if let Some(value) = var(setting) {
+            if HeaderValue::from_str(&value).is_err() {
+                problem(setting, format!("{value:?} is not a valid header value"));
+            }
+        }
+    }
+    for setting in [
+        FALLBACK_PATH_ENV,
+        CUSTOM_404_PATH_ENV,
+        ORIGIN_ERROR_PAGE_ENV,
+        CONTENT_WARNING_PAGE_ENV,
+    ] {
+        if let Some(path) = var(setting) {
+            // localized files must at least exist in the default language
+            let path = match var(DEFAULT_LANGUAGE_ENV) {
+                Some(language) => path.replace(LANGUAGE_PLACEHOLDER, language.trim()),
+                None => path.replace(LANGUAGE_PLACEHOLDER, DEFAULT_LANGUAGE),
+            };
+            if let Err(e) = std::fs::File::open(&path).and_then(|f| f.metadata()) {
+                problem(setting, format!("cannot read {path}: {e}"));
+            } else if !Path::new(&path).is_file() {
+                problem(setting, format!("{path} is not a file"));
+            }
+        }
+    }
+    for setting in [
+        VARIANT_HEADER_ENV,
+        CANARY_HEADER_ENV,
+        ROOT_HEADER_ENV,
+        RELEASE_PREVIEW_HEADER_ENV,
+    ] {
+        if let Some(name) = var(setting) {
+            if HeaderName::from_bytes(name.trim().as_bytes()).is_err() {
+                problem(setting, format!("{name:?} is not a valid header name"));
+            }
+        }
+    }
+    let headers = [
+        VARIANT_HEADER_ENV,
+        CANARY_HEADER_ENV,
+        ROOT_HEADER_ENV,
+        RELEASE_PREVIEW_HEADER_ENV,
+    ]
+    .map(|setting| (setting, var(setting).map(|h| h.trim().to_lowercase())));
+    for (i, (setting, name)) in headers.iter().enumerate() {
+        let other = headers[..i]
+            .iter()
+            .find(|(_, other)| name.is_some() && other == name);
+        if let Some((other, _)) = other {
+            problem(setting, format!("uses the same header as {other}"));
+        }
+    }
+    for setting in SETTINGS.iter().filter(|s| !s.values.is_empty()) {
+        if let Some(value) = var(setting.name) {
+            if !setting
+                .values
+                .contains(&value.trim().to_lowercase().as_str())
+            {
+                problem(
+                    setting.name,
+                    format!("{value:?} is not one of {}", setting.values.join(", ")),
+                );
+            }
+        }
+    }
+    for setting in [EXPERIMENT_SPLIT_ENV, CANARY_PERCENT_ENV] {
+        if let Some(value) = var(setting) {
+            if !value.trim().parse::<u32>().is_ok_and(|p| p <= 100) {
+                problem(
+                    setting,
+                    format!("{value:?} is not a percentage from 0 to 100"),
+                );
+            }
+        }
+    }
+    for setting in [
+        LARGE_DOWNLOAD_LIMIT_ENV,
+        LARGE_DOWNLOAD_SIZE_ENV,
+        LARGE_DOWNLOAD_RETRY_AFTER_ENV,
+        ORIGIN_TIMEOUT_ENV,
+        ORIGIN_RETRIES_ENV,
+        ORIGIN_RETRY_BACKOFF_ENV,
+        ORIGIN_BREAKER_THRESHOLD_ENV,
+        ORIGIN_BREAKER_COOLDOWN_ENV,
+        MAX_FILE_SIZE_ENV,
+        COMPRESSION_MAX_SIZE_ENV,
+        MINIFY_MAX_SIZE_ENV,
+        COMPRESSION_FLUSH_BYTES_ENV,
+        OIDC_SESSION_MAX_AGE_ENV,
+        CONTENT_WARNING_MAX_AGE_ENV,
+        DRAFTS_PREVIEW_MAX_AGE_ENV,
+        BANDWIDTH_QUOTA_ENV,
+        SECRET_ROTATION_UNTIL_ENV,
+        NOT_FOUND_CACHE_TTL_ENV,
+    ] {
+        if let Some(value) = var(setting) {
+            if value.trim().parse::<u64>().is_err() {
+                problem(setting, format!("{value:?} is not a whole number"));
+            }
+        }
+    }
+    if let Some(media_types) = var(MINIFY_ENV) {
+        for media_type in media_types.split(',').filter(|m| !minify::is_minifiable(m)) {
+            problem(
+                MINIFY_ENV,
+                format!(
+                    "{:?} is not a media type that can be minified",
+                    media_type.trim()
+                ),
+            );
+        }
+    }
+    if let Some(rules) = var(CONTENT_DISPOSITION_ENV) {
+        if let Err(e) = disposition::parse_rules(&rules) {
+            problem(CONTENT_DISPOSITION_ENV, e);
+        }
+    }
+    if let Some(aliases) = var(ALIASES_ENV) {
+        for alias in aliases.split(',').filter(|a| !a.contains('=')) {
+            problem(ALIASES_ENV, format!("{alias:?} is not of the form from=to"));
+        }
+        if var(CONFIG_FILE_ENV).is_none() {
+            for shadowed in shadowing::aliases(&aliases, &Config::default()) {
+                problem(ALIASES_ENV, shadowed);
+            }
+        }
+    }
+    // a fallback for every missing path leaves nothing to answer with the custom 404 page
+    if let (Some(_), Some(fallback)) = (var(CUSTOM_404_PATH_ENV), var(FALLBACK_PATH_ENV)) {
+        if !languages::is_template(&fallback) && Path::new(fallback.trim()).is_file() {
+            problem(
+                CUSTOM_404_PATH_ENV,
+                format!("is never served, since {FALLBACK_PATH_ENV} answers every missing path"),
+            );
+        }
+    }
+    // requests with a root only get the fallback and custom 404 documents within it
+    if var(ROOT_HEADER_ENV).is_some() {
+        for setting in [FALLBACK_PATH_ENV, CUSTOM_404_PATH_ENV] {
+            if var(setting).is_some_and(|path| !FileServer::stays_within(&path)) {
+                problem(
+                    setting,
+                    format!("has . or .. segments, so it is ignored under {ROOT_HEADER_ENV}"),
+                );
+            }
+        }
+    }
+    if let Some(roots) = var(SUBDOMAIN_ROOTS_ENV) {
+        if SubdomainRoots::parse(&roots).is_none() {
+            problem(
+                SUBDOMAIN_ROOTS_ENV,
+                format!("{roots:?} is not of the form *.domain=directory"),
+            );
+        }
+    }
+    if let Some(pattern) = var(SUBDOMAIN_PATTERN_ENV) {
+        if let Err(e) = Regex::new(pattern.trim()) {
+            problem(SUBDOMAIN_PATTERN_ENV, format!("invalid pattern: {e}"));
+        }
+    }
+    for setting in [CANARY_ROOT_ENV, RELEASES_DIR_ENV] {
+        if let Some(root) = var(setting) {
+            let dir = Path::new("/").join(root.trim().trim_matches('/'));
+            if !dir.is_dir() {
+                problem(setting, format!("{} is not a directory", dir.display()));
+            }
+        }
+    }
+    if let Some(path) = var(CONFIG_FILE_ENV) {
+        match Config::read(&path) {
+            Ok(config) => {
+                for (path, status) in &config.status {
+                    if StatusCode::from_u16(*status).is_err() {
+                        problem(
+                            CONFIG_FILE_ENV,
+                            format!("invalid status {status} configured for {path}"),
+                        );
+                    }
+                }
+                for name in config.settings.keys() {
+                    if !SETTINGS.iter().any(|s| s.name.eq_ignore_ascii_case(name)) {
+                        problem(CONFIG_FILE_ENV, format!("unknown setting {name}"));
+                    }
+                }
+                for mount in &config.mounts {
+                    let dir = Path::new("/").join(mount.directory.trim_matches('/'));
+                    if !dir.is_dir() {
+                        problem(
+                            CONFIG_FILE_ENV,
+                            format!("mount {} is not a directory", dir.display()),
+                        );
+                    }
+                    let cache_control = mount.cache_control.as_deref().unwrap_or_default();
+                    if HeaderValue::from_str(cache_control).is_err() {
+                        problem(
+                            CONFIG_FILE_ENV,
+                            format!("{cache_control:?} is not a valid header value"),
+                        );
+                    }
+                }
+                for rule in &config.methods {
+                    for method in &rule.allow {
+                        if Method::from_bytes(method.trim().to_uppercase().as_bytes()).is_err() {
+                            problem(
+                                CONFIG_FILE_ENV,
+                                format!("{method:?} is not a valid method for {}", rule.path),
+                            );
+                        }
+                    }
+                }
+                for rule in &config.access {
+                    if let Err(e) = rule.validate() {
+                        problem(CONFIG_FILE_ENV, e);
+                    }
+                }
+                for window in &config.deploy_windows {
+                    if let Err(e) = window.validate() {
+                        problem(CONFIG_FILE_ENV, e);
+                    }
+                }
+                for rule in &config.embargoes {
+                    if let Err(e) = rule.validate() {
+                        problem(CONFIG_FILE_ENV, e);
+                    }
+                }
+                for rule in &config.campaigns {
+                    if let Err(e) = rule.validate() {
+                        problem(CONFIG_FILE_ENV, e);
+                    }
+                }
+                for rule in &config.vary {
+                    for name in &rule.headers {
+                        if HeaderName::from_bytes(name.trim().as_bytes()).is_err() {
+                            problem(
+                                CONFIG_FILE_ENV,
+                                format!("{name:?} is not a valid header name for {}", rule.path),
+                            );
+                        }
+                    }
+                }
+                for rule in &config.user_agent {
+                    if let Err(e) = Regex::new(&rule.pattern) {
+                        problem(CONFIG_FILE_ENV, format!("invalid User-Agent pattern: {e}"));
+                    }
+                }
+                for shadowed in shadowing::config_rules(&config) {
+                    problem(CONFIG_FILE_ENV, shadowed);
+                }
+                if let Some(aliases) = var(ALIASES_ENV) {
+                    for shadowed in shadowing::aliases(&aliases, &config) {
+                        problem(ALIASES_ENV, shadowed);
+                    }
+                }
+            }
+            Err(e) => problem(CONFIG_FILE_ENV, format!("{e:#}")),
+        }
+    }
+    if let Some(path) = var(ASSET_MANIFEST_ENV) {
+        if let Err(e) = AssetManifest::read(&path) {
+            problem(ASSET_MANIFEST_ENV, format!("{e:#}"));
+        }
+    }
+    if let Some(pattern) = var(FINGERPRINT_PATTERN_ENV) {
+        if let Err(e) = Regex::new(pattern.trim()) {
+            problem(FINGERPRINT_PATTERN_ENV, format!("invalid pattern: {e}"));
+        }
+    }
+    if let Some(path) = var(ETAG_MANIFEST_ENV) {
+        if let Err(e) = precompressed::read_manifest(&path) {
+            problem(ETAG_MANIFEST_ENV, format!("{e:#}"));
+        }
+    }
+    if var(REDIRECT_DATABASE_ENV).is_some() {
+        if let Err(e) = redirect_table::table_name(var(REDIRECT_TABLE_ENV).as_deref()) {
+            problem(REDIRECT_TABLE_ENV, e.to_string());
+        }
+    }
+
+    for (setting, required) in DEPENDENT_SETTINGS {
+        if var(setting).is_some() && var(required).is_none() {
+            problem(setting, format!("has no effect without {required}"));
+        }
+    }
+    if var(OIDC_ISSUER_ENV).is_some() {
+        for required in [
+            OIDC_CLIENT_ID_ENV,
+            OIDC_CLIENT_SECRET_ENV,
+            OIDC_SESSION_SECRET_ENV,
+        ] {
+            if var(required).is_none() {
+                problem(OIDC_ISSUER_ENV, format!("requires {required}"));
+            }
+        }
+    }
+    if let Some(status) = var(BANDWIDTH_QUOTA_STATUS_ENV) {
+        let known = status
+            .trim()
+            .parse()
+            .is_ok_and(|status| QUOTA_STATUSES.contains(&status));
+        if !known {
+            problem(
+                BANDWIDTH_QUOTA_STATUS_ENV,
+                format!("{status:?} is neither 429 nor 509"),
+            );
+        }
+    }
+    if let Some(status) = var(MAX_FILE_SIZE_STATUS_ENV) {
+        let known = status
+            .trim()
+            .parse()
+            .is_ok_and(|status| MAX_FILE_SIZE_STATUSES.contains(&status));
+        if !known {
+            problem(
+                MAX_FILE_SIZE_STATUS_ENV,
+                format!("{status:?} is neither 403 nor 413"),
+            );
+        }
+    }
+    if var(DRAFTS_SECRET_ENV).is_some() && var(ADMIN_TOKEN_ENV).is_none() {
+        problem(
+            DRAFTS_SECRET_ENV,
+            "preview tokens are issued by the admin routes, which need ADMIN_TOKEN".to_string(),
+        );
+    }
+    if let Some(name) = var(STORAGE_BACKEND_ENV) {
+        if !backend::is_known(&name) {
+            problem(
+                STORAGE_BACKEND_ENV,
+                format!("{name:?} is not a known backend"),
+            );
+        } else if !host::SPIN && name.trim() == KEY_VALUE_BACKEND {
+            problem(
+                STORAGE_BACKEND_ENV,
+                "needs Spin host APIs, but the file server was built without the spin feature"
+                    .to_string(),
+            );
+        }
+        let store = var(STORAGE_STORE_ENV).filter(|store| !store.trim().is_empty());
+        if name.trim() == KEY_VALUE_BACKEND && store.is_none() {
+            problem(
+                STORAGE_STORE_ENV,
+                "must name the store files are read from, which shouldn't be the default one"
+                    .to_string(),
+            );
+        }
+    }
+    if var(ADMIN_TOKEN_ENV).is_none() {
+        for setting in [
+            DOWNLOAD_COUNTER_ENV,
+            ANALYTICS_ENV,
+            BANDWIDTH_ACCOUNTING_ENV,
+        ] {
+            if var(setting).is_some_and(|v| v.trim() == "true") {
+                problem(
+                    setting,
+                    format!("collects data that can't be viewed without {ADMIN_TOKEN_ENV}"),
+                );
+            }
+        }
+    }
+    if !host::SPIN {
+        for setting in [
+            ETAG_CACHE_ENV,
+            NOT_FOUND_CACHE_TTL_ENV,
+            DOWNLOAD_COUNTER_ENV,
+            LARGE_DOWNLOAD_LIMIT_ENV,
+            ANALYTICS_ENV,
+            BANDWIDTH_ACCOUNTING_ENV,
+            BANDWIDTH_QUOTA_ENV,
+            SESSION_COOKIE_ENV,
+            REDIRECT_DATABASE_ENV,
+        ] {
+            if var(setting).is_some() {
+                problem(
+                    setting,
+                    "needs Spin host APIs, but the file server was built without the spin feature"
+                        .to_string(),
+                );
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn check_vars(vars: &[(&str, &str)]) -> Vec<String> {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        check_with(|name| vars.get(name).map(|v| v.to_string()))
+            .iter()
+            .map(Problem::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_configuration() {
+        assert!(check_vars(&[]).is_empty());
+        assert!(check_vars(&[
+            ("CACHE_CONTROL", "no-cache"),
+            ("CASE_INSENSITIVE", "Redirect"),
+            ("VARIANT_HEADER", "X-Country"),
+            ("VARIANT_MODE", "suffix"),
+            ("EXPERIMENT_SPLIT", "50"),
+            ("ALIASES", "/latest.zip=v1.zip,/old=new"),
+            ("ETAG_CACHE", "true"),
+        ])
+        .is_empty());
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        assert_eq!(
+            check_vars(&[
+                ("CACHE_CONTROL", "max-age=60\n"),
+                ("VARIANT_HEADER", "X Country"),
+                ("CASE_INSENSITIVE", "yes"),
+                ("EXPERIMENT_SPLIT", "150"),
+                ("LARGE_DOWNLOAD_LIMIT", "-1"),
+                ("ALIASES", "/latest.zip"),
+                ("SERVER_TIMING", "on"),
+                ("CONTENT_DISPOSITION", "default,pdf=download"),
+                ("STORAGE_BACKEND", "s3"),
+                ("BANDWIDTH_QUOTA", "10GB"),
+                ("BANDWIDTH_QUOTA_STATUS", "503"),
+                ("MAX_FILE_SIZE", "2 GiB"),
+                ("MAX_FILE_SIZE_STATUS", "404"),
+                ("SUBDOMAIN_ROOTS", "preview.example.com=sites"),
+            ]),
+            [
+                r#"CACHE_CONTROL: "max-age=60\n" is not a valid header value"#,
+                r#"VARIANT_HEADER: "X Country" is not a valid header name"#,
+                r#"CASE_INSENSITIVE: "yes" is not one of true, false, serve, redirect"#,
+                r#"SERVER_TIMING: "on" is not one of true, false"#,
+                r#"EXPERIMENT_SPLIT: "150" is not a percentage from 0 to 100"#,
+                r#"LARGE_DOWNLOAD_LIMIT: "-1" is not a whole number"#,
+                r#"MAX_FILE_SIZE: "2 GiB" is not a whole number"#,
+                r#"BANDWIDTH_QUOTA: "10GB" is not a whole number"#,
+                r#"CONTENT_DISPOSITION: "download" is not one of inline, attachment"#,
+                r#"ALIASES: "/latest.zip" is not of the form from=to"#,
+                r#"SUBDOMAIN_ROOTS: "preview.example.com=sites" is not of the form *.domain=directory"#,
+                r#"BANDWIDTH_QUOTA_STATUS: "503" is neither 429 nor 509"#,
+                r#"MAX_FILE_SIZE_STATUS: "404" is neither 403 nor 413"#,
+                r#"STORAGE_BACKEND: "s3" is not a known backend"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = std::env::temp_dir();
+        let missing = dir.join("self-check-missing.html");
+        let manifest = dir.join(format!("self-check-{}.json", std::process::id()));
+        std::fs::write(&manifest, "[]").unwrap();
+        let problems = check_vars(&[
+            ("CUSTOM_404_PATH", missing.to_str().unwrap()),
+            ("FALLBACK_PATH", dir.to_str().unwrap()),
+            ("ASSET_MANIFEST", manifest.to_str().unwrap()),
+        ]);
+        std::fs::remove_file(&manifest).unwrap();
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].starts_with("FALLBACK_PATH: "));
+        assert!(problems[0].ends_with("is not a file"));
+        assert!(problems[1].starts_with("CUSTOM_404_PATH: cannot read"));
+        assert!(problems[2].starts_with("ASSET_MANIFEST: invalid asset manifest"));
+    }
+
+    #[test]
+    fn test_conflicts() {
+        assert_eq!(
+            check_vars(&[
+                ("VARIANT_HEADER", "X-Country"),
+                ("ROOT_HEADER", "x-country"),
+                ("ASSET_MANIFEST_MODE", "redirect"),
+                ("ANALYTICS", "true"),
+                ("REDIRECT_DATABASE", "default"),
+                ("REDIRECT_TABLE", "legacy urls"),
+                ("STORAGE_BACKEND", "key-value"),
+            ]),
+            [
+                "ROOT_HEADER: uses the same header as VARIANT_HEADER",
+                r#"REDIRECT_TABLE: invalid redirect table name "legacy urls""#,
+                "ASSET_MANIFEST_MODE: has no effect without ASSET_MANIFEST",
+                "STORAGE_STORE: must name the store files are read from, which shouldn't be the default one",
+                "ANALYTICS: collects data that can't be viewed without ADMIN_TOKEN",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shadowed_rules() {
+        assert_eq!(
+            check_vars(&[
+                ("FALLBACK_PATH", "hello-test.txt"),
+                ("CUSTOM_404_PATH", "hello-test.txt"),
+                ("ALIASES", "/a=hello-test.txt,/a=index.html"),
+            ]),
+            [
+                "ALIASES: the alias of /a is listed twice, only the first one applies",
+                "CUSTOM_404_PATH: is never served, since FALLBACK_PATH answers every missing path",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_root_directories() {
+        assert_eq!(
+            check_vars(&[
+                ("ROOT_HEADER", "spin-fileserver-root"),
+                ("CUSTOM_404_PATH", "./hello-test.txt"),
+            ]),
+            ["CUSTOM_404_PATH: has . or .. segments, so it is ignored under ROOT_HEADER"]
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! Session cookies checked against the key-value store, so the file server can sit behind the
+//! login of another component of the same application without implementing it.
+//!
+//! The other component writes an entry for every session, under a key made of a prefix and the
+//! cookie value, and deletes it on logout. Entries may be JSON objects with an `expires_at` field
+//! in seconds since the Unix epoch, since the key-value store doesn't expire entries itself; any
+//! other value is a session without expiry.
+
+use anyhow::{Context, Result};
+use http::{
+    header::{CACHE_CONTROL, LOCATION},
+    StatusCode,
+};
+
+use crate::{
+    config, error::FileServerError, etag_cache::now, host::Store, plain_response,
+    request_url::RequestUrl, settings, RequestContext, Response,
+};
+
+/// Environment variable for the name of the session cookie, enabling the gate
+pub(crate) const SESSION_COOKIE_ENV: &str = "SESSION_COOKIE";
+/// Environment variable for the label of the key-value store holding sessions
+pub(crate) const SESSION_STORE_ENV: &str = "SESSION_STORE";
+/// Environment variable for the prefix of the keys of sessions
+pub(crate) const SESSION_KEY_PREFIX_ENV: &str = "SESSION_KEY_PREFIX";
+/// Environment variable for the comma-separated request path prefixes requiring a session
+pub(crate) const SESSION_PREFIXES_ENV: &str = "SESSION_PREFIXES";
+/// Environment variable for the login page requests without a session are redirected to
+pub(crate) const SESSION_LOGIN_URL_ENV: &str = "SESSION_LOGIN_URL";
+/// Default label of the key-value store holding sessions
+pub(crate) const DEFAULT_SESSION_STORE: &str = "default";
+/// Default prefix of the keys of sessions
+pub(crate) const DEFAULT_SESSION_KEY_PREFIX: &str = "session:";
+/// Longest cookie value looked up, so clients can't make up arbitrarily long keys
+const MAX_SESSION_ID_LEN: usize = 256;
+
+/// Check the session of requests requiring one.
+/// Returns `None` if the gate isn't enabled, or the request may be served.
+pub(crate) fn handle(req: &RequestContext, path: &str) -> Option<Result<Response>> {
+    let cookie = settings::var(SESSION_COOKIE_ENV)?;
+    let prefixes = settings::var(SESSION_PREFIXES_ENV).unwrap_or_else(|| "/".to_string());
+    if !config::under_prefixes(&prefixes, path) {
+        return None;
+    }
+    let session = req.cookie(cookie.trim()).filter(|id| is_session_id(id));
+    match session.map(is_valid).transpose() {
+        Ok(Some(true)) => None,
+        Ok(_) => Some(Ok(denied(req, path))),
+        Err(e) => Some(Err(e)),
+    }
+}
+
+/// Check whether a session exists in the key-value store and hasn't expired.
+fn is_valid(id: &str) -> Result<bool> {
+    let label = store_label();
+    let store = Store::open(&label).map_err(|e| {
+        FileServerError::BackendUnavailable(format!(
+            "cannot open the key-value store {label} to check sessions: {e}"
+        ))
+    })?;
+    let entry = store
+        .get(&format!("{}{id}", key_prefix()))
+        .context("cannot read the session")?;
+    Ok(entry.is_some_and(|entry| !is_expired(&entry, now())))
+}
+
+/// Return the label of the key-value store holding sessions.
+fn store_label() -> String {
+    settings::var(SESSION_STORE_ENV).map_or_else(
+        || DEFAULT_SESSION_STORE.to_string(),
+        |l| l.trim().to_string(),
+    )
+}
+
+/// Return the prefix of the keys of sessions.
+fn key_prefix() -> String {
+    settings::var(SESSION_KEY_PREFIX_ENV).unwrap_or_else(|| DEFAULT_SESSION_KEY_PREFIX.to_string())
+}
+
+/// Return the prefix of the keys of sessions if the gate is enabled and keeps them in the store
+/// with the given label, so the key-value backend reading files from it doesn't serve them.
+pub(crate) fn key_prefix_in(label: &str) -> Option<String> {
+    settings::var(SESSION_COOKIE_ENV)?;
+    (store_label() == label).then(key_prefix)
+}
+
+/// Check whether a session entry has an expiry in the past.
+fn is_expired(entry: &[u8], now: u64) -> bool {
+    serde_json::from_slice::<serde_json::Value>(entry)
+        .ok()
+        .and_then(|entry| entry.get("expires_at")?.as_u64())
+        .is_some_and(|expires_at| expires_at <= now)
+}
+
+/// Check whether a cookie value can be a session ID, as opposed to arbitrary bytes.
+fn is_session_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_SESSION_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.~+/=".contains(&b))
+}
+
+/// Build the response to requests without a valid session: a redirect to the login page for
+/// navigations if there is one, `401 Unauthorized` otherwise.
+fn denied(req: &RequestContext, path: &str) -> Response {
+    let login = settings::var(SESSION_LOGIN_URL_ENV)
+        .filter(|_| req.method == http::Method::GET || req.method == http::Method::HEAD);
+    let Some(login) = login else {
+        return plain_response(StatusCode::UNAUTHORIZED);
+    };
+    // the login page can send users back where they were going
+    let mut return_to = RequestUrl::of(req).path(path);
+    if !req.query.is_empty() {
+        return_to = format!("{return_to}?{}", req.query);
+    }
+    let login = login.trim();
+    let separator = if login.contains('?') { '&' } else { '?' };
+    let location = format!("{login}{separator}return_to={}", encode(&return_to));
+    (
+        StatusCode::FOUND,
+        vec![
+            (LOCATION.as_str().to_string(), location.into_bytes()),
+            (CACHE_CONTROL.as_str().to_string(), b"no-store".to_vec()),
+        ],
+        None,
+    )
+}
+
+/// Percent-encode a query string parameter value.
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_expired() {
+        assert!(!is_expired(b"alice", 100));
+        assert!(!is_expired(br#"{ "user": "alice" }"#, 100));
+        assert!(!is_expired(br#"{ "expires_at": 200 }"#, 100));
+        assert!(is_expired(br#"{ "expires_at": 100 }"#, 100));
+    }
+
+    #[test]
+    fn test_is_session_id() {
+        assert!(is_session_id("3f2a-b9.c_d"));
+        assert!(is_session_id("aGVsbG8gd29ybGQ="));
+        assert!(!is_session_id(""));
+        assert!(!is_session_id("a b"));
+        assert!(!is_session_id(&"a".repeat(MAX_SESSION_ID_LEN + 1)));
+    }
+}
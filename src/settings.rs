@@ -0,0 +1,817 @@
+//! The settings of the file server, described in one place so the effective configuration can be
+//! reported by the `config` admin route and validated by the self-check.
+//!
+//! Each setting is looked up, in order of precedence, in the environment variable of the same
+//! name, in the Spin variable of the same name in lower case, and in the `[settings]` table of the
+//! configuration file.
+//!
+//! While a request is handled, settings are looked up in a scope that remembers the first value
+//! seen for each setting and the parsed configuration file, so the request sees one consistent
+//! configuration even if it changes underneath, and the sources aren't read again for every use.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    rc::Rc,
+};
+
+use crate::{
+    access_log::ACCESS_LOG_ENV,
+    admin::ADMIN_TOKEN_ENV,
+    analytics::ANALYTICS_ENV,
+    asset_manifest::{
+        ASSET_MANIFEST_ENV, ASSET_MANIFEST_MODE_ENV, ASSET_MANIFEST_REWRITE_ENV,
+        DEFAULT_FINGERPRINT_PATTERN, FINGERPRINT_PATTERN_ENV, IMMUTABLE_FINGERPRINTS_ENV,
+    },
+    backend::{DEFAULT_BACKEND, STORAGE_BACKEND_ENV, STORAGE_CHECKSUMS_ENV, STORAGE_STORE_ENV},
+    config::{Config, CONFIG_FILE_ENV},
+    content_addressed::{CANONICAL_LOCATION_ENV, CONTENT_ADDRESSED_ENV},
+    content_warning::{
+        CONTENT_WARNING_MAX_AGE_ENV, CONTENT_WARNING_PAGE_ENV, CONTENT_WARNING_PREFIXES_ENV,
+    },
+    csp::CONTENT_SECURITY_POLICY_ENV,
+    disposition::CONTENT_DISPOSITION_ENV,
+    downloads::{
+        DOWNLOAD_COUNTER_ENV, LARGE_DOWNLOAD_LIMIT_ENV, LARGE_DOWNLOAD_RETRY_AFTER_ENV,
+        LARGE_DOWNLOAD_SIZE_ENV,
+    },
+    drafts::{DRAFTS_PREVIEW_MAX_AGE_ENV, DRAFTS_SECRET_ENV},
+    etag_cache::ETAG_CACHE_ENV,
+    exif::STRIP_EXIF_ENV,
+    file_headers::FILE_HEADERS_ENV,
+    flush::COMPRESSION_FLUSH_BYTES_ENV,
+    honeypot::{HONEYPOT_PATHS_ENV, HONEYPOT_WEBHOOK_AUTHORIZATION_ENV, HONEYPOT_WEBHOOK_URL_ENV},
+    image_resize::{IMAGE_PLACEHOLDERS_ENV, IMAGE_RESIZING_ENV},
+    inventory::ASSET_INVENTORY_ENV,
+    languages::{DEFAULT_LANGUAGE, DEFAULT_LANGUAGE_ENV, LANGUAGE_COOKIE_ENV},
+    legacy_cache::LEGACY_PRAGMA_ENV,
+    metadata::FILE_METADATA_ENV,
+    minify::{DEFAULT_MINIFY_MAX_SIZE, MINIFY_ENV, MINIFY_MAX_SIZE_ENV},
+    mock_api::MOCK_API_PREFIX_ENV,
+    negotiation::NEGOTIATE_FORMATS_ENV,
+    not_found_cache::NOT_FOUND_CACHE_TTL_ENV,
+    oidc::{
+        OIDC_CLIENT_ID_ENV, OIDC_CLIENT_SECRET_ENV, OIDC_ISSUER_ENV, OIDC_PREFIXES_ENV,
+        OIDC_REDIRECT_URL_ENV, OIDC_SESSION_MAX_AGE_ENV, OIDC_SESSION_SECRET_ENV,
+    },
+    origin::{
+        ORIGIN_BREAKER_COOLDOWN_ENV, ORIGIN_BREAKER_THRESHOLD_ENV, ORIGIN_ERROR_PAGE_ENV,
+        ORIGIN_RETRIES_ENV, ORIGIN_RETRY_BACKOFF_ENV, ORIGIN_TIMEOUT_ENV, ORIGIN_URL_ENV,
+    },
+    precompressed::{ETAG_MANIFEST_ENV, PRECOMPRESSED_ENV},
+    prerender::{PRERENDERED_DIR_ENV, PRERENDER_CRAWLERS_ENV},
+    purge_webhook::{
+        PURGE_WEBHOOK_AUTHORIZATION_ENV, PURGE_WEBHOOK_FORMAT_ENV, PURGE_WEBHOOK_URL_ENV,
+    },
+    quota::{BANDWIDTH_ACCOUNTING_ENV, BANDWIDTH_QUOTA_ENV, BANDWIDTH_QUOTA_STATUS_ENV},
+    redirect_table::{DEFAULT_TABLE, REDIRECT_DATABASE_ENV, REDIRECT_TABLE_ENV},
+    releases::{RELEASES_DIR_ENV, RELEASE_PREVIEW_COOKIE_ENV, RELEASE_PREVIEW_HEADER_ENV},
+    reproducible::REPRODUCIBLE_ENV,
+    request_url::{ABSOLUTE_REDIRECTS_ENV, TRUST_FORWARDED_HEADERS_ENV},
+    rewrite_trace::REWRITE_TRACE_ENV,
+    scanners::BLOCK_SCANNERS_ENV,
+    secrets::SECRET_ROTATION_UNTIL_ENV,
+    self_check::SELF_CHECK_ENV,
+    session_gate::{
+        DEFAULT_SESSION_KEY_PREFIX, DEFAULT_SESSION_STORE, SESSION_COOKIE_ENV,
+        SESSION_KEY_PREFIX_ENV, SESSION_LOGIN_URL_ENV, SESSION_PREFIXES_ENV, SESSION_STORE_ENV,
+    },
+    size_limits::{COMPRESSION_MAX_SIZE_ENV, MAX_FILE_SIZE_ENV, MAX_FILE_SIZE_STATUS_ENV},
+    subdomains::{SUBDOMAIN_PATTERN_ENV, SUBDOMAIN_ROOTS_ENV},
+    subpath::REWRITE_ROOT_URLS_ENV,
+    surrogate::{SURROGATE_CONTROL_ENV, SURROGATE_KEYS_ENV},
+    timing::SERVER_TIMING_ENV,
+    version::VERSION_ROUTE_ENV,
+    ALIASES_ENV, CACHE_CONTROL_DEFAULT_VALUE, CACHE_CONTROL_ENV, CANARY_HEADER_ENV,
+    CANARY_PERCENT_ENV, CANARY_ROOT_ENV, CASE_INSENSITIVE_ENV, CUSTOM_404_PATH_ENV,
+    EXPERIMENT_SPLIT_ENV, FALLBACK_PATH_ENV, INDEX_HTML_ENV, ROOT_HEADER_ENV, VARIANT_HEADER_ENV,
+    VARIANT_MODE_ENV,
+};
+
+/// Values accepted by boolean settings
+const BOOLEAN: &[&str] = &["true", "false"];
+
+/// A setting read from the environment.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Setting {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// The value used when the setting isn't set, if any.
+    pub default: Option<&'static str>,
+    /// The accepted values, compared case-insensitively, or empty if any value is accepted.
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub values: &'static [&'static str],
+    /// Whether the value must not be reported.
+    #[serde(skip)]
+    pub secret: bool,
+}
+
+impl Setting {
+    const fn new(name: &'static str, description: &'static str) -> Self {
+        Self {
+            name,
+            description,
+            default: None,
+            values: &[],
+            secret: false,
+        }
+    }
+
+    const fn default(self, default: &'static str) -> Self {
+        Self {
+            default: Some(default),
+            ..self
+        }
+    }
+
+    const fn values(self, values: &'static [&'static str]) -> Self {
+        Self { values, ..self }
+    }
+
+    const fn boolean(self) -> Self {
+        self.values(BOOLEAN).default("false")
+    }
+}
+
+/// Every setting, in the order they are documented.
+pub(crate) const SETTINGS: &[Setting] = &[
+    Setting::new(CACHE_CONTROL_ENV, "Cache-Control header of responses")
+        .default(CACHE_CONTROL_DEFAULT_VALUE),
+    Setting::new(
+        LEGACY_PRAGMA_ENV,
+        "Send Pragma: no-cache when Cache-Control has no-cache or no-store",
+    )
+    .boolean(),
+    Setting::new(FALLBACK_PATH_ENV, "File served for paths that don't exist"),
+    Setting::new(
+        BLOCK_SCANNERS_ENV,
+        "Answer paths probed by scanners with a plain 404",
+    )
+    .boolean(),
+    Setting::new(
+        CUSTOM_404_PATH_ENV,
+        "File served as the body of 404 responses",
+    ),
+    Setting::new(
+        ABSOLUTE_REDIRECTS_ENV,
+        "Send redirect locations as absolute URLs",
+    )
+    .boolean(),
+    Setting::new(
+        TRUST_FORWARDED_HEADERS_ENV,
+        "Use the X-Forwarded-* headers of requests, set by a trusted proxy",
+    )
+    .boolean(),
+    Setting::new(
+        REWRITE_ROOT_URLS_ENV,
+        "Prefix root-relative URLs in HTML and CSS with the component route",
+    )
+    .boolean(),
+    Setting::new(
+        PRERENDERED_DIR_ENV,
+        "Directory of pages served to crawlers instead of the app",
+    ),
+    Setting::new(
+        PRERENDER_CRAWLERS_ENV,
+        "Regular expression matching the User-Agent of crawlers",
+    ),
+    Setting::new(
+        CASE_INSENSITIVE_ENV,
+        "How paths differing in case are handled",
+    )
+    .values(&["true", "false", "serve", "redirect"])
+    .default("false"),
+    Setting::new(
+        NOT_FOUND_CACHE_TTL_ENV,
+        "Seconds missing paths are remembered in the key-value store",
+    ),
+    Setting::new(INDEX_HTML_ENV, "How requests naming index.html are handled")
+        .values(&["serve", "redirect", "not-found"])
+        .default("serve"),
+    Setting::new(
+        DEFAULT_LANGUAGE_ENV,
+        "Language of localized fallback and 404 documents used by default",
+    )
+    .default(DEFAULT_LANGUAGE),
+    Setting::new(
+        LANGUAGE_COOKIE_ENV,
+        "Name of the cookie remembering the language of a client",
+    ),
+    Setting::new(ALIASES_ENV, "Comma-separated virtual=physical path aliases"),
+    Setting::new(
+        VARIANT_HEADER_ENV,
+        "Request header selecting a content variant",
+    ),
+    Setting::new(VARIANT_MODE_ENV, "Where content variants are stored")
+        .values(&["directory", "suffix"])
+        .default("directory"),
+    Setting::new(EXPERIMENT_SPLIT_ENV, "Percentage of clients in bucket b"),
+    Setting::new(CANARY_ROOT_ENV, "Directory of the canary release"),
+    Setting::new(
+        CANARY_PERCENT_ENV,
+        "Percentage of clients served the canary",
+    )
+    .default("0"),
+    Setting::new(
+        CANARY_HEADER_ENV,
+        "Request header the canary assignment is hashed from",
+    ),
+    Setting::new(
+        LARGE_DOWNLOAD_LIMIT_ENV,
+        "Maximum number of concurrent large downloads",
+    ),
+    Setting::new(
+        LARGE_DOWNLOAD_SIZE_ENV,
+        "Size in bytes from which downloads are large",
+    )
+    .default("10485760"),
+    Setting::new(
+        LARGE_DOWNLOAD_RETRY_AFTER_ENV,
+        "Seconds clients wait when the download limit is reached",
+    )
+    .default("30"),
+    Setting::new(
+        MAX_FILE_SIZE_ENV,
+        "Size in bytes of the largest files served",
+    ),
+    Setting::new(
+        MAX_FILE_SIZE_STATUS_ENV,
+        "Status of responses for larger files, 403 or 413",
+    )
+    .default("403"),
+    Setting::new(
+        COMPRESSION_MAX_SIZE_ENV,
+        "Size in bytes of the largest files compressed on the fly",
+    ),
+    Setting::new(
+        SURROGATE_CONTROL_ENV,
+        "Surrogate-Control header of responses",
+    ),
+    Setting::new(
+        SURROGATE_KEYS_ENV,
+        "Comma-separated surrogate keys: path, directory, extension or fixed keys",
+    ),
+    Setting::new(
+        CONTENT_DISPOSITION_ENV,
+        "Comma-separated extension=inline|attachment rules, or default",
+    ),
+    Setting::new(
+        CONTENT_SECURITY_POLICY_ENV,
+        "Content-Security-Policy header of HTML documents, with an optional {nonce}",
+    ),
+    Setting::new(
+        PURGE_WEBHOOK_URL_ENV,
+        "URL called when CDN caches need to be purged",
+    ),
+    Setting {
+        secret: true,
+        ..Setting::new(
+            PURGE_WEBHOOK_AUTHORIZATION_ENV,
+            "Authorization header of purge webhook requests",
+        )
+    },
+    Setting::new(
+        PURGE_WEBHOOK_FORMAT_ENV,
+        "Body format of purge webhook requests",
+    )
+    .values(&["json", "cloudflare"])
+    .default("json"),
+    Setting::new(
+        HONEYPOT_PATHS_ENV,
+        "Comma-separated paths whose requests are reported",
+    ),
+    Setting::new(
+        HONEYPOT_WEBHOOK_URL_ENV,
+        "URL requests for honeypot paths are reported to",
+    ),
+    Setting {
+        secret: true,
+        ..Setting::new(
+            HONEYPOT_WEBHOOK_AUTHORIZATION_ENV,
+            "Authorization header of honeypot webhook requests",
+        )
+    },
+    Setting::new(STORAGE_BACKEND_ENV, "Backend files are read from").default(DEFAULT_BACKEND),
+    Setting::new(
+        STORAGE_STORE_ENV,
+        "Key-value store of the key-value backend, required by it",
+    ),
+    Setting::new(
+        STORAGE_CHECKSUMS_ENV,
+        "Refuse files of the key-value backend that don't match their stored digest",
+    )
+    .boolean(),
+    Setting::new(
+        ORIGIN_URL_ENV,
+        "Base URL of the origin serving files missing locally",
+    ),
+    Setting::new(
+        ORIGIN_TIMEOUT_ENV,
+        "Milliseconds after which origin responses are discarded",
+    )
+    .default("10000"),
+    Setting::new(ORIGIN_RETRIES_ENV, "Retries of failed origin requests").default("2"),
+    Setting::new(
+        ORIGIN_RETRY_BACKOFF_ENV,
+        "Milliseconds before the first retry, doubled every retry",
+    )
+    .default("100"),
+    Setting::new(
+        ORIGIN_BREAKER_THRESHOLD_ENV,
+        "Consecutive origin failures that open the circuit breaker",
+    )
+    .default("5"),
+    Setting::new(
+        ORIGIN_BREAKER_COOLDOWN_ENV,
+        "Seconds the circuit breaker stays open",
+    )
+    .default("30"),
+    Setting::new(
+        ORIGIN_ERROR_PAGE_ENV,
+        "Page served when the origin is unavailable",
+    ),
+    Setting {
+        secret: true,
+        ..Setting::new(ADMIN_TOKEN_ENV, "Bearer token enabling the admin routes")
+    },
+    Setting::new(
+        VERSION_ROUTE_ENV,
+        "Serve the version route without the admin token",
+    )
+    .boolean(),
+    Setting::new(
+        SECRET_ROTATION_UNTIL_ENV,
+        "Seconds since the Unix epoch until which previous values of secrets are accepted",
+    ),
+    Setting::new(
+        OIDC_ISSUER_ENV,
+        "Issuer URL of the OpenID Connect identity provider users log in with",
+    ),
+    Setting::new(OIDC_CLIENT_ID_ENV, "OpenID Connect client ID"),
+    Setting {
+        secret: true,
+        ..Setting::new(OIDC_CLIENT_SECRET_ENV, "OpenID Connect client secret")
+    },
+    Setting {
+        secret: true,
+        ..Setting::new(
+            OIDC_SESSION_SECRET_ENV,
+            "Secret session cookies are signed with",
+        )
+    },
+    Setting::new(
+        OIDC_PREFIXES_ENV,
+        "Comma-separated path prefixes requiring a login",
+    )
+    .default("/"),
+    Setting::new(
+        OIDC_REDIRECT_URL_ENV,
+        "Login callback URL registered with the identity provider",
+    ),
+    Setting::new(OIDC_SESSION_MAX_AGE_ENV, "Seconds login sessions last").default("28800"),
+    Setting::new(
+        SESSION_COOKIE_ENV,
+        "Cookie holding sessions kept in the key-value store",
+    ),
+    Setting::new(
+        SESSION_STORE_ENV,
+        "Key-value store holding the sessions of SESSION_COOKIE",
+    )
+    .default(DEFAULT_SESSION_STORE),
+    Setting::new(SESSION_KEY_PREFIX_ENV, "Prefix of the keys of sessions")
+        .default(DEFAULT_SESSION_KEY_PREFIX),
+    Setting::new(
+        SESSION_PREFIXES_ENV,
+        "Comma-separated path prefixes requiring a session",
+    )
+    .default("/"),
+    Setting::new(
+        SESSION_LOGIN_URL_ENV,
+        "Login page requests without a session are redirected to",
+    ),
+    Setting::new(
+        CONTENT_WARNING_PREFIXES_ENV,
+        "Comma-separated path prefixes served after a content warning",
+    ),
+    Setting::new(
+        CONTENT_WARNING_PAGE_ENV,
+        "HTML file served as the content warning",
+    ),
+    Setting::new(
+        CONTENT_WARNING_MAX_AGE_ENV,
+        "Seconds an acknowledged content warning lasts",
+    )
+    .default("86400"),
+    Setting {
+        secret: true,
+        ..Setting::new(
+            DRAFTS_SECRET_ENV,
+            "Secret preview tokens of the drafts under /drafts are signed with",
+        )
+    },
+    Setting::new(
+        DRAFTS_PREVIEW_MAX_AGE_ENV,
+        "Seconds preview tokens of the drafts are valid",
+    )
+    .default("604800"),
+    Setting::new(
+        RELEASES_DIR_ENV,
+        "Directory holding one subdirectory per release",
+    ),
+    Setting::new(
+        RELEASE_PREVIEW_HEADER_ENV,
+        "Request header naming a release to preview",
+    ),
+    Setting::new(
+        RELEASE_PREVIEW_COOKIE_ENV,
+        "Cookie naming a release to preview",
+    ),
+    Setting::new(ASSET_MANIFEST_ENV, "Manifest of fingerprinted assets"),
+    Setting::new(
+        ASSET_MANIFEST_MODE_ENV,
+        "How logical asset names are served",
+    )
+    .values(&["serve", "redirect"])
+    .default("serve"),
+    Setting::new(
+        ASSET_MANIFEST_REWRITE_ENV,
+        "Rewrite asset references in HTML documents",
+    )
+    .boolean(),
+    Setting::new(
+        IMMUTABLE_FINGERPRINTS_ENV,
+        "Cache files with fingerprinted names forever",
+    )
+    .boolean(),
+    Setting::new(
+        FINGERPRINT_PATTERN_ENV,
+        "Regular expression matching fingerprinted file names",
+    )
+    .default(DEFAULT_FINGERPRINT_PATTERN),
+    Setting::new(MINIFY_ENV, "Comma-separated media types of files to minify"),
+    Setting::new(
+        MINIFY_MAX_SIZE_ENV,
+        "Size of the largest files to minify, in bytes",
+    )
+    .default(DEFAULT_MINIFY_MAX_SIZE),
+    Setting::new(PRECOMPRESSED_ENV, "Serve .br and .gz siblings of files").boolean(),
+    Setting::new(ETAG_MANIFEST_ENV, "Manifest of precomputed ETags"),
+    Setting::new(
+        COMPRESSION_FLUSH_BYTES_ENV,
+        "Uncompressed bytes after which gzip and deflate output is flushed",
+    ),
+    Setting::new(
+        CONTENT_ADDRESSED_ENV,
+        "Serve files of the ETag manifest by digest under /_cas/",
+    )
+    .boolean(),
+    Setting::new(
+        CANONICAL_LOCATION_ENV,
+        "Name the canonical copy of identical files in Content-Location",
+    )
+    .boolean(),
+    Setting::new(
+        REPRODUCIBLE_ENV,
+        "Serve files unchanged, and only if they match the ETag manifest",
+    )
+    .boolean(),
+    Setting::new(
+        ETAG_CACHE_ENV,
+        "Cache ETags and compressed bodies in the key-value store",
+    )
+    .boolean(),
+    Setting::new(FILE_METADATA_ENV, "Serve file metadata for ?meta requests").boolean(),
+    Setting::new(
+        FILE_HEADERS_ENV,
+        "Read per-file headers from sidecars and front-matter",
+    )
+    .boolean(),
+    Setting::new(
+        MOCK_API_PREFIX_ENV,
+        "Path prefix answered from JSON fixture files",
+    ),
+    Setting::new(
+        NEGOTIATE_FORMATS_ENV,
+        "Serve .html or .json files by the Accept header",
+    )
+    .boolean(),
+    Setting::new(
+        DOWNLOAD_COUNTER_ENV,
+        "Count downloads in the key-value store",
+    )
+    .boolean(),
+    Setting::new(ANALYTICS_ENV, "Record page views in the key-value store").boolean(),
+    Setting::new(
+        BANDWIDTH_ACCOUNTING_ENV,
+        "Count bytes served per host and month in the key-value store",
+    )
+    .boolean(),
+    Setting::new(
+        BANDWIDTH_QUOTA_ENV,
+        "Bytes each host may serve per month, enabling BANDWIDTH_ACCOUNTING",
+    ),
+    Setting::new(
+        BANDWIDTH_QUOTA_STATUS_ENV,
+        "Status of responses once the quota is exceeded, 429 or 509",
+    )
+    .default("429"),
+    Setting::new(
+        IMAGE_RESIZING_ENV,
+        "Resize PNG and JPEG images with w, h and q query parameters",
+    )
+    .boolean(),
+    Setting::new(
+        IMAGE_PLACEHOLDERS_ENV,
+        "Serve blurred placeholders of images for ?lqip",
+    )
+    .boolean(),
+    Setting::new(
+        STRIP_EXIF_ENV,
+        "Strip metadata such as GPS locations from JPEG images",
+    )
+    .boolean(),
+    Setting::new(
+        SERVER_TIMING_ENV,
+        "Log stage timings, and add a Server-Timing header to admin requests",
+    )
+    .boolean(),
+    Setting::new(
+        REWRITE_TRACE_ENV,
+        "Add an X-Rewrite-Trace header listing the rules applied to the path",
+    )
+    .boolean(),
+    Setting::new(
+        ACCESS_LOG_ENV,
+        "Format of access log lines written to stdout",
+    )
+    .values(&["json", "common", "combined"]),
+    Setting::new(CONFIG_FILE_ENV, "TOML configuration file"),
+    Setting::new(
+        ROOT_HEADER_ENV,
+        "Trusted request header scoping requests to a subdirectory",
+    ),
+    Setting::new(
+        SUBDOMAIN_ROOTS_ENV,
+        "Wildcard domain whose subdomains are served from subdirectories, as *.domain=directory",
+    ),
+    Setting::new(
+        SUBDOMAIN_PATTERN_ENV,
+        "Regular expression subdomains must match to be served",
+    ),
+    Setting::new(
+        REDIRECT_DATABASE_ENV,
+        "SQLite database of the redirect table",
+    ),
+    Setting::new(REDIRECT_TABLE_ENV, "Name of the redirect table").default(DEFAULT_TABLE),
+    Setting::new(SELF_CHECK_ENV, "Check the configuration on every request")
+        .values(&["log", "strict"]),
+    Setting::new(
+        ASSET_INVENTORY_ENV,
+        "Log a summary of the served files on the first request",
+    )
+    .boolean(),
+];
+
+/// The effective value of a setting, along with its description.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct Effective {
+    value: Option<String>,
+    source: Source,
+    #[serde(flatten)]
+    setting: &'static Setting,
+}
+
+/// Where the effective value of a setting comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Source {
+    Env,
+    Variable,
+    File,
+    Default,
+    Unset,
+}
+
+/// The settings looked up so far in the current scope.
+#[derive(Default)]
+struct Scoped {
+    values: HashMap<String, Option<(String, Source)>>,
+    config: Option<Rc<Config>>,
+}
+
+thread_local! {
+    static SCOPE: RefCell<Option<Scoped>> = const { RefCell::new(None) };
+}
+
+/// A settings scope, ended when dropped.
+pub(crate) struct Scope(());
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        SCOPE.with_borrow_mut(|scope| *scope = None);
+    }
+}
+
+/// Start a settings scope, for the duration of a request.
+pub(crate) fn scope() -> Scope {
+    SCOPE.with_borrow_mut(|scope| *scope = Some(Scoped::default()));
+    Scope(())
+}
+
+/// Return the configuration file parsed in the current scope, if any.
+pub(crate) fn scoped_config() -> Option<Rc<Config>> {
+    SCOPE.with_borrow(|scope| scope.as_ref()?.config.clone())
+}
+
+/// Remember the parsed configuration file for the rest of the current scope, if any.
+pub(crate) fn scope_config(config: &Rc<Config>) {
+    SCOPE.with_borrow_mut(|scope| {
+        if let Some(scope) = scope {
+            scope.config = Some(Rc::clone(config));
+        }
+    });
+}
+
+/// Return the value of a setting, given by its environment variable name.
+pub(crate) fn var(name: &str) -> Option<String> {
+    lookup(name).map(|(value, _)| value)
+}
+
+/// Return the value of a setting and where it comes from, as first seen in the current scope.
+fn lookup(name: &str) -> Option<(String, Source)> {
+    let scoped = SCOPE.with_borrow(|scope| scope.as_ref()?.values.get(name).cloned());
+    if let Some(found) = scoped {
+        return found;
+    }
+    let found = layered(
+        std::env::var(name).ok(),
+        || variable(name),
+        || file_setting(name),
+    );
+    SCOPE.with_borrow_mut(|scope| {
+        if let Some(scope) = scope {
+            scope.values.insert(name.to_string(), found.clone());
+        }
+    });
+    found
+}
+
+/// Pick the value of the layer with the highest precedence, only looking up lower layers when
+/// needed.
+fn layered(
+    env: Option<String>,
+    variable: impl FnOnce() -> Option<String>,
+    file: impl FnOnce() -> Option<String>,
+) -> Option<(String, Source)> {
+    env.map(|value| (value, Source::Env))
+        .or_else(|| variable().map(|value| (value, Source::Variable)))
+        .or_else(|| file().map(|value| (value, Source::File)))
+}
+
+/// Look up a Spin variable, which must be declared in the component's `variables` table.
+#[cfg(all(target_arch = "wasm32", feature = "spin"))]
+pub(crate) fn variable(name: &str) -> Option<String> {
+    spin_sdk::variables::get(&name.to_lowercase()).ok()
+}
+
+/// Spin variables are only available to the component built for and running in Spin.
+#[cfg(not(all(target_arch = "wasm32", feature = "spin")))]
+pub(crate) fn variable(_name: &str) -> Option<String> {
+    None
+}
+
+/// Look up a setting in the configuration file, which can't set its own path.
+/// Errors loading the file are reported when handling the request and by the self-check.
+fn file_setting(name: &str) -> Option<String> {
+    if name == CONFIG_FILE_ENV {
+        return None;
+    }
+    Config::load().ok()?.setting(name)
+}
+
+/// Return the effective configuration: every setting with its value and where it comes from,
+/// and the contents of the configuration file.
+pub(crate) fn effective() -> Result<serde_json::Value> {
+    let settings = effective_with(lookup);
+    Ok(serde_json::json!({
+        "settings": settings,
+        "config_file": *Config::load()?,
+        "config_file_sha256": Config::digest()?,
+    }))
+}
+
+fn effective_with(
+    lookup: impl Fn(&str) -> Option<(String, Source)>,
+) -> BTreeMap<&'static str, Effective> {
+    SETTINGS
+        .iter()
+        .map(|setting| {
+            let effective = match (lookup(setting.name), setting.default) {
+                (Some((_, source)), _) if setting.secret => Effective {
+                    value: Some("<redacted>".to_string()),
+                    source,
+                    setting,
+                },
+                (Some((value, source)), _) => Effective {
+                    value: Some(value),
+                    source,
+                    setting,
+                },
+                (None, Some(default)) => Effective {
+                    value: Some(default.to_string()),
+                    source: Source::Default,
+                    setting,
+                },
+                (None, None) => Effective {
+                    value: None,
+                    source: Source::Unset,
+                    setting,
+                },
+            };
+            (setting.name, effective)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_effective() {
+        let settings = effective_with(|name| match name {
+            "CACHE_CONTROL" => Some(("no-cache".to_string(), Source::Env)),
+            "ADMIN_TOKEN" => Some(("secret".to_string(), Source::Variable)),
+            _ => None,
+        });
+        assert_eq!(settings.len(), SETTINGS.len());
+        assert_eq!(
+            serde_json::to_value(&settings["CACHE_CONTROL"]).unwrap(),
+            serde_json::json!({
+                "value": "no-cache",
+                "source": "env",
+                "name": "CACHE_CONTROL",
+                "description": "Cache-Control header of responses",
+                "default": "max-age=60",
+            })
+        );
+        assert_eq!(settings["ADMIN_TOKEN"].value.as_deref(), Some("<redacted>"));
+        assert_eq!(settings["ADMIN_TOKEN"].source, Source::Variable);
+        assert_eq!(settings["PRECOMPRESSED"].value.as_deref(), Some("false"));
+        assert_eq!(settings["PRECOMPRESSED"].source, Source::Default);
+        assert_eq!(settings["FALLBACK_PATH"].source, Source::Unset);
+        // defaults defined as numbers elsewhere
+        assert_eq!(
+            settings["LARGE_DOWNLOAD_SIZE"].value,
+            Some(crate::downloads::DEFAULT_LARGE_DOWNLOAD_SIZE.to_string())
+        );
+        assert_eq!(
+            settings["LARGE_DOWNLOAD_RETRY_AFTER"].value,
+            Some(crate::downloads::DEFAULT_RETRY_AFTER.to_string())
+        );
+    }
+
+    #[test]
+    fn test_layered() {
+        let some = |value: &str| Some(value.to_string());
+        assert_eq!(
+            layered(some("env"), || some("variable"), || some("file")),
+            Some(("env".to_string(), Source::Env))
+        );
+        assert_eq!(
+            layered(None, || some("variable"), || some("file")),
+            Some(("variable".to_string(), Source::Variable))
+        );
+        assert_eq!(
+            layered(None, || None, || some("file")),
+            Some(("file".to_string(), Source::File))
+        );
+        assert_eq!(layered(None, || None, || None), None);
+        // lower layers aren't looked up when overridden
+        assert_eq!(
+            layered(some("env"), || unreachable!(), || unreachable!()),
+            Some(("env".to_string(), Source::Env))
+        );
+    }
+
+    #[test]
+    fn test_scope() {
+        const NAME: &str = "SETTINGS_SCOPE_TEST";
+        std::env::set_var(NAME, "first");
+        {
+            let _scope = scope();
+            assert_eq!(var(NAME).as_deref(), Some("first"));
+            std::env::set_var(NAME, "second");
+            assert_eq!(var(NAME).as_deref(), Some("first"));
+            assert!(scoped_config().is_none());
+            let config = Rc::default();
+            scope_config(&config);
+            assert!(Rc::ptr_eq(&scoped_config().unwrap(), &config));
+        }
+        assert_eq!(var(NAME).as_deref(), Some("second"));
+        assert!(scoped_config().is_none());
+        std::env::remove_var(NAME);
+    }
+}
@@ -0,0 +1,256 @@
+//! Rules that never apply because an earlier rule always applies instead, such as a User-Agent
+//! rule after a catch-all one, reported by the configuration check.
+//!
+//! Only rules that are certain to be shadowed are reported: a rule that only applies to some of
+//! the requests of a later one doesn't make it unreachable.
+
+use regex::Regex;
+
+use crate::{access, config::Config};
+
+/// User agents a pattern must match to be considered a catch-all.
+const SAMPLE_USER_AGENTS: [&str; 4] = [
+    "",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36",
+    "curl/8.4.0",
+    "Googlebot/2.1 (+http://www.google.com/bot.html)",
+];
+/// Request paths a glob must match to be considered a catch-all.
+const SAMPLE_PATHS: [&str; 3] = ["/", "/index.html", "/docs/api/v1.json"];
+
+/// Describe the aliases of a comma-separated `from=to` list that never apply, given the aliases
+/// of the configuration file, which the list overrides.
+pub(crate) fn aliases(aliases: &str, config: &Config) -> Vec<String> {
+    let mut shadowed = Vec::new();
+    let mut seen: Vec<&str> = Vec::new();
+    for (from, _) in aliases.split(',').filter_map(|a| a.split_once('=')) {
+        let from = from.trim();
+        if seen.iter().any(|seen| same_path(seen, from)) {
+            shadowed.push(format!(
+                "the alias of {from} is listed twice, only the first one applies"
+            ));
+            continue;
+        }
+        if let Some(overridden) = config.aliases.keys().find(|path| same_path(path, from)) {
+            shadowed.push(format!(
+                "the alias of {from} overrides the alias of {overridden} in the configuration file"
+            ));
+        }
+        seen.push(from);
+    }
+    shadowed
+}
+
+/// Describe the rules of the configuration file that never apply.
+pub(crate) fn config_rules(config: &Config) -> Vec<String> {
+    let mut shadowed = Vec::new();
+
+    let user_agent = config
+        .user_agent
+        .iter()
+        .map(|rule| Regex::new(&rule.pattern).ok())
+        .collect::<Vec<_>>();
+    for (i, rule) in config.user_agent.iter().enumerate() {
+        let earlier = config.user_agent[..i].iter().zip(&user_agent);
+        let shadowing = earlier
+            .filter(|(earlier, _)| earlier.pattern != rule.pattern)
+            .find(|(_, pattern)| pattern.as_ref().is_some_and(matches_every_user_agent));
+        let duplicate = config.user_agent[..i]
+            .iter()
+            .any(|earlier| earlier.pattern == rule.pattern);
+        if duplicate {
+            shadowed.push(format!(
+                "the User-Agent rule {:?} is listed twice, only the first one applies",
+                rule.pattern
+            ));
+        } else if let Some((earlier, _)) = shadowing {
+            shadowed.push(format!(
+                "the User-Agent rule {:?} never applies, since {:?} before it matches every User-Agent",
+                rule.pattern, earlier.pattern
+            ));
+        }
+    }
+
+    for (i, rule) in config.methods.iter().enumerate() {
+        if let Some(earlier) = config.methods[..i]
+            .iter()
+            .find(|earlier| covers(&earlier.path, &rule.path))
+        {
+            shadowed.push(format!(
+                "the method rule for {} never applies, since the rule for {} before it matches all its paths",
+                rule.path, earlier.path
+            ));
+        }
+    }
+
+    for (i, rule) in config.access.iter().enumerate() {
+        // rules restricted to some methods or headers let other requests through
+        if let Some(earlier) = config.access[..i].iter().find(|earlier| {
+            earlier.methods.is_empty()
+                && earlier.headers.is_empty()
+                && covers(&earlier.path, &rule.path)
+        }) {
+            shadowed.push(format!(
+                "the access rule for {} never applies, since the rule for {} before it matches all its requests",
+                rule.path, earlier.path
+            ));
+        }
+    }
+
+    for (i, mount) in config.mounts.iter().enumerate() {
+        let prefix = mount.prefix.trim_matches('/');
+        let duplicate = config.mounts[i + 1..]
+            .iter()
+            .any(|later| later.prefix.trim_matches('/') == prefix);
+        if duplicate {
+            shadowed.push(format!(
+                "the mount of /{prefix} is declared twice, only the last one applies"
+            ));
+        }
+    }
+
+    // once mounts are declared, paths outside of them aren't served
+    if !config.mounts.is_empty() {
+        let mut aliases = config.aliases.iter().collect::<Vec<_>>();
+        aliases.sort();
+        for (from, to) in aliases
+            .into_iter()
+            .filter(|(_, to)| config.mount(to).is_none())
+        {
+            shadowed.push(format!(
+                "the alias of {from} is never served, since {to} is not under a mount"
+            ));
+        }
+    }
+
+    shadowed
+}
+
+/// Check whether a glob matches every path another glob matches, as far as can be told: the
+/// globs are the same, the other one is a plain path it matches, or it matches any path.
+fn covers(glob: &str, other: &str) -> bool {
+    let plain = !other.contains(['*', '?']);
+    same_path(glob, other)
+        || (plain && access::glob_match(glob, other))
+        || SAMPLE_PATHS
+            .iter()
+            .all(|path| access::glob_match(glob, path))
+}
+
+/// Check whether a pattern matches any User-Agent, such as `.*` or an empty pattern.
+fn matches_every_user_agent(pattern: &Regex) -> bool {
+    SAMPLE_USER_AGENTS
+        .iter()
+        .all(|user_agent| pattern.is_match(user_agent))
+}
+
+fn same_path(a: &str, b: &str) -> bool {
+    crate::config::same_path(a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn test_user_agent_rules() {
+        let config = config(
+            r#"
+            [[user-agent]]
+            pattern = "Googlebot"
+            rewrite = "/prerendered/index.html"
+
+            [[user-agent]]
+            pattern = ".*"
+            rewrite = "/app.html"
+
+            [[user-agent]]
+            pattern = "curl"
+            redirect = "/plain.txt"
+
+            [[user-agent]]
+            pattern = "Googlebot"
+            redirect = "/bot.html"
+            "#,
+        );
+        assert_eq!(
+            config_rules(&config),
+            [
+                r#"the User-Agent rule "curl" never applies, since ".*" before it matches every User-Agent"#,
+                r#"the User-Agent rule "Googlebot" is listed twice, only the first one applies"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_path_rules() {
+        let config = config(
+            r#"
+            [aliases]
+            "/docs" = "docs/index.html"
+            "/app" = "app/index.html"
+
+            [[mount]]
+            prefix = "/app"
+            directory = "build"
+
+            [[mount]]
+            prefix = "/app/"
+            directory = "dist"
+
+            [[methods]]
+            path = "/api/**"
+            allow = ["GET"]
+
+            [[methods]]
+            path = "/api/users"
+            allow = ["GET", "POST"]
+
+            [[methods]]
+            path = "/api/*/avatar"
+            allow = ["PUT"]
+
+            [[access]]
+            path = "/admin/**"
+            methods = ["POST"]
+            action = "deny"
+
+            [[access]]
+            path = "/**"
+            action = "allow"
+
+            [[access]]
+            path = "/admin/secret.txt"
+            action = "deny"
+            "#,
+        );
+        assert_eq!(
+            config_rules(&config),
+            [
+                "the method rule for /api/users never applies, since the rule for /api/** before it matches all its paths",
+                "the access rule for /admin/secret.txt never applies, since the rule for /** before it matches all its requests",
+                "the mount of /app is declared twice, only the last one applies",
+                "the alias of /docs is never served, since docs/index.html is not under a mount",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_aliases() {
+        let config = config("[aliases]\n\"/docs\" = \"docs/v1\"\n");
+        assert_eq!(
+            aliases(
+                "/docs=docs/v2, /blog=blog/index.html, blog=news.html",
+                &config
+            ),
+            [
+                "the alias of /docs overrides the alias of /docs in the configuration file",
+                "the alias of blog is listed twice, only the first one applies",
+            ]
+        );
+    }
+}
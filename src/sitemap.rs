@@ -0,0 +1,191 @@
+//! Link checking of the served files against the sitemap of the site, reporting the URLs it lists
+//! that clients can't get, so broken deployments are noticed before crawlers do.
+//!
+//! Each URL is requested from the file server itself, as a client without cookies would, which
+//! also warms the ETag cache when it is enabled. Sitemap indexes are followed one level deep. Only
+//! the paths of URLs are compared with the component route, not their hosts.
+
+use anyhow::{Context, Result};
+use http::{header::LOCATION, Method, StatusCode, Uri};
+use regex::Regex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::{
+    backend, languages, request_url::RequestUrl, FileServer, FileServerPath, RequestContext,
+};
+
+/// Path of the sitemap checked, unless the `sitemap` query parameter names another
+pub(crate) const DEFAULT_SITEMAP: &str = "/sitemap.xml";
+
+/// The outcome of checking the URLs of a sitemap.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct Report {
+    /// The number of URLs requested.
+    pub checked: usize,
+    /// The status of URLs answered with an error, by URL.
+    pub broken: BTreeMap<String, u16>,
+    /// The location of URLs answered with a redirect, by URL.
+    pub redirects: BTreeMap<String, String>,
+    /// URLs outside the component route, which can't be checked.
+    pub outside: Vec<String>,
+}
+
+/// Check the URLs of the sitemap named by a request.
+/// Returns `None` if the sitemap doesn't exist.
+pub(crate) fn check(req: &RequestContext) -> Result<Option<Report>> {
+    let sitemap = req
+        .query
+        .split('&')
+        .find_map(|p| p.strip_prefix("sitemap="))
+        .unwrap_or(DEFAULT_SITEMAP);
+    let Some(xml) = read(sitemap)? else {
+        return Ok(None);
+    };
+    let base = RequestUrl::of(req).base().to_string();
+
+    let mut report = Report::default();
+    let pages = if xml.contains("<sitemapindex") {
+        let mut pages = Vec::new();
+        for loc in locations(&xml) {
+            match relative(&loc, &base) {
+                Some((path, _)) => match read(&path)? {
+                    Some(nested) => pages.extend(locations(&nested)),
+                    None => {
+                        report.broken.insert(loc, StatusCode::NOT_FOUND.as_u16());
+                    }
+                },
+                None => report.outside.push(loc),
+            }
+        }
+        pages
+    } else {
+        locations(&xml)
+    };
+
+    for loc in pages {
+        let Some((path, query)) = relative(&loc, &base) else {
+            report.outside.push(loc);
+            continue;
+        };
+        let page = RequestContext {
+            method: Method::HEAD,
+            path: path.into_bytes(),
+            query,
+            route: req.route.clone(),
+            internal: true,
+            ..Default::default()
+        };
+        report.checked += 1;
+        let (status, headers, _) = match FileServer::make_response(&page) {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Error checking {loc}: {e}");
+                report
+                    .broken
+                    .insert(loc, StatusCode::INTERNAL_SERVER_ERROR.as_u16());
+                continue;
+            }
+        };
+        if status.is_redirection() && status != StatusCode::NOT_MODIFIED {
+            let location = headers
+                .iter()
+                .find_map(|(k, v)| k.eq_ignore_ascii_case(LOCATION.as_str()).then_some(v))
+                .map(|v| String::from_utf8_lossy(v).into_owned())
+                .unwrap_or_default();
+            report.redirects.insert(loc, location);
+        } else if status.is_client_error() || status.is_server_error() {
+            report.broken.insert(loc, status.as_u16());
+        }
+    }
+    Ok(Some(report))
+}
+
+/// Read a sitemap from the served files, if it exists.
+fn read(path: &str) -> Result<Option<String>> {
+    match FileServer::resolve(path, &[languages::default_language()]) {
+        FileServerPath::Physical(file) => {
+            let xml = backend::current()
+                .read(&file)
+                .with_context(|| format!("Error reading sitemap {}", file.display()))?;
+            Ok(Some(String::from_utf8(xml).with_context(|| {
+                format!("sitemap {} is not valid UTF-8", file.display())
+            })?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Return the URLs listed by a sitemap or sitemap index.
+fn locations(xml: &str) -> Vec<String> {
+    let loc = Regex::new(r"(?s)<loc>\s*(.*?)\s*</loc>").expect("valid location pattern");
+    loc.captures_iter(xml)
+        .map(|caps| {
+            caps[1]
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&")
+        })
+        .collect()
+}
+
+/// Return the path relative to the component route and the query string of a URL, if the URL is
+/// under the route whose path is `base`.
+fn relative(url: &str, base: &str) -> Option<(String, String)> {
+    let url = url.parse::<Uri>().ok()?;
+    let rest = url.path().strip_prefix(base)?;
+    let path = match rest {
+        "" => "/",
+        rest if rest.starts_with('/') => rest,
+        _ => return None,
+    };
+    Some((
+        path.to_string(),
+        url.query().unwrap_or_default().to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locations() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+              <url><loc>https://example.com/docs/</loc><lastmod>2024-01-01</lastmod></url>
+              <url>
+                <loc>
+                  https://example.com/docs/search?q=a&amp;page=2
+                </loc>
+              </url>
+            </urlset>"#;
+        assert_eq!(
+            locations(xml),
+            [
+                "https://example.com/docs/",
+                "https://example.com/docs/search?q=a&page=2"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_relative() {
+        assert_eq!(
+            relative("https://example.com/docs/search?q=a", "/docs"),
+            Some(("/search".to_string(), "q=a".to_string()))
+        );
+        assert_eq!(
+            relative("https://example.com/docs", "/docs"),
+            Some(("/".to_string(), String::new()))
+        );
+        assert_eq!(relative("https://example.com/docsite/", "/docs"), None);
+        assert_eq!(relative("https://example.com/blog/", "/docs"), None);
+        assert_eq!(
+            relative("https://example.com/blog/", ""),
+            Some(("/blog/".to_string(), String::new()))
+        );
+    }
+}
@@ -0,0 +1,40 @@
+//! Limits on the size of the files served, protecting small instances from files mounted by
+//! accident, such as multi-gigabyte build artifacts: files above a maximum size aren't served at
+//! all, and files above a smaller threshold are sent without compressing them on the fly.
+
+use http::StatusCode;
+
+use crate::{plain_response, settings, Response};
+
+/// Environment variable for the size in bytes of the largest files served
+pub(crate) const MAX_FILE_SIZE_ENV: &str = "MAX_FILE_SIZE";
+/// Environment variable for the status of responses for larger files, 403 or 413
+pub(crate) const MAX_FILE_SIZE_STATUS_ENV: &str = "MAX_FILE_SIZE_STATUS";
+/// Environment variable for the size in bytes of the largest files compressed on the fly
+pub(crate) const COMPRESSION_MAX_SIZE_ENV: &str = "COMPRESSION_MAX_SIZE";
+/// Statuses responses for files above the maximum size can have
+pub(crate) const MAX_FILE_SIZE_STATUSES: [u16; 2] = [403, 413];
+
+/// Return the size of a setting, if set to a whole number.
+fn size(setting: &str) -> Option<u64> {
+    settings::var(setting).and_then(|v| v.trim().parse().ok())
+}
+
+/// Answer requests for files above the maximum size.
+/// Returns `None` if the file may be served.
+pub(crate) fn check(len: u64) -> Option<Response> {
+    if size(MAX_FILE_SIZE_ENV).is_none_or(|max| len <= max) {
+        return None;
+    }
+    let status = settings::var(MAX_FILE_SIZE_STATUS_ENV)
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|status| MAX_FILE_SIZE_STATUSES.contains(status))
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .unwrap_or(StatusCode::FORBIDDEN);
+    Some(plain_response(status))
+}
+
+/// Check whether a file of the given size may be compressed on the fly.
+pub(crate) fn compresses(len: u64) -> bool {
+    size(COMPRESSION_MAX_SIZE_ENV).is_none_or(|max| len <= max)
+}
@@ -0,0 +1,143 @@
+//! Sites served from a subdirectory named after the subdomain of the request, so that
+//! `pr-42.preview.example.com` serves `sites/pr-42/`. Preview systems can then publish a directory
+//! per branch and have it served at once, without changing the configuration.
+//!
+//! The subdirectory is the root of the request, isolated from the other sites like the roots of
+//! the root header.
+
+use regex::Regex;
+
+use crate::{access_log::strip_port, request_url::RequestUrl, settings, RequestContext};
+
+/// Environment variable for the wildcard domain and the directory holding its sites, such as
+/// `*.preview.example.com=sites`
+pub(crate) const SUBDOMAIN_ROOTS_ENV: &str = "SUBDOMAIN_ROOTS";
+/// Environment variable for the pattern subdomains must match to be served
+pub(crate) const SUBDOMAIN_PATTERN_ENV: &str = "SUBDOMAIN_PATTERN";
+
+/// A wildcard domain whose subdomains are served from the subdirectories of a directory.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct SubdomainRoots {
+    /// The domain under the wildcard, such as `preview.example.com`.
+    domain: String,
+    /// The directory holding a subdirectory per subdomain.
+    dir: String,
+}
+
+impl SubdomainRoots {
+    /// Return the configured mapping, if any.
+    pub fn from_env() -> Option<Self> {
+        Self::parse(&settings::var(SUBDOMAIN_ROOTS_ENV)?)
+    }
+
+    /// Parse a `*.domain=directory` mapping.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (pattern, dir) = value.split_once('=')?;
+        let domain = pattern.trim().strip_prefix("*.")?.trim_end_matches('.');
+        let dir = dir.trim().trim_matches('/');
+        let valid = !domain.is_empty()
+            && !domain.contains('*')
+            && !dir.is_empty()
+            && dir
+                .split('/')
+                .all(|segment| !segment.is_empty() && segment != "." && segment != "..");
+        valid.then(|| Self {
+            domain: domain.to_ascii_lowercase(),
+            dir: dir.to_string(),
+        })
+    }
+
+    /// Return the subdirectory serving a host, if it is a subdomain of the wildcard domain.
+    /// Subdomains that aren't a single DNS label or don't match the pattern are errors.
+    fn root(&self, host: &str, pattern: Option<&Regex>) -> Option<Result<String, String>> {
+        let host = strip_port(host).trim_end_matches('.');
+        let subdomain = host.strip_suffix(&self.domain)?.strip_suffix('.')?;
+        let is_label = !subdomain.is_empty()
+            && subdomain.len() <= 63
+            && subdomain
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && !subdomain.starts_with('-')
+            && !subdomain.ends_with('-');
+        let allowed = is_label && pattern.is_none_or(|pattern| pattern.is_match(subdomain));
+        Some(if allowed {
+            Ok(format!("{}/{subdomain}", self.dir))
+        } else {
+            Err(format!("{host} is not a served subdomain"))
+        })
+    }
+}
+
+/// Return the subdirectory the host of a request is served from, if subdomain roots are
+/// configured and the host is a subdomain of the wildcard domain.
+pub(crate) fn root(req: &RequestContext) -> Option<Result<String, String>> {
+    let roots = SubdomainRoots::from_env()?;
+    let host = RequestUrl::of(req).host()?;
+    // an invalid pattern is reported by the configuration check, and serves no subdomain
+    let pattern = match settings::var(SUBDOMAIN_PATTERN_ENV) {
+        Some(pattern) => match Regex::new(pattern.trim()) {
+            Ok(pattern) => Some(pattern),
+            Err(_) => return Some(Err(format!("{SUBDOMAIN_PATTERN_ENV} is invalid"))),
+        },
+        None => None,
+    };
+    roots.root(&host, pattern.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            SubdomainRoots::parse("*.Preview.example.com = /sites/"),
+            Some(SubdomainRoots {
+                domain: "preview.example.com".to_string(),
+                dir: "sites".to_string(),
+            })
+        );
+        for invalid in [
+            "preview.example.com=sites",
+            "*.example.com",
+            "*.=sites",
+            "*.*.example.com=sites",
+            "*.example.com=../sites",
+            "*.example.com=",
+        ] {
+            assert_eq!(SubdomainRoots::parse(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_root() {
+        let roots = SubdomainRoots::parse("*.preview.example.com=sites").unwrap();
+        assert_eq!(
+            roots.root("pr-42.preview.example.com:3000", None),
+            Some(Ok("sites/pr-42".to_string()))
+        );
+        assert_eq!(roots.root("preview.example.com", None), None);
+        assert_eq!(roots.root("pr-42.example.com", None), None);
+        assert_eq!(roots.root("xpreview.example.com", None), None);
+        for invalid in [
+            "a.b.preview.example.com",
+            "-x.preview.example.com",
+            "a_b.preview.example.com",
+            "..preview.example.com",
+        ] {
+            assert!(
+                roots.root(invalid, None).is_some_and(|root| root.is_err()),
+                "{invalid}"
+            );
+        }
+
+        let pattern = Regex::new("^pr-[0-9]+$").unwrap();
+        assert_eq!(
+            roots.root("pr-7.preview.example.com", Some(&pattern)),
+            Some(Ok("sites/pr-7".to_string()))
+        );
+        assert!(roots
+            .root("main.preview.example.com", Some(&pattern))
+            .is_some_and(|root| root.is_err()));
+    }
+}
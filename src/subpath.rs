@@ -0,0 +1,208 @@
+//! Rewriting of root-relative URLs in HTML and CSS documents, so sites built to be served at `/`
+//! work unchanged when the component is mounted under another route, such as `/docs/...`.
+//!
+//! URLs starting with a single `/` in `href`, `src`, `srcset`, `action` and `poster` attributes,
+//! CSS `url()` references and `@import` rules get the path of the component route prepended,
+//! including the `X-Forwarded-Prefix` of trusted proxies. Protocol-relative URLs and URLs already
+//! under the route are left alone. The prepended path is escaped for the attribute or style sheet
+//! it ends up in, so it can't break out of the URL.
+
+use regex::{Captures, Regex};
+use std::{fmt::Write, path::Path};
+
+use crate::{request_url, settings};
+
+/// Environment variable enabling the rewriting of root-relative URLs
+pub(crate) const REWRITE_ROOT_URLS_ENV: &str = "REWRITE_ROOT_URLS";
+
+/// The kind of document URLs are rewritten in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Document {
+    Html,
+    Css,
+}
+
+/// Return the kind of document a file is, if its URLs are rewritten.
+fn document(file: &Path) -> Option<Document> {
+    let enabled = settings::var(REWRITE_ROOT_URLS_ENV).is_some_and(|v| v.trim() == "true");
+    if !enabled {
+        return None;
+    }
+    let extension = file.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => Some(Document::Html),
+        "css" => Some(Document::Css),
+        _ => None,
+    }
+}
+
+/// Check whether the URLs of a file are rewritten when served.
+pub(crate) fn applies(file: &Path) -> bool {
+    document(file).is_some()
+}
+
+/// Check whether the response for a file depends on the `X-Forwarded-Prefix` header, which its
+/// rewritten URLs include.
+pub(crate) fn varies(file: &Path) -> bool {
+    applies(file) && request_url::trusts_forwarded_headers()
+}
+
+/// Rewrite the root-relative URLs of a file for a component route whose path is `base`.
+pub(crate) fn rewrite(file: &Path, contents: String, base: &str) -> String {
+    let base = base.trim_end_matches('/');
+    match document(file) {
+        Some(_) if base.is_empty() => contents,
+        Some(Document::Html) => css(&html(&contents, base), base),
+        Some(Document::Css) => css(&contents, base),
+        None => contents,
+    }
+}
+
+/// Prefix a URL with the base path, escaped with `escape`, if it is root-relative.
+fn prefix(url: &str, base: &str, escape: fn(&str) -> String) -> String {
+    let root_relative = url.starts_with('/') && !url.starts_with("//");
+    let under_base = url
+        .strip_prefix(base)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['/', '?', '#']));
+    if root_relative && !under_base {
+        format!("{}{url}", escape(base))
+    } else {
+        url.to_string()
+    }
+}
+
+/// Escape text for an HTML attribute value.
+fn escape_attribute(text: &str) -> String {
+    text.chars().fold(String::new(), |mut escaped, c| {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            c => escaped.push(c),
+        }
+        escaped
+    })
+}
+
+/// Escape text for a CSS string or unquoted `url()`, as hexadecimal escapes of every character
+/// other than ASCII letters, digits, `/`, `-`, `_` and `.`.
+fn escape_css(text: &str) -> String {
+    text.chars().fold(String::new(), |mut escaped, c| {
+        if c.is_ascii_alphanumeric() || "/-_.".contains(c) {
+            escaped.push(c);
+        } else {
+            let _ = write!(escaped, "\\{:x} ", c as u32);
+        }
+        escaped
+    })
+}
+
+/// Rewrite the URL attributes of an HTML document.
+fn html(src: &str, base: &str) -> String {
+    let attribute = Regex::new(r#"(?i)\b(href|src|srcset|action|poster)\s*=\s*("[^"]*"|'[^']*')"#)
+        .expect("valid attribute pattern");
+    attribute
+        .replace_all(src, |caps: &Captures| {
+            let quoted = &caps[2];
+            let (quote, value) = (&quoted[..1], &quoted[1..quoted.len() - 1]);
+            let value = if caps[1].eq_ignore_ascii_case("srcset") {
+                // candidates are URLs followed by an optional descriptor
+                value
+                    .split(',')
+                    .map(|candidate| {
+                        let trimmed = candidate.trim_start();
+                        let leading = &candidate[..candidate.len() - trimmed.len()];
+                        format!("{leading}{}", prefix(trimmed, base, escape_attribute))
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",")
+            } else {
+                prefix(value, base, escape_attribute)
+            };
+            format!("{}={quote}{value}{quote}", &caps[1])
+        })
+        .into_owned()
+}
+
+/// Rewrite the `url()` references and `@import` rules of a style sheet, or of the inline styles
+/// of an HTML document.
+fn css(src: &str, base: &str) -> String {
+    let reference = Regex::new(r#"(?i)(url\(\s*|@import\s+)("[^"]*"|'[^']*'|[^\s"'()]+)"#)
+        .expect("valid reference pattern");
+    reference
+        .replace_all(src, |caps: &Captures| {
+            let value = &caps[2];
+            let rewritten = match value.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let url = prefix(&value[1..value.len() - 1], base, escape_css);
+                    format!("{quote}{url}{quote}")
+                }
+                _ => prefix(value, base, escape_css),
+            };
+            format!("{}{rewritten}", &caps[1])
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_html() {
+        assert_eq!(
+            html(
+                r#"<a href="/guide/">Guide</a> <img SRC='/logo.png' srcset="/a.png 1x, /b.png 2x">"#,
+                "/docs"
+            ),
+            r#"<a href="/docs/guide/">Guide</a> <img SRC='/docs/logo.png' srcset="/docs/a.png 1x, /docs/b.png 2x">"#
+        );
+        for unchanged in [
+            r#"<a href="//cdn.example.com/a.js">"#,
+            r#"<a href="https://example.com/">"#,
+            r#"<a href="guide/">"#,
+            r#"<a href="/docs/guide/">"#,
+            r#"<a href="/docs">"#,
+            r##"<a href="#top">"##,
+        ] {
+            assert_eq!(html(unchanged, "/docs"), unchanged);
+        }
+        assert_eq!(
+            html(r#"<a href="/docsite/">"#, "/docs"),
+            r#"<a href="/docs/docsite/">"#
+        );
+    }
+
+    #[test]
+    fn test_css() {
+        assert_eq!(
+            css(
+                r#"@import "/base.css"; body { background: url(/bg.png) } i { src: url( '/i.woff' ) }"#,
+                "/docs"
+            ),
+            r#"@import "/docs/base.css"; body { background: url(/docs/bg.png) } i { src: url( '/docs/i.woff' ) }"#
+        );
+        assert_eq!(
+            css("a { background: url(data:image/png;base64,AAAA) }", "/docs"),
+            "a { background: url(data:image/png;base64,AAAA) }"
+        );
+    }
+
+    #[test]
+    fn test_escaped_base() {
+        let base = r#"/x"><script>alert(1)</script>"#;
+        assert_eq!(
+            html(r#"<a href="/guide/"><img src='/a.png'>"#, base),
+            r#"<a href="/x&quot;&gt;&lt;script&gt;alert(1)&lt;/script&gt;/guide/"><img src='/x&quot;&gt;&lt;script&gt;alert(1)&lt;/script&gt;/a.png'>"#
+        );
+        assert_eq!(
+            css(
+                r#"a { background: url(/bg.png) } @import "/b.css";"#,
+                "/a b\")"
+            ),
+            r#"a { background: url(/a\20 b\22 \29 /bg.png) } @import "/a\20 b\22 \29 /b.css";"#
+        );
+    }
+}
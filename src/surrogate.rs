@@ -0,0 +1,94 @@
+//! `Surrogate-Control` and `Surrogate-Key` headers, read by CDNs such as Fastly to cache responses
+//! separately from browsers and to purge them by tag.
+
+use std::path::Path;
+
+use crate::settings;
+
+/// Environment variable for the `Surrogate-Control` header of responses
+pub(crate) const SURROGATE_CONTROL_ENV: &str = "SURROGATE_CONTROL";
+/// Environment variable for the comma-separated list of surrogate keys of responses
+pub(crate) const SURROGATE_KEYS_ENV: &str = "SURROGATE_KEYS";
+/// Name of the `Surrogate-Control` header
+const SURROGATE_CONTROL: &str = "surrogate-control";
+/// Name of the `Surrogate-Key` header
+const SURROGATE_KEY: &str = "surrogate-key";
+
+/// Return the surrogate headers of a response for a request path, if configured.
+pub(crate) fn headers(path: &str) -> Vec<(String, Vec<u8>)> {
+    let mut headers = Vec::new();
+    if let Some(control) = settings::var(SURROGATE_CONTROL_ENV) {
+        headers.push((
+            SURROGATE_CONTROL.to_string(),
+            control.trim().as_bytes().to_vec(),
+        ));
+    }
+    if let Some(keys) = settings::var(SURROGATE_KEYS_ENV) {
+        let keys = keys_for(&keys, path);
+        if !keys.is_empty() {
+            headers.push((SURROGATE_KEY.to_string(), keys.join(" ").into_bytes()));
+        }
+    }
+    headers
+}
+
+/// Derive the keys of a request path from the configured list, where `path` stands for the path
+/// itself, `directory` for each of its parent directories, `extension` for its file extension, and
+/// anything else for a fixed key.
+fn keys_for(configured: &str, path: &str) -> Vec<String> {
+    let path = format!("/{}", path.trim_start_matches('/'));
+    let mut keys = Vec::new();
+    for kind in configured
+        .split(',')
+        .map(str::trim)
+        .filter(|k| !k.is_empty())
+    {
+        match kind {
+            "path" => keys.push(path.clone()),
+            "directory" => {
+                keys.extend(path.match_indices('/').map(|(i, _)| path[..=i].to_string()))
+            }
+            "extension" => keys.extend(
+                Path::new(&path)
+                    .extension()
+                    .map(|ext| format!(".{}", ext.to_string_lossy().to_lowercase())),
+            ),
+            key => keys.push(key.to_string()),
+        }
+    }
+    // keys are separated by spaces in the header
+    keys.into_iter()
+        .map(|key| key.replace(' ', "%20"))
+        .fold(Vec::new(), |mut keys, key| {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+            keys
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_for() {
+        assert_eq!(
+            keys_for(
+                "path, directory, extension, site-docs",
+                "docs/api/Index.HTML"
+            ),
+            [
+                "/docs/api/Index.HTML",
+                "/",
+                "/docs/",
+                "/docs/api/",
+                ".html",
+                "site-docs"
+            ]
+        );
+        assert_eq!(keys_for("path", "/my file.txt"), ["/my%20file.txt"]);
+        assert_eq!(keys_for("path, path", "/"), ["/"]);
+        assert_eq!(keys_for("extension", "/docs/"), Vec::<String>::new());
+    }
+}
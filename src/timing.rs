@@ -0,0 +1,128 @@
+//! Per-stage request timings, logged to stderr and reported in the `Server-Timing` response
+//! header of requests carrying the admin token.
+//!
+//! These aren't OpenTelemetry spans or metrics, and don't replace them: version 3.0 of the Spin SDK,
+//! which the component is built with, has no API for emitting telemetry, so the file server's
+//! stages don't show up in OpenTelemetry traces yet.
+
+use http::header::HeaderName;
+use std::time::{Duration, Instant};
+
+use crate::{
+    admin::{self, ADMIN_TOKEN_ENV},
+    settings, RequestContext,
+};
+
+/// Environment variable enabling stage timing logs and the `Server-Timing` header
+pub(crate) const SERVER_TIMING_ENV: &str = "SERVER_TIMING";
+/// The `Server-Timing` header, which the `http` crate doesn't define
+const SERVER_TIMING: HeaderName = HeaderName::from_static("server-timing");
+
+/// Check whether timings are enabled.
+pub(crate) fn enabled() -> bool {
+    settings::var(SERVER_TIMING_ENV).is_some_and(|v| v.trim() == "true")
+}
+
+/// Durations of the stages of building a response.
+#[derive(Debug, Default)]
+pub(crate) struct Timings {
+    enabled: bool,
+    /// Whether the request may see the timings, which reveal what the server does internally.
+    exposed: bool,
+    stages: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    /// Time a request, exposing the timings to it if it carries the admin token.
+    pub fn from_env(req: &RequestContext) -> Self {
+        let enabled = enabled();
+        let exposed = enabled
+            && settings::var(ADMIN_TOKEN_ENV)
+                .is_some_and(|token| admin::is_authorized(req, &token));
+        Self {
+            enabled,
+            exposed,
+            stages: Vec::new(),
+        }
+    }
+
+    /// Run `f`, recording its duration as `stage` if timings are enabled.
+    pub fn measure<T>(&mut self, stage: &'static str, f: impl FnOnce() -> T) -> T {
+        if !self.enabled {
+            return f();
+        }
+        let start = Instant::now();
+        let result = f();
+        self.stages.push((stage, start.elapsed()));
+        result
+    }
+
+    /// Return the `Server-Timing` header for the recorded stages, if the request may see them.
+    pub fn header(&self) -> Option<(String, Vec<u8>)> {
+        if !self.exposed {
+            return None;
+        }
+        let value = self
+            .stages
+            .iter()
+            .map(|(stage, duration)| format!("{stage};dur={:.3}", duration.as_secs_f64() * 1000.0))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Some((SERVER_TIMING.as_str().to_string(), value.into_bytes()))
+    }
+
+    /// Log the recorded stages of the request for a path, if timings are enabled.
+    pub fn log(&self, path: &str) {
+        if let Some(summary) = self.summary() {
+            eprintln!("Timings for {path}: {summary}");
+        }
+    }
+
+    fn summary(&self) -> Option<String> {
+        if !self.enabled || self.stages.is_empty() {
+            return None;
+        }
+        Some(
+            self.stages
+                .iter()
+                .map(|(stage, duration)| {
+                    format!("{stage}={:.3}ms", duration.as_secs_f64() * 1000.0)
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timings_header() {
+        let mut timings = Timings {
+            enabled: true,
+            exposed: true,
+            stages: vec![("resolve", Duration::from_micros(1500))],
+        };
+        assert_eq!(timings.measure("etag", || 42), 42);
+        let (name, value) = timings.header().unwrap();
+        assert_eq!(name, "server-timing");
+        let value = String::from_utf8(value).unwrap();
+        assert!(value.starts_with("resolve;dur=1.500, etag;dur="), "{value}");
+        assert!(timings
+            .summary()
+            .unwrap()
+            .starts_with("resolve=1.500ms, etag="));
+
+        // timings are only logged for other requests
+        timings.exposed = false;
+        assert!(timings.header().is_none());
+        assert!(timings.summary().is_some());
+
+        let mut timings = Timings::default();
+        timings.measure("resolve", || ());
+        assert!(timings.header().is_none());
+        assert!(timings.summary().is_none());
+    }
+}
@@ -0,0 +1,83 @@
+//! Build metadata of the deployed component, so deployment tooling can check which build is live:
+//! the version of the component, when it was built, the backend files are read from and the
+//! digest of the asset manifest.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::{
+    access_log::civil_time,
+    admin::{self, ADMIN_PREFIX},
+    asset_manifest::AssetManifest,
+    backend::{self, DEFAULT_BACKEND, STORAGE_BACKEND_ENV},
+    settings, Response,
+};
+
+/// Environment variable serving the version route without the admin token
+pub(crate) const VERSION_ROUTE_ENV: &str = "VERSION_ROUTE";
+/// Admin route of the build metadata
+pub(crate) const VERSION_ROUTE: &str = "version";
+/// Seconds since the Unix epoch the component was built at, recorded by the build script
+const BUILT_AT: &str = env!("SPIN_FILESERVER_BUILT_AT");
+
+/// The build metadata served by the version route.
+#[derive(Debug, Serialize)]
+pub(crate) struct Version {
+    /// The version of the component.
+    version: &'static str,
+    /// When the component was built, in RFC 3339 format.
+    built_at: String,
+    /// The backend files are read from.
+    backend: String,
+    /// The SHA-256 digest of the asset manifest, if one is configured.
+    asset_manifest_sha256: Option<String>,
+}
+
+impl Version {
+    /// Return the metadata of the running build.
+    pub fn current() -> Result<Self> {
+        let backend = settings::var(STORAGE_BACKEND_ENV)
+            .map(|name| name.trim().to_string())
+            .filter(|name| backend::is_known(name))
+            .unwrap_or_else(|| DEFAULT_BACKEND.to_string());
+        Ok(Self {
+            version: env!("CARGO_PKG_VERSION"),
+            built_at: rfc3339(BUILT_AT.parse().unwrap_or_default()),
+            backend,
+            asset_manifest_sha256: AssetManifest::digest()?,
+        })
+    }
+}
+
+/// Serve the version route to requests without the admin token, if enabled.
+/// Returns `None` if the path isn't the version route or it is only an admin route.
+pub(crate) fn handle(path: &str) -> Option<Result<Response>> {
+    let enabled = settings::var(VERSION_ROUTE_ENV).is_some_and(|v| v.trim() == "true");
+    if !enabled || path.strip_prefix(ADMIN_PREFIX) != Some(VERSION_ROUTE) {
+        return None;
+    }
+    Some(Version::current().and_then(|version| admin::json_response(&version)))
+}
+
+/// Format seconds since the Unix epoch as an RFC 3339 UTC time.
+pub(crate) fn rfc3339(secs: u64) -> String {
+    let ((year, month, day), time) = civil_time(UNIX_EPOCH + Duration::from_secs(secs));
+    format!("{year:04}-{month:02}-{day:02}T{time}Z")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(1709208000), "2024-02-29T12:00:00Z");
+    }
+
+    #[test]
+    fn test_built_at() {
+        assert!(BUILT_AT.parse::<u64>().is_ok(), "{BUILT_AT}");
+    }
+}
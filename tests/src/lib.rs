@@ -11,15 +11,60 @@ fn defaults_to_index() {
     );
 }
 
+// Files only needed by the tests are in `tests/fixtures`, which is mounted along with the rest
+// of the repository at `/tests/fixtures`.
+
+fn get(path: &str) -> http::types::IncomingResponse {
+    let request = http::types::OutgoingRequest::new(http::types::Headers::new());
+    request.set_path_with_query(Some(path)).unwrap();
+    spin_test_sdk::perform_request(request)
+}
+
 #[spin_test]
 fn defaults_to_index_within_directory() {
-    let request = http::types::OutgoingRequest::new(http::types::Headers::new());
-    request.set_path_with_query(Some("/subdirectory")).unwrap();
-    let _response = spin_test_sdk::perform_request(request);
-    // TODO: we need the ability to add a file to the file system within the test.
-    // The file should be `subdirectory/index.html`
+    for path in [
+        "/tests/fixtures/subdirectory",
+        "/tests/fixtures/subdirectory/",
+    ] {
+        let response = get(path);
+        assert_eq!(response.status(), 200, "{path}");
+        assert_eq!(
+            response.body_as_string().unwrap(),
+            "<html>\n\n<body>\n    This is index in subdirectory\n</body>\n\n</html>\n"
+        );
+    }
+}
+
+#[spin_test]
+fn directory_without_index_is_not_found() {
+    let response = get("/tests/fixtures/no-index");
+    assert_eq!(response.status(), 404);
+    assert_eq!(get("/tests/fixtures/no-index/page.html").status(), 200);
+}
+
+#[spin_test]
+fn serves_dotfiles() {
+    let response = get("/tests/fixtures/.dotfile");
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.body_as_string().unwrap(), "dotfile\n");
+
+    let response = get("/tests/fixtures/.well-known/security.txt");
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.body_as_string().unwrap(),
+        "Contact: mailto:security@example.com\n"
+    );
+}
 
-    // assert_eq!(response.status(), 200);
+#[spin_test]
+fn traversal_stays_within_mounted_files() {
+    // `..` can't leave the directory mounted at `/`
+    for path in [
+        "/../../../etc/passwd",
+        "/tests/fixtures/../../../../etc/passwd",
+    ] {
+        assert_eq!(get(path).status(), 404, "{path}");
+    }
 }
 
 #[spin_test]
@@ -73,7 +118,7 @@ fn fetches_file() {
     assert_eq!(response.status(), 200);
     assert_eq!(
         response.headers().get(&"etag".into()),
-        vec![hex_encoded_sha256(&readme)]
+        vec![quoted(std::str::from_utf8(&hex_encoded_sha256(&readme)).unwrap()).into_bytes()]
     );
     let body = response.body_as_string().unwrap();
     let expected_body_begin = "# Static file server for Spin applications";
@@ -129,3 +174,362 @@ fn prefers_brotoli_encoding_multi_header() {
         vec![String::from("br").into_bytes()]
     );
 }
+
+// Conformance with the conditional request, range and caching semantics of RFC 9110 and RFC 9111,
+// checked against `hello-test.txt` ("hello, world!\n", 14 bytes).
+
+const HELLO_ETAG: &str = "4dca0fd5f424a31b03ab807cbae77eb32bf2d089eed1cee154b3afed458de0dc";
+
+fn hello_request(headers: &[(&str, &str)]) -> http::types::OutgoingRequest {
+    let fields = http::types::Headers::new();
+    for (name, value) in headers {
+        fields
+            .append(&name.to_string(), &value.as_bytes().to_vec())
+            .unwrap();
+    }
+    let request = http::types::OutgoingRequest::new(fields);
+    request
+        .set_path_with_query(Some("/hello-test.txt"))
+        .unwrap();
+    request
+}
+
+fn quoted(etag: &str) -> String {
+    format!("\"{etag}\"")
+}
+
+#[spin_test]
+fn rfc9110_responses_carry_validators() {
+    let response = spin_test_sdk::perform_request(hello_request(&[]));
+    assert_eq!(response.status(), 200);
+    // section 8.8.3: entity tags are quoted strings
+    assert_eq!(
+        response.headers().get(&"etag".into()),
+        vec![quoted(HELLO_ETAG).into_bytes()]
+    );
+    assert_eq!(
+        response.headers().get(&"accept-ranges".into()),
+        vec![b"bytes".to_vec()]
+    );
+    // section 12.5.5: the encoding depends on Accept-Encoding
+    assert_eq!(
+        response.headers().get(&"vary".into()),
+        vec![b"accept-encoding".to_vec()]
+    );
+}
+
+#[spin_test]
+fn rfc9110_if_none_match_uses_weak_comparison() {
+    // section 13.1.2
+    for if_none_match in [
+        quoted(HELLO_ETAG),
+        format!("W/{}", quoted(HELLO_ETAG)),
+        format!("\"other\", {}", quoted(HELLO_ETAG)),
+        "*".to_string(),
+        // clients that stored the entity tag unquoted, as it used to be sent
+        HELLO_ETAG.to_string(),
+    ] {
+        let response =
+            spin_test_sdk::perform_request(hello_request(&[("if-none-match", &if_none_match)]));
+        assert_eq!(response.status(), 304, "{if_none_match}");
+        assert!(response.body().unwrap().is_empty());
+        // section 15.4.5: a 304 carries the headers a 200 would have
+        assert_eq!(
+            response.headers().get(&"etag".into()),
+            vec![quoted(HELLO_ETAG).into_bytes()]
+        );
+        assert_eq!(
+            response.headers().get(&"vary".into()),
+            vec![b"accept-encoding".to_vec()]
+        );
+    }
+
+    let response = spin_test_sdk::perform_request(hello_request(&[("if-none-match", "\"other\"")]));
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.body_as_string().unwrap(), "hello, world!\n");
+}
+
+#[spin_test]
+fn rfc9110_if_match_uses_strong_comparison() {
+    // section 13.1.1
+    for (if_match, expected) in [
+        (quoted(HELLO_ETAG), 200),
+        (format!("\"other\", {}", quoted(HELLO_ETAG)), 200),
+        ("*".to_string(), 200),
+        (format!("W/{}", quoted(HELLO_ETAG)), 412),
+        ("\"other\"".to_string(), 412),
+    ] {
+        let response = spin_test_sdk::perform_request(hello_request(&[("if-match", &if_match)]));
+        assert_eq!(response.status(), expected, "{if_match}");
+    }
+}
+
+#[spin_test]
+fn rfc9110_conditions_are_evaluated_in_order() {
+    // section 13.2.2: If-Match, then If-None-Match, then Range
+    let current = quoted(HELLO_ETAG);
+    let response = spin_test_sdk::perform_request(hello_request(&[
+        ("if-match", "\"other\""),
+        ("if-none-match", &current),
+        ("range", "bytes=0-4"),
+    ]));
+    assert_eq!(response.status(), 412);
+
+    let response = spin_test_sdk::perform_request(hello_request(&[
+        ("if-none-match", &current),
+        ("range", "bytes=0-4"),
+    ]));
+    assert_eq!(response.status(), 304);
+
+    let response = spin_test_sdk::perform_request(hello_request(&[
+        ("if-match", &current),
+        ("range", "bytes=0-4"),
+    ]));
+    assert_eq!(response.status(), 206);
+}
+
+#[spin_test]
+fn rfc9110_single_byte_ranges() {
+    // section 14.1.2 and 15.3.7
+    for (range, content_range, body) in [
+        ("bytes=0-4", "bytes 0-4/14", "hello"),
+        ("bytes=7-", "bytes 7-13/14", "world!\n"),
+        ("bytes=-6", "bytes 8-13/14", "orld!\n"),
+        ("bytes=10-100", "bytes 10-13/14", "ld!\n"),
+    ] {
+        let response = spin_test_sdk::perform_request(hello_request(&[("range", range)]));
+        assert_eq!(response.status(), 206, "{range}");
+        assert_eq!(
+            response.headers().get(&"content-range".into()),
+            vec![content_range.as_bytes().to_vec()]
+        );
+        assert_eq!(response.body_as_string().unwrap(), body);
+    }
+
+    // section 15.5.17
+    let response = spin_test_sdk::perform_request(hello_request(&[("range", "bytes=20-")]));
+    assert_eq!(response.status(), 416);
+    assert_eq!(
+        response.headers().get(&"content-range".into()),
+        vec![b"bytes */14".to_vec()]
+    );
+
+    // section 14.2: ranges a server doesn't support are ignored
+    for range in ["bytes=0-1,3-4", "items=0-1", "bytes=4-0"] {
+        let response = spin_test_sdk::perform_request(hello_request(&[("range", range)]));
+        assert_eq!(response.status(), 200, "{range}");
+        assert_eq!(response.body_as_string().unwrap(), "hello, world!\n");
+    }
+}
+
+#[spin_test]
+fn rfc9110_if_range_requires_a_strong_match() {
+    // section 13.1.5
+    for (if_range, expected) in [
+        (quoted(HELLO_ETAG), 206),
+        (format!("W/{}", quoted(HELLO_ETAG)), 200),
+        ("\"other\"".to_string(), 200),
+        ("Wed, 21 Oct 2015 07:28:00 GMT".to_string(), 200),
+    ] {
+        let response = spin_test_sdk::perform_request(hello_request(&[
+            ("range", "bytes=7-"),
+            ("if-range", &if_range),
+        ]));
+        assert_eq!(response.status(), expected, "{if_range}");
+    }
+}
+
+#[spin_test]
+fn rfc9110_etags_are_specific_to_the_encoding() {
+    // section 8.8.3: representations in other encodings have their own entity tag
+    let response = spin_test_sdk::perform_request(hello_request(&[("accept-encoding", "br")]));
+    assert_eq!(response.status(), 200);
+    let etag = response.headers().get(&"etag".into()).remove(0);
+    assert_ne!(etag, quoted(HELLO_ETAG).as_bytes());
+
+    let response = spin_test_sdk::perform_request(hello_request(&[
+        ("accept-encoding", "br"),
+        ("if-none-match", &quoted(HELLO_ETAG)),
+    ]));
+    assert_eq!(response.status(), 200);
+    let response = spin_test_sdk::perform_request(hello_request(&[
+        ("accept-encoding", "br"),
+        ("if-none-match", &String::from_utf8(etag).unwrap()),
+    ]));
+    assert_eq!(response.status(), 304);
+}
+
+#[spin_test]
+fn rfc9110_ranges_are_served_unencoded() {
+    let response = spin_test_sdk::perform_request(hello_request(&[
+        ("accept-encoding", "gzip"),
+        ("range", "bytes=0-4"),
+    ]));
+    assert_eq!(response.status(), 206);
+    assert!(response
+        .headers()
+        .get(&"content-encoding".into())
+        .is_empty());
+    assert_eq!(
+        response.headers().get(&"vary".into()),
+        vec![b"accept-encoding".to_vec()]
+    );
+    assert_eq!(response.body_as_string().unwrap(), "hello");
+}
+
+// A matrix of encodings, conditional headers and the two storage backends, plus the built-in
+// favicon fallback, since bugs tend to show up in how they interact rather than in any one of
+// them. `spin.toml` declares the `storage_backend` and `storage_store` variables and the `site`
+// key-value store so tests can switch backends.
+
+/// Where the file served by a case of the matrix comes from.
+#[derive(Clone, Copy, Debug)]
+enum Source {
+    /// `hello-test.txt` in the mounted directory
+    Filesystem,
+    /// `hello-test.txt` in the `site` key-value store
+    KeyValue,
+    /// the favicon the file server falls back to, as no `favicon.ico` is mounted, whatever the
+    /// backend
+    FaviconFallback,
+}
+
+impl Source {
+    /// Configure the file server to serve from this source, and return the path to request and
+    /// the contents expected.
+    fn install(self) -> (&'static str, Vec<u8>) {
+        use spin_test_sdk::bindings::fermyon::spin_test_virt::{key_value, variables};
+        let hello = std::fs::read("hello-test.txt").unwrap();
+        match self {
+            Source::Filesystem => ("/hello-test.txt", hello),
+            Source::KeyValue => {
+                variables::set("storage_backend", "key-value");
+                variables::set("storage_store", "site");
+                key_value::Store::open("site").set("hello-test.txt", &hello);
+                ("/hello-test.txt", hello)
+            }
+            Source::FaviconFallback => ("/favicon.ico", std::fs::read("spin-favicon.ico").unwrap()),
+        }
+    }
+}
+
+const ENCODINGS: [&str; 4] = ["identity", "gzip", "deflate", "br"];
+
+fn sha256_hex(buffer: &[u8]) -> String {
+    use sha2::Digest;
+    hex::encode(sha2::Sha256::digest(buffer))
+}
+
+fn perform(path: &str, headers: &[(&str, &str)]) -> http::types::IncomingResponse {
+    let fields = http::types::Headers::new();
+    for (name, value) in headers {
+        fields
+            .append(&name.to_string(), &value.as_bytes().to_vec())
+            .unwrap();
+    }
+    let request = http::types::OutgoingRequest::new(fields);
+    request.set_path_with_query(Some(path)).unwrap();
+    spin_test_sdk::perform_request(request)
+}
+
+fn header(response: &http::types::IncomingResponse, name: &str) -> Option<String> {
+    let values = response.headers().get(&name.to_string());
+    values
+        .first()
+        .map(|value| String::from_utf8(value.clone()).unwrap())
+}
+
+/// Check the responses to every encoding and conditional header for one source.
+fn check_matrix(source: Source) {
+    let (path, contents) = source.install();
+    for encoding in ENCODINGS {
+        let case = format!("{source:?} {encoding}");
+        let accept = [("accept-encoding", encoding)];
+        let response = perform(path, &accept);
+        assert_eq!(response.status(), 200, "{case}");
+        let content_encoding = (encoding != "identity").then(|| encoding.to_string());
+        assert_eq!(
+            header(&response, "content-encoding"),
+            content_encoding,
+            "{case}"
+        );
+        assert_eq!(
+            header(&response, "vary").as_deref(),
+            Some("accept-encoding"),
+            "{case}"
+        );
+        let etag = header(&response, "etag").unwrap();
+        if encoding == "identity" {
+            // the entity tag depends on the bytes served, not on where they're stored
+            assert_eq!(etag, quoted(&sha256_hex(&contents)), "{case}");
+            assert_eq!(response.body().unwrap(), contents, "{case}");
+        } else {
+            assert_ne!(etag, quoted(&sha256_hex(&contents)), "{case}");
+        }
+
+        let current = etag.clone();
+        let weak = format!("W/{current}");
+        for (condition, value, expected) in [
+            ("if-none-match", current.as_str(), 304),
+            ("if-none-match", weak.as_str(), 304),
+            ("if-none-match", "\"other\"", 200),
+            ("if-match", current.as_str(), 200),
+            ("if-match", "\"other\"", 412),
+        ] {
+            let response = perform(path, &[accept[0], (condition, value)]);
+            assert_eq!(response.status(), expected, "{case} {condition}: {value}");
+            if expected == 304 {
+                assert_eq!(header(&response, "etag").as_ref(), Some(&etag), "{case}");
+                assert!(response.body().unwrap().is_empty(), "{case}");
+            }
+        }
+
+        // ranges are served unencoded, whatever the client accepts, and the fallback favicon is
+        // only served whole
+        let response = perform(path, &[accept[0], ("range", "bytes=0-4")]);
+        assert_eq!(header(&response, "content-encoding"), None, "{case} range");
+        if matches!(source, Source::FaviconFallback) {
+            assert_eq!(response.status(), 200, "{case} range");
+            assert_eq!(response.body().unwrap(), contents, "{case} range");
+        } else {
+            assert_eq!(response.status(), 206, "{case} range");
+            assert_eq!(response.body().unwrap(), &contents[..5], "{case} range");
+        }
+    }
+}
+
+#[spin_test]
+fn matrix_filesystem() {
+    check_matrix(Source::Filesystem);
+}
+
+#[spin_test]
+fn matrix_key_value() {
+    check_matrix(Source::KeyValue);
+}
+
+#[spin_test]
+fn matrix_favicon_fallback() {
+    check_matrix(Source::FaviconFallback);
+}
+
+#[spin_test]
+fn key_value_files_are_verified() {
+    use spin_test_sdk::bindings::fermyon::spin_test_virt::{key_value, variables};
+    let (path, hello) = Source::KeyValue.install();
+    variables::set("storage_checksums", "true");
+    let store = key_value::Store::open("site");
+    assert_eq!(perform(path, &[]).status(), 500);
+
+    store.set(
+        "spin-fileserver:sha256:hello-test.txt",
+        sha256_hex(&hello).as_bytes(),
+    );
+    let response = perform(path, &[]);
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.body().unwrap(), hello);
+
+    // a tampered value of the same size is refused
+    store.set("hello-test.txt", b"hello, there!\n");
+    assert_eq!(perform(path, &[]).status(), 500);
+}